@@ -0,0 +1,621 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use async_graphql::Enum;
+use async_stream::try_stream;
+use axum::body::Bytes;
+use chrono::{DateTime, Datelike, Timelike, Utc};
+use futures_util::{stream::BoxStream, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Enum, Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    /// Uncompressed zip, with ZIP64 extensions when needed
+    Zip,
+    /// POSIX tar, with PAX headers for long names and large files
+    Tar,
+}
+
+impl ArchiveFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "zip",
+            ArchiveFormat::Tar => "tar",
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Zip => "application/zip",
+            ArchiveFormat::Tar => "application/x-tar",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ArchiveEntry {
+    /// Path of the file on disk
+    pub path: PathBuf,
+    /// Path of the file inside the archive, always `/` separated
+    pub name: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Lists all files under `root`, named relative to the parent of `root`
+/// so the archive contains a single top level entry. Symbolic links are not followed.
+pub async fn collect_entries(root: PathBuf) -> io::Result<Vec<ArchiveEntry>> {
+    tokio::task::spawn_blocking(move || {
+        let base = root.parent().map(Path::to_path_buf).unwrap_or_default();
+        let mut entries = vec![];
+        let mut pending = vec![root];
+        while let Some(path) = pending.pop() {
+            // Links are skipped so the archive cant reach files outside of `root`
+            let metadata = std::fs::symlink_metadata(&path)?;
+            if metadata.is_dir() {
+                let mut children = std::fs::read_dir(&path)?
+                    .map(|entry| entry.map(|entry| entry.path()))
+                    .collect::<io::Result<Vec<_>>>()?;
+                // Reverse so popping walks the directory in name order
+                children.sort_by(|a, b| b.cmp(a));
+                pending.extend(children);
+            } else if metadata.is_file() {
+                let name = path
+                    .strip_prefix(&base)
+                    .unwrap_or(&path)
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy())
+                    .collect::<Vec<_>>()
+                    .join("/");
+                entries.push(ArchiveEntry {
+                    name,
+                    size: metadata.len(),
+                    modified: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                    path,
+                });
+            }
+        }
+        Ok(entries)
+    })
+    .await?
+}
+
+pub fn archive_stream(
+    format: ArchiveFormat,
+    entries: Vec<ArchiveEntry>,
+) -> BoxStream<'static, io::Result<Bytes>> {
+    match format {
+        ArchiveFormat::Zip => zip_stream(entries).boxed(),
+        ArchiveFormat::Tar => tar_stream(entries).boxed(),
+    }
+}
+
+/// Size of the archive `archive_stream` produces, failing for names the format cant hold
+pub fn archive_size(format: ArchiveFormat, entries: &[ArchiveEntry]) -> io::Result<u64> {
+    match format {
+        ArchiveFormat::Zip => zip_size(entries, ZIP64_LIMIT),
+        ArchiveFormat::Tar => Ok(entries
+            .iter()
+            .map(|entry| {
+                tar_entry_header(entry).len() as u64 + entry.size + tar_padding(entry.size) as u64
+            })
+            .sum::<u64>()
+            + TAR_BLOCK as u64 * 2),
+    }
+}
+
+/// Reads the next chunk of at most `remaining` bytes, failing if the file
+/// is shorter than it was when the entries were collected.
+async fn read_chunk(
+    file: &mut tokio::fs::File,
+    buf: &mut [u8],
+    remaining: u64,
+) -> io::Result<usize> {
+    let to_read = remaining.min(buf.len() as u64) as usize;
+    let read = file.read(&mut buf[..to_read]).await?;
+    if read == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file shrunk while archiving",
+        ));
+    }
+    Ok(read)
+}
+
+struct ZipCentralRecord {
+    name: String,
+    crc: u32,
+    size: u64,
+    offset: u64,
+    time: u16,
+    date: u16,
+    /// Whether the local header has a ZIP64 extra field
+    zip64: bool,
+}
+
+/// Marker written in 32 bit fields whose value is in the ZIP64 extra field
+const ZIP64_LIMIT: u64 = 0xFFFF_FFFF;
+/// General purpose flags: sizes in data descriptor, UTF-8 names
+const ZIP_FLAGS: u16 = 0x0808;
+/// Version made by: unix, spec 4.5
+const ZIP_VERSION_MADE_BY: u16 = (3 << 8) | 45;
+
+fn zip_version_needed(zip64: bool) -> u16 {
+    if zip64 {
+        45
+    } else {
+        20
+    }
+}
+
+fn check_zip_name(name: &str) -> io::Result<()> {
+    if name.len() > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Name too long for a zip archive {}", name),
+        ));
+    }
+    Ok(())
+}
+
+fn dos_datetime(modified: SystemTime) -> (u16, u16) {
+    let datetime: DateTime<Utc> = modified.into();
+    if datetime.year() < 1980 {
+        // Earliest representable date, 1980-01-01 00:00
+        return (0, (1 << 5) | 1);
+    }
+    let time = (datetime.hour() << 11) | (datetime.minute() << 5) | (datetime.second() / 2);
+    let date = (((datetime.year() - 1980) as u32) << 9) | (datetime.month() << 5) | datetime.day();
+    (time as u16, date as u16)
+}
+
+fn zip_local_header(name: &str, zip64: bool, time: u16, date: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(30 + name.len() + 20);
+    header.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+    header.extend_from_slice(&zip_version_needed(zip64).to_le_bytes());
+    header.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+    // Stored
+    header.extend_from_slice(&0u16.to_le_bytes());
+    header.extend_from_slice(&time.to_le_bytes());
+    header.extend_from_slice(&date.to_le_bytes());
+    // Crc and sizes follow in the data descriptor
+    header.extend_from_slice(&0u32.to_le_bytes());
+    let size_marker = if zip64 { ZIP64_LIMIT as u32 } else { 0 };
+    header.extend_from_slice(&size_marker.to_le_bytes());
+    header.extend_from_slice(&size_marker.to_le_bytes());
+    header.extend_from_slice(&(name.len() as u16).to_le_bytes());
+    let extra_len: u16 = if zip64 { 20 } else { 0 };
+    header.extend_from_slice(&extra_len.to_le_bytes());
+    header.extend_from_slice(name.as_bytes());
+    if zip64 {
+        header.extend_from_slice(&0x0001u16.to_le_bytes());
+        header.extend_from_slice(&16u16.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+        header.extend_from_slice(&0u64.to_le_bytes());
+    }
+    header
+}
+
+fn zip_data_descriptor(crc: u32, size: u64, zip64: bool) -> Vec<u8> {
+    let mut descriptor = Vec::with_capacity(24);
+    descriptor.extend_from_slice(&0x0807_4b50u32.to_le_bytes());
+    descriptor.extend_from_slice(&crc.to_le_bytes());
+    if zip64 {
+        descriptor.extend_from_slice(&size.to_le_bytes());
+        descriptor.extend_from_slice(&size.to_le_bytes());
+    } else {
+        descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+        descriptor.extend_from_slice(&(size as u32).to_le_bytes());
+    }
+    descriptor
+}
+
+/// Central directory for the records, `zip64_from` is the size or offset from which
+/// ZIP64 fields are used
+fn zip_central_directory(records: &[ZipCentralRecord], offset: u64, zip64_from: u64) -> Vec<u8> {
+    let mut directory = vec![];
+    for record in records {
+        let zip64 = record.zip64;
+        directory.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&ZIP_VERSION_MADE_BY.to_le_bytes());
+        directory.extend_from_slice(&zip_version_needed(zip64).to_le_bytes());
+        directory.extend_from_slice(&ZIP_FLAGS.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&record.time.to_le_bytes());
+        directory.extend_from_slice(&record.date.to_le_bytes());
+        directory.extend_from_slice(&record.crc.to_le_bytes());
+        let (size, local_offset) = if zip64 {
+            (ZIP64_LIMIT as u32, ZIP64_LIMIT as u32)
+        } else {
+            (record.size as u32, record.offset as u32)
+        };
+        directory.extend_from_slice(&size.to_le_bytes());
+        directory.extend_from_slice(&size.to_le_bytes());
+        directory.extend_from_slice(&(record.name.len() as u16).to_le_bytes());
+        let extra_len: u16 = if zip64 { 28 } else { 0 };
+        directory.extend_from_slice(&extra_len.to_le_bytes());
+        // Comment length, disk number, internal attributes
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        directory.extend_from_slice(&0u16.to_le_bytes());
+        // Regular file, rw-r--r--
+        directory.extend_from_slice(&(0o100644u32 << 16).to_le_bytes());
+        directory.extend_from_slice(&local_offset.to_le_bytes());
+        directory.extend_from_slice(record.name.as_bytes());
+        if zip64 {
+            directory.extend_from_slice(&0x0001u16.to_le_bytes());
+            directory.extend_from_slice(&24u16.to_le_bytes());
+            directory.extend_from_slice(&record.size.to_le_bytes());
+            directory.extend_from_slice(&record.size.to_le_bytes());
+            directory.extend_from_slice(&record.offset.to_le_bytes());
+        }
+    }
+
+    let directory_size = directory.len() as u64;
+    let count = records.len() as u64;
+    let zip64 = count >= 0xFFFF || directory_size >= zip64_from || offset >= zip64_from;
+    if zip64 {
+        let record_offset = offset + directory_size;
+        directory.extend_from_slice(&0x0606_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&44u64.to_le_bytes());
+        directory.extend_from_slice(&ZIP_VERSION_MADE_BY.to_le_bytes());
+        directory.extend_from_slice(&zip_version_needed(true).to_le_bytes());
+        directory.extend_from_slice(&0u32.to_le_bytes());
+        directory.extend_from_slice(&0u32.to_le_bytes());
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&count.to_le_bytes());
+        directory.extend_from_slice(&directory_size.to_le_bytes());
+        directory.extend_from_slice(&offset.to_le_bytes());
+
+        directory.extend_from_slice(&0x0706_4b50u32.to_le_bytes());
+        directory.extend_from_slice(&0u32.to_le_bytes());
+        directory.extend_from_slice(&record_offset.to_le_bytes());
+        directory.extend_from_slice(&1u32.to_le_bytes());
+    }
+
+    directory.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+    directory.extend_from_slice(&0u16.to_le_bytes());
+    directory.extend_from_slice(&0u16.to_le_bytes());
+    let short_count = count.min(0xFFFF) as u16;
+    directory.extend_from_slice(&short_count.to_le_bytes());
+    directory.extend_from_slice(&short_count.to_le_bytes());
+    let (short_size, short_offset) = if zip64 {
+        (ZIP64_LIMIT as u32, ZIP64_LIMIT as u32)
+    } else {
+        (directory_size as u32, offset as u32)
+    };
+    directory.extend_from_slice(&short_size.to_le_bytes());
+    directory.extend_from_slice(&short_offset.to_le_bytes());
+    directory.extend_from_slice(&0u16.to_le_bytes());
+    directory
+}
+
+/// Size of the zip archive of the entries, built from the same headers as `zip_stream_from`
+fn zip_size(entries: &[ArchiveEntry], zip64_from: u64) -> io::Result<u64> {
+    let mut offset = 0u64;
+    let mut records = Vec::with_capacity(entries.len());
+    for entry in entries {
+        check_zip_name(&entry.name)?;
+        let zip64 = entry.size >= zip64_from || offset >= zip64_from;
+        records.push(ZipCentralRecord {
+            name: entry.name.clone(),
+            crc: 0,
+            size: entry.size,
+            offset,
+            time: 0,
+            date: 0,
+            zip64,
+        });
+        offset += zip_local_header(&entry.name, zip64, 0, 0).len() as u64
+            + entry.size
+            + zip_data_descriptor(0, entry.size, zip64).len() as u64;
+    }
+    Ok(offset + zip_central_directory(&records, offset, zip64_from).len() as u64)
+}
+
+/// Streams the entries as a stored (uncompressed) zip archive.
+///
+/// Crc is only known after a file is read, so it is written in a data
+/// descriptor after each file.
+pub fn zip_stream(entries: Vec<ArchiveEntry>) -> impl Stream<Item = io::Result<Bytes>> {
+    zip_stream_from(entries, ZIP64_LIMIT)
+}
+
+/// Zip stream using ZIP64 fields for the sizes and offsets from `zip64_from`
+fn zip_stream_from(
+    entries: Vec<ArchiveEntry>,
+    zip64_from: u64,
+) -> impl Stream<Item = io::Result<Bytes>> {
+    try_stream! {
+        let mut offset = 0u64;
+        let mut records = Vec::with_capacity(entries.len());
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        for entry in entries {
+            check_zip_name(&entry.name)?;
+            // Entries starting past the limit need ZIP64 for their offset, the local
+            // header then declares the same version as the central record
+            let zip64 = entry.size >= zip64_from || offset >= zip64_from;
+            let (time, date) = dos_datetime(entry.modified);
+            let header = zip_local_header(&entry.name, zip64, time, date);
+            let header_offset = offset;
+            offset += header.len() as u64;
+            yield Bytes::from(header);
+
+            let mut file = tokio::fs::File::open(&entry.path).await?;
+            let mut hasher = crc32fast::Hasher::new();
+            let mut remaining = entry.size;
+            while remaining > 0 {
+                let read = read_chunk(&mut file, &mut buf, remaining).await?;
+                hasher.update(&buf[..read]);
+                remaining -= read as u64;
+                yield Bytes::copy_from_slice(&buf[..read]);
+            }
+            offset += entry.size;
+
+            let crc = hasher.finalize();
+            let descriptor = zip_data_descriptor(crc, entry.size, zip64);
+            offset += descriptor.len() as u64;
+            yield Bytes::from(descriptor);
+
+            records.push(ZipCentralRecord {
+                name: entry.name,
+                crc,
+                size: entry.size,
+                offset: header_offset,
+                time,
+                date,
+                zip64,
+            });
+        }
+        yield Bytes::from(zip_central_directory(&records, offset, zip64_from));
+    }
+}
+
+const TAR_BLOCK: usize = 512;
+/// Largest size that fits the 11 octal digits of a ustar header
+const TAR_MAX_SIZE: u64 = 0o77777777777;
+
+fn tar_octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let formatted = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(&formatted.as_bytes()[formatted.len() - width..]);
+    field[width] = 0;
+}
+
+fn tar_header(name: &str, size: u64, mtime: u64, typeflag: u8) -> [u8; TAR_BLOCK] {
+    let mut header = [0u8; TAR_BLOCK];
+    let name = name.as_bytes();
+    let name_len = name.len().min(100);
+    header[..name_len].copy_from_slice(&name[..name_len]);
+    tar_octal(&mut header[100..108], 0o644);
+    tar_octal(&mut header[108..116], 0);
+    tar_octal(&mut header[116..124], 0);
+    tar_octal(&mut header[124..136], size.min(TAR_MAX_SIZE));
+    tar_octal(&mut header[136..148], mtime);
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // Checksum is computed with its own field set to spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum = header.iter().map(|byte| *byte as u64).sum::<u64>();
+    tar_octal(&mut header[148..155], checksum);
+    header[155] = b' ';
+    header
+}
+
+/// A PAX record is `"<length> <key>=<value>\n"` where length counts itself
+fn pax_record(key: &str, value: &str) -> String {
+    let content_len = key.len() + value.len() + 3;
+    let mut len = content_len + 1;
+    while len != content_len + len.to_string().len() {
+        len = content_len + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value)
+}
+
+fn tar_padding(size: u64) -> usize {
+    (TAR_BLOCK - (size % TAR_BLOCK as u64) as usize) % TAR_BLOCK
+}
+
+fn tar_entry_header(entry: &ArchiveEntry) -> Vec<u8> {
+    let mtime = entry
+        .modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default();
+
+    let mut pax = String::new();
+    if entry.name.len() > 100 {
+        pax.push_str(&pax_record("path", &entry.name));
+    }
+    if entry.size > TAR_MAX_SIZE {
+        pax.push_str(&pax_record("size", &entry.size.to_string()));
+    }
+
+    let mut header = vec![];
+    if !pax.is_empty() {
+        header.extend_from_slice(&tar_header("././@PaxHeader", pax.len() as u64, mtime, b'x'));
+        header.extend_from_slice(pax.as_bytes());
+        header.resize(header.len() + tar_padding(pax.len() as u64), 0);
+    }
+    header.extend_from_slice(&tar_header(&entry.name, entry.size, mtime, b'0'));
+    header
+}
+
+/// Streams the entries as a tar archive.
+pub fn tar_stream(entries: Vec<ArchiveEntry>) -> impl Stream<Item = io::Result<Bytes>> {
+    try_stream! {
+        let mut buf = vec![0u8; CHUNK_SIZE];
+        for entry in entries {
+            yield Bytes::from(tar_entry_header(&entry));
+
+            let mut file = tokio::fs::File::open(&entry.path).await?;
+            let mut remaining = entry.size;
+            while remaining > 0 {
+                let read = read_chunk(&mut file, &mut buf, remaining).await?;
+                remaining -= read as u64;
+                yield Bytes::copy_from_slice(&buf[..read]);
+            }
+
+            let padding = tar_padding(entry.size);
+            if padding > 0 {
+                yield Bytes::from(vec![0u8; padding]);
+            }
+        }
+        // End of archive is marked by two empty blocks
+        yield Bytes::from(vec![0u8; TAR_BLOCK * 2]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use futures_util::TryStreamExt;
+
+    use super::*;
+
+    const FILES: &[(&str, &[u8])] = &[
+        ("Season 1/Épisode 1.mkv", b"first episode"),
+        ("Season 1/Extras/字幕.srt", b"subtitles"),
+        ("notes.txt", b""),
+    ];
+
+    /// Writes the files in a `Show` folder and lists it as archive entries
+    async fn entries(dir: &Path, files: &[(String, Vec<u8>)]) -> Vec<ArchiveEntry> {
+        for (name, contents) in files {
+            let path = dir.join("Show").join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        }
+        collect_entries(dir.join("Show")).await.unwrap()
+    }
+
+    fn files() -> Vec<(String, Vec<u8>)> {
+        FILES
+            .iter()
+            .map(|(name, contents)| (name.to_string(), contents.to_vec()))
+            .collect()
+    }
+
+    fn expected(files: &[(String, Vec<u8>)]) -> Vec<(String, Vec<u8>)> {
+        let mut expected = files
+            .iter()
+            .map(|(name, contents)| (format!("Show/{}", name), contents.clone()))
+            .collect::<Vec<_>>();
+        expected.sort();
+        expected
+    }
+
+    async fn collect(stream: impl Stream<Item = io::Result<Bytes>>) -> io::Result<Vec<u8>> {
+        stream
+            .try_fold(vec![], |mut archive, bytes| async move {
+                archive.extend_from_slice(&bytes);
+                Ok(archive)
+            })
+            .await
+    }
+
+    /// Name, contents and local header offset of each file of the zip archive
+    fn read_zip(archive: &[u8]) -> Vec<(String, Vec<u8>, u64)> {
+        let mut zip = zip::ZipArchive::new(Cursor::new(archive)).unwrap();
+        (0..zip.len())
+            .map(|index| {
+                let mut file = zip.by_index(index).unwrap();
+                let mut contents = vec![];
+                file.read_to_end(&mut contents).unwrap();
+                (file.name().to_string(), contents, file.header_start())
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn zip_round_trips_nested_folders_and_unicode_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = entries(dir.path(), &files()).await;
+        let size = archive_size(ArchiveFormat::Zip, &entries).unwrap();
+        let archive = collect(zip_stream(entries)).await.unwrap();
+        assert_eq!(archive.len() as u64, size);
+
+        let mut read = read_zip(&archive)
+            .into_iter()
+            .map(|(name, contents, _)| (name, contents))
+            .collect::<Vec<_>>();
+        read.sort();
+        assert_eq!(read, expected(&files()));
+    }
+
+    #[tokio::test]
+    async fn zip_round_trips_zip64_offsets() {
+        // A low limit takes the path of offsets past 4 GiB without writing that much
+        let zip64_from = 16;
+        let dir = tempfile::tempdir().unwrap();
+        let entries = entries(dir.path(), &files()).await;
+        let size = zip_size(&entries, zip64_from).unwrap();
+        let archive = collect(zip_stream_from(entries, zip64_from)).await.unwrap();
+        assert_eq!(archive.len() as u64, size);
+
+        let read = read_zip(&archive);
+        assert!(read.iter().any(|(_, _, offset)| *offset >= zip64_from));
+        for (_, _, offset) in read.iter() {
+            let start = *offset as usize;
+            let version = u16::from_le_bytes([archive[start + 4], archive[start + 5]]);
+            assert_eq!(version, zip_version_needed(*offset >= zip64_from));
+        }
+        let mut read = read
+            .into_iter()
+            .map(|(name, contents, _)| (name, contents))
+            .collect::<Vec<_>>();
+        read.sort();
+        assert_eq!(read, expected(&files()));
+    }
+
+    #[tokio::test]
+    async fn zip_rejects_names_longer_than_the_header_holds() {
+        let entries = vec![ArchiveEntry {
+            path: PathBuf::from("missing"),
+            name: "a".repeat(u16::MAX as usize + 1),
+            size: 0,
+            modified: SystemTime::UNIX_EPOCH,
+        }];
+        assert!(archive_size(ArchiveFormat::Zip, &entries).is_err());
+        let err = collect(zip_stream(entries)).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[tokio::test]
+    async fn tar_round_trips_nested_folders_and_long_names() {
+        let mut files = files();
+        files.push((
+            format!("Season 2/{}.mkv", "Long Épisode Name ".repeat(8)),
+            b"long".to_vec(),
+        ));
+        let dir = tempfile::tempdir().unwrap();
+        let entries = entries(dir.path(), &files).await;
+        let size = archive_size(ArchiveFormat::Tar, &entries).unwrap();
+        let archive = collect(tar_stream(entries)).await.unwrap();
+        assert_eq!(archive.len() as u64, size);
+
+        let mut tar = tar::Archive::new(Cursor::new(archive));
+        let mut read = tar
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let mut entry = entry.unwrap();
+                let name = entry.path().unwrap().to_string_lossy().into_owned();
+                let mut contents = vec![];
+                entry.read_to_end(&mut contents).unwrap();
+                (name, contents)
+            })
+            .collect::<Vec<_>>();
+        read.sort();
+        assert_eq!(read, expected(&files));
+    }
+}
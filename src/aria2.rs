@@ -0,0 +1,485 @@
+use axum::{
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use futures_util::StreamExt;
+use serde_json::{json, Map, Value};
+use transmission::Torrent;
+
+use crate::{
+    auth,
+    context::SharedData,
+    events::{TorrentEvent, TorrentEventKind},
+    structures::{AddTorrentOptions, TorrentState},
+    torrent_struc::TorrentStats,
+};
+
+const ARIA2_VERSION: &str = "1.36.0";
+
+/// aria2 notifications sent to websocket clients for a torrent event
+fn notifications(event: &TorrentEvent) -> Vec<String> {
+    let methods: &[&str] = match (event.kind, event.state) {
+        (TorrentEventKind::Completed, _) => {
+            &["aria2.onBtDownloadComplete", "aria2.onDownloadComplete"]
+        }
+        (TorrentEventKind::Removed, _) => &["aria2.onDownloadStop"],
+        (TorrentEventKind::StateChanged, Some(TorrentState::Downloading)) => {
+            &["aria2.onDownloadStart"]
+        }
+        (TorrentEventKind::StateChanged, Some(TorrentState::Stopped)) => &["aria2.onDownloadPause"],
+        (TorrentEventKind::StateChanged, Some(TorrentState::Error)) => &["aria2.onDownloadError"],
+        _ => &[],
+    };
+    methods
+        .iter()
+        .map(|method| {
+            json!({
+                "jsonrpc": "2.0",
+                "method": method,
+                "params": [{ "gid": gid(event.torrent_id) }],
+            })
+            .to_string()
+        })
+        .collect()
+}
+
+fn gid(torrent_id: i32) -> String {
+    format!("{:016x}", torrent_id)
+}
+
+fn torrent_id(gid: &str) -> Option<i32> {
+    i64::from_str_radix(gid, 16)
+        .ok()
+        .and_then(|id| i32::try_from(id).ok())
+}
+
+fn aria2_status(stats: &TorrentStats) -> &'static str {
+    match stats.state {
+        TorrentState::Downloading | TorrentState::Seeding | TorrentState::Checking => "active",
+        TorrentState::DownloadingWait | TorrentState::SeedingWait | TorrentState::CheckingWait => {
+            "waiting"
+        }
+        TorrentState::Stopped if stats.percent_done >= 1.0 => "complete",
+        TorrentState::Stopped => "paused",
+        TorrentState::Error => "error",
+    }
+}
+
+fn torrent_status(data: &SharedData, torrent: &Torrent, keys: &[String]) -> Value {
+    let stats = TorrentStats::from(torrent.stats());
+    let info = torrent.info();
+    let dir = data.download_dirs.dir(torrent.id());
+    let completed = stats.size_when_done.saturating_sub(stats.left_until_done);
+    let files_completed = if keys.is_empty() || keys.iter().any(|key| key == "files") {
+        data.pieces.files_completed(torrent, &info)
+    } else {
+        vec![]
+    };
+    let files = info
+        .files
+        .iter()
+        .zip(files_completed)
+        .enumerate()
+        .map(|(index, (file, file_completed))| {
+            let path = dir.join(&file.name);
+            json!({
+                "index": (index + 1).to_string(),
+                "path": path.to_string_lossy(),
+                "length": file.length.to_string(),
+                "completedLength": file_completed.to_string(),
+                "selected": (file.dnd == 0).to_string(),
+                "uris": [],
+            })
+        })
+        .collect::<Vec<_>>();
+    let status = json!({
+        "gid": gid(torrent.id()),
+        "status": aria2_status(&stats),
+        "totalLength": stats.size_when_done.to_string(),
+        "completedLength": completed.to_string(),
+        "uploadLength": stats.uploaded_ever.to_string(),
+        "downloadSpeed": ((stats.piece_download_speed_kbps * 1000.0) as i64).to_string(),
+        "uploadSpeed": ((stats.piece_upload_speed_kbps * 1000.0) as i64).to_string(),
+        "infoHash": info.hash_string,
+        "numSeeders": stats.peers_sending_to_us.to_string(),
+        "seeder": (stats.percent_done >= 1.0).to_string(),
+        "pieceLength": info.piece_size.to_string(),
+        "numPieces": info.piece_count.to_string(),
+        "connections": stats.peers_connected.to_string(),
+        "errorCode": if stats.state == TorrentState::Error { "1" } else { "0" },
+        "errorMessage": stats.error_string,
+        "dir": dir.to_string_lossy(),
+        "files": files,
+        "bittorrent": {
+            "announceList": info.trackers.iter().map(|tracker| vec![tracker.announce.clone()]).collect::<Vec<_>>(),
+            "comment": info.comment,
+            "creationDate": info.date_created.timestamp(),
+            "mode": if info.is_folder { "multi" } else { "single" },
+            "info": { "name": info.name },
+        },
+    });
+    if keys.is_empty() {
+        return status;
+    }
+    match status {
+        Value::Object(status) => Value::Object(
+            status
+                .into_iter()
+                .filter(|(key, _)| keys.contains(key))
+                .collect::<Map<_, _>>(),
+        ),
+        status => status,
+    }
+}
+
+fn string_list(value: Option<&Value>) -> Vec<String> {
+    value
+        .and_then(Value::as_array)
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|value| value.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn add_options(options: Option<&Value>) -> AddTorrentOptions {
+    let flag = |name: &str| {
+        options
+            .and_then(|options| options.get(name))
+            .and_then(Value::as_str)
+            .map(|value| value == "true")
+            .unwrap_or(false)
+    };
+    AddTorrentOptions {
+        paused: flag("pause"),
+        sequential_download: flag("bt-prioritize-piece-sequential"),
+        download_dir: options
+            .and_then(|options| options.get("dir"))
+            .and_then(Value::as_str)
+            .map(String::from),
+        start_at: None,
+    }
+}
+
+fn find_torrent(data: &SharedData, gid: Option<&Value>) -> Result<Torrent, String> {
+    let gid = gid.and_then(Value::as_str).ok_or("GID is not provided")?;
+    torrent_id(gid)
+        .and_then(|id| {
+            data.torrents
+                .get(&id)
+                .map(|torrent| torrent.value().clone())
+        })
+        .ok_or_else(|| format!("GID {} is not found", gid))
+}
+
+/// Lists torrents with one of the aria2 statuses, paged like `tellWaiting`
+fn tell_statuses(data: &SharedData, params: &[Value], statuses: &[&str], paged: bool) -> Value {
+    let (keys, offset, num) = if paged {
+        (
+            string_list(params.get(2)),
+            params.first().and_then(Value::as_i64).unwrap_or(0),
+            params.get(1).and_then(Value::as_u64).unwrap_or(u64::MAX),
+        )
+    } else {
+        (string_list(params.first()), 0, u64::MAX)
+    };
+    let mut torrents = data
+        .torrents
+        .iter()
+        .map(|torrent| torrent.value().clone())
+        .filter(|torrent| statuses.contains(&aria2_status(&TorrentStats::from(torrent.stats()))))
+        .collect::<Vec<_>>();
+    torrents.sort_by_key(|torrent| torrent.id());
+    // A negative offset counts from the end, listing in reverse
+    if offset < 0 {
+        torrents.reverse();
+    }
+    let skip = if offset < 0 {
+        (-offset - 1) as usize
+    } else {
+        offset as usize
+    };
+    Value::Array(
+        torrents
+            .iter()
+            .skip(skip)
+            .take(num.min(usize::MAX as u64) as usize)
+            .map(|torrent| torrent_status(data, torrent, &keys))
+            .collect(),
+    )
+}
+
+/// The secret is passed as a `token:` prefixed first parameter, it is
+/// removed so the method parameters start at 0
+fn take_token(params: &mut Vec<Value>) -> Option<String> {
+    let token = params
+        .first()
+        .and_then(Value::as_str)
+        .and_then(|token| token.strip_prefix("token:"))
+        .map(String::from);
+    if token.is_some() {
+        params.remove(0);
+    }
+    token
+}
+
+/// Whether the request, or one of the batch, carries a token accepted by `check`
+fn has_valid_token(message: &Value, check: impl Fn(&str) -> bool) -> bool {
+    let requests = match message {
+        Value::Array(requests) => requests.iter().collect::<Vec<_>>(),
+        request => vec![request],
+    };
+    requests.into_iter().any(|request| {
+        request
+            .get("params")
+            .and_then(Value::as_array)
+            .and_then(|params| params.first())
+            .and_then(Value::as_str)
+            .and_then(|token| token.strip_prefix("token:"))
+            .map(&check)
+            .unwrap_or(false)
+    })
+}
+
+async fn call(data: &SharedData, method: &str, mut params: Vec<Value>) -> Result<Value, String> {
+    let token = take_token(&mut params);
+    if auth::auth_required() && !auth::check_token(token.as_deref().unwrap_or_default()) {
+        return Err("Unauthorized".into());
+    }
+    match method {
+        "aria2.addUri" => {
+            let uris = string_list(params.first());
+            let magnet = uris
+                .iter()
+                .find(|uri| uri.starts_with("magnet:"))
+                .ok_or("Only magnet uris are supported")?;
+            let id = data.add_magnet(magnet, add_options(params.get(1))).await?;
+            Ok(json!(gid(id)))
+        }
+        "aria2.addTorrent" => {
+            let metainfo = params
+                .first()
+                .and_then(Value::as_str)
+                .and_then(|torrent| base64::decode(torrent).ok())
+                .ok_or("Invalid torrent")?;
+            let id = data
+                .add_metainfo(&metainfo, add_options(params.get(2)))
+                .await?;
+            Ok(json!(gid(id)))
+        }
+        "aria2.tellStatus" => {
+            let torrent = find_torrent(data, params.first())?;
+            Ok(torrent_status(data, &torrent, &string_list(params.get(1))))
+        }
+        "aria2.tellActive" => Ok(tell_statuses(data, &params, &["active"], false)),
+        "aria2.tellWaiting" => Ok(tell_statuses(data, &params, &["waiting", "paused"], true)),
+        "aria2.tellStopped" => Ok(tell_statuses(
+            data,
+            &params,
+            &["complete", "error", "removed"],
+            true,
+        )),
+        "aria2.pause" | "aria2.forcePause" => {
+            let torrent = find_torrent(data, params.first())?;
+            data.stop_torrent(&torrent);
+            Ok(json!(gid(torrent.id())))
+        }
+        "aria2.unpause" => {
+            let torrent = find_torrent(data, params.first())?;
+            data.start_torrent(&torrent);
+            Ok(json!(gid(torrent.id())))
+        }
+        "aria2.remove" | "aria2.forceRemove" => {
+            let torrent = find_torrent(data, params.first())?;
+            data.remove_torrent(torrent.id(), false).await;
+            Ok(json!(gid(torrent.id())))
+        }
+        "aria2.getVersion" => Ok(json!({
+            "version": ARIA2_VERSION,
+            "enabledFeatures": ["BitTorrent", "Metalink"],
+        })),
+        "aria2.getGlobalStat" => {
+            let statuses = data
+                .torrents
+                .iter()
+                .map(|torrent| TorrentStats::from(torrent.value().stats()))
+                .collect::<Vec<_>>();
+            let count = |names: &[&str]| {
+                statuses
+                    .iter()
+                    .filter(|stats| names.contains(&aria2_status(stats)))
+                    .count()
+                    .to_string()
+            };
+            let download_speed = statuses
+                .iter()
+                .map(|stats| (stats.piece_download_speed_kbps * 1000.0) as i64)
+                .sum::<i64>();
+            let upload_speed = statuses
+                .iter()
+                .map(|stats| (stats.piece_upload_speed_kbps * 1000.0) as i64)
+                .sum::<i64>();
+            Ok(json!({
+                "downloadSpeed": download_speed.to_string(),
+                "uploadSpeed": upload_speed.to_string(),
+                "numActive": count(&["active"]),
+                "numWaiting": count(&["waiting", "paused"]),
+                "numStopped": count(&["complete", "error"]),
+                "numStoppedTotal": count(&["complete", "error"]),
+            }))
+        }
+        _ => Err(format!("No such method: {}", method)),
+    }
+}
+
+async fn handle_request(data: &SharedData, request: Value) -> Value {
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = request
+        .get("method")
+        .and_then(Value::as_str)
+        .unwrap_or_default()
+        .to_string();
+    let params = match request.get("params") {
+        Some(Value::Array(params)) => params.clone(),
+        _ => vec![],
+    };
+    log::info!("aria2 rpc {}", method);
+    match call(data, &method, params).await {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(message) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": 1, "message": message },
+        }),
+    }
+}
+
+/// Handles a single request or a batch of requests
+async fn handle_message(data: &SharedData, message: Value) -> Value {
+    match message {
+        Value::Array(requests) => {
+            let mut responses = vec![];
+            for request in requests {
+                responses.push(handle_request(data, request).await);
+            }
+            Value::Array(responses)
+        }
+        request => handle_request(data, request).await,
+    }
+}
+
+pub async fn aria2_http(
+    Extension(data): Extension<SharedData>,
+    Json(message): Json<Value>,
+) -> Response {
+    Json(handle_message(&data, message).await).into_response()
+}
+
+pub async fn aria2_websocket(
+    ws: WebSocketUpgrade,
+    Extension(data): Extension<SharedData>,
+) -> Response {
+    ws.on_upgrade(|socket| handle_socket(socket, data))
+}
+
+/// Answers requests on the socket and sends it torrent notifications.
+///
+/// Notifications name every torrent, so they are only sent once a request on the
+/// socket gave the secret.
+async fn handle_socket(mut socket: WebSocket, data: SharedData) {
+    let events = data.events.subscribe(None);
+    tokio::pin!(events);
+    let mut authorized = !auth::auth_required();
+    loop {
+        tokio::select! {
+            message = socket.recv() => {
+                let text = match message {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                    Some(Ok(_)) => continue,
+                };
+                let response = match serde_json::from_str::<Value>(&text) {
+                    Ok(message) => {
+                        authorized = authorized || has_valid_token(&message, auth::check_token);
+                        handle_message(&data, message).await
+                    }
+                    Err(_) => json!({
+                        "jsonrpc": "2.0",
+                        "id": null,
+                        "error": { "code": -32700, "message": "Parse error." },
+                    }),
+                };
+                if socket.send(Message::Text(response.to_string())).await.is_err() {
+                    return;
+                }
+            }
+            event = events.next(), if authorized => {
+                let event = match event {
+                    Some(event) => event,
+                    None => return,
+                };
+                for notification in notifications(&event) {
+                    if socket.send(Message::Text(notification)).await.is_err() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_is_taken_from_the_first_param() {
+        let mut params = vec![json!("token:secret"), json!(["magnet:?xt=urn:btih:ab"])];
+        assert_eq!(take_token(&mut params).as_deref(), Some("secret"));
+        assert_eq!(params, vec![json!(["magnet:?xt=urn:btih:ab"])]);
+    }
+
+    #[test]
+    fn params_without_token_are_kept() {
+        let mut params = vec![json!("0000000000000001"), json!(["gid"])];
+        assert_eq!(take_token(&mut params), None);
+        assert_eq!(params.len(), 2);
+        assert_eq!(take_token(&mut vec![]), None);
+    }
+
+    #[test]
+    fn token_is_found_in_requests_and_batches() {
+        let request = |token: &str| json!({ "method": "aria2.tellActive", "params": [token] });
+        let check = |token: &str| token == "secret";
+        assert!(!has_valid_token(
+            &json!({ "method": "aria2.tellActive" }),
+            check
+        ));
+        assert!(!has_valid_token(&request("token:wrong"), check));
+        assert!(!has_valid_token(&request("secret"), check));
+        assert!(has_valid_token(&request("token:secret"), check));
+        assert!(has_valid_token(
+            &json!([request("token:wrong"), request("token:secret")]),
+            check
+        ));
+    }
+
+    #[test]
+    fn gid_maps_back_to_the_torrent_id() {
+        assert_eq!(gid(1), "0000000000000001");
+        assert_eq!(gid(255), "00000000000000ff");
+        for id in [0, 1, 42, i32::MAX] {
+            assert_eq!(torrent_id(&gid(id)), Some(id));
+        }
+    }
+
+    #[test]
+    fn invalid_gid_is_not_a_torrent() {
+        assert_eq!(torrent_id("not-a-gid"), None);
+        assert_eq!(torrent_id("ffffffffff"), None);
+        assert_eq!(torrent_id(""), None);
+    }
+}
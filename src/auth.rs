@@ -0,0 +1,133 @@
+use axum::http::HeaderMap;
+
+lazy_static::lazy_static! {
+    /// Username and password required by the compatibility APIs, if configured
+    pub static ref CREDENTIALS: Option<(String, String)> = match (
+        std::env::var("TOREXPO_USERNAME"),
+        std::env::var("TOREXPO_PASSWORD"),
+    ) {
+        (Ok(username), Ok(password)) => Some((username, password)),
+        _ => None,
+    };
+}
+
+pub fn auth_required() -> bool {
+    CREDENTIALS.is_some()
+}
+
+/// Compares secrets without returning early on the first differing byte,
+/// so the time taken doesn't tell how much of a guess was right
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+pub fn check_credentials(username: &str, password: &str) -> bool {
+    credentials_match(CREDENTIALS.as_ref(), username, password)
+}
+
+fn credentials_match(
+    credentials: Option<&(String, String)>,
+    username: &str,
+    password: &str,
+) -> bool {
+    match credentials {
+        Some((expected_username, expected_password)) => {
+            // Both are always compared so a wrong username takes as long as a wrong password
+            let username_valid = constant_time_eq(username, expected_username);
+            let password_valid = constant_time_eq(password, expected_password);
+            username_valid && password_valid
+        }
+        None => true,
+    }
+}
+
+/// Checks the `authorization: Basic` header against the configured credentials
+pub fn check_basic_auth(headers: &HeaderMap) -> bool {
+    basic_auth_matches(CREDENTIALS.as_ref(), headers)
+}
+
+/// Checks the `authorization: Basic` header against the given credentials
+pub fn basic_auth_matches(credentials: Option<&(String, String)>, headers: &HeaderMap) -> bool {
+    if credentials.is_none() {
+        return true;
+    }
+    headers
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| base64::decode(encoded.trim()).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .map(|decoded| match decoded.split_once(':') {
+            Some((username, password)) => credentials_match(credentials, username, password),
+            None => false,
+        })
+        .unwrap_or(false)
+}
+
+/// Checks a bare secret token against the configured password
+pub fn check_token(token: &str) -> bool {
+    match &*CREDENTIALS {
+        Some((_, expected_password)) => constant_time_eq(token, expected_password),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    fn credentials() -> Option<(String, String)> {
+        Some(("user".into(), "secret".into()))
+    }
+
+    fn basic(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "authorization",
+            HeaderValue::from_str(&format!("Basic {}", base64::encode(value))).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn secrets_are_compared_whole() {
+        assert!(constant_time_eq("secret", "secret"));
+        assert!(!constant_time_eq("secret", "secreT"));
+        assert!(!constant_time_eq("secret", "secret2"));
+        assert!(!constant_time_eq("", "secret"));
+    }
+
+    #[test]
+    fn basic_auth_requires_both_credentials() {
+        let credentials = credentials();
+        assert!(basic_auth_matches(
+            credentials.as_ref(),
+            &basic("user:secret")
+        ));
+        assert!(!basic_auth_matches(
+            credentials.as_ref(),
+            &basic("user:wrong")
+        ));
+        assert!(!basic_auth_matches(
+            credentials.as_ref(),
+            &basic("other:secret")
+        ));
+        assert!(!basic_auth_matches(
+            credentials.as_ref(),
+            &basic("usersecret")
+        ));
+        assert!(!basic_auth_matches(credentials.as_ref(), &HeaderMap::new()));
+    }
+
+    #[test]
+    fn anyone_is_allowed_without_credentials() {
+        assert!(basic_auth_matches(None, &HeaderMap::new()));
+        assert!(credentials_match(None, "", ""));
+    }
+}
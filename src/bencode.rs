@@ -0,0 +1,182 @@
+use std::collections::BTreeMap;
+
+/// Nesting allowed when decoding, deeper values are rejected
+const MAX_DEPTH: usize = 32;
+
+/// Minimal bencode values, enough to read and write metainfo files and tracker responses
+#[derive(Debug, PartialEq)]
+pub enum Bencode {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Bencode>),
+    Dict(BTreeMap<Vec<u8>, Bencode>),
+}
+
+impl Bencode {
+    pub fn string(value: &str) -> Self {
+        Bencode::Bytes(value.as_bytes().to_vec())
+    }
+
+    pub fn dict<'a>(entries: impl IntoIterator<Item = (&'a str, Bencode)>) -> Self {
+        Bencode::Dict(
+            entries
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    /// Value of `key` if this is a dictionary that has it
+    pub fn get(&self, key: &str) -> Option<&Bencode> {
+        match self {
+            Bencode::Dict(entries) => entries.get(key.as_bytes()),
+            _ => None,
+        }
+    }
+
+    /// Parses a single bencoded value, none if the data is not valid bencode
+    /// or has anything after the value
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let (value, rest) = Self::decode_value(data, 0)?;
+        rest.is_empty().then_some(value)
+    }
+
+    fn decode_value(data: &[u8], depth: usize) -> Option<(Self, &[u8])> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        match *data.first()? {
+            b'i' => {
+                let end = data.iter().position(|byte| *byte == b'e')?;
+                let value = std::str::from_utf8(&data[1..end]).ok()?.parse().ok()?;
+                Some((Bencode::Int(value), &data[end + 1..]))
+            }
+            b'l' => {
+                let mut rest = &data[1..];
+                let mut items = vec![];
+                while *rest.first()? != b'e' {
+                    let (item, next) = Self::decode_value(rest, depth + 1)?;
+                    items.push(item);
+                    rest = next;
+                }
+                Some((Bencode::List(items), &rest[1..]))
+            }
+            b'd' => {
+                let mut rest = &data[1..];
+                let mut entries = BTreeMap::new();
+                while *rest.first()? != b'e' {
+                    let (key, next) = match Self::decode_value(rest, depth + 1)? {
+                        (Bencode::Bytes(key), next) => (key, next),
+                        _ => return None,
+                    };
+                    let (value, next) = Self::decode_value(next, depth + 1)?;
+                    entries.insert(key, value);
+                    rest = next;
+                }
+                Some((Bencode::Dict(entries), &rest[1..]))
+            }
+            b'0'..=b'9' => {
+                let colon = data.iter().position(|byte| *byte == b':')?;
+                let length = std::str::from_utf8(&data[..colon])
+                    .ok()?
+                    .parse::<usize>()
+                    .ok()?;
+                let rest = &data[colon + 1..];
+                if rest.len() < length {
+                    return None;
+                }
+                Some((Bencode::Bytes(rest[..length].to_vec()), &rest[length..]))
+            }
+            _ => None,
+        }
+    }
+
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Bencode::Int(value) => out.extend_from_slice(format!("i{}e", value).as_bytes()),
+            Bencode::Bytes(bytes) => {
+                out.extend_from_slice(format!("{}:", bytes.len()).as_bytes());
+                out.extend_from_slice(bytes);
+            }
+            Bencode::List(items) => {
+                out.push(b'l');
+                for item in items {
+                    item.encode(out);
+                }
+                out.push(b'e');
+            }
+            Bencode::Dict(entries) => {
+                out.push(b'd');
+                for (key, value) in entries {
+                    Bencode::Bytes(key.clone()).encode(out);
+                    value.encode(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(&mut out);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_dictionaries_with_sorted_keys() {
+        let value = Bencode::dict([
+            ("name", Bencode::string("show")),
+            ("length", Bencode::Int(-3)),
+            (
+                "list",
+                Bencode::List(vec![Bencode::Int(1), Bencode::string("")]),
+            ),
+        ]);
+        assert_eq!(
+            value.to_bytes(),
+            b"d6:lengthi-3e4:listli1e0:e4:name4:showe".to_vec()
+        );
+    }
+
+    #[test]
+    fn decodes_what_it_encodes() {
+        let value = Bencode::dict([
+            (
+                "info",
+                Bencode::dict([("pieces", Bencode::Bytes(vec![0, 255, 10]))]),
+            ),
+            ("announce-list", Bencode::List(vec![Bencode::List(vec![])])),
+        ]);
+        let decoded = Bencode::decode(&value.to_bytes()).unwrap();
+        assert_eq!(decoded, value);
+        assert!(matches!(decoded.get("info"), Some(Bencode::Dict(_))));
+        assert_eq!(decoded.get("missing"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_bencode() {
+        let invalid: [&[u8]; 9] = [
+            b"",
+            b"<html>",
+            b"i12",
+            b"ixe",
+            b"5:abc",
+            b"l1:a",
+            b"di1e1:ae",
+            b"d4:infoe",
+            b"1:ab",
+        ];
+        for data in invalid {
+            assert_eq!(Bencode::decode(data), None, "{:?}", data);
+        }
+        let nested = [vec![b'l'; MAX_DEPTH + 2], vec![b'e'; MAX_DEPTH + 2]].concat();
+        assert_eq!(Bencode::decode(&nested), None);
+        let nested = [vec![b'l'; MAX_DEPTH], vec![b'e'; MAX_DEPTH]].concat();
+        assert!(Bencode::decode(&nested).is_some());
+    }
+}
@@ -0,0 +1,45 @@
+use futures_util::StreamExt;
+use tokio::sync::mpsc;
+
+use crate::{context::SharedData, events::TorrentEventKind, extract::EXTRACT_ARCHIVES};
+
+/// Runs the steps of completed torrents one torrent at a time, in a fixed order.
+///
+/// Archives are extracted first, then scripts run, then library rules move or link the
+/// files, so no step sees files another one is still moving or reading.
+pub async fn completion_pipeline(data: SharedData) {
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    // Completions are queued so none are missed while a torrent is processed
+    let listen = async {
+        let events = data.events.subscribe(None);
+        futures_util::pin_mut!(events);
+        while let Some(event) = events.next().await {
+            if event.kind == TorrentEventKind::Completed {
+                let _ = sender.send(event.torrent_id);
+            }
+        }
+    };
+    let process = async {
+        while let Some(torrent_id) = receiver.recv().await {
+            let torrent = match data.torrents.get(&torrent_id) {
+                Some(torrent) => torrent.value().clone(),
+                None => continue,
+            };
+            if *EXTRACT_ARCHIVES {
+                data.extractor.extract(&data.download_dirs, &torrent).await;
+            }
+            data.scripts
+                .run_completed(&data.download_dirs, &torrent)
+                .await;
+            data.library
+                .apply(
+                    &data.download_dirs,
+                    &data.seeding,
+                    &data.extractor,
+                    &torrent,
+                )
+                .await;
+        }
+    };
+    tokio::join!(listen, process);
+}
@@ -2,7 +2,259 @@ use dashmap::DashMap;
 use std::sync::Arc;
 use transmission::{Client, Torrent};
 
+use crate::{
+    bencode::Bencode,
+    cookie_profiles::CookieProfileManager,
+    create_torrent::TorrentCreator,
+    disk_space::DiskGuard,
+    download_dirs::DownloadDirs,
+    events::EventLog,
+    extract::Extractor,
+    feeds::FeedManager,
+    library::LibraryManager,
+    pieces::PieceTracker,
+    priority::{PriorityMode, PriorityModes},
+    retention::RetentionManager,
+    scheduler::Scheduler,
+    scripts::ScriptManager,
+    search::IndexerManager,
+    seed_buster::SeedingTorrents,
+    structures::{AddTorrentOptions, HttpHeader},
+    tracker::Tracker,
+    webhooks::WebhookManager,
+};
+
+/// Largest .torrent file downloaded from a url
+const MAX_METAINFO_SIZE: usize = 10 * 1024 * 1024;
+const URL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Info hash of each torrent as lowercase hex, cached as reading it builds the torrent's info
+#[derive(Default)]
+pub struct TorrentHashes {
+    hashes: DashMap<i32, String>,
+}
+
+impl TorrentHashes {
+    pub fn hash(&self, torrent: &Torrent) -> String {
+        self.hashes
+            .entry(torrent.id())
+            .or_insert_with(|| torrent.info().hash_string.to_lowercase())
+            .clone()
+    }
+
+    pub fn forget(&self, torrent_id: i32) {
+        self.hashes.remove(&torrent_id);
+    }
+}
+
+#[derive(Clone)]
 pub struct SharedData {
-    pub client: Client,
+    pub client: Arc<Client>,
     pub torrents: Arc<DashMap<i32, Torrent>>,
+    pub download_dirs: Arc<DownloadDirs>,
+    pub priority_modes: Arc<PriorityModes>,
+    pub pieces: Arc<PieceTracker>,
+    pub events: Arc<EventLog>,
+    pub feeds: Arc<FeedManager>,
+    pub indexers: Arc<IndexerManager>,
+    pub cookie_profiles: Arc<CookieProfileManager>,
+    pub webhooks: Arc<WebhookManager>,
+    pub scripts: Arc<ScriptManager>,
+    pub extractor: Arc<Extractor>,
+    pub library: Arc<LibraryManager>,
+    pub disk: Arc<DiskGuard>,
+    pub retention: Arc<RetentionManager>,
+    pub scheduler: Arc<Scheduler>,
+    pub creator: Arc<TorrentCreator>,
+    pub seeding: Arc<SeedingTorrents>,
+    pub hashes: Arc<TorrentHashes>,
+    pub tracker: Arc<Tracker>,
+    pub http: reqwest::Client,
+}
+
+impl SharedData {
+    /// Registers a torrent added to the client and applies the add options.
+    ///
+    /// The torrent is removed again when its download dir isn't allowed or it doesn't fit
+    /// on the disk.
+    pub async fn insert_torrent(
+        &self,
+        torrent: Torrent,
+        options: AddTorrentOptions,
+    ) -> Result<i32, String> {
+        let id = torrent.id();
+        if let Some(download_dir) = &options.download_dir {
+            if let Err(err) = self.download_dirs.set_dir(&torrent, download_dir).await {
+                torrent.remove(false);
+                return Err(err);
+            }
+        }
+        if let Err(err) = self
+            .disk
+            .check_add(&self.torrents, &self.download_dirs, &torrent)
+            .await
+        {
+            torrent.remove(false);
+            self.download_dirs.remove(id).await;
+            return Err(err);
+        }
+        let mode = PriorityMode {
+            sequential: options.sequential_download,
+        };
+        if mode != PriorityMode::default() {
+            if let Err(err) = self.priority_modes.set_mode(&torrent, None, mode).await {
+                log::warn!("Cant set priority mode of {} {}", torrent.name(), err);
+            }
+        }
+        if options.paused {
+            torrent.stop();
+        }
+        if let Some(at) = options.start_at {
+            self.scheduler.schedule_start(&torrent, at).await;
+        }
+        self.torrents.insert(id, torrent);
+        Ok(id)
+    }
+
+    pub async fn add_magnet(
+        &self,
+        magnet_link: &str,
+        options: AddTorrentOptions,
+    ) -> Result<i32, String> {
+        let torrent = self
+            .client
+            .add_torrent_magnet(magnet_link)
+            .map_err(|err| err.to_string())?;
+        self.insert_torrent(torrent, options).await
+    }
+
+    /// Adds a torrent from the contents of a .torrent file
+    pub async fn add_metainfo(
+        &self,
+        metainfo: &[u8],
+        options: AddTorrentOptions,
+    ) -> Result<i32, String> {
+        let tmpdir =
+            tempfile::tempdir().map_err(|err| format!("Cant create temporary dir {}", err))?;
+        let path = tmpdir.path().join("metainfo.torrent");
+        tokio::fs::write(&path, metainfo)
+            .await
+            .map_err(|err| format!("Cant create temporary file {}", err))?;
+        let torrent = self
+            .client
+            .add_torrent_file(path.to_str().ok_or("Not valid path")?)
+            .map_err(|err| err.to_string())?;
+        self.insert_torrent(torrent, options).await
+    }
+
+    /// Adds a magnet link, or the .torrent file at an HTTP url.
+    ///
+    /// The request sends the given headers and the cookies of the cookie profile,
+    /// or of the profile matching the url's domain when none is named.
+    pub async fn add_url(
+        &self,
+        url: &str,
+        headers: &[HttpHeader],
+        cookie_profile: Option<&str>,
+        options: AddTorrentOptions,
+    ) -> Result<i32, String> {
+        if url.starts_with("magnet:") {
+            return self.add_magnet(url, options).await;
+        }
+        let url = reqwest::Url::parse(url).map_err(|err| format!("Not valid url {}", err))?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("Only magnet and http urls are supported".into());
+        }
+        // Cookies given as a header replace those of the matching profile
+        let has_cookies = headers
+            .iter()
+            .any(|header| header.name.eq_ignore_ascii_case("cookie"));
+        let cookies = if has_cookies && cookie_profile.is_none() {
+            None
+        } else {
+            self.cookie_profiles.cookies_for(&url, cookie_profile)?
+        };
+        let mut request = self.http.get(url).timeout(URL_TIMEOUT);
+        for header in headers {
+            request = request.header(header.name.as_str(), header.value.as_str());
+        }
+        if let Some(cookies) = cookies {
+            request = request.header(reqwest::header::COOKIE, cookies);
+        }
+        let mut response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(|err| err.to_string())?;
+        if response
+            .content_length()
+            .map(|length| length > MAX_METAINFO_SIZE as u64)
+            .unwrap_or(false)
+        {
+            return Err("Torrent file too large".into());
+        }
+        let mut metainfo = vec![];
+        while let Some(chunk) = response.chunk().await.map_err(|err| err.to_string())? {
+            metainfo.extend_from_slice(&chunk);
+            if metainfo.len() > MAX_METAINFO_SIZE {
+                return Err("Torrent file too large".into());
+            }
+        }
+        // Trackers answer with their login page when the cookies have expired
+        let decoded = Bencode::decode(&metainfo);
+        let info = decoded.as_ref().and_then(|decoded| decoded.get("info"));
+        if !matches!(info, Some(Bencode::Dict(_))) {
+            return Err("Not a torrent file, check the cookies of the tracker".into());
+        }
+        self.add_metainfo(&metainfo, options).await
+    }
+
+    /// Info hash of the torrent as lowercase hex
+    pub fn torrent_hash(&self, torrent: &Torrent) -> String {
+        self.hashes.hash(torrent)
+    }
+
+    /// Finds a torrent by info hash, which unlike its id stays the same across restarts
+    pub fn torrent_by_hash(&self, hash: &str) -> Option<Torrent> {
+        let hash = hash.to_lowercase();
+        let torrents = self
+            .torrents
+            .iter()
+            .map(|torrent| torrent.value().clone())
+            .collect::<Vec<_>>();
+        torrents
+            .into_iter()
+            .find(|torrent| self.torrent_hash(torrent) == hash)
+    }
+
+    /// Starts a torrent on request of the user, the disk guard stops tracking it
+    pub fn start_torrent(&self, torrent: &Torrent) {
+        self.disk.forget(torrent.id());
+        torrent.start();
+    }
+
+    /// Stops a torrent on request of the user, the disk guard won't resume it
+    pub fn stop_torrent(&self, torrent: &Torrent) {
+        self.disk.forget(torrent.id());
+        torrent.stop();
+    }
+
+    pub async fn remove_torrent(&self, torrent_id: i32, delete_data: bool) -> bool {
+        match self.torrents.remove(&torrent_id) {
+            Some((_id, torrent)) => {
+                self.extractor
+                    .remove(&self.download_dirs, &torrent, delete_data)
+                    .await;
+                torrent.remove(delete_data);
+                self.priority_modes.remove(torrent_id).await;
+                self.download_dirs.remove(torrent_id).await;
+                self.pieces.forget(torrent_id);
+                self.scheduler.forget(torrent_id).await;
+                self.seeding.forget(torrent_id).await;
+                self.hashes.forget(torrent_id);
+                true
+            }
+            None => false,
+        }
+    }
 }
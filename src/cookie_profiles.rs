@@ -0,0 +1,197 @@
+use async_graphql::{InputObject, SimpleObject};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::CONFIG_DIR;
+
+/// Cookies sent when downloading .torrent files from a tracker
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct CookieProfile {
+    pub name: String,
+    /// Domain the cookies are sent to, including its subdomains
+    pub domain: String,
+    /// Value of the `Cookie` header, like `uid=1; pass=secret`
+    #[graphql(skip)]
+    pub cookies: String,
+}
+
+#[derive(InputObject)]
+pub struct CookieProfileInput {
+    pub name: String,
+    pub domain: String,
+    pub cookies: String,
+}
+
+/// Cookie profiles by name, saved in the config directory
+#[derive(Default)]
+pub struct CookieProfileManager {
+    profiles: DashMap<String, CookieProfile>,
+}
+
+impl CookieProfile {
+    fn matches(&self, host: &str) -> bool {
+        let domain = self.domain.trim_start_matches('.').to_lowercase();
+        let host = host.to_lowercase();
+        host == domain || host.ends_with(&format!(".{}", domain))
+    }
+}
+
+impl CookieProfileManager {
+    fn path() -> std::path::PathBuf {
+        std::path::Path::new(&CONFIG_DIR.clone()).join("cookie_profiles.json")
+    }
+
+    pub async fn load() -> Self {
+        let manager = CookieProfileManager::default();
+        if let Ok(saved) = tokio::fs::read(Self::path()).await {
+            match serde_json::from_slice::<Vec<CookieProfile>>(&saved) {
+                Ok(saved) => {
+                    for profile in saved {
+                        manager.profiles.insert(profile.name.clone(), profile);
+                    }
+                }
+                Err(err) => log::warn!("Cant read cookie profiles {:#?}", err),
+            }
+        }
+        manager
+    }
+
+    async fn save(&self) {
+        match serde_json::to_vec_pretty(&self.profiles()) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save cookie profiles {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save cookie profiles {:#?}", err),
+        }
+    }
+
+    pub fn profiles(&self) -> Vec<CookieProfile> {
+        let mut profiles = self
+            .profiles
+            .iter()
+            .map(|profile| profile.value().clone())
+            .collect::<Vec<_>>();
+        profiles.sort_by(|a, b| a.name.cmp(&b.name));
+        profiles
+    }
+
+    /// Adds the profile, replacing any profile of the same name
+    pub async fn set_profile(&self, input: CookieProfileInput) -> Result<CookieProfile, String> {
+        let domain = input.domain.trim().trim_start_matches('.').to_lowercase();
+        if domain.is_empty() {
+            return Err("Domain is required".into());
+        }
+        let profile = CookieProfile {
+            name: input.name,
+            domain,
+            cookies: input.cookies.trim().to_string(),
+        };
+        self.profiles.insert(profile.name.clone(), profile.clone());
+        self.save().await;
+        Ok(profile)
+    }
+
+    pub async fn remove_profile(&self, name: &str) -> bool {
+        if self.profiles.remove(name).is_some() {
+            self.save().await;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Cookies to send to the url's host.
+    ///
+    /// A named profile must match the host, so cookies never leak to another tracker.
+    /// Without a name the profile with the most specific matching domain is used.
+    pub fn cookies_for(
+        &self,
+        url: &reqwest::Url,
+        name: Option<&str>,
+    ) -> Result<Option<String>, String> {
+        let host = url.host_str().unwrap_or_default();
+        match name {
+            Some(name) => {
+                let profile = self.profiles.get(name).ok_or("Cookie profile not found")?;
+                if profile.matches(host) {
+                    Ok(Some(profile.cookies.clone()))
+                } else {
+                    Err(format!("Cookie profile {} is not for {}", name, host))
+                }
+            }
+            None => Ok(self
+                .profiles
+                .iter()
+                .filter(|profile| profile.matches(host))
+                .max_by_key(|profile| profile.domain.len())
+                .map(|profile| profile.cookies.clone())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manager(profiles: &[(&str, &str, &str)]) -> CookieProfileManager {
+        let manager = CookieProfileManager::default();
+        for (name, domain, cookies) in profiles {
+            manager.profiles.insert(
+                name.to_string(),
+                CookieProfile {
+                    name: name.to_string(),
+                    domain: domain.to_string(),
+                    cookies: cookies.to_string(),
+                },
+            );
+        }
+        manager
+    }
+
+    fn url(url: &str) -> reqwest::Url {
+        reqwest::Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn picks_most_specific_domain() {
+        let manager = manager(&[
+            ("tracker", "tracker.example", "uid=1"),
+            ("downloads", "dl.tracker.example", "uid=2"),
+        ]);
+        let cookies = |link| manager.cookies_for(&url(link), None).unwrap();
+        assert_eq!(
+            cookies("https://tracker.example/1.torrent"),
+            Some("uid=1".into())
+        );
+        assert_eq!(
+            cookies("https://www.Tracker.example/1.torrent"),
+            Some("uid=1".into())
+        );
+        assert_eq!(
+            cookies("https://dl.tracker.example/1.torrent"),
+            Some("uid=2".into())
+        );
+        assert_eq!(cookies("https://othertracker.example/1.torrent"), None);
+    }
+
+    #[test]
+    fn named_profile_must_match_host() {
+        let manager = manager(&[("tracker", "tracker.example", "uid=1")]);
+        assert_eq!(
+            manager.cookies_for(
+                &url("https://dl.tracker.example/1.torrent"),
+                Some("tracker")
+            ),
+            Ok(Some("uid=1".into()))
+        );
+        assert!(manager
+            .cookies_for(&url("https://other.example/1.torrent"), Some("tracker"))
+            .is_err());
+        assert!(manager
+            .cookies_for(&url("https://tracker.example/1.torrent"), Some("missing"))
+            .is_err());
+    }
+}
@@ -0,0 +1,707 @@
+use std::{
+    collections::VecDeque,
+    io::Read,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use async_graphql::{ComplexObject, Enum, SimpleObject};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::sync::Notify;
+
+use crate::{
+    bencode::Bencode,
+    context::SharedData,
+    download_link::{encode_link, expiry_from_secs, MetainfoLinkStructure},
+    new_id,
+    structures::AddTorrentOptions,
+    torrent_struc::hash_hex,
+    tracker::TRACKER_ENABLED,
+    CONFIG_DIR, DOWNLOAD_DIR,
+};
+
+/// Creations kept, oldest are forgotten first
+const MAX_CREATIONS: usize = 100;
+const MIN_PIECE_SIZE: u32 = 16 * 1024;
+const MAX_PIECE_SIZE: u32 = 64 * 1024 * 1024;
+/// Piece count aimed for when no piece size is given
+const TARGET_PIECES: u64 = 1500;
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum CreationState {
+    /// Waiting for earlier creations to finish
+    Queued,
+    Hashing,
+    Done,
+    Failed,
+}
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone, PartialEq)]
+#[graphql(complex)]
+#[serde(rename_all = "camelCase")]
+pub struct TorrentCreation {
+    pub id: String,
+    pub name: String,
+    /// File or folder in the download directory the torrent is made of
+    pub path: String,
+    pub trackers: Vec<String>,
+    pub webseeds: Vec<String>,
+    pub piece_size: u32,
+    pub private: bool,
+    pub comment: Option<String>,
+    /// Seed the torrent once it is created
+    pub seed: bool,
+    pub state: CreationState,
+    pub pieces_hashed: u32,
+    pub piece_count: u32,
+    /// Info hash in hex once created
+    pub hash: Option<String>,
+    /// Torrent seeding the created files
+    pub torrent_id: Option<i32>,
+    pub error: Option<String>,
+    pub created: DateTime<Utc>,
+    /// Where the .torrent file is saved
+    #[graphql(skip)]
+    pub file: Option<String>,
+}
+
+#[ComplexObject]
+impl TorrentCreation {
+    /// Part of the pieces hashed, from 0 to 1
+    async fn progress(&self) -> f32 {
+        if self.piece_count == 0 {
+            return 0.0;
+        }
+        self.pieces_hashed as f32 / self.piece_count as f32
+    }
+
+    /// Link to download the created .torrent file
+    async fn download_link(&self, expiry_secs: Option<u64>) -> Option<String> {
+        let file = self.file.as_ref()?;
+        let coded = MetainfoLinkStructure {
+            file: file.as_str().into(),
+            name: self.name.as_str().into(),
+            expiry: expiry_from_secs(expiry_secs),
+        };
+        encode_link(&coded).map(|link| format!("/metainfo/{}", link))
+    }
+}
+
+/// File or folder at the path relative to the download directory, with symlinks resolved
+/// so a link can't lead outside of the download directory
+fn resolve_path(download_dir: &Path, relative: &str) -> Result<PathBuf, String> {
+    let download_dir = download_dir
+        .canonicalize()
+        .map_err(|err| format!("Cant read download dir {}", err))?;
+    let root = download_dir
+        .join(relative)
+        .canonicalize()
+        .map_err(|_| "File not found".to_string())?;
+    if !root.starts_with(&download_dir) {
+        return Err(format!("{} is outside the download directory", relative));
+    }
+    Ok(root)
+}
+
+/// Regular files under the path with their length and path components, in a stable order
+fn collect_files(root: &Path) -> std::io::Result<Vec<(PathBuf, u64, Vec<String>)>> {
+    let metadata = std::fs::metadata(root)?;
+    if metadata.is_file() {
+        return Ok(vec![(root.to_path_buf(), metadata.len(), vec![])]);
+    }
+    let mut files = vec![];
+    let mut folders = vec![root.to_path_buf()];
+    while let Some(folder) = folders.pop() {
+        for entry in std::fs::read_dir(&folder)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                folders.push(entry.path());
+            } else if file_type.is_file() {
+                let path = entry.path();
+                let components = path
+                    .strip_prefix(root)
+                    .unwrap_or(&path)
+                    .components()
+                    .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                    .collect::<Vec<_>>();
+                files.push((path, entry.metadata()?.len(), components));
+            }
+        }
+    }
+    files.sort_by(|a, b| a.2.cmp(&b.2));
+    Ok(files)
+}
+
+/// Smallest power of two piece size giving at most the targeted number of pieces
+fn auto_piece_size(total: u64) -> u32 {
+    let mut piece_size = MIN_PIECE_SIZE;
+    while piece_size < MAX_PIECE_SIZE && total / piece_size as u64 > TARGET_PIECES {
+        piece_size *= 2;
+    }
+    piece_size
+}
+
+/// Torrents being made from files in the download directory, one at a time
+#[derive(Default)]
+pub struct TorrentCreator {
+    creations: Mutex<VecDeque<TorrentCreation>>,
+    queued: Notify,
+}
+
+impl TorrentCreator {
+    fn path() -> PathBuf {
+        Path::new(&CONFIG_DIR.clone()).join("creations.json")
+    }
+
+    fn folder() -> PathBuf {
+        Path::new(&CONFIG_DIR.clone()).join("created")
+    }
+
+    /// Loads earlier creations, those interrupted by a restart are queued again
+    pub async fn load() -> Self {
+        let creator = TorrentCreator::default();
+        if let Ok(saved) = tokio::fs::read(Self::path()).await {
+            match serde_json::from_slice::<VecDeque<TorrentCreation>>(&saved) {
+                Ok(mut saved) => {
+                    for creation in saved.iter_mut() {
+                        if creation.state == CreationState::Hashing {
+                            creation.state = CreationState::Queued;
+                            creation.pieces_hashed = 0;
+                        }
+                    }
+                    if saved
+                        .iter()
+                        .any(|creation| creation.state == CreationState::Queued)
+                    {
+                        creator.queued.notify_one();
+                    }
+                    *creator.creations.lock().unwrap() = saved;
+                }
+                Err(err) => log::warn!("Cant read torrent creations {:#?}", err),
+            }
+        }
+        creator
+    }
+
+    async fn save(&self) {
+        let saved = serde_json::to_vec_pretty(&*self.creations.lock().unwrap());
+        match saved {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save torrent creations {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save torrent creations {:#?}", err),
+        }
+    }
+
+    /// Creations, latest first
+    pub fn creations(&self) -> Vec<TorrentCreation> {
+        self.creations
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .cloned()
+            .collect()
+    }
+
+    pub fn creation(&self, id: &str) -> Option<TorrentCreation> {
+        self.creations
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|creation| creation.id == id)
+            .cloned()
+    }
+
+    fn update(&self, id: &str, update: impl FnOnce(&mut TorrentCreation)) {
+        let mut creations = self.creations.lock().unwrap();
+        if let Some(creation) = creations.iter_mut().find(|creation| creation.id == id) {
+            update(creation);
+        }
+    }
+
+    /// Queues a torrent of the file or folder at the path, relative to the download directory
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create(
+        &self,
+        path: String,
+        trackers: Vec<String>,
+        webseeds: Vec<String>,
+        piece_size: Option<u32>,
+        private: bool,
+        comment: Option<String>,
+        seed: bool,
+    ) -> Result<TorrentCreation, String> {
+        let relative = Path::new(&path);
+        let name = match relative.file_name() {
+            Some(name)
+                if relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_))) =>
+            {
+                name.to_string_lossy().into_owned()
+            }
+            _ => return Err(format!("Invalid path {}", path)),
+        };
+        if let Some(piece_size) = piece_size {
+            if !piece_size.is_power_of_two()
+                || !(MIN_PIECE_SIZE..=MAX_PIECE_SIZE).contains(&piece_size)
+            {
+                return Err(format!(
+                    "Piece size must be a power of two from {} to {}",
+                    MIN_PIECE_SIZE, MAX_PIECE_SIZE
+                ));
+            }
+        }
+        for url in trackers.iter().chain(webseeds.iter()) {
+            reqwest::Url::parse(url).map_err(|err| format!("Not valid url {} {}", url, err))?;
+        }
+        let checked = path.clone();
+        tokio::task::spawn_blocking(move || resolve_path(Path::new(&*DOWNLOAD_DIR), &checked))
+            .await
+            .map_err(|err| err.to_string())??;
+        let creation = TorrentCreation {
+            id: new_id(),
+            name,
+            path,
+            trackers,
+            webseeds,
+            piece_size: piece_size.unwrap_or(0),
+            private,
+            comment,
+            seed,
+            state: CreationState::Queued,
+            pieces_hashed: 0,
+            piece_count: 0,
+            hash: None,
+            torrent_id: None,
+            error: None,
+            created: Utc::now(),
+            file: None,
+        };
+        let forgotten = {
+            let mut creations = self.creations.lock().unwrap();
+            let forgotten = if creations.len() >= MAX_CREATIONS {
+                creations.pop_front()
+            } else {
+                None
+            };
+            creations.push_back(creation.clone());
+            forgotten
+        };
+        if let Some(file) = forgotten.and_then(|creation| creation.file) {
+            let _ = tokio::fs::remove_file(file).await;
+        }
+        self.save().await;
+        self.queued.notify_one();
+        Ok(creation)
+    }
+
+    /// Forgets the creation and deletes its .torrent file, the seeding torrent is kept
+    pub async fn remove(&self, id: &str) -> bool {
+        let removed = {
+            let mut creations = self.creations.lock().unwrap();
+            let position = creations
+                .iter()
+                .position(|creation| creation.id == id && creation.state != CreationState::Hashing);
+            position.and_then(|position| creations.remove(position))
+        };
+        match removed {
+            Some(creation) => {
+                if let Some(file) = creation.file {
+                    let _ = tokio::fs::remove_file(file).await;
+                }
+                self.save().await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn next_queued(&self) -> Option<TorrentCreation> {
+        self.creations
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|creation| creation.state == CreationState::Queued)
+            .cloned()
+    }
+}
+
+/// Hashes the files and writes the metainfo, returning it with its info hash
+fn build_metainfo(
+    creator: &TorrentCreator,
+    creation: &TorrentCreation,
+    root: &Path,
+    announce_urls: &[String],
+) -> Result<(Vec<u8>, [u8; 20]), String> {
+    let files = collect_files(root).map_err(|err| format!("Cant read files {}", err))?;
+    let total = files.iter().map(|file| file.1).sum::<u64>();
+    if total == 0 {
+        return Err("No data to create a torrent of".into());
+    }
+    let piece_size = match creation.piece_size {
+        0 => auto_piece_size(total),
+        piece_size => piece_size,
+    };
+    let piece_count = total.div_ceil(piece_size as u64) as u32;
+    creator.update(&creation.id, |creation| {
+        creation.piece_size = piece_size;
+        creation.piece_count = piece_count;
+    });
+
+    let mut pieces = Vec::with_capacity(piece_count as usize * 20);
+    let mut piece = Vec::with_capacity(piece_size as usize);
+    let mut hashed = 0u64;
+    let mut hash_piece = |piece: &mut Vec<u8>| {
+        pieces.extend_from_slice(&Sha1::digest(piece.as_slice()));
+        hashed += piece.len() as u64;
+        piece.clear();
+        let pieces_hashed = (pieces.len() / 20) as u32;
+        creator.update(&creation.id, |creation| {
+            creation.pieces_hashed = pieces_hashed
+        });
+    };
+    for (path, _, _) in files.iter() {
+        let mut file = std::fs::File::open(path)
+            .map_err(|err| format!("Cant open {} {}", path.to_string_lossy(), err))?;
+        loop {
+            let missing = piece_size as u64 - piece.len() as u64;
+            (&mut file)
+                .take(missing)
+                .read_to_end(&mut piece)
+                .map_err(|err| format!("Cant read {} {}", path.to_string_lossy(), err))?;
+            if piece.len() < piece_size as usize {
+                break;
+            }
+            hash_piece(&mut piece);
+        }
+    }
+    if !piece.is_empty() {
+        hash_piece(&mut piece);
+    }
+    if hashed != total {
+        return Err("Files changed while hashing".into());
+    }
+
+    let mut info = vec![
+        ("name", Bencode::string(&creation.name)),
+        ("piece length", Bencode::Int(piece_size as i64)),
+        ("pieces", Bencode::Bytes(pieces)),
+    ];
+    if files.len() == 1 && files[0].2.is_empty() {
+        info.push(("length", Bencode::Int(total as i64)));
+    } else {
+        let files = files
+            .iter()
+            .map(|(_, length, components)| {
+                Bencode::dict([
+                    ("length", Bencode::Int(*length as i64)),
+                    (
+                        "path",
+                        Bencode::List(
+                            components
+                                .iter()
+                                .map(|part| Bencode::string(part))
+                                .collect(),
+                        ),
+                    ),
+                ])
+            })
+            .collect();
+        info.push(("files", Bencode::List(files)));
+    }
+    if creation.private {
+        info.push(("private", Bencode::Int(1)));
+    }
+    let info = Bencode::dict(info);
+    let encoded_info = info.to_bytes();
+    let mut hash = [0u8; 20];
+    hash.copy_from_slice(&Sha1::digest(&encoded_info));
+
+    let mut metainfo = vec![
+        ("created by", Bencode::string("torexpo")),
+        ("creation date", Bencode::Int(Utc::now().timestamp())),
+        ("info", info),
+    ];
+    if let Some(announce) = announce_urls.first() {
+        metainfo.push(("announce", Bencode::string(announce)));
+        // Each tracker in its own tier, tried in order
+        let tiers = announce_urls
+            .iter()
+            .map(|url| Bencode::List(vec![Bencode::string(url)]))
+            .collect();
+        metainfo.push(("announce-list", Bencode::List(tiers)));
+    }
+    if !creation.webseeds.is_empty() {
+        let webseeds = creation.webseeds.iter().map(|url| Bencode::string(url));
+        metainfo.push(("url-list", Bencode::List(webseeds.collect())));
+    }
+    if let Some(comment) = &creation.comment {
+        metainfo.push(("comment", Bencode::string(comment)));
+    }
+    Ok((Bencode::dict(metainfo).to_bytes(), hash))
+}
+
+/// Hashes the creation's files, saves the .torrent file and seeds it when asked
+async fn run_creation(data: &SharedData, creation: &TorrentCreation) -> Result<(), String> {
+    let creator = data.creator.clone();
+    let job = creation.clone();
+    let mut announce_urls = creation.trackers.clone();
+    for url in data.tracker.announce_urls() {
+        if !announce_urls.contains(&url) {
+            announce_urls.push(url);
+        }
+    }
+    let urls = announce_urls.clone();
+    // Links may have changed since the creation was queued
+    let (metainfo, hash) = tokio::task::spawn_blocking(move || {
+        let root = resolve_path(Path::new(&*DOWNLOAD_DIR), &job.path)?;
+        build_metainfo(&creator, &job, &root, &urls)
+    })
+    .await
+    .map_err(|err| err.to_string())??;
+    tokio::fs::create_dir_all(TorrentCreator::folder())
+        .await
+        .map_err(|err| format!("Cant create folder {}", err))?;
+    let file = TorrentCreator::folder().join(format!("{}.torrent", creation.id));
+    tokio::fs::write(&file, &metainfo)
+        .await
+        .map_err(|err| format!("Cant save torrent file {}", err))?;
+    let hash = hash_hex(&hash);
+    if *TRACKER_ENABLED {
+        data.tracker.allow(&hash).await?;
+    }
+    data.creator.update(&creation.id, |creation| {
+        creation.trackers = announce_urls;
+        creation.hash = Some(hash);
+        creation.file = Some(file.to_string_lossy().into_owned());
+    });
+    if creation.seed {
+        // The files are found where they already are
+        let parent = Path::new(&creation.path)
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .map(|parent| {
+                Path::new(&*DOWNLOAD_DIR)
+                    .join(parent)
+                    .to_string_lossy()
+                    .into_owned()
+            });
+        let options = AddTorrentOptions {
+            download_dir: parent,
+            ..Default::default()
+        };
+        let torrent_id = data.add_metainfo(&metainfo, options).await?;
+        // The torrent is complete as soon as it is verified, the seed buster would stop it
+        if let Some(torrent) = data
+            .torrents
+            .get(&torrent_id)
+            .map(|torrent| torrent.value().clone())
+        {
+            data.seeding.keep(&torrent).await;
+            torrent.start();
+        }
+        data.creator.update(&creation.id, |creation| {
+            creation.torrent_id = Some(torrent_id)
+        });
+    }
+    Ok(())
+}
+
+/// Works through queued torrent creations one at a time
+pub async fn creation_worker(data: SharedData) {
+    loop {
+        data.creator.queued.notified().await;
+        while let Some(creation) = data.creator.next_queued() {
+            data.creator.update(&creation.id, |creation| {
+                creation.state = CreationState::Hashing
+            });
+            data.creator.save().await;
+            log::info!("Creating torrent of {}", creation.path);
+            let result = run_creation(&data, &creation).await;
+            data.creator.update(&creation.id, |creation| match result {
+                Ok(_) => creation.state = CreationState::Done,
+                Err(err) => {
+                    log::warn!("Cant create torrent of {} {}", creation.path, err);
+                    creation.state = CreationState::Failed;
+                    creation.error = Some(err);
+                }
+            });
+            data.creator.save().await;
+        }
+    }
+}
+
+/// Polls a creation, yielding it whenever it changes until it is done or failed
+pub fn creation_stream(
+    creator: Arc<TorrentCreator>,
+    id: String,
+) -> impl futures_util::Stream<Item = TorrentCreation> {
+    async_stream::stream! {
+        let mut last_sent = None;
+        while let Some(creation) = creator.creation(&id) {
+            if last_sent.as_ref() != Some(&creation) {
+                last_sent = Some(creation.clone());
+                let finished =
+                    matches!(creation.state, CreationState::Done | CreationState::Failed);
+                yield creation;
+                if finished {
+                    break;
+                }
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn creation(piece_size: u32) -> TorrentCreation {
+        TorrentCreation {
+            id: "creation".into(),
+            name: "show".into(),
+            path: "show".into(),
+            trackers: vec![],
+            webseeds: vec!["https://seed.example/".into()],
+            piece_size,
+            private: true,
+            comment: None,
+            seed: false,
+            state: CreationState::Hashing,
+            pieces_hashed: 0,
+            piece_count: 0,
+            hash: None,
+            torrent_id: None,
+            error: None,
+            created: Utc::now(),
+            file: None,
+        }
+    }
+
+    fn bytes(value: Option<&Bencode>) -> &[u8] {
+        match value {
+            Some(Bencode::Bytes(bytes)) => bytes,
+            _ => panic!("not a byte string"),
+        }
+    }
+
+    fn int(value: Option<&Bencode>) -> i64 {
+        match value {
+            Some(Bencode::Int(value)) => *value,
+            _ => panic!("not an integer"),
+        }
+    }
+
+    #[test]
+    fn auto_piece_size_targets_piece_count() {
+        assert_eq!(auto_piece_size(0), MIN_PIECE_SIZE);
+        assert_eq!(
+            auto_piece_size(TARGET_PIECES * MIN_PIECE_SIZE as u64),
+            MIN_PIECE_SIZE
+        );
+        assert_eq!(
+            auto_piece_size((TARGET_PIECES + 1) * MIN_PIECE_SIZE as u64),
+            2 * MIN_PIECE_SIZE
+        );
+        assert_eq!(auto_piece_size(1 << 50), MAX_PIECE_SIZE);
+    }
+
+    #[test]
+    fn paths_are_resolved_inside_the_download_dir() {
+        let temp = tempfile::tempdir().unwrap();
+        let download_dir = temp.path().join("downloads");
+        std::fs::create_dir_all(download_dir.join("show/season")).unwrap();
+        std::fs::create_dir(temp.path().join("private")).unwrap();
+        std::os::unix::fs::symlink(temp.path().join("private"), download_dir.join("escape"))
+            .unwrap();
+        std::os::unix::fs::symlink(download_dir.join("show"), download_dir.join("link")).unwrap();
+        let download_dir_resolved = download_dir.canonicalize().unwrap();
+
+        assert_eq!(
+            resolve_path(&download_dir, "show/season"),
+            Ok(download_dir_resolved.join("show/season"))
+        );
+        assert_eq!(
+            resolve_path(&download_dir, "link"),
+            Ok(download_dir_resolved.join("show"))
+        );
+        assert!(resolve_path(&download_dir, "escape").is_err());
+        assert!(resolve_path(&download_dir, "missing").is_err());
+    }
+
+    #[test]
+    fn build_metainfo_of_a_folder() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::write(root.join("sub").join("b.txt"), b"defg").unwrap();
+        std::fs::write(root.join("a.txt"), b"abc").unwrap();
+        let announce_urls = ["https://tracker.example/announce".to_string()];
+        let (metainfo, hash) = build_metainfo(
+            &TorrentCreator::default(),
+            &creation(4),
+            root,
+            &announce_urls,
+        )
+        .unwrap();
+
+        let metainfo = Bencode::decode(&metainfo).unwrap();
+        assert_eq!(
+            bytes(metainfo.get("announce")),
+            b"https://tracker.example/announce"
+        );
+        assert_eq!(
+            bytes(metainfo.get("url-list").and_then(|urls| match urls {
+                Bencode::List(urls) => urls.first(),
+                _ => None,
+            })),
+            b"https://seed.example/"
+        );
+        let info = metainfo.get("info").unwrap();
+        assert_eq!(hash.as_slice(), Sha1::digest(info.to_bytes()).as_slice());
+        assert_eq!(bytes(info.get("name")), b"show");
+        assert_eq!(int(info.get("piece length")), 4);
+        assert_eq!(int(info.get("private")), 1);
+        // Files are hashed in path order as one stream of pieces
+        let mut pieces = Sha1::digest(b"abcd").to_vec();
+        pieces.extend_from_slice(&Sha1::digest(b"efg"));
+        assert_eq!(bytes(info.get("pieces")), pieces.as_slice());
+        let files = match info.get("files") {
+            Some(Bencode::List(files)) => files,
+            _ => panic!("no files"),
+        };
+        let files = files
+            .iter()
+            .map(|file| {
+                let path = match file.get("path") {
+                    Some(Bencode::List(path)) => path
+                        .iter()
+                        .map(|part| String::from_utf8_lossy(bytes(Some(part))).into_owned())
+                        .collect::<Vec<_>>(),
+                    _ => panic!("no path"),
+                };
+                (path.join("/"), int(file.get("length")))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            vec![("a.txt".to_string(), 3), ("sub/b.txt".to_string(), 4)]
+        );
+    }
+
+    #[test]
+    fn build_metainfo_of_an_empty_folder() {
+        let temp = tempfile::tempdir().unwrap();
+        let result = build_metainfo(&TorrentCreator::default(), &creation(0), temp.path(), &[]);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,409 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use async_graphql::SimpleObject;
+use dashmap::DashMap;
+use transmission::Torrent;
+
+use crate::{
+    context::{SharedData, TorrentHashes},
+    download_dirs::DownloadDirs,
+    structures::TorrentState,
+    CONFIG_DIR, DOWNLOAD_DIR, INCOMPLETE_DIR,
+};
+
+fn env_bytes(name: &str) -> Option<u64> {
+    std::env::var(name)
+        .ok()
+        .and_then(|bytes| bytes.parse().ok())
+}
+
+lazy_static::lazy_static! {
+    /// Free space adds must leave on the download volume
+    static ref DISK_RESERVE: u64 = env_bytes("TOREXPO_DISK_RESERVE").unwrap_or(0);
+    /// Downloads are paused when free space drops below this, disabled when not set
+    static ref PAUSE_BELOW: Option<u64> = env_bytes("TOREXPO_DISK_PAUSE_BELOW");
+    /// Paused downloads resume once free space is back above this
+    static ref RESUME_ABOVE: Option<u64> = PAUSE_BELOW.map(|pause_below| {
+        resume_threshold(pause_below, env_bytes("TOREXPO_DISK_RESUME_ABOVE"))
+    });
+}
+
+/// Downloads resume at twice the pause threshold unless set, never below it
+fn resume_threshold(pause_below: u64, resume_above: Option<u64>) -> u64 {
+    resume_above
+        .unwrap_or_else(|| pause_below.saturating_mul(2))
+        .max(pause_below)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum SpaceAction {
+    Pause,
+    Resume,
+    /// Between the thresholds nothing changes, so downloads don't flap
+    Keep,
+}
+
+fn space_action(available: u64, pause_below: u64, resume_above: u64) -> SpaceAction {
+    if available < pause_below {
+        SpaceAction::Pause
+    } else if available >= resume_above {
+        SpaceAction::Resume
+    } else {
+        SpaceAction::Keep
+    }
+}
+
+#[derive(SimpleObject, Clone)]
+pub struct Volume {
+    /// What the volume holds, `download`, `incomplete` or `config`
+    pub name: String,
+    pub path: String,
+    /// Size of the volume in bytes
+    pub total: u64,
+    /// Bytes available to torexpo
+    pub available: u64,
+    pub used: u64,
+}
+
+#[derive(SimpleObject)]
+pub struct DiskSpace {
+    pub volumes: Vec<Volume>,
+    /// Free space adds must leave
+    pub reserve: u64,
+    pub pause_below: Option<u64>,
+    pub resume_above: Option<u64>,
+    /// Torrents paused because the disk is almost full
+    pub paused_torrents: Vec<i32>,
+}
+
+fn volume(name: &str, path: &Path) -> Option<Volume> {
+    let total = fs2::total_space(path).ok()?;
+    let available = fs2::available_space(path).ok()?;
+    Some(Volume {
+        name: name.into(),
+        path: path.to_string_lossy().into(),
+        total,
+        available,
+        used: total.saturating_sub(fs2::free_space(path).unwrap_or(available)),
+    })
+}
+
+/// Folder new data is written to while downloading
+fn download_volume(download_dir: &Path) -> PathBuf {
+    match &*INCOMPLETE_DIR {
+        Some(incomplete_dir) => PathBuf::from(incomplete_dir),
+        None => download_dir.to_path_buf(),
+    }
+}
+
+/// Keeps torrents from filling the disk
+#[derive(Default)]
+pub struct DiskGuard {
+    /// Torrents stopped by the guard, started again once there is space
+    paused: Mutex<HashSet<i32>>,
+    /// Magnet links whose size was checked once their metadata arrived
+    checked: Mutex<HashSet<i32>>,
+    hashes: Arc<TorrentHashes>,
+}
+
+impl DiskGuard {
+    fn path() -> PathBuf {
+        Path::new(&CONFIG_DIR.clone()).join("disk_guard.json")
+    }
+
+    /// Loads the torrents paused for space, the loaded torrents were checked when added
+    pub async fn load(torrents: &DashMap<i32, Torrent>, hashes: &Arc<TorrentHashes>) -> Self {
+        let guard = DiskGuard {
+            hashes: hashes.clone(),
+            ..Default::default()
+        };
+        guard
+            .checked
+            .lock()
+            .unwrap()
+            .extend(torrents.iter().map(|torrent| *torrent.key()));
+        let saved = match tokio::fs::read(Self::path()).await {
+            Ok(saved) => serde_json::from_slice::<HashSet<String>>(&saved),
+            Err(_) => return guard,
+        };
+        match saved {
+            Ok(saved) => guard.paused.lock().unwrap().extend(
+                torrents
+                    .iter()
+                    .filter(|torrent| saved.contains(&hashes.hash(torrent.value())))
+                    .map(|torrent| *torrent.key()),
+            ),
+            Err(err) => log::warn!("Cant read paused torrents {:#?}", err),
+        }
+        guard
+    }
+
+    async fn save(&self, torrents: &DashMap<i32, Torrent>) {
+        let paused = self.paused.lock().unwrap().clone();
+        let saved = paused
+            .iter()
+            .filter_map(|id| torrents.get(id))
+            .map(|torrent| self.hashes.hash(torrent.value()))
+            .collect::<HashSet<_>>();
+        match serde_json::to_vec_pretty(&saved) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save paused torrents {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save paused torrents {:#?}", err),
+        }
+    }
+
+    pub fn disk_space(&self) -> DiskSpace {
+        let mut volumes = vec![];
+        volumes.extend(volume("download", Path::new(&*DOWNLOAD_DIR)));
+        if let Some(incomplete_dir) = &*INCOMPLETE_DIR {
+            volumes.extend(volume("incomplete", Path::new(incomplete_dir)));
+        }
+        volumes.extend(volume("config", Path::new(&*CONFIG_DIR)));
+        let mut paused_torrents = self
+            .paused
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect::<Vec<_>>();
+        paused_torrents.sort_unstable();
+        DiskSpace {
+            volumes,
+            reserve: *DISK_RESERVE,
+            pause_below: *PAUSE_BELOW,
+            resume_above: *RESUME_ABOVE,
+            paused_torrents,
+        }
+    }
+
+    /// Torrents the guard paused and will resume
+    fn paused(&self) -> HashSet<i32> {
+        self.paused.lock().unwrap().clone()
+    }
+
+    /// Stops managing a torrent the user started or stopped
+    pub fn forget(&self, torrent_id: i32) {
+        self.paused.lock().unwrap().remove(&torrent_id);
+    }
+
+    /// Checks the torrent fits on the disk, with the data running downloads still need.
+    ///
+    /// Torrents without metadata pass, they are checked once it arrives.
+    pub async fn check_add(
+        &self,
+        torrents: &Arc<DashMap<i32, Torrent>>,
+        download_dirs: &Arc<DownloadDirs>,
+        torrent: &Torrent,
+    ) -> Result<(), String> {
+        let torrent_id = torrent.id();
+        let (torrents, download_dirs, torrent) =
+            (torrents.clone(), download_dirs.clone(), torrent.clone());
+        let checked = tokio::task::spawn_blocking(move || {
+            let stats = torrent.stats();
+            if stats.metadata_percent_complete < 1.0 {
+                return None;
+            }
+            Some(fits_on_disk(
+                &torrents,
+                &download_dirs,
+                &torrent,
+                stats.size_when_done,
+            ))
+        })
+        .await
+        .map_err(|err| err.to_string())?;
+        match checked {
+            Some(result) => {
+                self.checked.lock().unwrap().insert(torrent_id);
+                result
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Stops magnet links too large for the disk once their metadata arrives
+    async fn check_magnets(&self, data: &SharedData) {
+        let checked = self.checked.lock().unwrap().clone();
+        let torrents = data.torrents.clone();
+        let unchecked = tokio::task::spawn_blocking(move || {
+            torrents
+                .iter()
+                .filter(|torrent| !checked.contains(torrent.key()))
+                .filter(|torrent| torrent.value().stats().metadata_percent_complete >= 1.0)
+                .map(|torrent| torrent.value().clone())
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_default();
+        for torrent in unchecked {
+            if let Err(err) = self
+                .check_add(&data.torrents, &data.download_dirs, &torrent)
+                .await
+            {
+                log::warn!("Stopping {} {}", torrent.name(), err);
+                torrent.stop();
+            }
+        }
+        let mut checked = self.checked.lock().unwrap();
+        checked.retain(|id| data.torrents.contains_key(id));
+    }
+
+    /// Pauses downloads when space runs low and resumes them once it is freed
+    async fn check_free_space(&self, data: &SharedData) {
+        let (pause_below, resume_above) = match (*PAUSE_BELOW, *RESUME_ABOVE) {
+            (Some(pause_below), Some(resume_above)) => (pause_below, resume_above),
+            _ => return,
+        };
+        let torrents = data.torrents.clone();
+        let checked = tokio::task::spawn_blocking(move || {
+            let available =
+                fs2::available_space(download_volume(Path::new(&*DOWNLOAD_DIR))).ok()?;
+            let downloading = torrents
+                .iter()
+                .filter(|torrent| {
+                    matches!(
+                        TorrentState::from(torrent.value().stats().state),
+                        TorrentState::Downloading | TorrentState::DownloadingWait
+                    )
+                })
+                .map(|torrent| torrent.value().clone())
+                .collect::<Vec<_>>();
+            Some((available, downloading))
+        })
+        .await;
+        let (available, downloading) = match checked {
+            Ok(Some(checked)) => checked,
+            _ => return,
+        };
+        let mut paused = self.paused.lock().unwrap();
+        paused.retain(|id| data.torrents.contains_key(id));
+        match space_action(available, pause_below, resume_above) {
+            SpaceAction::Pause => {
+                for torrent in downloading {
+                    log::warn!("Pausing {}, {} bytes free", torrent.name(), available);
+                    torrent.stop();
+                    paused.insert(torrent.id());
+                }
+            }
+            SpaceAction::Resume => {
+                for id in paused.drain() {
+                    if let Some(torrent) = data.torrents.get(&id) {
+                        log::info!(
+                            "Resuming {}, {} bytes free",
+                            torrent.value().name(),
+                            available
+                        );
+                        torrent.value().start();
+                    }
+                }
+            }
+            SpaceAction::Keep => {}
+        }
+    }
+}
+
+/// Checks the torrent fits on the disk next to the data running downloads still need.
+///
+/// Stopped torrents, including those waiting for a scheduled start, are not counted.
+fn fits_on_disk(
+    torrents: &DashMap<i32, Torrent>,
+    download_dirs: &DownloadDirs,
+    torrent: &Torrent,
+    size_when_done: u64,
+) -> Result<(), String> {
+    let torrent_id = torrent.id();
+    let available = match fs2::available_space(download_volume(&download_dirs.dir(torrent_id))) {
+        Ok(available) => available,
+        Err(err) => {
+            log::warn!("Cant read free disk space {:#?}", err);
+            return Ok(());
+        }
+    };
+    let pending = torrents
+        .iter()
+        .filter(|other| *other.key() != torrent_id)
+        .map(|other| other.value().stats())
+        .filter(|stats| TorrentState::from(stats.state) != TorrentState::Stopped)
+        .map(|stats| stats.left_until_done)
+        .sum::<u64>();
+    // Files already in place, like those of torrents created here, need no space,
+    // whether they are in the download or the incomplete directory
+    let present = torrent
+        .info()
+        .files
+        .iter()
+        .filter_map(|file| {
+            std::fs::metadata(download_dirs.data_path(torrent_id, &file.name))
+                .ok()
+                .map(|metadata| metadata.len().min(file.length))
+        })
+        .sum::<u64>();
+    check_space(
+        size_when_done.saturating_sub(present) + pending,
+        *DISK_RESERVE,
+        available,
+    )
+}
+
+fn check_space(needed: u64, reserve: u64, available: u64) -> Result<(), String> {
+    let needed = needed + reserve;
+    if needed > available {
+        return Err(format!(
+            "Not enough disk space, {} bytes needed with the reserve but {} available",
+            needed, available
+        ));
+    }
+    Ok(())
+}
+
+/// Watches free disk space and the size of magnet links once they get their metadata
+pub async fn disk_guard(data: SharedData) {
+    let mut saved = data.disk.paused();
+    loop {
+        tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+        data.disk.check_magnets(&data).await;
+        data.disk.check_free_space(&data).await;
+        // Saved whenever the guard or the user changed the paused torrents
+        let paused = data.disk.paused();
+        if paused != saved {
+            data.disk.save(&data.torrents).await;
+            saved = paused;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_needs_room_for_the_reserve() {
+        assert!(check_space(100, 0, 100).is_ok());
+        assert!(check_space(100, 50, 150).is_ok());
+        assert!(check_space(100, 50, 149).is_err());
+        assert!(check_space(0, 0, 0).is_ok());
+    }
+
+    #[test]
+    fn downloads_pause_below_and_resume_above() {
+        assert_eq!(space_action(99, 100, 200), SpaceAction::Pause);
+        assert_eq!(space_action(100, 100, 200), SpaceAction::Keep);
+        assert_eq!(space_action(199, 100, 200), SpaceAction::Keep);
+        assert_eq!(space_action(200, 100, 200), SpaceAction::Resume);
+    }
+
+    #[test]
+    fn resume_threshold_is_never_below_pause() {
+        assert_eq!(resume_threshold(100, None), 200);
+        assert_eq!(resume_threshold(100, Some(150)), 150);
+        assert_eq!(resume_threshold(100, Some(50)), 100);
+        assert_eq!(resume_threshold(u64::MAX, None), u64::MAX);
+    }
+}
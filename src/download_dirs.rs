@@ -0,0 +1,268 @@
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use transmission::Torrent;
+
+use crate::{
+    context::TorrentHashes, CONFIG_DIR, DOWNLOAD_DIR, INCOMPLETE_DIR, RENAME_PARTIAL_FILES,
+};
+
+lazy_static::lazy_static! {
+    /// Folders torrents may download into, the default one and those listed like `PATH`
+    /// in `TOREXPO_ALLOWED_DOWNLOAD_DIRS`
+    static ref ALLOWED_DIRS: Vec<PathBuf> = allowed_dirs(
+        &DOWNLOAD_DIR,
+        std::env::var_os("TOREXPO_ALLOWED_DOWNLOAD_DIRS").as_deref(),
+    );
+}
+
+fn allowed_dirs(download_dir: &str, extra: Option<&OsStr>) -> Vec<PathBuf> {
+    std::iter::once(PathBuf::from(download_dir))
+        .chain(extra.map(std::env::split_paths).into_iter().flatten())
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(|dir| resolve(&dir))
+        .collect()
+}
+
+/// Absolute form of the path with symlinks resolved in the part which exists,
+/// the rest is resolved without touching the disk as it doesn't exist yet
+fn resolve(path: &Path) -> PathBuf {
+    let path = std::env::current_dir().unwrap_or_default().join(path);
+    for existing in path.ancestors() {
+        if let Ok(mut resolved) = existing.canonicalize() {
+            for component in path.strip_prefix(existing).unwrap_or(&path).components() {
+                match component {
+                    Component::CurDir => {}
+                    Component::ParentDir => {
+                        resolved.pop();
+                    }
+                    component => resolved.push(component),
+                }
+            }
+            return resolved;
+        }
+    }
+    path
+}
+
+/// Checks the directory is inside the default download directory or an allowed one
+pub fn check_allowed(dir: &Path) -> Result<(), String> {
+    check_allowed_in(dir, &ALLOWED_DIRS)
+}
+
+fn check_allowed_in(dir: &Path, allowed: &[PathBuf]) -> Result<(), String> {
+    let dir = resolve(dir);
+    if allowed.iter().any(|allowed| dir.starts_with(allowed)) {
+        Ok(())
+    } else {
+        Err(format!("Download dir {} is not allowed", dir.display()))
+    }
+}
+
+/// Writes the incomplete directory and `.part` suffix options to `settings.json` in the
+/// config directory, which libtransmission reads over the client config when it starts
+pub fn write_session_settings(config_dir: &str) {
+    let path = Path::new(config_dir).join("settings.json");
+    let mut settings = std::fs::read(&path)
+        .ok()
+        .and_then(|saved| {
+            serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(&saved).ok()
+        })
+        .unwrap_or_default();
+    settings.insert(
+        "incomplete-dir-enabled".into(),
+        INCOMPLETE_DIR.is_some().into(),
+    );
+    if let Some(incomplete_dir) = &*INCOMPLETE_DIR {
+        settings.insert("incomplete-dir".into(), incomplete_dir.as_str().into());
+    }
+    settings.insert(
+        "rename-partial-files".into(),
+        (*RENAME_PARTIAL_FILES).into(),
+    );
+    let saved = std::fs::create_dir_all(config_dir).and_then(|_| {
+        std::fs::write(
+            &path,
+            serde_json::to_vec_pretty(&settings).unwrap_or_default(),
+        )
+    });
+    if let Err(err) = saved {
+        log::warn!("Cant save session settings {:#?}", err);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SavedDir {
+    #[serde(skip)]
+    hash: String,
+    dir: String,
+}
+
+/// Download directory of each torrent, saved in the config directory
+#[derive(Default)]
+pub struct DownloadDirs {
+    dirs: DashMap<i32, SavedDir>,
+    hashes: Arc<TorrentHashes>,
+}
+
+impl DownloadDirs {
+    fn path() -> PathBuf {
+        std::path::Path::new(&CONFIG_DIR.clone()).join("download_dirs.json")
+    }
+
+    /// Loads saved directories for the given torrents, matched by info hash
+    pub async fn load(torrents: &DashMap<i32, Torrent>, hashes: &Arc<TorrentHashes>) -> Self {
+        let dirs = DownloadDirs {
+            hashes: hashes.clone(),
+            ..Default::default()
+        };
+        let saved = match tokio::fs::read(Self::path()).await {
+            Ok(saved) => serde_json::from_slice::<HashMap<String, SavedDir>>(&saved),
+            Err(_) => return dirs,
+        };
+        match saved {
+            Ok(mut saved) => {
+                for torrent in torrents.iter() {
+                    let hash = hashes.hash(torrent.value());
+                    if let Some(mut dir) = saved.remove(&hash) {
+                        dir.hash = hash;
+                        dirs.dirs.insert(*torrent.key(), dir);
+                    }
+                }
+            }
+            Err(err) => log::warn!("Cant read download dirs {:#?}", err),
+        }
+        dirs
+    }
+
+    async fn save(&self) {
+        let saved = self
+            .dirs
+            .iter()
+            .map(|dir| (dir.hash.clone(), dir.value().clone()))
+            .collect::<HashMap<_, _>>();
+        match serde_json::to_vec_pretty(&saved) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save download dirs {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save download dirs {:#?}", err),
+        }
+    }
+
+    /// Directory the files of the torrent are downloaded to
+    pub fn dir(&self, torrent_id: i32) -> PathBuf {
+        self.dirs
+            .get(&torrent_id)
+            .map(|dir| PathBuf::from(&dir.dir))
+            .unwrap_or_else(|| PathBuf::from(DOWNLOAD_DIR.clone()))
+    }
+
+    /// Path of a file or folder of the torrent on disk.
+    ///
+    /// Until the torrent completes its data may be in the incomplete directory, and
+    /// files may have a `.part` suffix. Checks the disk, so call it off the async runtime.
+    pub fn data_path(&self, torrent_id: i32, name: &str) -> PathBuf {
+        let path = self.dir(torrent_id).join(name);
+        let mut candidates = vec![path.clone()];
+        if let Some(incomplete_dir) = &*INCOMPLETE_DIR {
+            candidates.push(Path::new(incomplete_dir).join(name));
+        }
+        if *RENAME_PARTIAL_FILES {
+            let partial = candidates
+                .iter()
+                .map(|candidate| {
+                    let mut partial = candidate.clone().into_os_string();
+                    partial.push(".part");
+                    PathBuf::from(partial)
+                })
+                .collect::<Vec<_>>();
+            candidates.extend(partial);
+        }
+        candidates
+            .into_iter()
+            .find(|candidate| candidate.exists())
+            .unwrap_or(path)
+    }
+
+    /// Downloads the torrent to the directory, which has to be an allowed one
+    pub async fn set_dir(&self, torrent: &Torrent, dir: &str) -> Result<(), String> {
+        check_allowed(Path::new(dir))?;
+        tokio::fs::create_dir_all(dir)
+            .await
+            .map_err(|err| format!("Cant create download dir {}", err))?;
+        torrent.clone().set_download_dir(PathBuf::from(dir));
+        self.dirs.insert(
+            torrent.id(),
+            SavedDir {
+                hash: self.hashes.hash(torrent),
+                dir: dir.into(),
+            },
+        );
+        self.save().await;
+        Ok(())
+    }
+
+    pub async fn remove(&self, torrent_id: i32) {
+        if self.dirs.remove(&torrent_id).is_some() {
+            self.save().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allowed_dirs_are_read_like_path() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        let extra =
+            std::env::join_paths([root.join("media"), PathBuf::new(), root.join("tv")]).unwrap();
+        assert_eq!(
+            allowed_dirs(root.join("downloads").to_str().unwrap(), Some(&extra)),
+            vec![root.join("downloads"), root.join("media"), root.join("tv")]
+        );
+        assert_eq!(
+            allowed_dirs(root.join("downloads").to_str().unwrap(), None),
+            vec![root.join("downloads")]
+        );
+    }
+
+    #[test]
+    fn dirs_outside_the_allowed_ones_are_refused() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::create_dir(root.join("downloads")).unwrap();
+        let allowed = [root.join("downloads")];
+        assert!(check_allowed_in(&root.join("downloads/movies/new"), &allowed).is_ok());
+        assert!(check_allowed_in(&root.join("downloads/movies/../tv"), &allowed).is_ok());
+        assert!(check_allowed_in(&root.join("downloads/../elsewhere"), &allowed).is_err());
+        assert!(check_allowed_in(&root.join("downloads/new/../../elsewhere"), &allowed).is_err());
+        assert!(check_allowed_in(Path::new("/etc"), &allowed).is_err());
+        assert!(check_allowed_in(&root, &allowed).is_err());
+    }
+
+    #[test]
+    fn symlinks_out_of_the_allowed_dirs_are_refused() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        std::fs::create_dir_all(root.join("downloads/movies")).unwrap();
+        std::fs::create_dir(root.join("elsewhere")).unwrap();
+        std::os::unix::fs::symlink(root.join("elsewhere"), root.join("downloads/link")).unwrap();
+        std::os::unix::fs::symlink(root.join("downloads/movies"), root.join("movies")).unwrap();
+        let allowed = [root.join("downloads")];
+        assert!(check_allowed_in(&root.join("downloads/link"), &allowed).is_err());
+        assert!(check_allowed_in(&root.join("downloads/link/new"), &allowed).is_err());
+        assert!(check_allowed_in(&root.join("downloads/link/../other"), &allowed).is_err());
+        assert!(check_allowed_in(&root.join("movies/new"), &allowed).is_ok());
+    }
+}
@@ -0,0 +1,235 @@
+use std::borrow::Cow;
+
+use axum::http::StatusCode;
+use bincode::Options;
+use chrono::{DateTime, Duration, Utc};
+use magic_crypt::MagicCryptTrait;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{archive::ArchiveFormat, MCRYPT};
+
+/// Kind of a link, written in front of its structure so that a link of one kind is
+/// never accepted as another
+pub trait LinkKind {
+    const KIND: u8;
+}
+
+/// A structure that can be encrypted into a download link
+pub trait SignedLink: LinkKind + Serialize + DeserializeOwned {
+    /// Time after which the link is no longer valid
+    fn expiry(&self) -> Option<&DateTime<Utc>>;
+}
+
+/// Link to a single file of a torrent, located on disk when it is downloaded.
+///
+/// Links name the torrent by info hash, its id changes when the server restarts.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DownloadLinkStructure<'a> {
+    pub hash: Cow<'a, str>,
+    /// Path of the file relative to the torrent's download directory
+    pub name: Cow<'a, str>,
+    pub expiry: Option<DateTime<Utc>>,
+}
+
+impl LinkKind for DownloadLinkStructure<'_> {
+    const KIND: u8 = 0;
+}
+
+impl SignedLink for DownloadLinkStructure<'static> {
+    fn expiry(&self) -> Option<&DateTime<Utc>> {
+        self.expiry.as_ref()
+    }
+}
+
+/// Link to the original metainfo (.torrent) file of a torrent
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetainfoLinkStructure<'a> {
+    pub file: Cow<'a, str>,
+    /// Name of the torrent, used as the downloaded filename
+    pub name: Cow<'a, str>,
+    pub expiry: Option<DateTime<Utc>>,
+}
+
+impl LinkKind for MetainfoLinkStructure<'_> {
+    const KIND: u8 = 1;
+}
+
+impl SignedLink for MetainfoLinkStructure<'static> {
+    fn expiry(&self) -> Option<&DateTime<Utc>> {
+        self.expiry.as_ref()
+    }
+}
+
+/// Link to a whole torrent or one of its folders, streamed as an archive.
+///
+/// Located on disk when it is served, like download links.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveLinkStructure<'a> {
+    pub hash: Cow<'a, str>,
+    /// Folder inside the torrent to archive, the whole torrent when not set
+    pub folder: Option<Cow<'a, str>>,
+    pub format: ArchiveFormat,
+    pub expiry: Option<DateTime<Utc>>,
+}
+
+impl LinkKind for ArchiveLinkStructure<'_> {
+    const KIND: u8 = 2;
+}
+
+impl SignedLink for ArchiveLinkStructure<'static> {
+    fn expiry(&self) -> Option<&DateTime<Utc>> {
+        self.expiry.as_ref()
+    }
+}
+
+/// Link to stream a file of a torrent while it downloads
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StreamLinkStructure {
+    /// Info hash of the torrent
+    pub hash: String,
+    pub file_index: u32,
+    pub expiry: Option<DateTime<Utc>>,
+}
+
+impl LinkKind for StreamLinkStructure {
+    const KIND: u8 = 3;
+}
+
+impl SignedLink for StreamLinkStructure {
+    fn expiry(&self) -> Option<&DateTime<Utc>> {
+        self.expiry.as_ref()
+    }
+}
+
+pub fn expiry_from_secs(expiry_secs: Option<u64>) -> Option<DateTime<Utc>> {
+    expiry_secs.map(|secs| chrono::Utc::now() + Duration::seconds(secs as i64))
+}
+
+/// `Content-Disposition` value for a file served from a link.
+///
+/// The quoted name is an ASCII fallback, the full name is in the RFC 5987 `filename*`.
+pub fn content_disposition(disposition: &str, filename: &str) -> String {
+    let fallback = filename
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    format!(
+        "{}; filename=\"{}\"; filename*=UTF-8''{}",
+        disposition,
+        fallback,
+        urlencoding::encode(filename)
+    )
+}
+
+/// Trailing bytes are rejected, so a structure never decodes from a longer one
+fn link_options() -> impl Options {
+    bincode::DefaultOptions::new().reject_trailing_bytes()
+}
+
+/// Encrypts the structure, tagged with its kind, into an url safe link segment
+pub fn encode_link<T: LinkKind + Serialize>(structure: &T) -> Option<String> {
+    match link_options().serialize(structure) {
+        Ok(bincoded) => {
+            let tagged = [&[T::KIND], bincoded.as_slice()].concat();
+            let encrypted = MCRYPT.encrypt_bytes_to_base64(&tagged);
+            Some(urlencoding::encode(&encrypted).into_owned())
+        }
+        Err(_) => None,
+    }
+}
+
+/// Decrypts a link segment of the structure's kind and checks that it has not expired
+pub fn decode_link<T: SignedLink>(link: &str) -> Result<T, (StatusCode, String)> {
+    let invalid = || (StatusCode::BAD_REQUEST, "invalid download link".to_string());
+    let data = MCRYPT
+        .decrypt_base64_to_bytes(link)
+        .map_err(|_| invalid())?;
+    let structure = match data.split_first() {
+        Some((kind, data)) if *kind == T::KIND => link_options()
+            .deserialize::<T>(data)
+            .map_err(|_| invalid())?,
+        _ => return Err(invalid()),
+    };
+    if let Some(expiry) = structure.expiry() {
+        if &chrono::Utc::now() > expiry {
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "Expired download link".to_string(),
+            ));
+        }
+    }
+    Ok(structure)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn download_link() -> String {
+        encode_link(&DownloadLinkStructure {
+            hash: "ab".repeat(20).into(),
+            name: "Folder/File.mkv".into(),
+            expiry: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn link_decodes_as_its_kind() {
+        let link = urlencoding::decode(&download_link()).unwrap().into_owned();
+        let structure = decode_link::<DownloadLinkStructure>(&link).unwrap();
+        assert_eq!(structure.hash, "ab".repeat(20));
+        assert_eq!(structure.name, "Folder/File.mkv");
+    }
+
+    #[test]
+    fn link_does_not_decode_as_another_kind() {
+        let link = urlencoding::decode(&download_link()).unwrap().into_owned();
+        assert!(decode_link::<MetainfoLinkStructure>(&link).is_err());
+    }
+
+    #[test]
+    fn archive_link_names_the_torrent_and_folder() {
+        let link = encode_link(&ArchiveLinkStructure {
+            hash: "ab".repeat(20).into(),
+            folder: Some("Season 1".into()),
+            format: ArchiveFormat::Zip,
+            expiry: None,
+        })
+        .unwrap();
+        let link = urlencoding::decode(&link).unwrap().into_owned();
+        let structure = decode_link::<ArchiveLinkStructure>(&link).unwrap();
+        assert_eq!(structure.hash, "ab".repeat(20));
+        assert_eq!(structure.folder.as_deref(), Some("Season 1"));
+        assert!(decode_link::<DownloadLinkStructure>(&link).is_err());
+    }
+
+    #[test]
+    fn content_disposition_escapes_the_name() {
+        assert_eq!(
+            content_disposition("attachment", "Show \"Name\" é.zip"),
+            "attachment; filename=\"Show _Name_ _.zip\"; filename*=UTF-8''Show%20%22Name%22%20%C3%A9.zip"
+        );
+        let header = content_disposition("inline", "a\\b\n.mkv");
+        assert!(axum::http::HeaderValue::from_str(&header).is_ok());
+    }
+
+    #[test]
+    fn expired_link_is_rejected() {
+        let link = encode_link(&MetainfoLinkStructure {
+            file: "torrents/file.torrent".into(),
+            name: "Torrent".into(),
+            expiry: Some(Utc::now() - Duration::seconds(1)),
+        })
+        .unwrap();
+        let link = urlencoding::decode(&link).unwrap().into_owned();
+        let err = decode_link::<MetainfoLinkStructure>(&link).unwrap_err();
+        assert_eq!(err.0, StatusCode::UNAUTHORIZED);
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use async_graphql::{Enum, SimpleObject};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use transmission::Torrent;
+
+use crate::{structures::TorrentState, CONFIG_DIR};
+
+/// Events kept in memory to replay to subscribers that reconnect
+const RECENT_EVENTS: usize = 1000;
+
+/// Ids reserved on disk at a time, so ids are not saved for every event
+const RESERVED_IDS: u64 = 1000;
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Serialize, Debug)]
+pub enum TorrentEventKind {
+    /// The torrent was added
+    Added,
+    /// The torrent was removed
+    Removed,
+    /// The state of the torrent changed
+    StateChanged,
+    /// The torrent finished downloading
+    Completed,
+}
+
+#[derive(SimpleObject, Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct TorrentEvent {
+    /// Position of the event in the global event stream
+    pub id: u64,
+    pub kind: TorrentEventKind,
+    pub torrent_id: i32,
+    /// Name of the torrent when the event happened
+    pub name: String,
+    /// State of the torrent, none once it is removed
+    pub state: Option<TorrentState>,
+    pub time: DateTime<Utc>,
+}
+
+/// Global stream of torrent events, numbered in order
+pub struct EventLog {
+    sender: broadcast::Sender<TorrentEvent>,
+    recent: Mutex<VecDeque<TorrentEvent>>,
+    next_id: AtomicU64,
+    /// Ids below this are reserved on disk
+    reserved: AtomicU64,
+    path: Option<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SavedIds {
+    next_id: u64,
+}
+
+/// An event log that isn't saved, ids start from 1 on every run
+impl Default for EventLog {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(256);
+        Self {
+            sender,
+            recent: Mutex::new(VecDeque::with_capacity(RECENT_EVENTS)),
+            next_id: AtomicU64::new(1),
+            reserved: AtomicU64::new(u64::MAX),
+            path: None,
+        }
+    }
+}
+
+impl EventLog {
+    pub async fn load() -> Self {
+        Self::load_from(Path::new(&CONFIG_DIR.clone()).join("events.json")).await
+    }
+
+    /// Ids continue after the ones reserved by the previous run, so clients
+    /// resuming with the last id they saw don't miss or repeat events
+    async fn load_from(path: PathBuf) -> Self {
+        let mut next_id = 1;
+        if let Ok(saved) = tokio::fs::read(&path).await {
+            match serde_json::from_slice::<SavedIds>(&saved) {
+                Ok(saved) => next_id = saved.next_id,
+                Err(err) => log::warn!("Cant read event ids {:#?}", err),
+            }
+        }
+        Self {
+            next_id: AtomicU64::new(next_id),
+            reserved: AtomicU64::new(next_id),
+            path: Some(path),
+            ..Default::default()
+        }
+    }
+
+    fn reserve(&self, next_id: u64) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        match serde_json::to_vec_pretty(&SavedIds { next_id }) {
+            Ok(saved) => {
+                if let Err(err) = std::fs::write(path, saved) {
+                    log::warn!("Cant save event ids {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save event ids {:#?}", err),
+        }
+    }
+
+    pub fn publish(
+        &self,
+        kind: TorrentEventKind,
+        torrent_id: i32,
+        name: String,
+        state: Option<TorrentState>,
+    ) {
+        // Ids are assigned under the lock so events are kept and sent in order
+        let mut recent = self.recent.lock().unwrap();
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        if id >= self.reserved.load(Ordering::SeqCst) {
+            self.reserved.store(id + RESERVED_IDS, Ordering::SeqCst);
+            self.reserve(id + RESERVED_IDS);
+        }
+        let event = TorrentEvent {
+            id,
+            kind,
+            torrent_id,
+            name,
+            state,
+            time: Utc::now(),
+        };
+        log::info!("Torrent event {:?}", event);
+        if recent.len() >= RECENT_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // Sending only fails when nobody is subscribed
+        let _ = self.sender.send(event);
+    }
+
+    /// New events, preceded by the events after `last_id` that are still kept
+    pub fn subscribe(&self, last_id: Option<u64>) -> impl Stream<Item = TorrentEvent> {
+        let recent = self.recent.lock().unwrap();
+        let mut receiver = self.sender.subscribe();
+        let missed = match last_id {
+            Some(last_id) => recent
+                .iter()
+                .filter(|event| event.id > last_id)
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+        drop(recent);
+        async_stream::stream! {
+            for event in missed {
+                yield event;
+            }
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => yield event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Event subscriber skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+}
+
+/// Publishes events for torrents that are added, removed, change state or complete
+pub async fn event_watcher(torrents: Arc<DashMap<i32, Torrent>>, events: Arc<EventLog>) {
+    let mut known: Option<HashMap<i32, (String, TorrentState, bool)>> = None;
+    loop {
+        tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+        let current = torrents
+            .iter()
+            .map(|torrent| {
+                let stats = torrent.value().stats();
+                (
+                    *torrent.key(),
+                    (
+                        torrent.value().name().to_string(),
+                        TorrentState::from(stats.state),
+                        stats.percent_done >= 1.0,
+                    ),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        // Torrents present on startup are not reported as added
+        if let Some(known) = &known {
+            for (id, (name, state, done)) in current.iter() {
+                match known.get(id) {
+                    None => {
+                        events.publish(TorrentEventKind::Added, *id, name.clone(), Some(*state))
+                    }
+                    Some((_, known_state, known_done)) => {
+                        if known_state != state {
+                            events.publish(
+                                TorrentEventKind::StateChanged,
+                                *id,
+                                name.clone(),
+                                Some(*state),
+                            );
+                        }
+                        if *done && !known_done {
+                            events.publish(
+                                TorrentEventKind::Completed,
+                                *id,
+                                name.clone(),
+                                Some(*state),
+                            );
+                        }
+                    }
+                }
+            }
+            for (id, (name, _, _)) in known.iter() {
+                if !current.contains_key(id) {
+                    events.publish(TorrentEventKind::Removed, *id, name.clone(), None);
+                }
+            }
+        }
+        known = Some(current);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::StreamExt;
+
+    use super::*;
+
+    fn publish(log: &EventLog, torrent_id: i32) {
+        log.publish(
+            TorrentEventKind::Added,
+            torrent_id,
+            "Torrent".into(),
+            Some(TorrentState::Downloading),
+        );
+    }
+
+    #[tokio::test]
+    async fn ids_keep_increasing_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("events.json");
+        let log = EventLog::load_from(path.clone()).await;
+        publish(&log, 1);
+        publish(&log, 2);
+        let last_id = log.recent.lock().unwrap().back().unwrap().id;
+        drop(log);
+
+        let log = EventLog::load_from(path).await;
+        publish(&log, 3);
+        let id = log.recent.lock().unwrap().back().unwrap().id;
+        assert!(id > last_id);
+    }
+
+    #[tokio::test]
+    async fn subscribers_resume_after_the_last_id() {
+        let log = EventLog::default();
+        publish(&log, 1);
+        publish(&log, 2);
+        publish(&log, 3);
+        let events = log.subscribe(Some(1)).take(2).collect::<Vec<_>>().await;
+        let ids = events.iter().map(|event| event.id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![2, 3]);
+    }
+}
@@ -0,0 +1,663 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Read},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use async_graphql::{ComplexObject, Context, SimpleObject};
+use chrono::{DateTime, Duration, Utc};
+use dashmap::{DashMap, DashSet};
+use serde::{Deserialize, Serialize};
+use transmission::Torrent;
+
+use crate::{
+    context::{SharedData, TorrentHashes},
+    download_dirs::DownloadDirs,
+    download_link::{encode_link, expiry_from_secs, DownloadLinkStructure},
+    env_flag, CONFIG_DIR,
+};
+
+lazy_static::lazy_static! {
+    /// Extract archives of torrents when they complete
+    pub static ref EXTRACT_ARCHIVES: bool = env_flag("TOREXPO_EXTRACT_ARCHIVES");
+    /// Delete extracted archives once the torrent is done seeding, the torrent is
+    /// removed from the client first as it can't be seeded or verified without them
+    static ref DELETE_ARCHIVES: bool = env_flag("TOREXPO_EXTRACT_DELETE_ARCHIVES");
+    /// Ratio after which a torrent is done seeding
+    static ref DELETE_ARCHIVES_RATIO: f32 = std::env::var("TOREXPO_EXTRACT_DELETE_RATIO")
+        .ok()
+        .and_then(|ratio| ratio.parse().ok())
+        .unwrap_or(1.0);
+    /// Seconds without uploads after which a torrent is done seeding
+    static ref DELETE_ARCHIVES_IDLE_SECS: i64 = std::env::var("TOREXPO_EXTRACT_DELETE_IDLE_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(24 * 60 * 60);
+    /// Bytes an archive may extract to
+    static ref MAX_EXTRACTED_SIZE: u64 = std::env::var("TOREXPO_EXTRACT_MAX_SIZE")
+        .ok()
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(50 * 1024 * 1024 * 1024);
+}
+
+/// Files an archive may extract to
+const MAX_EXTRACTED_FILES: usize = 100_000;
+/// Folders tried for an archive before giving up
+const MAX_FOLDER_NUMBER: usize = 100;
+const CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum ArchiveKind {
+    Zip,
+    Tar,
+    TarGz,
+    SevenZip,
+}
+
+impl ArchiveKind {
+    /// Kind of archive and the name without its extension
+    fn of(name: &str) -> Option<(Self, &str)> {
+        let lower = name.to_lowercase();
+        [
+            (".tar.gz", ArchiveKind::TarGz),
+            (".tgz", ArchiveKind::TarGz),
+            (".tar", ArchiveKind::Tar),
+            (".zip", ArchiveKind::Zip),
+            (".7z", ArchiveKind::SevenZip),
+        ]
+        .into_iter()
+        .find(|(extension, _)| lower.ends_with(extension))
+        .map(|(extension, kind)| (kind, &name[..name.len() - extension.len()]))
+    }
+}
+
+/// File extracted from an archive of a torrent
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[graphql(complex)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtractedFile {
+    #[graphql(skip)]
+    #[serde(skip)]
+    pub torrent_id: i32,
+    /// Info hash of the torrent, signed into links
+    #[graphql(skip)]
+    #[serde(skip)]
+    pub torrent_hash: String,
+    /// Path of the file relative to the download directory
+    pub name: String,
+    pub length: u64,
+}
+
+#[ComplexObject]
+impl ExtractedFile {
+    async fn download_link<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        expiry_secs: Option<u64>,
+    ) -> Option<String> {
+        let data = ctx.data::<SharedData>().ok()?;
+        let path = data.download_dirs.dir(self.torrent_id).join(&self.name);
+        let pathcheck = path.clone();
+        let exists = tokio::task::spawn_blocking(move || pathcheck.exists()).await;
+        if !matches!(exists, Ok(true)) {
+            return None;
+        }
+        let coded = DownloadLinkStructure {
+            hash: self.torrent_hash.as_str().into(),
+            name: self.name.as_str().into(),
+            expiry: expiry_from_secs(expiry_secs),
+        };
+        encode_link(&coded).map(|link| format!("/download/{}", link))
+    }
+}
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExtractedArchive {
+    /// Path of the archive relative to the download directory
+    pub archive: String,
+    /// Folder the archive was extracted to, relative to the download directory
+    pub folder: String,
+    pub files: Vec<ExtractedFile>,
+    pub extracted: DateTime<Utc>,
+    /// Why the archive couldn't be extracted
+    pub error: Option<String>,
+}
+
+/// Archives extracted from each torrent, by info hash, saved in the config directory
+#[derive(Default)]
+pub struct Extractor {
+    archives: DashMap<String, Vec<ExtractedArchive>>,
+    /// Info hashes of the torrents being extracted
+    extracting: DashSet<String>,
+    hashes: Arc<TorrentHashes>,
+}
+
+impl Extractor {
+    fn path() -> PathBuf {
+        Path::new(&CONFIG_DIR.clone()).join("extracted.json")
+    }
+
+    /// Loads the archives extracted from the given torrents
+    pub async fn load(torrents: &DashMap<i32, Torrent>, hashes: &Arc<TorrentHashes>) -> Self {
+        let extractor = Extractor {
+            hashes: hashes.clone(),
+            ..Default::default()
+        };
+        let saved = match tokio::fs::read(Self::path()).await {
+            Ok(saved) => serde_json::from_slice::<HashMap<String, Vec<ExtractedArchive>>>(&saved),
+            Err(_) => return extractor,
+        };
+        match saved {
+            Ok(mut saved) => {
+                for torrent in torrents.iter() {
+                    let hash = hashes.hash(torrent.value());
+                    if let Some(archives) = saved.remove(&hash) {
+                        extractor.archives.insert(hash, archives);
+                    }
+                }
+            }
+            Err(err) => log::warn!("Cant read extracted archives {:#?}", err),
+        }
+        extractor
+    }
+
+    async fn save(&self) {
+        let saved = self
+            .archives
+            .iter()
+            .map(|archives| (archives.key().clone(), archives.value().clone()))
+            .collect::<HashMap<_, _>>();
+        match serde_json::to_vec_pretty(&saved) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save extracted archives {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save extracted archives {:#?}", err),
+        }
+    }
+
+    pub fn archives(&self, torrent: &Torrent) -> Vec<ExtractedArchive> {
+        let hash = self.hashes.hash(torrent);
+        let mut archives = self
+            .archives
+            .get(&hash)
+            .map(|archives| archives.value().clone())
+            .unwrap_or_default();
+        for archive in archives.iter_mut() {
+            for file in archive.files.iter_mut() {
+                file.torrent_id = torrent.id();
+                file.torrent_hash = hash.clone();
+            }
+        }
+        archives
+    }
+
+    /// Archives of the torrent without a successful extraction, or without any
+    /// when `retry_failed` is not set
+    fn pending(
+        &self,
+        info: &transmission::torrent::TorrentInfo,
+        retry_failed: bool,
+    ) -> Vec<String> {
+        let done = self
+            .archives
+            .get(&info.hash_string)
+            .map(|archives| {
+                archives
+                    .iter()
+                    .filter(|archive| !retry_failed || archive.error.is_none())
+                    .map(|archive| archive.archive.clone())
+                    .collect::<Vec<_>>()
+            })
+            .unwrap_or_default();
+        info.files
+            .iter()
+            .filter(|file| file.dnd == 0 && ArchiveKind::of(&file.name).is_some())
+            .map(|file| file.name.clone())
+            .filter(|name| !done.contains(name))
+            .collect()
+    }
+
+    /// Extracts the archives of the torrent that weren't extracted yet, retrying failed ones
+    pub async fn extract(&self, dirs: &DownloadDirs, torrent: &Torrent) -> Vec<ExtractedArchive> {
+        let info = torrent.info();
+        let hash = self.hashes.hash(torrent);
+        let download_dir = dirs.dir(torrent.id());
+        let pending = self.pending(&info, true);
+        if pending.is_empty() {
+            return self.archives(torrent);
+        }
+        self.extracting.insert(hash.clone());
+        for name in pending {
+            let dir = download_dir.clone();
+            let archive_name = name.clone();
+            let result = tokio::task::spawn_blocking(move || extract_archive(&dir, &archive_name))
+                .await
+                .unwrap_or_else(|err| Err(err.to_string()));
+            let archive = match result {
+                Ok((folder, files)) => {
+                    log::info!("Extracted {} files from {}", files.len(), name);
+                    ExtractedArchive {
+                        archive: name,
+                        folder,
+                        files,
+                        extracted: Utc::now(),
+                        error: None,
+                    }
+                }
+                Err(err) => {
+                    log::warn!("Cant extract {} {}", name, err);
+                    ExtractedArchive {
+                        archive: name,
+                        folder: String::new(),
+                        files: vec![],
+                        extracted: Utc::now(),
+                        error: Some(err),
+                    }
+                }
+            };
+            let mut archives = self.archives.entry(hash.clone()).or_default();
+            archives.retain(|existing| existing.archive != archive.archive);
+            archives.push(archive);
+        }
+        self.extracting.remove(&hash);
+        self.save().await;
+        self.archives(torrent)
+    }
+
+    /// Archives of the torrent which were extracted, once every archive of the torrent
+    /// went through extraction
+    fn extracted_archives(&self, info: &transmission::torrent::TorrentInfo) -> Vec<String> {
+        if self.extracting.contains(&info.hash_string) || !self.pending(info, false).is_empty() {
+            return vec![];
+        }
+        self.successful(&info.hash_string)
+    }
+
+    fn successful(&self, hash: &str) -> Vec<String> {
+        self.archives
+            .get(hash)
+            .map(|archives| {
+                archives
+                    .iter()
+                    .filter(|archive| archive.error.is_none())
+                    .map(|archive| archive.archive.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Forgets the archives of a removed torrent, deleting the extracted files with its data
+    pub async fn remove(&self, dirs: &DownloadDirs, torrent: &Torrent, delete_data: bool) {
+        let archives = match self.archives.remove(&self.hashes.hash(torrent)) {
+            Some((_hash, archives)) => archives,
+            None => return,
+        };
+        if delete_data {
+            let download_dir = dirs.dir(torrent.id());
+            for archive in archives.iter().filter(|archive| !archive.folder.is_empty()) {
+                if let Err(err) =
+                    tokio::fs::remove_dir_all(download_dir.join(&archive.folder)).await
+                {
+                    log::warn!("Cant delete extracted folder {:#?}", err);
+                }
+            }
+        }
+        self.save().await;
+    }
+}
+
+/// Extracts the archive next to it, into a folder named after it.
+///
+/// Returns the folder and the extracted files, relative to the download directory.
+fn extract_archive(
+    download_dir: &Path,
+    name: &str,
+) -> Result<(String, Vec<ExtractedFile>), String> {
+    let (kind, stem) = ArchiveKind::of(name).ok_or("Not a supported archive")?;
+    let folder = create_folder(download_dir, stem)?;
+    let dest = download_dir.join(&folder);
+    let mut extraction = Extraction {
+        download_dir,
+        dest: &dest,
+        files: vec![],
+        remaining: *MAX_EXTRACTED_SIZE,
+    };
+    let file =
+        File::open(download_dir.join(name)).map_err(|err| format!("Cant open archive {}", err))?;
+    let result = match kind {
+        ArchiveKind::Zip => extraction.zip(file),
+        ArchiveKind::Tar => extraction.tar(file),
+        ArchiveKind::TarGz => extraction.tar(flate2::read::GzDecoder::new(file)),
+        ArchiveKind::SevenZip => extraction.seven_zip(&download_dir.join(name)),
+    };
+    match result {
+        Ok(()) => Ok((folder, extraction.files)),
+        Err(err) => {
+            // Partial extractions are removed so a retry starts clean, the folder is this run's
+            let _ = std::fs::remove_dir_all(&dest);
+            Err(err)
+        }
+    }
+}
+
+/// Creates a new folder to extract to, named after the archive and numbered when taken.
+///
+/// Returns the folder relative to the download directory.
+fn create_folder(download_dir: &Path, stem: &str) -> Result<String, String> {
+    for number in 0..MAX_FOLDER_NUMBER {
+        let folder = match number {
+            0 => stem.to_string(),
+            1 => format!("{}.extracted", stem),
+            number => format!("{}.extracted.{}", stem, number),
+        };
+        match std::fs::create_dir(download_dir.join(&folder)) {
+            Ok(()) => return Ok(folder),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(format!("Cant create folder {}", err)),
+        }
+    }
+    Err("No free folder to extract to".into())
+}
+
+struct Extraction<'a> {
+    download_dir: &'a Path,
+    dest: &'a Path,
+    files: Vec<ExtractedFile>,
+    /// Bytes that can still be extracted
+    remaining: u64,
+}
+
+impl<'a> Extraction<'a> {
+    /// Writes an entry of the archive, refusing names that escape the folder
+    fn write(&mut self, name: &str, reader: &mut dyn Read) -> Result<(), String> {
+        let relative = Path::new(name.trim_start_matches("./"));
+        if relative.as_os_str().is_empty()
+            || !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!("Unsafe path in archive {}", name));
+        }
+        if self.files.len() >= MAX_EXTRACTED_FILES {
+            return Err("Archive has too many files".into());
+        }
+        let path = self.dest.join(relative);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|err| format!("Cant create folder {}", err))?;
+        }
+        let mut file = File::create(&path).map_err(|err| format!("Cant create file {}", err))?;
+        // Sizes in headers can't be trusted, so the copy itself is limited
+        let copied = io::copy(&mut reader.take(self.remaining + 1), &mut file)
+            .map_err(|err| format!("Cant extract {} {}", name, err))?;
+        if copied > self.remaining {
+            return Err("Archive is larger than the extraction limit".into());
+        }
+        self.remaining -= copied;
+        let name = path
+            .strip_prefix(self.download_dir)
+            .unwrap_or(&path)
+            .to_string_lossy()
+            .into_owned();
+        self.files.push(ExtractedFile {
+            torrent_id: 0,
+            torrent_hash: String::new(),
+            name,
+            length: copied,
+        });
+        Ok(())
+    }
+
+    fn zip(&mut self, file: File) -> Result<(), String> {
+        let mut archive = zip::ZipArchive::new(file).map_err(|err| err.to_string())?;
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).map_err(|err| err.to_string())?;
+            if entry.is_dir() {
+                continue;
+            }
+            let name = entry.name().to_string();
+            self.write(&name, &mut entry)?;
+        }
+        Ok(())
+    }
+
+    fn tar(&mut self, reader: impl Read) -> Result<(), String> {
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries().map_err(|err| err.to_string())? {
+            let mut entry = entry.map_err(|err| err.to_string())?;
+            // Links could point outside the folder, only regular files are extracted
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = entry
+                .path()
+                .map_err(|err| err.to_string())?
+                .to_string_lossy()
+                .into_owned();
+            self.write(&name, &mut entry)?;
+        }
+        Ok(())
+    }
+
+    fn seven_zip(&mut self, path: &Path) -> Result<(), String> {
+        let mut archive = sevenz_rust::SevenZReader::open(path, sevenz_rust::Password::empty())
+            .map_err(|err| format!("{:?}", err))?;
+        let mut result = Ok(());
+        archive
+            .for_each_entries(|entry, reader| {
+                if entry.is_directory() || !entry.has_stream() {
+                    return Ok(true);
+                }
+                result = self.write(entry.name(), reader);
+                Ok(result.is_ok())
+            })
+            .map_err(|err| format!("{:?}", err))?;
+        result
+    }
+}
+
+/// Whether a torrent with the ratio, idle for this long, is done seeding
+fn seeding_finished(ratio: f32, idle: Duration) -> bool {
+    ratio >= *DELETE_ARCHIVES_RATIO || idle >= Duration::seconds(*DELETE_ARCHIVES_IDLE_SECS)
+}
+
+/// Deletes the archives from the download directory
+async fn delete_files(download_dir: &Path, archives: &[String]) {
+    for name in archives {
+        match tokio::fs::remove_file(download_dir.join(name)).await {
+            Ok(()) => log::info!("Deleted extracted archive {}", name),
+            Err(err) => log::warn!("Cant delete extracted archive {} {:#?}", name, err),
+        }
+    }
+}
+
+/// Removes the torrent from the client, then deletes its extracted archives.
+///
+/// libtransmission errors on missing data when a torrent starts or is verified, so
+/// the torrent has to go before its archives do. The extracted folders are kept.
+async fn delete_archives(data: &SharedData, torrent: &Torrent) {
+    let info = torrent.info();
+    let archives = data.extractor.extracted_archives(&info);
+    if archives.is_empty() {
+        return;
+    }
+    let download_dir = data.download_dirs.dir(torrent.id());
+    log::info!("Removing {}, its archives are deleted", info.name);
+    if data.remove_torrent(torrent.id(), false).await {
+        delete_files(&download_dir, &archives).await;
+    }
+}
+
+/// Deletes the extracted archives of complete torrents once they are done seeding.
+///
+/// Archives are extracted by the completion pipeline. Torrents which aren't kept seeding
+/// are stopped when they complete, so they are done once the idle time has passed.
+/// Kept torrents, created ones and those moved into the library, are never touched.
+pub async fn archive_cleaner(data: SharedData) {
+    if !*EXTRACT_ARCHIVES || !*DELETE_ARCHIVES {
+        return;
+    }
+    loop {
+        tokio::time::sleep(CLEANUP_INTERVAL).await;
+        let torrents = data
+            .torrents
+            .iter()
+            .map(|torrent| torrent.value().clone())
+            .collect::<Vec<_>>();
+        let now = Utc::now().naive_utc();
+        for torrent in torrents {
+            if data.seeding.is_kept(torrent.id()) {
+                continue;
+            }
+            let stats = torrent.stats();
+            if stats.percent_done >= 1.0 && seeding_finished(stats.ratio, now - stats.activity_date)
+            {
+                delete_archives(&data, &torrent).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_kind_strips_extension() {
+        assert_eq!(
+            ArchiveKind::of("Show.tar.gz"),
+            Some((ArchiveKind::TarGz, "Show"))
+        );
+        assert_eq!(
+            ArchiveKind::of("dir/Show.ZIP"),
+            Some((ArchiveKind::Zip, "dir/Show"))
+        );
+        assert_eq!(
+            ArchiveKind::of("Show.7z"),
+            Some((ArchiveKind::SevenZip, "Show"))
+        );
+        assert_eq!(ArchiveKind::of("Show.mkv"), None);
+    }
+
+    #[test]
+    fn seeding_finishes_at_the_ratio_or_idle_time() {
+        let day = Duration::days(1);
+        assert!(!seeding_finished(0.5, Duration::minutes(5)));
+        assert!(seeding_finished(1.0, Duration::minutes(5)));
+        assert!(seeding_finished(0.0, day));
+        assert!(!seeding_finished(0.0, day - Duration::seconds(1)));
+    }
+
+    #[test]
+    fn create_folder_numbers_taken_names() {
+        let temp = tempfile::tempdir().unwrap();
+        let download_dir = temp.path().to_path_buf();
+        assert_eq!(create_folder(&download_dir, "show"), Ok("show".into()));
+        assert_eq!(
+            create_folder(&download_dir, "show"),
+            Ok("show.extracted".into())
+        );
+        assert_eq!(
+            create_folder(&download_dir, "show"),
+            Ok("show.extracted.2".into())
+        );
+    }
+
+    #[test]
+    fn write_refuses_unsafe_paths() {
+        let temp = tempfile::tempdir().unwrap();
+        let download_dir = temp.path().to_path_buf();
+        let dest = download_dir.join("show");
+        let mut extraction = Extraction {
+            download_dir: &download_dir,
+            dest: &dest,
+            files: vec![],
+            remaining: 1024,
+        };
+        for name in ["", "./", "../escaped", "/etc/escaped", "dir/../../escaped"] {
+            assert!(
+                extraction.write(name, &mut &b"data"[..]).is_err(),
+                "{name} was extracted"
+            );
+        }
+        assert!(extraction.files.is_empty());
+        assert!(!download_dir.join("escaped").exists());
+
+        extraction
+            .write("./dir/file.txt", &mut &b"data"[..])
+            .unwrap();
+        assert_eq!(extraction.files.len(), 1);
+        assert_eq!(extraction.files[0].name, "show/dir/file.txt");
+        assert_eq!(extraction.files[0].length, 4);
+        assert_eq!(
+            std::fs::read(dest.join("dir/file.txt")).unwrap(),
+            b"data".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn extracted_archives_are_deleted() {
+        use std::io::Write;
+
+        let temp = tempfile::tempdir().unwrap();
+        let download_dir = temp.path().to_path_buf();
+        let mut zip = zip::ZipWriter::new(File::create(download_dir.join("show.zip")).unwrap());
+        zip.start_file("episode.txt", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(b"episode").unwrap();
+        zip.finish().unwrap();
+
+        let (folder, files) = extract_archive(&download_dir, "show.zip").unwrap();
+        assert_eq!(folder, "show");
+        assert_eq!(files[0].name, "show/episode.txt");
+        let extractor = Extractor::default();
+        extractor.archives.insert(
+            "hash".into(),
+            vec![
+                ExtractedArchive {
+                    archive: "show.zip".into(),
+                    folder,
+                    files,
+                    extracted: Utc::now(),
+                    error: None,
+                },
+                ExtractedArchive {
+                    archive: "broken.zip".into(),
+                    folder: String::new(),
+                    files: vec![],
+                    extracted: Utc::now(),
+                    error: Some("Not a zip".into()),
+                },
+            ],
+        );
+        let archives = extractor.successful("hash");
+        assert_eq!(archives, vec!["show.zip".to_string()]);
+
+        delete_files(&download_dir, &archives).await;
+        assert!(!download_dir.join("show.zip").exists());
+        assert_eq!(
+            std::fs::read(download_dir.join("show/episode.txt")).unwrap(),
+            b"episode".to_vec()
+        );
+    }
+
+    #[test]
+    fn write_stops_at_the_size_limit() {
+        let temp = tempfile::tempdir().unwrap();
+        let download_dir = temp.path().to_path_buf();
+        let dest = download_dir.join("show");
+        let mut extraction = Extraction {
+            download_dir: &download_dir,
+            dest: &dest,
+            files: vec![],
+            remaining: 6,
+        };
+        extraction.write("first", &mut &b"data"[..]).unwrap();
+        assert!(extraction.write("second", &mut &b"data"[..]).is_err());
+        assert_eq!(extraction.remaining, 2);
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    future::Future,
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_graphql::{InputObject, SimpleObject};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{context::SharedData, new_id, structures::AddTorrentOptions, CONFIG_DIR};
+
+/// Grabbed items kept in the history
+const HISTORY_LENGTH: usize = 500;
+/// Shortest interval between two checks of a feed
+const MIN_INTERVAL_SECS: u64 = 10;
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static::lazy_static! {
+    static ref EPISODE: Regex =
+        Regex::new(r"(?i)^(.*?)[\s._-]*s(\d{1,2})[\s._-]*e(\d{1,3})").unwrap();
+    static ref EPISODE_CROSS: Regex =
+        Regex::new(r"(?i)^(.*?)[\s._-]*(\d{1,2})x(\d{2,3})\b").unwrap();
+}
+
+#[derive(SimpleObject, InputObject, Serialize, Deserialize, Clone, Default)]
+#[graphql(input_name = "FeedRuleInput")]
+#[serde(default, rename_all = "camelCase")]
+pub struct FeedRule {
+    /// Name of the rule, shown in the history
+    pub name: String,
+    /// Regex matched against item titles
+    pub include: String,
+    /// Regex of item titles to skip even if they match `include`
+    pub exclude: Option<String>,
+    /// Grab each episode only once, recognized by SxxEyy or NxNN in the title
+    #[graphql(default)]
+    pub dedupe_episodes: bool,
+    /// Options matched torrents are added with
+    #[graphql(default)]
+    pub options: AddTorrentOptions,
+    /// Include and exclude regexes, compiled when the rule is validated
+    #[graphql(skip)]
+    #[serde(skip)]
+    compiled: Option<(Regex, Option<Regex>)>,
+}
+
+impl FeedRule {
+    /// Compiles the regexes of the rule, failing if one is invalid
+    fn compile(&mut self) -> Result<(), String> {
+        let include =
+            Regex::new(&self.include).map_err(|err| format!("Invalid include regex {}", err))?;
+        let exclude = match &self.exclude {
+            Some(exclude) => {
+                Some(Regex::new(exclude).map_err(|err| format!("Invalid exclude regex {}", err))?)
+            }
+            None => None,
+        };
+        self.compiled = Some((include, exclude));
+        Ok(())
+    }
+
+    fn matches(&self, title: &str) -> bool {
+        match &self.compiled {
+            Some((include, exclude)) => {
+                include.is_match(title)
+                    && !exclude
+                        .as_ref()
+                        .map(|exclude| exclude.is_match(title))
+                        .unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+}
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Feed {
+    pub id: String,
+    pub name: String,
+    /// URL of the RSS or Atom feed
+    pub url: String,
+    /// Seconds between two checks of the feed
+    pub interval_secs: u64,
+    pub enabled: bool,
+    /// Rules tried in order, the first matching rule adds the item
+    pub rules: Vec<FeedRule>,
+    pub last_checked: Option<DateTime<Utc>>,
+    /// Error of the last check, if it failed
+    pub last_error: Option<String>,
+}
+
+#[derive(InputObject)]
+pub struct FeedInput {
+    pub name: String,
+    pub url: String,
+    #[graphql(default = 900)]
+    pub interval_secs: u64,
+    #[graphql(default = true)]
+    pub enabled: bool,
+    #[graphql(default)]
+    pub rules: Vec<FeedRule>,
+}
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FeedHistoryEntry {
+    pub feed_id: String,
+    pub rule: String,
+    /// Title of the feed item
+    pub title: String,
+    pub link: String,
+    /// Episode key used for deduplication
+    pub episode: Option<String>,
+    /// Id of the added torrent, none if adding failed
+    pub torrent_id: Option<i32>,
+    pub error: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+/// An item of a fetched feed
+struct FeedItem {
+    guid: String,
+    title: String,
+    link: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct FeedState {
+    history: VecDeque<FeedHistoryEntry>,
+    /// Item guids already processed, per feed
+    seen: HashMap<String, HashSet<String>>,
+    /// Episodes already grabbed, per feed and rule
+    episodes: HashMap<String, HashSet<String>>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedFeeds {
+    feeds: Vec<Feed>,
+    #[serde(flatten)]
+    state: FeedState,
+}
+
+/// Feed subscriptions and what was grabbed from them, saved in the config directory
+#[derive(Default)]
+pub struct FeedManager {
+    feeds: DashMap<String, Feed>,
+    state: Mutex<FeedState>,
+}
+
+impl FeedManager {
+    fn path() -> std::path::PathBuf {
+        std::path::Path::new(&CONFIG_DIR.clone()).join("feeds.json")
+    }
+
+    pub async fn load() -> Self {
+        let manager = FeedManager::default();
+        if let Ok(saved) = tokio::fs::read(Self::path()).await {
+            match serde_json::from_slice::<SavedFeeds>(&saved) {
+                Ok(saved) => {
+                    for mut feed in saved.feeds {
+                        for rule in feed.rules.iter_mut() {
+                            if let Err(err) = rule.compile() {
+                                log::warn!("Cant read feed rule {} {}", rule.name, err);
+                            }
+                        }
+                        manager.feeds.insert(feed.id.clone(), feed);
+                    }
+                    *manager.state.lock().unwrap() = saved.state;
+                }
+                Err(err) => log::warn!("Cant read feeds {:#?}", err),
+            }
+        }
+        manager
+    }
+
+    async fn save(&self) {
+        let saved = {
+            let state = self.state.lock().unwrap();
+            let mut feeds = self
+                .feeds
+                .iter()
+                .map(|feed| feed.value().clone())
+                .collect::<Vec<_>>();
+            feeds.sort_by(|a, b| a.name.cmp(&b.name));
+            serde_json::to_vec_pretty(&SavedFeeds {
+                feeds,
+                state: state.clone(),
+            })
+        };
+        match saved {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save feeds {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save feeds {:#?}", err),
+        }
+    }
+
+    pub fn feeds(&self) -> Vec<Feed> {
+        let mut feeds = self
+            .feeds
+            .iter()
+            .map(|feed| feed.value().clone())
+            .collect::<Vec<_>>();
+        feeds.sort_by(|a, b| a.name.cmp(&b.name));
+        feeds
+    }
+
+    /// Latest grabbed items first
+    pub fn history(&self, feed_id: Option<&str>, limit: usize) -> Vec<FeedHistoryEntry> {
+        self.state
+            .lock()
+            .unwrap()
+            .history
+            .iter()
+            .rev()
+            .filter(|entry| feed_id.map(|id| entry.feed_id == id).unwrap_or(true))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub async fn add_feed(&self, mut input: FeedInput) -> Result<Feed, String> {
+        for rule in input.rules.iter_mut() {
+            rule.compile()?;
+        }
+        let feed = Feed {
+            id: new_id(),
+            name: input.name,
+            url: input.url,
+            interval_secs: input.interval_secs.max(MIN_INTERVAL_SECS),
+            enabled: input.enabled,
+            rules: input.rules,
+            last_checked: None,
+            last_error: None,
+        };
+        self.feeds.insert(feed.id.clone(), feed.clone());
+        self.save().await;
+        Ok(feed)
+    }
+
+    pub async fn update_feed(&self, id: &str, mut input: FeedInput) -> Result<Feed, String> {
+        for rule in input.rules.iter_mut() {
+            rule.compile()?;
+        }
+        let feed = {
+            let mut feed = self.feeds.get_mut(id).ok_or("Feed not found")?;
+            feed.name = input.name;
+            feed.url = input.url;
+            feed.interval_secs = input.interval_secs.max(MIN_INTERVAL_SECS);
+            feed.enabled = input.enabled;
+            feed.rules = input.rules;
+            feed.clone()
+        };
+        self.save().await;
+        Ok(feed)
+    }
+
+    pub async fn remove_feed(&self, id: &str) -> bool {
+        if self.feeds.remove(id).is_none() {
+            return false;
+        }
+        {
+            let mut state = self.state.lock().unwrap();
+            state.seen.remove(id);
+            let prefix = format!("{}/", id);
+            state.episodes.retain(|key, _| !key.starts_with(&prefix));
+        }
+        self.save().await;
+        true
+    }
+
+    /// Makes the feed due for a check
+    pub fn refresh_feed(&self, id: &str) -> bool {
+        match self.feeds.get_mut(id) {
+            Some(mut feed) => {
+                feed.last_checked = None;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn due_feeds(&self) -> Vec<Feed> {
+        let now = Utc::now();
+        self.feeds
+            .iter()
+            .filter(|feed| feed.enabled)
+            .filter(|feed| match feed.last_checked {
+                Some(last_checked) => {
+                    (now - last_checked).num_seconds() >= feed.interval_secs as i64
+                }
+                None => true,
+            })
+            .map(|feed| feed.value().clone())
+            .collect()
+    }
+
+    async fn check_feed(&self, data: &SharedData, feed: &Feed) {
+        let result = fetch_items(&data.http, &feed.url).await;
+        let error = match result {
+            Ok(items) => {
+                let add = |link: String, options| async move {
+                    data.add_url(&link, &[], None, options).await
+                };
+                self.grab_items(feed, items, add).await;
+                None
+            }
+            Err(err) => {
+                log::warn!("Cant check feed {} {}", feed.name, err);
+                Some(err)
+            }
+        };
+        if let Some(mut feed) = self.feeds.get_mut(&feed.id) {
+            feed.last_checked = Some(Utc::now());
+            feed.last_error = error;
+        }
+        self.save().await;
+    }
+
+    /// Adds the new items matching the feed's rules with `add`, recording them in the history
+    async fn grab_items<F, Fut>(&self, feed: &Feed, items: Vec<FeedItem>, add: F)
+    where
+        F: Fn(String, AddTorrentOptions) -> Fut,
+        Fut: Future<Output = Result<i32, String>>,
+    {
+        for item in items.iter() {
+            let seen = {
+                let state = self.state.lock().unwrap();
+                state
+                    .seen
+                    .get(&feed.id)
+                    .map(|seen| seen.contains(&item.guid))
+                    .unwrap_or(false)
+            };
+            if seen {
+                continue;
+            }
+            let rule = match feed.rules.iter().find(|rule| rule.matches(&item.title)) {
+                Some(rule) => rule,
+                None => continue,
+            };
+            let episodes_key = format!("{}/{}", feed.id, rule.name);
+            let episode = if rule.dedupe_episodes {
+                episode_key(&item.title)
+            } else {
+                None
+            };
+            let duplicate = episode
+                .as_ref()
+                .map(|episode| {
+                    let state = self.state.lock().unwrap();
+                    state
+                        .episodes
+                        .get(&episodes_key)
+                        .map(|episodes| episodes.contains(episode))
+                        .unwrap_or(false)
+                })
+                .unwrap_or(false);
+            if duplicate {
+                continue;
+            }
+
+            let result = match &item.link {
+                Some(link) => add(link.clone(), rule.options.clone()).await,
+                None => Err("Item has no link".into()),
+            };
+            log::info!("Feed {} grabbed {} {:?}", feed.name, item.title, result);
+            // Failed items are not retried, the error is kept in the history instead
+            let mut state = self.state.lock().unwrap();
+            state
+                .seen
+                .entry(feed.id.clone())
+                .or_default()
+                .insert(item.guid.clone());
+            if result.is_ok() {
+                if let Some(episode) = &episode {
+                    state
+                        .episodes
+                        .entry(episodes_key)
+                        .or_default()
+                        .insert(episode.clone());
+                }
+            }
+            if state.history.len() >= HISTORY_LENGTH {
+                state.history.pop_front();
+            }
+            state.history.push_back(FeedHistoryEntry {
+                feed_id: feed.id.clone(),
+                rule: rule.name.clone(),
+                title: item.title.clone(),
+                link: item.link.clone().unwrap_or_default(),
+                episode,
+                torrent_id: result.as_ref().ok().copied(),
+                error: result.err(),
+                time: Utc::now(),
+            });
+        }
+
+        // Items that left the feed won't come back, so they don't need to be remembered
+        let guids = items.iter().map(|item| &item.guid).collect::<HashSet<_>>();
+        if let Some(seen) = self.state.lock().unwrap().seen.get_mut(&feed.id) {
+            seen.retain(|guid| guids.contains(guid));
+        }
+    }
+}
+
+/// Checks feeds when they are due and adds the items matching their rules
+pub async fn feed_poller(data: SharedData) {
+    loop {
+        tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+        for feed in data.feeds.due_feeds() {
+            data.feeds.check_feed(&data, &feed).await;
+        }
+    }
+}
+
+async fn fetch_items(client: &reqwest::Client, url: &str) -> Result<Vec<FeedItem>, String> {
+    let body = client
+        .get(url)
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| err.to_string())?
+        .bytes()
+        .await
+        .map_err(|err| err.to_string())?;
+    let feed = feed_rs::parser::parse(body.as_ref()).map_err(|err| err.to_string())?;
+    Ok(feed
+        .entries
+        .into_iter()
+        .map(|entry| {
+            let links = entry
+                .media
+                .iter()
+                .flat_map(|media| media.content.iter())
+                .filter_map(|content| content.url.as_ref().map(|url| url.to_string()))
+                .chain(entry.links.iter().map(|link| link.href.clone()))
+                .collect::<Vec<_>>();
+            // Prefer magnet links, then .torrent files, then whatever is linked
+            let link = links
+                .iter()
+                .find(|link| link.starts_with("magnet:"))
+                .or_else(|| links.iter().find(|link| link.ends_with(".torrent")))
+                .or_else(|| links.first())
+                .cloned();
+            FeedItem {
+                guid: entry.id,
+                title: entry.title.map(|title| title.content).unwrap_or_default(),
+                link,
+            }
+        })
+        .collect())
+}
+
+/// Show and episode of a title like `Show.Name.S01E02.1080p`, normalized for comparison
+fn episode_key(title: &str) -> Option<String> {
+    let captures = EPISODE
+        .captures(title)
+        .or_else(|| EPISODE_CROSS.captures(title))?;
+    let show = captures[1]
+        .chars()
+        .filter(|c| c.is_alphanumeric())
+        .collect::<String>()
+        .to_lowercase();
+    let season = captures[2].parse::<u32>().ok()?;
+    let episode = captures[3].parse::<u32>().ok()?;
+    Some(format!("{} s{:02}e{:02}", show, season, episode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn episode_key_reads_season_and_episode() {
+        let key = Some("showname s01e02".to_string());
+        assert_eq!(episode_key("Show.Name.S01E02.1080p.WEB"), key);
+        assert_eq!(episode_key("Show Name - s1e2 [720p]"), key);
+        assert_eq!(episode_key("show_name_S01_E02"), key);
+        assert_eq!(episode_key("Show Name 1x02 HDTV"), key);
+        assert_eq!(
+            episode_key("Other.Show.S12E103"),
+            Some("othershow s12e103".into())
+        );
+    }
+
+    #[test]
+    fn episode_key_needs_an_episode() {
+        assert_eq!(episode_key("Movie.Name.2019.1080p.BluRay"), None);
+        assert_eq!(episode_key("Show.Name.Season.1.Complete"), None);
+    }
+
+    #[test]
+    fn rule_excludes_matching_titles() {
+        let mut rule = FeedRule {
+            include: "(?i)show.name".into(),
+            exclude: Some("(?i)720p".into()),
+            ..Default::default()
+        };
+        rule.compile().unwrap();
+        assert!(rule.matches("Show.Name.S01E02.1080p"));
+        assert!(!rule.matches("Show.Name.S01E02.720p"));
+        assert!(!rule.matches("Other.Show.S01E02.1080p"));
+    }
+
+    #[test]
+    fn rule_with_invalid_regex_is_rejected() {
+        let mut rule = FeedRule {
+            include: "(unclosed".into(),
+            ..Default::default()
+        };
+        assert!(rule.compile().is_err());
+        assert!(!rule.matches("(unclosed"));
+    }
+
+    const RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0"><channel><title>Releases</title>
+<item><guid>1</guid><title>Show.Name.S01E02.1080p</title>
+<link>https://example.com/1.torrent</link>
+<enclosure url="magnet:?xt=urn:btih:1" type="application/x-bittorrent"/></item>
+<item><guid>2</guid><title>Show.Name.S01E02.2160p</title>
+<link>https://example.com/2.torrent</link></item>
+<item><guid>3</guid><title>Show.Name.S01E03.720p</title>
+<link>https://example.com/3.torrent</link></item>
+<item><guid>4</guid><title>Other.Show.S01E01.1080p</title>
+<link>https://example.com/4.torrent</link></item>
+</channel></rss>"#;
+
+    /// Serves the feed on a local port, standing in for a feed server
+    fn serve_feed() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let app = axum::Router::new().route("/feed.xml", axum::routing::get(|| async { RSS }));
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service());
+        tokio::spawn(server);
+        format!("http://{}/feed.xml", address)
+    }
+
+    #[tokio::test]
+    async fn feed_is_polled_and_matching_items_grabbed_once() {
+        let url = serve_feed();
+        let mut rule = FeedRule {
+            name: "show".into(),
+            include: "(?i)^show.name".into(),
+            exclude: Some("(?i)720p".into()),
+            dedupe_episodes: true,
+            ..Default::default()
+        };
+        rule.compile().unwrap();
+        let feed = Feed {
+            id: "feed".into(),
+            name: "Releases".into(),
+            url: url.clone(),
+            interval_secs: MIN_INTERVAL_SECS,
+            enabled: true,
+            rules: vec![rule],
+            last_checked: None,
+            last_error: None,
+        };
+        let manager = FeedManager::default();
+        let added = Mutex::new(vec![]);
+        let add = |link: String, _| {
+            let mut added = added.lock().unwrap();
+            added.push(link);
+            let id = added.len() as i32;
+            async move { Ok(id) }
+        };
+
+        let client = reqwest::Client::new();
+        let items = fetch_items(&client, &url).await.unwrap();
+        assert_eq!(items.len(), 4);
+        manager.grab_items(&feed, items, add).await;
+        // The magnet link is preferred, the other 1080p item is the same episode
+        assert_eq!(*added.lock().unwrap(), vec!["magnet:?xt=urn:btih:1"]);
+        let history = manager.history(Some("feed"), 10);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].episode.as_deref(), Some("showname s01e02"));
+        assert_eq!(history[0].torrent_id, Some(1));
+
+        // Items seen on the previous poll are not grabbed again
+        let items = fetch_items(&client, &url).await.unwrap();
+        manager.grab_items(&feed, items, add).await;
+        assert_eq!(added.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,357 @@
+// tonic handlers and interceptors have to return its large `Status` error
+#![allow(clippy::result_large_err)]
+
+use std::pin::Pin;
+
+use chrono::{TimeZone, Utc};
+use futures_util::{Stream, StreamExt};
+use tonic::{transport::Server, Request, Response, Status};
+
+use crate::{
+    auth,
+    context::SharedData,
+    priority::PriorityMode,
+    structures::{monitor_torrent_stream, AddTorrentOptions, TorrentState},
+    torrent_struc::{TorrentInfoSummary, TorrentStats},
+};
+
+pub mod proto {
+    tonic::include_proto!("torexpo");
+}
+
+use proto::torexpo_server::{Torexpo, TorexpoServer};
+
+/// Serves the gRPC service on its own port until it fails
+pub async fn serve(data: SharedData) -> Result<(), String> {
+    let port = std::env::var("TOREXPO_GRPC_PORT").unwrap_or_else(|_| "50051".into());
+    let addr = format!("0.0.0.0:{}", port)
+        .parse::<std::net::SocketAddr>()
+        .map_err(|err| format!("Invalid TOREXPO_GRPC_PORT {}: {}", port, err))?;
+    let service = TorexpoServer::with_interceptor(TorexpoService { data }, check_auth);
+    Server::builder()
+        .add_service(service)
+        .serve(addr)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+/// Checks the `authorization: Basic` metadata against the configured credentials
+fn check_auth(request: Request<()>) -> Result<Request<()>, Status> {
+    authorize(request, auth::CREDENTIALS.as_ref())
+}
+
+fn authorize(
+    request: Request<()>,
+    credentials: Option<&(String, String)>,
+) -> Result<Request<()>, Status> {
+    let headers = request.metadata().clone().into_headers();
+    if auth::basic_auth_matches(credentials, &headers) {
+        Ok(request)
+    } else {
+        Err(Status::unauthenticated("Unauthorized"))
+    }
+}
+
+struct TorexpoService {
+    data: SharedData,
+}
+
+impl TorexpoService {
+    fn find_torrent(&self, torrent_id: i32) -> Result<transmission::Torrent, Status> {
+        self.data
+            .torrents
+            .get(&torrent_id)
+            .map(|torrent| torrent.value().clone())
+            .ok_or_else(|| Status::not_found("Torrent not found"))
+    }
+}
+
+impl From<TorrentState> for proto::TorrentState {
+    fn from(state: TorrentState) -> Self {
+        match state {
+            TorrentState::Downloading => proto::TorrentState::Downloading,
+            TorrentState::DownloadingWait => proto::TorrentState::DownloadingWait,
+            TorrentState::Seeding => proto::TorrentState::Seeding,
+            TorrentState::SeedingWait => proto::TorrentState::SeedingWait,
+            TorrentState::Stopped => proto::TorrentState::Stopped,
+            TorrentState::Checking => proto::TorrentState::Checking,
+            TorrentState::CheckingWait => proto::TorrentState::CheckingWait,
+            TorrentState::Error => proto::TorrentState::Error,
+        }
+    }
+}
+
+impl From<PriorityMode> for proto::PriorityMode {
+    fn from(mode: PriorityMode) -> Self {
+        Self {
+            sequential: mode.sequential,
+        }
+    }
+}
+
+impl From<proto::PriorityMode> for PriorityMode {
+    fn from(mode: proto::PriorityMode) -> Self {
+        Self {
+            sequential: mode.sequential,
+        }
+    }
+}
+
+impl From<proto::AddTorrentOptions> for AddTorrentOptions {
+    fn from(options: proto::AddTorrentOptions) -> Self {
+        Self {
+            paused: options.paused,
+            sequential_download: options.sequential_download,
+            download_dir: options.download_dir,
+            start_at: options
+                .start_at
+                .and_then(|start_at| Utc.timestamp_opt(start_at, 0).single()),
+        }
+    }
+}
+
+impl From<&TorrentStats> for proto::TorrentStats {
+    fn from(stats: &TorrentStats) -> Self {
+        Self {
+            state: proto::TorrentState::from(stats.state) as i32,
+            error_string: stats.error_string.clone(),
+            percent_complete: stats.percent_complete,
+            percent_done: stats.percent_done,
+            metadata_percent_complete: stats.metadata_percent_complete,
+            recheck_progress: stats.recheck_progress,
+            piece_download_speed_kbps: stats.piece_download_speed_kbps,
+            piece_upload_speed_kbps: stats.piece_upload_speed_kbps,
+            eta: stats.eta,
+            peers_connected: stats.peers_connected,
+            peers_sending_to_us: stats.peers_sending_to_us,
+            peers_getting_from_us: stats.peers_getting_from_us,
+            size_when_done: stats.size_when_done,
+            left_until_done: stats.left_until_done,
+            uploaded_ever: stats.uploaded_ever,
+            downloaded_ever: stats.downloaded_ever,
+            ratio: stats.ratio,
+            added_date: stats.added_date.timestamp(),
+            done_date: stats.done_date.timestamp(),
+            finished: stats.finished,
+            is_stalled: stats.is_stalled,
+        }
+    }
+}
+
+fn torrent_message(data: &SharedData, torrent: &transmission::Torrent) -> proto::Torrent {
+    let stats = TorrentStats::from(torrent.stats());
+    let info = torrent.info();
+    let summary = TorrentInfoSummary::from(&info);
+    let files = info
+        .files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| proto::TorrentFile {
+            index: index as u32,
+            length: file.length,
+            name: file.name.clone(),
+            dnd: file.dnd as i32,
+            first_piece: file.first_piece,
+            last_piece: file.last_piece,
+            offset: file.offset,
+            priority_mode: Some(
+                data.priority_modes
+                    .mode(torrent.id(), Some(index as u32))
+                    .into(),
+            ),
+        })
+        .collect();
+    proto::Torrent {
+        id: torrent.id(),
+        name: torrent.name().into(),
+        state: proto::TorrentState::from(stats.state) as i32,
+        stats: Some((&stats).into()),
+        info: Some(proto::TorrentInfo {
+            name: summary.name,
+            total_size: summary.total_size,
+            hash_hex: summary.hash_hex,
+            magnet_link: summary.magnet_link,
+            comment: summary.comment,
+            creator: summary.creator,
+            date_created: summary.date_created.timestamp(),
+            piece_size: summary.piece_size,
+            piece_count: summary.piece_count,
+            is_private: summary.is_private,
+            is_folder: summary.is_folder,
+            webseeds: summary.webseeds,
+            trackers: summary
+                .trackers
+                .into_iter()
+                .map(|tracker| proto::TrackerInfo {
+                    tier: tracker.tier,
+                    announce: tracker.announce,
+                    scrape: tracker.scrape,
+                    id: tracker.id,
+                })
+                .collect(),
+            files,
+        }),
+        priority_mode: Some(data.priority_modes.mode(torrent.id(), None).into()),
+    }
+}
+
+#[tonic::async_trait]
+impl Torexpo for TorexpoService {
+    async fn list_torrents(
+        &self,
+        _request: Request<proto::ListTorrentsRequest>,
+    ) -> Result<Response<proto::ListTorrentsResponse>, Status> {
+        let mut torrents = self
+            .data
+            .torrents
+            .iter()
+            .map(|torrent| torrent_message(&self.data, torrent.value()))
+            .collect::<Vec<_>>();
+        torrents.sort_by_key(|torrent| torrent.id);
+        Ok(Response::new(proto::ListTorrentsResponse { torrents }))
+    }
+
+    async fn get_torrent(
+        &self,
+        request: Request<proto::TorrentRequest>,
+    ) -> Result<Response<proto::Torrent>, Status> {
+        let torrent = self.find_torrent(request.get_ref().torrent_id)?;
+        Ok(Response::new(torrent_message(&self.data, &torrent)))
+    }
+
+    async fn add_magnet_link(
+        &self,
+        request: Request<proto::AddMagnetLinkRequest>,
+    ) -> Result<Response<proto::AddTorrentResponse>, Status> {
+        let request = request.into_inner();
+        let torrent_id = self
+            .data
+            .add_magnet(
+                &request.magnet_link,
+                request.options.map(Into::into).unwrap_or_default(),
+            )
+            .await
+            .map_err(Status::invalid_argument)?;
+        Ok(Response::new(proto::AddTorrentResponse { torrent_id }))
+    }
+
+    async fn add_torrent_file(
+        &self,
+        request: Request<proto::AddTorrentFileRequest>,
+    ) -> Result<Response<proto::AddTorrentResponse>, Status> {
+        let request = request.into_inner();
+        let torrent_id = self
+            .data
+            .add_metainfo(
+                &request.torrent,
+                request.options.map(Into::into).unwrap_or_default(),
+            )
+            .await
+            .map_err(Status::invalid_argument)?;
+        Ok(Response::new(proto::AddTorrentResponse { torrent_id }))
+    }
+
+    async fn remove(
+        &self,
+        request: Request<proto::TorrentRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        if self
+            .data
+            .remove_torrent(request.get_ref().torrent_id, true)
+            .await
+        {
+            Ok(Response::new(proto::Empty {}))
+        } else {
+            Err(Status::not_found("Torrent not found"))
+        }
+    }
+
+    async fn start(
+        &self,
+        request: Request<proto::TorrentRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let torrent = self.find_torrent(request.get_ref().torrent_id)?;
+        self.data.start_torrent(&torrent);
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn stop(
+        &self,
+        request: Request<proto::TorrentRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let torrent = self.find_torrent(request.get_ref().torrent_id)?;
+        self.data.stop_torrent(&torrent);
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    async fn set_priority_mode(
+        &self,
+        request: Request<proto::SetPriorityModeRequest>,
+    ) -> Result<Response<proto::Empty>, Status> {
+        let request = request.into_inner();
+        let torrent = self.find_torrent(request.torrent_id)?;
+        self.data
+            .priority_modes
+            .set_mode(
+                &torrent,
+                request.file_index,
+                request.mode.map(Into::into).unwrap_or_default(),
+            )
+            .await
+            .map_err(Status::invalid_argument)?;
+        Ok(Response::new(proto::Empty {}))
+    }
+
+    type MonitorTorrentStream =
+        Pin<Box<dyn Stream<Item = Result<proto::Torrent, Status>> + Send + 'static>>;
+
+    async fn monitor_torrent(
+        &self,
+        request: Request<proto::MonitorTorrentRequest>,
+    ) -> Result<Response<Self::MonitorTorrentStream>, Status> {
+        let request = request.into_inner();
+        self.find_torrent(request.torrent_id)?;
+        let data = self.data.clone();
+        let stream = monitor_torrent_stream(
+            data.torrents.clone(),
+            request.torrent_id,
+            request.auto_stop.unwrap_or(true),
+            request.refresh_duration_millis.unwrap_or(500),
+        )
+        .map(move |torrent| Ok(torrent_message(&data, &torrent)));
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tonic::{metadata::MetadataValue, Code};
+
+    use super::*;
+
+    fn request(authorization: Option<&str>) -> Request<()> {
+        let mut request = Request::new(());
+        if let Some(authorization) = authorization {
+            let value = format!("Basic {}", base64::encode(authorization));
+            request
+                .metadata_mut()
+                .insert("authorization", MetadataValue::try_from(value).unwrap());
+        }
+        request
+    }
+
+    #[test]
+    fn interceptor_requires_the_credentials() {
+        let credentials = Some(("user".to_string(), "secret".to_string()));
+        assert!(authorize(request(Some("user:secret")), credentials.as_ref()).is_ok());
+        let err = authorize(request(Some("user:wrong")), credentials.as_ref()).unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+        let err = authorize(request(None), credentials.as_ref()).unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+    }
+
+    #[test]
+    fn interceptor_allows_everyone_without_credentials() {
+        assert!(authorize(request(None), None).is_ok());
+    }
+}
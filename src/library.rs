@@ -0,0 +1,621 @@
+use std::{
+    collections::VecDeque,
+    path::{Component, Path, PathBuf},
+    sync::Mutex,
+};
+
+use async_graphql::{Enum, InputObject, SimpleObject};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use transmission::Torrent;
+
+use crate::{
+    download_dirs::{check_allowed, DownloadDirs},
+    extract::Extractor,
+    new_id,
+    seed_buster::SeedingTorrents,
+    structures::TorrentState,
+    CONFIG_DIR,
+};
+
+lazy_static::lazy_static! {
+    /// Folder the paths of library rules are relative to
+    static ref LIBRARY_DIR: PathBuf = PathBuf::from(
+        std::env::var("TOREXPO_LIBRARY_DIR").unwrap_or_else(|_| "library".into())
+    );
+}
+
+/// History entries kept, oldest are forgotten first
+const MAX_HISTORY: usize = 500;
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum LibraryAction {
+    /// Move the torrent's data into the library and keep seeding it from there
+    Move,
+    /// Hardlink each file into the library, copying when the library is on another file system
+    Hardlink,
+    /// Copy each file into the library
+    Copy,
+}
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryRule {
+    pub id: String,
+    pub name: String,
+    /// Host of one of the torrent's trackers, matching its subdomains too
+    pub tracker_host: Option<String>,
+    /// Regex matched against the torrent's name
+    pub name_regex: Option<String>,
+    pub action: LibraryAction,
+    /// Path in the library directory, with `{tracker}`, `{name}`, `{file}`, `{filename}`
+    /// and `{ext}` replaced.
+    ///
+    /// Moves use it for the new download directory, so the file placeholders are
+    /// not available to them.
+    pub path_template: String,
+    pub enabled: bool,
+}
+
+#[derive(InputObject)]
+pub struct LibraryRuleInput {
+    pub name: String,
+    pub tracker_host: Option<String>,
+    pub name_regex: Option<String>,
+    pub action: LibraryAction,
+    pub path_template: String,
+    #[graphql(default = true)]
+    pub enabled: bool,
+}
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LibraryHistoryEntry {
+    pub torrent_id: i32,
+    pub torrent_name: String,
+    pub rule_id: String,
+    pub rule_name: String,
+    pub action: LibraryAction,
+    /// Paths the files were placed at
+    pub paths: Vec<String>,
+    pub error: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedLibrary {
+    rules: Vec<LibraryRule>,
+    history: VecDeque<LibraryHistoryEntry>,
+}
+
+/// Rules placing completed torrents in the library, tried in order
+#[derive(Default)]
+pub struct LibraryManager {
+    rules: Mutex<Vec<LibraryRule>>,
+    history: Mutex<VecDeque<LibraryHistoryEntry>>,
+}
+
+/// Values of the template placeholders for a torrent
+struct TemplateValues {
+    tracker: String,
+    name: String,
+}
+
+/// Value usable as a single path component
+fn component(value: &str) -> String {
+    let value = value.replace(['/', '\\'], "_");
+    match value.as_str() {
+        "" | "." | ".." => "_".into(),
+        _ => value,
+    }
+}
+
+impl TemplateValues {
+    fn new(torrent: &Torrent) -> Self {
+        Self {
+            tracker: component(
+                &tracker_hosts(torrent)
+                    .into_iter()
+                    .next()
+                    .unwrap_or_default(),
+            ),
+            name: component(torrent.name()),
+        }
+    }
+
+    /// Renders the template into a path inside the library directory
+    fn render(&self, template: &str, file: Option<&str>) -> Result<PathBuf, String> {
+        let mut rendered = template
+            .replace("{tracker}", &self.tracker)
+            .replace("{name}", &self.name);
+        if let Some(file) = file {
+            let path = Path::new(file);
+            let filename = path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let ext = path
+                .extension()
+                .map(|ext| ext.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            rendered = rendered
+                .replace("{file}", file)
+                .replace("{filename}", &filename)
+                .replace("{ext}", &ext);
+        }
+        let relative = Path::new(&rendered);
+        if rendered.contains('{')
+            || relative.as_os_str().is_empty()
+            || !relative
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(format!("Invalid library path {}", rendered));
+        }
+        Ok(LIBRARY_DIR.join(relative))
+    }
+}
+
+/// Lowercase hosts of the torrent's trackers
+pub fn tracker_hosts(torrent: &Torrent) -> Vec<String> {
+    torrent
+        .info()
+        .trackers
+        .iter()
+        .filter_map(|tracker| reqwest::Url::parse(&tracker.announce).ok())
+        .filter_map(|url| url.host_str().map(str::to_lowercase))
+        .collect()
+}
+
+impl LibraryRule {
+    fn validate(&self) -> Result<(), String> {
+        if let Some(name_regex) = &self.name_regex {
+            Regex::new(name_regex).map_err(|err| format!("Invalid name regex {}", err))?;
+        }
+        let values = TemplateValues {
+            tracker: "tracker".into(),
+            name: "name".into(),
+        };
+        let file = match self.action {
+            LibraryAction::Move => None,
+            LibraryAction::Hardlink | LibraryAction::Copy => Some("file.ext"),
+        };
+        values.render(&self.path_template, file).map(|_| ())
+    }
+
+    fn matches(&self, torrent: &Torrent) -> bool {
+        self.matches_name(torrent.name(), &tracker_hosts(torrent))
+    }
+
+    fn matches_name(&self, name: &str, tracker_hosts: &[String]) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if let Some(host) = &self.tracker_host {
+            let host = host.to_lowercase();
+            let matched = tracker_hosts
+                .iter()
+                .any(|tracker| *tracker == host || tracker.ends_with(&format!(".{}", host)));
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(name_regex) = &self.name_regex {
+            let matched = Regex::new(name_regex)
+                .map(|name_regex| name_regex.is_match(name))
+                .unwrap_or(false);
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Moves a file, copying it when renaming across file systems fails
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if std::fs::rename(from, to).is_err() {
+        if let Err(err) = std::fs::copy(from, to) {
+            // A partial copy is not left behind
+            let _ = std::fs::remove_file(to);
+            return Err(err);
+        }
+        std::fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+impl LibraryManager {
+    fn path() -> PathBuf {
+        Path::new(&CONFIG_DIR.clone()).join("library.json")
+    }
+
+    pub async fn load() -> Self {
+        let manager = LibraryManager::default();
+        if let Ok(saved) = tokio::fs::read(Self::path()).await {
+            match serde_json::from_slice::<SavedLibrary>(&saved) {
+                Ok(saved) => {
+                    *manager.rules.lock().unwrap() = saved.rules;
+                    *manager.history.lock().unwrap() = saved.history;
+                }
+                Err(err) => log::warn!("Cant read library rules {:#?}", err),
+            }
+        }
+        manager
+    }
+
+    async fn save(&self) {
+        let saved = serde_json::to_vec_pretty(&SavedLibrary {
+            rules: self.rules(),
+            history: self.history.lock().unwrap().clone(),
+        });
+        match saved {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save library rules {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save library rules {:#?}", err),
+        }
+    }
+
+    pub fn rules(&self) -> Vec<LibraryRule> {
+        self.rules.lock().unwrap().clone()
+    }
+
+    /// What rules did with torrents, latest first
+    pub fn history(&self, torrent_id: Option<i32>, limit: usize) -> Vec<LibraryHistoryEntry> {
+        self.history
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|entry| torrent_id.map(|id| entry.torrent_id == id).unwrap_or(true))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    /// Adds the rule after the existing ones
+    pub async fn add_rule(&self, input: LibraryRuleInput) -> Result<LibraryRule, String> {
+        let rule = LibraryRule {
+            id: new_id(),
+            name: input.name,
+            tracker_host: input.tracker_host,
+            name_regex: input.name_regex,
+            action: input.action,
+            path_template: input.path_template,
+            enabled: input.enabled,
+        };
+        rule.validate()?;
+        self.rules.lock().unwrap().push(rule.clone());
+        self.save().await;
+        Ok(rule)
+    }
+
+    pub async fn update_rule(
+        &self,
+        id: &str,
+        input: LibraryRuleInput,
+    ) -> Result<LibraryRule, String> {
+        let rule = {
+            let mut rules = self.rules.lock().unwrap();
+            let rule = rules
+                .iter_mut()
+                .find(|rule| rule.id == id)
+                .ok_or("Library rule not found")?;
+            let updated = LibraryRule {
+                id: rule.id.clone(),
+                name: input.name,
+                tracker_host: input.tracker_host,
+                name_regex: input.name_regex,
+                action: input.action,
+                path_template: input.path_template,
+                enabled: input.enabled,
+            };
+            updated.validate()?;
+            *rule = updated.clone();
+            updated
+        };
+        self.save().await;
+        Ok(rule)
+    }
+
+    pub async fn remove_rule(&self, id: &str) -> bool {
+        let removed = {
+            let mut rules = self.rules.lock().unwrap();
+            let count = rules.len();
+            rules.retain(|rule| rule.id != id);
+            rules.len() != count
+        };
+        if removed {
+            self.save().await;
+        }
+        removed
+    }
+
+    /// Applies the first matching rule to the torrent, returning what it did
+    pub async fn apply(
+        &self,
+        dirs: &DownloadDirs,
+        seeding: &SeedingTorrents,
+        extractor: &Extractor,
+        torrent: &Torrent,
+    ) -> Option<LibraryHistoryEntry> {
+        let rule = self
+            .rules()
+            .into_iter()
+            .find(|rule| rule.matches(torrent))?;
+        let result = match rule.action {
+            LibraryAction::Move => move_torrent(dirs, seeding, extractor, torrent, &rule).await,
+            LibraryAction::Hardlink | LibraryAction::Copy => link_files(dirs, torrent, &rule).await,
+        };
+        let (paths, error) = match result {
+            Ok(paths) => (paths, None),
+            Err(err) => {
+                log::warn!("Cant apply library rule {} {}", rule.name, err);
+                (vec![], Some(err))
+            }
+        };
+        let entry = LibraryHistoryEntry {
+            torrent_id: torrent.id(),
+            torrent_name: torrent.name().into(),
+            rule_id: rule.id,
+            rule_name: rule.name,
+            action: rule.action,
+            paths,
+            error,
+            time: Utc::now(),
+        };
+        {
+            let mut history = self.history.lock().unwrap();
+            if history.len() >= MAX_HISTORY {
+                history.pop_front();
+            }
+            history.push_back(entry.clone());
+        }
+        self.save().await;
+        Some(entry)
+    }
+}
+
+/// Moves the torrent's files into the rendered directory and seeds them from there.
+///
+/// Files extracted from its archives are relative to the download directory too,
+/// so they move along.
+async fn move_torrent(
+    dirs: &DownloadDirs,
+    seeding: &SeedingTorrents,
+    extractor: &Extractor,
+    torrent: &Torrent,
+    rule: &LibraryRule,
+) -> Result<Vec<String>, String> {
+    let target = TemplateValues::new(torrent).render(&rule.path_template, None)?;
+    let source = dirs.dir(torrent.id());
+    if source == target {
+        return Ok(vec![]);
+    }
+    check_allowed(&target)?;
+    let extracted = extractor
+        .archives(torrent)
+        .into_iter()
+        .flat_map(|archive| archive.files)
+        .map(|file| file.name);
+    let names = torrent
+        .info()
+        .files
+        .iter()
+        .map(|file| file.name.clone())
+        .chain(extracted)
+        .collect::<Vec<_>>();
+    let was_running = TorrentState::from(torrent.stats().state) != TorrentState::Stopped;
+    torrent.stop();
+    let result = relocate(dirs, torrent, names, source, target).await;
+    // Moved torrents seed from the library, others keep running from where their files are
+    if result.is_ok() {
+        seeding.keep(torrent).await;
+    }
+    if was_running || result.is_ok() {
+        torrent.start();
+    }
+    result
+}
+
+/// Moves the files and points the torrent at the target, moving them back on failure
+async fn relocate(
+    dirs: &DownloadDirs,
+    torrent: &Torrent,
+    names: Vec<String>,
+    source: PathBuf,
+    target: PathBuf,
+) -> Result<Vec<String>, String> {
+    let to = target.clone();
+    let moved = tokio::task::spawn_blocking(move || move_files(&names, &source, &to))
+        .await
+        .map_err(|err| err.to_string())?
+        .map_err(|err| format!("Cant move files {}", err))?;
+    if let Err(err) = dirs.set_dir(torrent, &target.to_string_lossy()).await {
+        tokio::task::spawn_blocking(move || move_back(&moved))
+            .await
+            .map_err(|err| err.to_string())?;
+        return Err(err);
+    }
+    Ok(moved
+        .iter()
+        .map(|(_, destination)| destination.to_string_lossy().into_owned())
+        .collect())
+}
+
+/// Moves the files between the folders, moving them back when one of them fails
+fn move_files(
+    names: &[String],
+    from: &Path,
+    to: &Path,
+) -> std::io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut moved = vec![];
+    for name in names.iter() {
+        let (file, destination) = (from.join(name), to.join(name));
+        if !file.exists() {
+            continue;
+        }
+        let result = match destination.parent() {
+            Some(parent) => std::fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|_| move_file(&file, &destination));
+        if let Err(err) = result {
+            move_back(&moved);
+            return Err(err);
+        }
+        moved.push((file, destination));
+    }
+    // Folders left empty by the move are removed
+    for (file, _) in moved.iter() {
+        let mut parent = file.parent();
+        while let Some(folder) = parent.filter(|folder| *folder != from) {
+            if std::fs::remove_dir(folder).is_err() {
+                break;
+            }
+            parent = folder.parent();
+        }
+    }
+    Ok(moved)
+}
+
+/// Moves files back to where they were before a failed move
+fn move_back(moved: &[(PathBuf, PathBuf)]) {
+    for (file, destination) in moved.iter().rev() {
+        let result = match file.parent() {
+            Some(parent) => std::fs::create_dir_all(parent),
+            None => Ok(()),
+        }
+        .and_then(|_| move_file(destination, file));
+        if let Err(err) = result {
+            log::warn!("Cant move back {} {:#?}", destination.display(), err);
+        }
+    }
+}
+
+/// Hardlinks or copies each file of the torrent to its rendered path
+async fn link_files(
+    dirs: &DownloadDirs,
+    torrent: &Torrent,
+    rule: &LibraryRule,
+) -> Result<Vec<String>, String> {
+    let values = TemplateValues::new(torrent);
+    let source = dirs.dir(torrent.id());
+    let mut files = vec![];
+    for file in torrent.info().files.iter().filter(|file| file.dnd == 0) {
+        // The torrent's top folder is replaced by the template
+        let relative = Path::new(&file.name);
+        let mut components = relative.components();
+        components.next();
+        let inner = match components.as_path() {
+            inner if inner.as_os_str().is_empty() => relative,
+            inner => inner,
+        };
+        let destination = values.render(&rule.path_template, Some(&inner.to_string_lossy()))?;
+        files.push((source.join(&file.name), destination));
+    }
+    let action = rule.action;
+    tokio::task::spawn_blocking(move || {
+        let mut placed = vec![];
+        for (file, destination) in files {
+            if let Some(parent) = destination.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            if destination.exists() {
+                std::fs::remove_file(&destination)?;
+            }
+            let linked = action == LibraryAction::Hardlink
+                && std::fs::hard_link(&file, &destination).is_ok();
+            if !linked {
+                std::fs::copy(&file, &destination)?;
+            }
+            placed.push(destination.to_string_lossy().into_owned());
+        }
+        Ok::<_, std::io::Error>(placed)
+    })
+    .await
+    .map_err(|err| err.to_string())?
+    .map_err(|err| format!("Cant place files {}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(tracker_host: Option<&str>, name_regex: Option<&str>) -> LibraryRule {
+        LibraryRule {
+            id: "rule".into(),
+            name: "Rule".into(),
+            tracker_host: tracker_host.map(String::from),
+            name_regex: name_regex.map(String::from),
+            action: LibraryAction::Copy,
+            path_template: "{tracker}/{name}/{file}".into(),
+            enabled: true,
+        }
+    }
+
+    #[test]
+    fn tracker_host_matches_its_subdomains() {
+        let rule = rule(Some("Tracker.example"), None);
+        assert!(rule.matches_name("Show", &["tracker.example".into()]));
+        assert!(rule.matches_name("Show", &["announce.tracker.example".into()]));
+        assert!(!rule.matches_name("Show", &["othertracker.example".into()]));
+        assert!(!rule.matches_name("Show", &[]));
+    }
+
+    #[test]
+    fn name_regex_and_enabled_are_checked() {
+        let mut rule = rule(None, Some("(?i)s\\d+e\\d+"));
+        assert!(rule.matches_name("Show.S01E02.mkv", &[]));
+        assert!(!rule.matches_name("Movie.2022.mkv", &[]));
+        rule.enabled = false;
+        assert!(!rule.matches_name("Show.S01E02.mkv", &[]));
+    }
+
+    #[test]
+    fn template_stays_in_the_library() {
+        let values = TemplateValues {
+            tracker: component("tracker.example"),
+            name: component("../Show"),
+        };
+        assert_eq!(
+            values.render("{tracker}/{name}/{filename}", Some("Season 1/e01.mkv")),
+            Ok(LIBRARY_DIR.join("tracker.example/.._Show/e01.mkv"))
+        );
+        assert!(values.render("../{name}", None).is_err());
+        assert!(values.render("/{name}", None).is_err());
+        assert!(values.render("{name}/{file}", None).is_err());
+    }
+
+    #[test]
+    fn rule_with_invalid_template_is_rejected() {
+        let mut rule = rule(None, None);
+        assert!(rule.validate().is_ok());
+        rule.action = LibraryAction::Move;
+        assert!(rule.validate().is_err());
+        rule.path_template = "{tracker}/{name}".into();
+        assert!(rule.validate().is_ok());
+    }
+
+    #[test]
+    fn moved_files_take_their_folders_along() {
+        let temp = tempfile::tempdir().unwrap();
+        let (from, to) = (temp.path().join("from"), temp.path().join("to"));
+        std::fs::create_dir_all(from.join("show")).unwrap();
+        std::fs::write(from.join("show.zip"), b"archive").unwrap();
+        std::fs::write(from.join("show/episode.txt"), b"episode").unwrap();
+        let names = ["show.zip".to_string(), "show/episode.txt".to_string()];
+        let moved = move_files(&names, &from, &to).unwrap();
+        assert_eq!(moved.len(), 2);
+        assert!(!from.join("show").exists());
+        assert_eq!(
+            std::fs::read(to.join("show/episode.txt")).unwrap(),
+            b"episode".to_vec()
+        );
+    }
+}
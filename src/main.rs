@@ -1,4 +1,4 @@
-use std::sync::Arc;
+use std::{borrow::Cow, sync::Arc};
 
 use async_graphql::{
     http::{playground_source, GraphQLPlaygroundConfig},
@@ -6,36 +6,111 @@ use async_graphql::{
 };
 use async_graphql_axum::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
 use axum::{
-    body::{boxed, Body, BoxBody},
+    body::{boxed, Body, BoxBody, StreamBody},
     extract::Path,
-    http::{Method, Request, Response, StatusCode},
+    http::{HeaderName, HeaderValue, Method, Request, Response, StatusCode},
     response::{self, IntoResponse},
     routing::get,
     Extension, Router, Server,
 };
+use completion::completion_pipeline;
+use cookie_profiles::CookieProfileManager;
+use create_torrent::{creation_worker, TorrentCreator};
 use dashmap::DashMap;
-use magic_crypt::{new_magic_crypt, MagicCrypt256, MagicCryptTrait};
-use seed_buster::seed_buster;
-use structures::{DownloadLinkStructure, MainSchema, SubscriptionRoot};
+use disk_space::{disk_guard, DiskGuard};
+use download_dirs::{write_session_settings, DownloadDirs};
+use download_link::{
+    content_disposition, decode_link, ArchiveLinkStructure, DownloadLinkStructure,
+    MetainfoLinkStructure,
+};
+use events::{event_watcher, EventLog};
+use extract::{archive_cleaner, Extractor};
+use feeds::{feed_poller, FeedManager};
+use library::LibraryManager;
+use magic_crypt::{new_magic_crypt, MagicCrypt256};
+use pieces::PieceTracker;
+use priority::{priority_scheduler, PriorityModes};
+use qbittorrent::QbittorrentState;
+use rand::RngCore;
+use retention::{retention_enforcer, RetentionManager};
+use scheduler::{scheduled_starter, Scheduler};
+use scripts::{script_runner, ScriptManager};
+use search::IndexerManager;
+use seed_buster::{seed_buster, SeedingTorrents};
+use structures::{MainSchema, SubscriptionRoot};
 use tower::ServiceExt;
 use tower_http::cors::{Any, CorsLayer};
+use tracker::{udp_tracker, Tracker};
 use transmission::{Client, Torrent};
+use webhooks::{webhook_dispatcher, WebhookManager};
 
 use crate::{
-    context::SharedData,
+    context::{SharedData, TorrentHashes},
     structures::{MutationRoot, QueryRoot},
 };
 
+pub mod archive;
+pub mod aria2;
+pub mod auth;
+pub mod bencode;
+pub mod completion;
 pub mod context;
+pub mod cookie_profiles;
+pub mod create_torrent;
+pub mod disk_space;
+pub mod download_dirs;
+pub mod download_link;
+pub mod events;
+pub mod extract;
+pub mod feeds;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod library;
+pub mod pieces;
+pub mod priority;
+pub mod qbittorrent;
+pub mod rest;
+pub mod retention;
+pub mod scheduler;
+pub mod scripts;
+pub mod search;
 pub mod seed_buster;
+pub mod sse;
+pub mod streaming;
 pub mod structures;
 pub mod torrent_struc;
+pub mod tracker;
+pub mod transmission_rpc;
+pub mod watch_folder;
+pub mod webhooks;
 
 lazy_static::lazy_static! {
     pub static ref DOWNLOAD_DIR: String = std::env::var("TOREXPO_DOWNLOAD_DIR").unwrap_or_else(|_| "downloads".into());
+    /// Folder torrents download into until they complete, disabled when not set
+    pub static ref INCOMPLETE_DIR: Option<String> = std::env::var("TOREXPO_INCOMPLETE_DIR").ok().filter(|dir| !dir.is_empty());
+    /// Add `.part` to the names of files until they complete
+    pub static ref RENAME_PARTIAL_FILES: bool = env_flag("TOREXPO_RENAME_PARTIAL_FILES");
+    pub static ref CONFIG_DIR: String = std::env::var("TOREXPO_CONFIG_DIR").unwrap_or_else(|_| "config".into());
+    /// Url the server is reachable at, prefixed to the links sent to webhooks
+    /// and to the announce urls of the embedded tracker
+    pub static ref PUBLIC_URL: String = std::env::var("TOREXPO_PUBLIC_URL").map(|url| url.trim_end_matches('/').to_string()).unwrap_or_default();
     pub static ref MCRYPT:MagicCrypt256 = new_magic_crypt!(std::env::var("TOREXPO_DOWNLOAD_ENCRYPT_KEY").unwrap_or_else(|_| "download key".into()), 256);
 }
 
+/// Whether the env var is set to `1` or `true`
+pub fn env_flag(name: &str) -> bool {
+    std::env::var(name)
+        .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+}
+
+/// Random hex id, unguessable so it can also serve as a session token
+pub fn new_id() -> String {
+    let mut bytes = [0u8; 16];
+    rand::rngs::OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
 async fn graphql_handler(schema: Extension<MainSchema>, req: GraphQLRequest) -> GraphQLResponse {
     schema.execute(req.into_inner()).await.into()
 }
@@ -78,11 +153,17 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tokio_uring::start(async {
         let torrents = Arc::new(DashMap::new());
         let download_dir = DOWNLOAD_DIR.clone();
-        let config_dir = std::env::var("TOREXPO_CONFIG_DIR").unwrap_or_else(|_| "config".into());
+        let config_dir = CONFIG_DIR.clone();
         let transmission_config = transmission::ClientConfig::new()
             .app_name("torexpo")
             .download_dir(&download_dir)
             .config_dir(&config_dir);
+        if let Some(incomplete_dir) = &*INCOMPLETE_DIR {
+            if let Err(err) = std::fs::create_dir_all(incomplete_dir) {
+                log::warn!("Cant create incomplete dir {:#?}", err);
+            }
+        }
+        write_session_settings(&config_dir);
         let transmission_client = transmission::Client::new(transmission_config);
 
         let loaded_torrents = load_torrents(&transmission_client, &config_dir).await;
@@ -90,74 +171,243 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             torrents.insert(torrent.id(), torrent);
         }
 
+        let hashes = Arc::new(TorrentHashes::default());
+        let priority_modes = Arc::new(PriorityModes::load(&torrents, &hashes).await);
+        let pieces = Arc::new(PieceTracker::default());
+        let events = Arc::new(EventLog::load().await);
+
+        let http = reqwest::Client::builder()
+            .user_agent(concat!("torexpo/", env!("CARGO_PKG_VERSION")))
+            .build()?;
+        let download_dirs = Arc::new(DownloadDirs::load(&torrents, &hashes).await);
+        let seeding = Arc::new(SeedingTorrents::load(&torrents, &hashes).await);
         let data = SharedData {
-            client: transmission_client,
+            client: Arc::new(transmission_client),
             torrents: torrents.clone(),
+            download_dirs: download_dirs.clone(),
+            priority_modes: priority_modes.clone(),
+            pieces: pieces.clone(),
+            events: events.clone(),
+            feeds: Arc::new(FeedManager::load().await),
+            indexers: Arc::new(IndexerManager::load().await),
+            cookie_profiles: Arc::new(CookieProfileManager::load().await),
+            webhooks: Arc::new(WebhookManager::load().await),
+            scripts: Arc::new(ScriptManager::load().await),
+            extractor: Arc::new(Extractor::load(&torrents, &hashes).await),
+            library: Arc::new(LibraryManager::load().await),
+            disk: Arc::new(DiskGuard::load(&torrents, &hashes).await),
+            retention: Arc::new(RetentionManager::load().await),
+            scheduler: Arc::new(Scheduler::load(&torrents, &hashes).await),
+            creator: Arc::new(TorrentCreator::load().await),
+            seeding: seeding.clone(),
+            hashes,
+            tracker: Arc::new(Tracker::load().await),
+            http,
         };
 
+        // gRPC runs on its own, the HTTP server keeps serving when it fails
+        #[cfg(feature = "grpc")]
+        tokio::spawn({
+            let data = data.clone();
+            async move {
+                if let Err(err) = grpc::serve(data).await {
+                    log::error!("Cant serve grpc {}", err);
+                }
+            }
+        });
+
         let schema = Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
-            .data(data)
+            .data(data.clone())
             .finish();
         let cors = CorsLayer::new()
-            .allow_methods([Method::GET, Method::POST, Method::OPTIONS])
+            .allow_methods([Method::GET, Method::POST, Method::DELETE, Method::OPTIONS])
+            .allow_headers(Any)
+            .expose_headers([HeaderName::from_static("x-transmission-session-id")])
             // allow requests from any origin
             .allow_origin(Any);
 
         let app = Router::new()
             .route("/", get(graphql_playground).post(graphql_handler))
             .route("/ws", GraphQLSubscription::new(schema.clone()))
+            .route("/sse", get(sse::graphql_sse).post(sse::graphql_sse))
             .route("/download/:download_link", get(serve_file))
+            .route("/metainfo/:download_link", get(serve_metainfo))
+            .route("/archive/:download_link", get(serve_archive))
+            .route("/stream/:download_link", get(streaming::serve_stream))
+            .route(
+                "/transmission/rpc",
+                get(transmission_rpc::transmission_rpc).post(transmission_rpc::transmission_rpc),
+            )
+            .route(
+                "/jsonrpc",
+                get(aria2::aria2_websocket).post(aria2::aria2_http),
+            )
+            .nest("/api/v1", rest::router())
+            .nest("/api/v2", qbittorrent::router())
+            .merge(tracker::router())
             .layer(Extension(schema))
+            .layer(Extension(data.clone()))
+            .layer(Extension(Arc::new(QbittorrentState::load().await)))
             .layer(cors);
 
         let port = std::env::var("TOREXPO_PORT").unwrap_or_else(|_| "8080".into());
-        let torrent_buster_proc = seed_buster(torrents);
+        let torrent_buster_proc = seed_buster(torrents.clone(), seeding);
+        let priority_proc =
+            priority_scheduler(torrents.clone(), priority_modes, pieces, download_dirs);
+        let events_proc = event_watcher(torrents, events);
+        let watch_proc = watch_folder::watch_folder(data.clone());
+        let feeds_proc = feed_poller(data.clone());
+        let webhooks_proc = webhook_dispatcher(data.clone());
+        let scripts_proc = script_runner(data.clone());
+        let completion_proc = completion_pipeline(data.clone());
+        let archive_proc = archive_cleaner(data.clone());
+        let disk_proc = disk_guard(data.clone());
+        let retention_proc = retention_enforcer(data.clone());
+        let scheduler_proc = scheduled_starter(data.clone());
+        let creation_proc = creation_worker(data.clone());
+        let tracker_proc = udp_tracker(data.clone());
         let server_proc = Server::bind(&format!("0.0.0.0:{}", port).parse().unwrap())
-            .serve(app.into_make_service());
-        futures_util::future::select(Box::pin(torrent_buster_proc), server_proc).await;
-    });
-    Ok(())
+            .serve(app.into_make_service_with_connect_info::<std::net::SocketAddr>());
+        futures_util::future::select(
+            Box::pin(async {
+                tokio::join!(
+                    torrent_buster_proc,
+                    priority_proc,
+                    events_proc,
+                    watch_proc,
+                    feeds_proc,
+                    webhooks_proc,
+                    scripts_proc,
+                    completion_proc,
+                    archive_proc,
+                    disk_proc,
+                    retention_proc,
+                    scheduler_proc,
+                    creation_proc,
+                    tracker_proc,
+                )
+            }),
+            server_proc,
+        )
+        .await;
+        Ok::<_, Box<dyn std::error::Error>>(())
+    })
 }
 
 async fn serve_file(
     Path(download_link): Path<String>,
+    Extension(data): Extension<SharedData>,
     req: Request<Body>,
 ) -> Result<Response<BoxBody>, (StatusCode, String)> {
     log::info!("Requested download link {download_link}");
-    match MCRYPT.decrypt_base64_to_bytes(download_link) {
-        Ok(data) => match bincode::deserialize::<DownloadLinkStructure>(&data) {
-            Ok(structure) => {
-                if let Some(expiry) = &structure.expiry {
-                    if &chrono::Utc::now() > expiry {
-                        return Err((
-                            StatusCode::UNAUTHORIZED,
-                            "Expired download link".to_string(),
-                        ));
-                    }
-                }
-                let path = std::path::Path::new(structure.file.as_ref());
-                let servefile = tower_http::services::ServeFile::new(&path);
-                let filename = path.file_name();
-                log::info!("Downloading {}", path.to_string_lossy());
-                match servefile.oneshot(req).await {
-                    Ok(mut res) => {
-                        if let Some(filename) = filename {
-                            if let Ok(value) =
-                                format!("filename=\"{}\"", filename.to_string_lossy()).try_into()
-                            {
-                                res.headers_mut().insert("content-disposition", value);
-                            }
-                        }
-                        Ok(res.map(boxed))
-                    }
-                    Err(err) => Err((
-                        StatusCode::INTERNAL_SERVER_ERROR,
-                        format!("Something went wrong: {}", err),
-                    )),
+    let structure = decode_link::<DownloadLinkStructure>(&download_link)?;
+    let torrent_id = data
+        .torrent_by_hash(&structure.hash)
+        .map(|torrent| torrent.id())
+        .ok_or((StatusCode::NOT_FOUND, "Torrent not found".to_string()))?;
+    // Files move out of the incomplete dir and lose their `.part` suffix once complete
+    let (dirs, name) = (data.download_dirs.clone(), structure.name.clone());
+    let path = tokio::task::spawn_blocking(move || dirs.data_path(torrent_id, &name))
+        .await
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    let filename = std::path::Path::new(structure.name.as_ref())
+        .file_name()
+        .map(|name| name.to_string_lossy());
+    log::info!("Downloading {}", path.to_string_lossy());
+    serve_path(&path, filename, req).await
+}
+
+async fn serve_metainfo(
+    Path(download_link): Path<String>,
+    req: Request<Body>,
+) -> Result<Response<BoxBody>, (StatusCode, String)> {
+    log::info!("Requested metainfo link {download_link}");
+    let structure = decode_link::<MetainfoLinkStructure>(&download_link)?;
+    let path = std::path::Path::new(structure.file.as_ref());
+    log::info!("Downloading metainfo {}", path.to_string_lossy());
+    let filename = format!("{}.torrent", structure.name);
+    let mut res = serve_path(path, Some(filename.into()), req).await?;
+    res.headers_mut().insert(
+        "content-type",
+        HeaderValue::from_static("application/x-bittorrent"),
+    );
+    Ok(res)
+}
+
+async fn serve_archive(
+    Path(download_link): Path<String>,
+    Extension(data): Extension<SharedData>,
+) -> Result<Response<BoxBody>, (StatusCode, String)> {
+    log::info!("Requested archive link {download_link}");
+    let structure = decode_link::<ArchiveLinkStructure>(&download_link)?;
+    let torrent = data
+        .torrent_by_hash(&structure.hash)
+        .ok_or((StatusCode::NOT_FOUND, "Torrent not found".to_string()))?;
+    let (torrent_id, name) = (torrent.id(), torrent.name().to_string());
+    // The torrent may have moved out of the incomplete dir or into the library
+    let (dirs, folder) = (data.download_dirs.clone(), structure.folder.clone());
+    let path = tokio::task::spawn_blocking(move || {
+        let path = dirs.data_path(torrent_id, &name);
+        match folder {
+            Some(folder) => path.join(folder.as_ref()),
+            None => path,
+        }
+    })
+    .await
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    log::info!("Archiving {}", path.to_string_lossy());
+    let entries = archive::collect_entries(path.clone())
+        .await
+        .map_err(|err| (StatusCode::NOT_FOUND, format!("Cant read files: {}", err)))?;
+    let archived = match &structure.folder {
+        Some(folder) => std::path::Path::new(folder.as_ref())
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned()),
+        None => Some(torrent.name().to_string()),
+    };
+    let filename = format!(
+        "{}.{}",
+        archived.unwrap_or_else(|| "download".into()),
+        structure.format.extension()
+    );
+    let size = archive::archive_size(structure.format, &entries)
+        .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+    let body = StreamBody::new(archive::archive_stream(structure.format, entries));
+    Response::builder()
+        .header("content-type", structure.format.content_type())
+        .header("content-length", size)
+        .header(
+            "content-disposition",
+            content_disposition("attachment", &filename),
+        )
+        .body(boxed(body))
+        .map_err(|err| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Something went wrong: {}", err),
+            )
+        })
+}
+
+async fn serve_path(
+    path: &std::path::Path,
+    filename: Option<Cow<'_, str>>,
+    req: Request<Body>,
+) -> Result<Response<BoxBody>, (StatusCode, String)> {
+    let servefile = tower_http::services::ServeFile::new(path);
+    match servefile.oneshot(req).await {
+        Ok(mut res) => {
+            if let Some(filename) = filename {
+                let disposition = content_disposition("attachment", &filename);
+                if let Ok(value) = HeaderValue::from_str(&disposition) {
+                    res.headers_mut().insert("content-disposition", value);
                 }
             }
-            Err(_) => Err((StatusCode::BAD_REQUEST, "invalid download link".to_string())),
-        },
-        Err(_err) => Err((StatusCode::BAD_REQUEST, "invalid download link".to_string())),
+            Ok(res.map(boxed))
+        }
+        Err(err) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Something went wrong: {}", err),
+        )),
     }
 }
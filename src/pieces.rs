@@ -0,0 +1,314 @@
+use std::{
+    collections::HashSet,
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use chrono::NaiveDateTime;
+use dashmap::DashMap;
+use sha1::{Digest, Sha1};
+use transmission::Torrent;
+
+use crate::download_dirs::DownloadDirs;
+
+/// Pieces which have been verified on disk, per torrent.
+///
+/// Verified pieces never become unavailable, so they are only checked once.
+#[derive(Default)]
+pub struct PieceTracker {
+    verified: DashMap<i32, HashSet<u32>>,
+    /// Check time libtransmission had for pieces found missing, per torrent and piece
+    missing: DashMap<(i32, u32), NaiveDateTime>,
+}
+
+impl PieceTracker {
+    pub fn is_verified(&self, torrent_id: i32, piece: u32) -> bool {
+        self.verified
+            .get(&torrent_id)
+            .map(|pieces| pieces.contains(&piece))
+            .unwrap_or(false)
+    }
+
+    fn mark_verified(&self, torrent_id: i32, piece: u32) {
+        self.verified.entry(torrent_id).or_default().insert(piece);
+    }
+
+    /// Checks whether the piece is downloaded, hashing it from disk if needed
+    pub async fn has_piece(
+        &self,
+        torrent: &Torrent,
+        layout: &Arc<TorrentLayout>,
+        piece: u32,
+    ) -> std::io::Result<bool> {
+        let torrent_id = torrent.id();
+        if self.is_verified(torrent_id, piece) {
+            return Ok(true);
+        }
+        let complete = torrent.stats().percent_complete >= 1.0;
+        // libtransmission checks a piece once all of it is written, so a missing piece
+        // only needs hashing again after its check time changes
+        let checked = layout.checked.get(piece as usize).copied();
+        let unchanged = checked.is_some()
+            && self.missing.get(&(torrent_id, piece)).map(|time| *time) == checked;
+        if !complete && unchanged {
+            return Ok(false);
+        }
+        self.check_piece(torrent_id, layout, piece, complete, checked)
+            .await
+    }
+
+    /// Hashes the piece from disk unless the torrent is complete, for callers which know
+    /// libtransmission wrote more data since the layout was loaded
+    pub async fn recheck_piece(
+        &self,
+        torrent: &Torrent,
+        layout: &Arc<TorrentLayout>,
+        piece: u32,
+    ) -> std::io::Result<bool> {
+        let torrent_id = torrent.id();
+        if self.is_verified(torrent_id, piece) {
+            return Ok(true);
+        }
+        let complete = torrent.stats().percent_complete >= 1.0;
+        self.check_piece(torrent_id, layout, piece, complete, None)
+            .await
+    }
+
+    async fn check_piece(
+        &self,
+        torrent_id: i32,
+        layout: &Arc<TorrentLayout>,
+        piece: u32,
+        complete: bool,
+        checked: Option<NaiveDateTime>,
+    ) -> std::io::Result<bool> {
+        let verified = complete || {
+            let layout = layout.clone();
+            tokio::task::spawn_blocking(move || layout.verify_piece(piece)).await?
+        };
+        if verified {
+            self.mark_verified(torrent_id, piece);
+            self.missing.remove(&(torrent_id, piece));
+        } else if let Some(checked) = checked {
+            self.missing.insert((torrent_id, piece), checked);
+        }
+        Ok(verified)
+    }
+
+    /// Whether libtransmission has the piece, from when it checked it.
+    ///
+    /// A checked piece passed its hash unless it was found missing since that check.
+    fn has_checked_piece(&self, torrent_id: i32, piece: u32, checked: NaiveDateTime) -> bool {
+        self.is_verified(torrent_id, piece)
+            || (checked.timestamp() > 0
+                && self.missing.get(&(torrent_id, piece)).map(|time| *time) != Some(checked))
+    }
+
+    /// Bytes downloaded of each file of the torrent, from the pieces the file covers.
+    ///
+    /// Uses the check state libtransmission keeps for each piece, nothing is read from disk.
+    pub fn files_completed(
+        &self,
+        torrent: &Torrent,
+        info: &transmission::torrent::TorrentInfo,
+    ) -> Vec<u64> {
+        let stats = torrent.stats();
+        if stats.percent_complete >= 1.0 {
+            return info.files.iter().map(|file| file.length).collect();
+        }
+        let torrent_id = torrent.id();
+        info.files
+            .iter()
+            .map(|file| {
+                // Wanted files are complete once the torrent is done
+                if file.length == 0 || (file.dnd == 0 && stats.percent_done >= 1.0) {
+                    return file.length;
+                }
+                let pieces = (file.first_piece..=file.last_piece)
+                    .filter(|piece| {
+                        info.pieces
+                            .get(*piece as usize)
+                            .map(|checked| {
+                                self.has_checked_piece(torrent_id, *piece, checked.time_checked)
+                            })
+                            .unwrap_or(false)
+                    })
+                    .collect::<Vec<_>>();
+                bytes_in_pieces(info.piece_size as u64, file.offset, file.length, &pieces)
+            })
+            .collect()
+    }
+
+    pub fn forget(&self, torrent_id: i32) {
+        self.verified.remove(&torrent_id);
+        self.missing.retain(|(id, _), _| *id != torrent_id);
+    }
+}
+
+/// Bytes of the file inside the pieces, pieces it shares with its neighbours only
+/// count for the part they overlap it
+fn bytes_in_pieces(piece_size: u64, file_offset: u64, file_length: u64, pieces: &[u32]) -> u64 {
+    let file_end = file_offset + file_length;
+    pieces
+        .iter()
+        .map(|piece| {
+            let start = (*piece as u64 * piece_size).max(file_offset);
+            let end = ((*piece as u64 + 1) * piece_size).min(file_end);
+            end.saturating_sub(start)
+        })
+        .sum()
+}
+
+/// Paths of the files on disk, found off the async runtime as it checks the disk
+async fn locate(
+    dirs: &Arc<DownloadDirs>,
+    torrent_id: i32,
+    names: Vec<String>,
+) -> Result<Vec<PathBuf>, tokio::task::JoinError> {
+    let dirs = dirs.clone();
+    tokio::task::spawn_blocking(move || {
+        names
+            .iter()
+            .map(|name| dirs.data_path(torrent_id, name))
+            .collect()
+    })
+    .await
+}
+
+#[derive(Clone)]
+pub struct LayoutFile {
+    /// Name of the file in the torrent
+    pub name: String,
+    pub path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Where the pieces of a torrent live on disk
+#[derive(Clone)]
+pub struct TorrentLayout {
+    pub piece_size: u64,
+    pub total_size: u64,
+    pub hashes: Vec<[u8; 20]>,
+    /// When libtransmission last checked each piece
+    pub checked: Vec<NaiveDateTime>,
+    pub files: Vec<LayoutFile>,
+}
+
+impl TorrentLayout {
+    /// Locates the files of the torrent
+    pub async fn load(
+        dirs: &Arc<DownloadDirs>,
+        torrent_id: i32,
+        info: &transmission::torrent::TorrentInfo,
+    ) -> Result<Self, tokio::task::JoinError> {
+        let names = info
+            .files
+            .iter()
+            .map(|file| file.name.clone())
+            .collect::<Vec<_>>();
+        let paths = locate(dirs, torrent_id, names).await?;
+        Ok(Self {
+            piece_size: info.piece_size as u64,
+            total_size: info.total_size,
+            hashes: info.pieces.iter().map(|piece| piece.hash).collect(),
+            checked: info.pieces.iter().map(|piece| piece.time_checked).collect(),
+            files: info
+                .files
+                .iter()
+                .zip(paths)
+                .map(|(file, path)| LayoutFile {
+                    name: file.name.clone(),
+                    path,
+                    offset: file.offset,
+                    length: file.length,
+                })
+                .collect(),
+        })
+    }
+
+    /// Locates the files again, they move out of the incomplete directory and lose
+    /// their `.part` suffix as they complete
+    pub async fn relocate(
+        &mut self,
+        dirs: &Arc<DownloadDirs>,
+        torrent_id: i32,
+    ) -> Result<(), tokio::task::JoinError> {
+        let names = self
+            .files
+            .iter()
+            .map(|file| file.name.clone())
+            .collect::<Vec<_>>();
+        let paths = locate(dirs, torrent_id, names).await?;
+        for (file, path) in self.files.iter_mut().zip(paths) {
+            file.path = path;
+        }
+        Ok(())
+    }
+
+    pub fn piece_at(&self, torrent_offset: u64) -> u32 {
+        (torrent_offset / self.piece_size) as u32
+    }
+
+    pub fn last_piece(&self) -> u32 {
+        (self.hashes.len() as u32).saturating_sub(1)
+    }
+
+    /// Reads the piece from the files it spans and compares it to its hash
+    fn verify_piece(&self, piece: u32) -> bool {
+        let start = piece as u64 * self.piece_size;
+        let end = (start + self.piece_size).min(self.total_size);
+        let mut hasher = Sha1::new();
+        let mut buf = vec![];
+        for file in self.files.iter() {
+            let file_end = file.offset + file.length;
+            if file_end <= start || file.offset >= end {
+                continue;
+            }
+            let read_start = start.max(file.offset);
+            let read_end = end.min(file_end);
+            buf.resize((read_end - read_start) as usize, 0);
+            let read = std::fs::File::open(&file.path).and_then(|mut disk_file| {
+                disk_file.seek(SeekFrom::Start(read_start - file.offset))?;
+                disk_file.read_exact(&mut buf)
+            });
+            if read.is_err() {
+                return false;
+            }
+            hasher.update(&buf);
+        }
+        self.hashes
+            .get(piece as usize)
+            .map(|hash| hasher.finalize().as_slice() == hash)
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_counts_the_overlap_of_shared_pieces() {
+        // File from byte 100 to 350 with 100 byte pieces covers pieces 1 to 3
+        assert_eq!(bytes_in_pieces(100, 100, 250, &[1, 2, 3]), 250);
+        assert_eq!(bytes_in_pieces(100, 100, 250, &[3]), 50);
+        assert_eq!(bytes_in_pieces(100, 150, 100, &[1]), 50);
+        assert_eq!(bytes_in_pieces(100, 150, 100, &[2]), 50);
+        assert_eq!(bytes_in_pieces(100, 150, 100, &[]), 0);
+    }
+
+    #[test]
+    fn checked_pieces_count_unless_found_missing() {
+        let tracker = PieceTracker::default();
+        let never = NaiveDateTime::from_timestamp_opt(0, 0).unwrap();
+        let checked = NaiveDateTime::from_timestamp_opt(1_000, 0).unwrap();
+        assert!(!tracker.has_checked_piece(1, 0, never));
+        assert!(tracker.has_checked_piece(1, 0, checked));
+        tracker.missing.insert((1, 0), checked);
+        assert!(!tracker.has_checked_piece(1, 0, checked));
+        tracker.mark_verified(1, 0);
+        assert!(tracker.has_checked_piece(1, 0, never));
+    }
+}
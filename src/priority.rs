@@ -0,0 +1,317 @@
+use std::{collections::HashMap, sync::Arc};
+
+use async_graphql::{InputObject, SimpleObject};
+use dashmap::{mapref::entry::Entry, DashMap, DashSet};
+use serde::{Deserialize, Serialize};
+use transmission::{torrent::Priority, Torrent};
+
+use crate::{
+    context::TorrentHashes,
+    download_dirs::DownloadDirs,
+    pieces::{PieceTracker, TorrentLayout},
+    CONFIG_DIR,
+};
+
+/// Pieces of the current file checked each round in sequential mode, to bound hashing
+const SEQUENTIAL_WINDOW: u32 = 8;
+
+/// Raises files to high priority so their pieces are requested before any other.
+///
+/// libtransmission only takes priorities per file, a piece gets the highest one
+/// of the files it belongs to.
+pub fn raise_files(torrent: &Torrent, files: &[u32]) {
+    if !files.is_empty() {
+        torrent
+            .clone()
+            .set_files_priorities_by_id(files.to_vec(), Priority::High);
+    }
+}
+
+#[derive(
+    SimpleObject, InputObject, Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq,
+)]
+#[graphql(input_name = "PriorityModeInput")]
+pub struct PriorityMode {
+    /// Download files one after another, in the order of the torrent.
+    ///
+    /// Pieces inside a file keep libtransmission's order, so it changes nothing for
+    /// torrents with a single file.
+    #[graphql(default)]
+    pub sequential: bool,
+}
+
+impl PriorityMode {
+    fn is_enabled(&self) -> bool {
+        self.sequential
+    }
+
+    fn merge(&self, other: &PriorityMode) -> PriorityMode {
+        PriorityMode {
+            sequential: self.sequential || other.sequential,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+struct TorrentModes {
+    #[serde(skip)]
+    hash: String,
+    torrent: PriorityMode,
+    files: HashMap<u32, PriorityMode>,
+}
+
+/// Priority modes of torrents and their files, saved in the config directory
+#[derive(Default)]
+pub struct PriorityModes {
+    modes: DashMap<i32, TorrentModes>,
+    /// First piece not known to be downloaded, per torrent file
+    cursors: DashMap<(i32, u32), u32>,
+    /// Torrent files raised to high priority by the sequential mode
+    raised: DashSet<(i32, u32)>,
+    /// Streams reading each torrent file, which is kept at high priority while they run
+    streamed: DashMap<(i32, u32), usize>,
+}
+
+/// Keeps a streamed file at high priority until it is dropped.
+///
+/// libtransmission only takes priorities per file, so the whole file is raised
+/// rather than the pieces under the requested range.
+pub struct FileBoost {
+    modes: Arc<PriorityModes>,
+    torrent: Torrent,
+    file_index: u32,
+}
+
+impl Drop for FileBoost {
+    fn drop(&mut self) {
+        let key = (self.torrent.id(), self.file_index);
+        if let Entry::Occupied(mut streams) = self.modes.streamed.entry(key) {
+            *streams.get_mut() -= 1;
+            if *streams.get() == 0 {
+                streams.remove();
+                // The sequential mode may still need the file
+                if !self.modes.raised.contains(&key) {
+                    self.torrent
+                        .clone()
+                        .set_files_priorities_by_id(vec![self.file_index], Priority::Normal);
+                }
+            }
+        }
+    }
+}
+
+impl PriorityModes {
+    fn path() -> std::path::PathBuf {
+        std::path::Path::new(&CONFIG_DIR.clone()).join("priorities.json")
+    }
+
+    /// Loads saved modes for the given torrents, matched by info hash
+    pub async fn load(torrents: &DashMap<i32, Torrent>, hashes: &Arc<TorrentHashes>) -> Self {
+        let priority_modes = PriorityModes::default();
+        let saved = match tokio::fs::read(Self::path()).await {
+            Ok(saved) => serde_json::from_slice::<HashMap<String, TorrentModes>>(&saved),
+            Err(_) => return priority_modes,
+        };
+        match saved {
+            Ok(mut saved) => {
+                for torrent in torrents.iter() {
+                    let hash = hashes.hash(torrent.value());
+                    if let Some(mut modes) = saved.remove(&hash) {
+                        modes.hash = hash;
+                        priority_modes.modes.insert(*torrent.key(), modes);
+                    }
+                }
+            }
+            Err(err) => log::warn!("Cant read priority modes {:#?}", err),
+        }
+        priority_modes
+    }
+
+    async fn save(&self) {
+        let saved = self
+            .modes
+            .iter()
+            .map(|modes| (modes.hash.clone(), modes.value().clone()))
+            .collect::<HashMap<_, _>>();
+        match serde_json::to_vec_pretty(&saved) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save priority modes {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save priority modes {:#?}", err),
+        }
+    }
+
+    /// Mode set on the torrent, or on one of its files
+    pub fn mode(&self, torrent_id: i32, file_index: Option<u32>) -> PriorityMode {
+        self.modes
+            .get(&torrent_id)
+            .and_then(|modes| match file_index {
+                Some(file_index) => modes.files.get(&file_index).copied(),
+                None => Some(modes.torrent),
+            })
+            .unwrap_or_default()
+    }
+
+    fn effective_mode(&self, torrent_id: i32, file_index: u32) -> PriorityMode {
+        self.mode(torrent_id, None)
+            .merge(&self.mode(torrent_id, Some(file_index)))
+    }
+
+    pub async fn set_mode(
+        &self,
+        torrent: &Torrent,
+        file_index: Option<u32>,
+        mode: PriorityMode,
+    ) -> Result<(), String> {
+        let torrent_id = torrent.id();
+        let info = torrent.info();
+        if let Some(file_index) = file_index {
+            if file_index as usize >= info.files.len() {
+                return Err(format!("File {file_index} not found"));
+            }
+        }
+        {
+            let mut modes = self
+                .modes
+                .entry(torrent_id)
+                .or_insert_with(|| TorrentModes {
+                    hash: info.hash_string.clone(),
+                    ..Default::default()
+                });
+            match file_index {
+                Some(file_index) if mode.is_enabled() => {
+                    modes.files.insert(file_index, mode);
+                }
+                Some(file_index) => {
+                    modes.files.remove(&file_index);
+                }
+                None => modes.torrent = mode,
+            }
+        }
+        self.modes.remove_if(&torrent_id, |_, modes| {
+            !modes.torrent.is_enabled() && modes.files.is_empty()
+        });
+        self.cursors.retain(|(id, _), _| *id != torrent_id);
+        // Drop the old raises, the scheduler raises the file the new modes need again
+        let raised = self
+            .raised
+            .iter()
+            .filter(|raised| raised.0 == torrent_id)
+            .map(|raised| raised.1)
+            .collect::<Vec<_>>();
+        self.raised.retain(|(id, _)| *id != torrent_id);
+        let raised = raised
+            .into_iter()
+            .filter(|file_index| !self.streamed.contains_key(&(torrent_id, *file_index)))
+            .collect::<Vec<_>>();
+        if !raised.is_empty() {
+            torrent
+                .clone()
+                .set_files_priorities_by_id(raised, Priority::Normal);
+        }
+        self.save().await;
+        Ok(())
+    }
+
+    /// Raises the file while the returned boost is kept
+    pub fn boost_file(self: &Arc<Self>, torrent: &Torrent, file_index: u32) -> FileBoost {
+        let key = (torrent.id(), file_index);
+        let mut streams = self.streamed.entry(key).or_insert(0);
+        *streams += 1;
+        if *streams == 1 && !self.raised.contains(&key) {
+            raise_files(torrent, &[file_index]);
+        }
+        FileBoost {
+            modes: self.clone(),
+            torrent: torrent.clone(),
+            file_index,
+        }
+    }
+
+    pub async fn remove(&self, torrent_id: i32) {
+        if self.modes.remove(&torrent_id).is_some() {
+            self.save().await;
+        }
+        self.cursors.retain(|(id, _), _| *id != torrent_id);
+        self.raised.retain(|(id, _)| *id != torrent_id);
+    }
+
+    /// Raises the file that should be downloaded next under the torrent's modes
+    async fn apply(&self, dirs: &Arc<DownloadDirs>, torrent: &Torrent, tracker: &PieceTracker) {
+        let stats = torrent.stats();
+        if stats.percent_done >= 1.0 || stats.metadata_percent_complete < 1.0 {
+            return;
+        }
+        let torrent_id = torrent.id();
+        let info = torrent.info();
+        let layout = match TorrentLayout::load(dirs, torrent_id, &info).await {
+            Ok(layout) => Arc::new(layout),
+            Err(_) => return,
+        };
+        for (file_index, file) in info.files.iter().enumerate() {
+            let file_index = file_index as u32;
+            // Skip files which are not wanted or not downloaded in order
+            if file.dnd != 0 || !self.effective_mode(torrent_id, file_index).sequential {
+                continue;
+            }
+            let start = self
+                .cursors
+                .get(&(torrent_id, file_index))
+                .map(|cursor| *cursor)
+                .unwrap_or(file.first_piece);
+            let mut next = start;
+            while next <= file.last_piece
+                && next - start < SEQUENTIAL_WINDOW
+                && tracker
+                    .has_piece(torrent, &layout, next)
+                    .await
+                    .unwrap_or(false)
+            {
+                next += 1;
+            }
+            self.cursors.insert((torrent_id, file_index), next);
+            if next <= file.last_piece {
+                // The files before it are downloaded, this one goes next
+                if self.raised.insert((torrent_id, file_index)) {
+                    raise_files(torrent, &[file_index]);
+                }
+                break;
+            }
+        }
+    }
+}
+
+/// Keeps the next file of torrents with priority modes at high priority
+pub async fn priority_scheduler(
+    torrents: Arc<DashMap<i32, Torrent>>,
+    modes: Arc<PriorityModes>,
+    tracker: Arc<PieceTracker>,
+    dirs: Arc<DownloadDirs>,
+) {
+    loop {
+        tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+        let torrent_ids = modes.modes.iter().map(|m| *m.key()).collect::<Vec<_>>();
+        for torrent_id in torrent_ids {
+            let torrent = match torrents.get(&torrent_id) {
+                Some(torrent) => torrent.value().clone(),
+                None => continue,
+            };
+            modes.apply(&dirs, &torrent, &tracker).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_modes_add_to_the_torrent_mode() {
+        let sequential = PriorityMode { sequential: true };
+        assert!(PriorityMode::default().merge(&sequential).sequential);
+        assert!(sequential.merge(&PriorityMode::default()).sequential);
+        assert!(!PriorityMode::default().is_enabled());
+    }
+}
@@ -0,0 +1,885 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{Form, Multipart, Query},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use transmission::Torrent;
+
+use crate::{
+    auth,
+    context::SharedData,
+    new_id,
+    structures::{AddTorrentOptions, HttpHeader, TorrentState},
+    torrent_struc::{magnet_link, TorrentStats},
+    CONFIG_DIR, DOWNLOAD_DIR, INCOMPLETE_DIR, RENAME_PARTIAL_FILES,
+};
+
+const APP_VERSION: &str = "v4.3.9";
+const WEBAPI_VERSION: &str = "2.8.3";
+/// Sessions unused for this long are logged out, like qBittorrent's default
+const SESSION_TIMEOUT: Duration = Duration::from_secs(3600);
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Category {
+    name: String,
+    save_path: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedCategories {
+    categories: HashMap<String, Category>,
+    /// Category of each torrent by info hash
+    torrents: HashMap<String, String>,
+}
+
+/// State of the qBittorrent compatible API, categories are saved in the config directory
+#[derive(Default)]
+pub struct QbittorrentState {
+    /// Last use of each session
+    sessions: DashMap<String, Instant>,
+    categories: DashMap<String, Category>,
+    torrent_categories: DashMap<String, String>,
+    rid: AtomicI64,
+}
+
+impl QbittorrentState {
+    fn path() -> std::path::PathBuf {
+        std::path::Path::new(&CONFIG_DIR.clone()).join("qbittorrent.json")
+    }
+
+    pub async fn load() -> Self {
+        match tokio::fs::read(Self::path()).await {
+            Ok(saved) => Self::from_saved(&saved),
+            Err(_) => QbittorrentState::default(),
+        }
+    }
+
+    fn from_saved(saved: &[u8]) -> Self {
+        let state = QbittorrentState::default();
+        match serde_json::from_slice::<SavedCategories>(saved) {
+            Ok(saved) => {
+                state.categories.extend(saved.categories);
+                state.torrent_categories.extend(saved.torrents);
+            }
+            Err(err) => log::warn!("Cant read qbittorrent categories {:#?}", err),
+        }
+        state
+    }
+
+    fn saved(&self) -> SavedCategories {
+        SavedCategories {
+            categories: self
+                .categories
+                .iter()
+                .map(|category| (category.key().clone(), category.value().clone()))
+                .collect(),
+            torrents: self
+                .torrent_categories
+                .iter()
+                .map(|category| (category.key().clone(), category.value().clone()))
+                .collect(),
+        }
+    }
+
+    async fn save(&self) {
+        match serde_json::to_vec_pretty(&self.saved()) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save qbittorrent categories {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save qbittorrent categories {:#?}", err),
+        }
+    }
+
+    fn category_of(&self, hash: &str) -> String {
+        self.torrent_categories
+            .get(hash)
+            .map(|category| category.value().clone())
+            .unwrap_or_default()
+    }
+
+    fn start_session(&self) -> String {
+        self.sessions
+            .retain(|_, last_used| last_used.elapsed() < SESSION_TIMEOUT);
+        let sid = new_id();
+        self.sessions.insert(sid.clone(), Instant::now());
+        sid
+    }
+
+    /// Checks the `SID` cookie, any request is allowed if no credentials are configured
+    fn is_authorized(&self, headers: &HeaderMap) -> bool {
+        !auth::auth_required() || self.has_session(headers)
+    }
+
+    /// Whether the `SID` cookie names a session which didn't time out, which is kept alive
+    fn has_session(&self, headers: &HeaderMap) -> bool {
+        headers
+            .get_all("cookie")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().strip_prefix("SID="))
+            .any(|sid| match self.sessions.get_mut(sid) {
+                Some(mut last_used) if last_used.elapsed() < SESSION_TIMEOUT => {
+                    *last_used = Instant::now();
+                    true
+                }
+                _ => false,
+            })
+    }
+}
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/auth/login", post(login))
+        .route("/auth/logout", post(logout))
+        .route("/app/version", get(app_version))
+        .route("/app/webapiVersion", get(webapi_version))
+        .route("/app/preferences", get(preferences))
+        .route("/app/defaultSavePath", get(default_save_path))
+        .route("/torrents/info", get(torrents_info).post(torrents_info))
+        .route("/torrents/files", get(torrent_files).post(torrent_files))
+        .route("/torrents/add", post(torrents_add))
+        .route("/torrents/pause", post(torrents_pause))
+        .route("/torrents/resume", post(torrents_resume))
+        .route("/torrents/delete", post(torrents_delete))
+        .route("/torrents/categories", get(categories))
+        .route("/torrents/createCategory", post(create_category))
+        .route("/torrents/editCategory", post(create_category))
+        .route("/torrents/removeCategories", post(remove_categories))
+        .route("/torrents/setCategory", post(set_category))
+        .route("/sync/maindata", get(maindata))
+}
+
+fn forbidden() -> Response {
+    (StatusCode::FORBIDDEN, "Forbidden").into_response()
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    username: String,
+    password: String,
+}
+
+async fn login(
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    if !auth::check_credentials(&form.username, &form.password) {
+        return "Fails.".into_response();
+    }
+    let sid = state.start_session();
+    (
+        [("set-cookie", format!("SID={}; HttpOnly; path=/", sid))],
+        "Ok.",
+    )
+        .into_response()
+}
+
+async fn logout(
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+) -> Response {
+    headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().strip_prefix("SID="))
+        .for_each(|sid| {
+            state.sessions.remove(sid);
+        });
+    "Ok.".into_response()
+}
+
+async fn app_version() -> &'static str {
+    APP_VERSION
+}
+
+async fn webapi_version() -> &'static str {
+    WEBAPI_VERSION
+}
+
+async fn default_save_path() -> String {
+    DOWNLOAD_DIR.clone()
+}
+
+async fn preferences(
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    Json(json!({
+        "save_path": DOWNLOAD_DIR.clone(),
+        "temp_path_enabled": INCOMPLETE_DIR.is_some(),
+        "temp_path": INCOMPLETE_DIR.clone().unwrap_or_default(),
+        "incomplete_files_ext": *RENAME_PARTIAL_FILES,
+        "max_ratio_enabled": false,
+        "max_ratio": -1,
+        "max_seeding_time_enabled": false,
+        "max_seeding_time": -1,
+        "queueing_enabled": false,
+        "dht": true,
+    }))
+    .into_response()
+}
+
+/// Lowercase hashes of a `|` separated list, none when it includes `all`
+fn parse_hashes(hashes: &str) -> Option<Vec<String>> {
+    let hashes = hashes
+        .split('|')
+        .map(|hash| hash.trim().to_lowercase())
+        .collect::<Vec<_>>();
+    if hashes.iter().any(|hash| hash == "all") {
+        None
+    } else {
+        Some(hashes)
+    }
+}
+
+/// Selects torrents by a `|` separated list of hashes, or `all`
+fn select_torrents(data: &SharedData, hashes: &str) -> Vec<Torrent> {
+    let hashes = parse_hashes(hashes);
+    data.torrents
+        .iter()
+        .map(|torrent| torrent.value().clone())
+        .filter(|torrent| match &hashes {
+            Some(hashes) => hashes.contains(&data.torrent_hash(torrent)),
+            None => true,
+        })
+        .collect()
+}
+
+fn qbittorrent_state(stats: &TorrentStats) -> &'static str {
+    let done = stats.percent_done >= 1.0;
+    if stats.metadata_percent_complete < 1.0 {
+        return "metaDL";
+    }
+    match stats.state {
+        TorrentState::Downloading if stats.is_stalled => "stalledDL",
+        TorrentState::Downloading => "downloading",
+        TorrentState::DownloadingWait => "queuedDL",
+        TorrentState::Seeding if stats.is_stalled => "stalledUP",
+        TorrentState::Seeding => "uploading",
+        TorrentState::SeedingWait => "queuedUP",
+        TorrentState::Checking | TorrentState::CheckingWait if done => "checkingUP",
+        TorrentState::Checking | TorrentState::CheckingWait => "checkingDL",
+        TorrentState::Stopped if done => "pausedUP",
+        TorrentState::Stopped => "pausedDL",
+        TorrentState::Error => "error",
+    }
+}
+
+fn torrent_json(data: &SharedData, state: &QbittorrentState, torrent: &Torrent) -> Value {
+    let stats = TorrentStats::from(torrent.stats());
+    let info = torrent.info();
+    let mode = data.priority_modes.mode(torrent.id(), None);
+    let save_path = data.download_dirs.dir(torrent.id());
+    let content_path = save_path.join(&info.name);
+    json!({
+        "hash": info.hash_string,
+        "name": info.name,
+        "size": stats.size_when_done,
+        "total_size": info.total_size,
+        "progress": stats.percent_done,
+        "dlspeed": (stats.piece_download_speed_kbps * 1000.0) as i64,
+        "upspeed": (stats.piece_upload_speed_kbps * 1000.0) as i64,
+        "priority": stats.queue_position,
+        "num_seeds": stats.peers_sending_to_us,
+        "num_leechs": stats.peers_getting_from_us,
+        "ratio": stats.ratio.max(0.0),
+        "eta": if stats.eta < 0 { 8640000 } else { stats.eta },
+        "state": qbittorrent_state(&stats),
+        "category": state.category_of(&info.hash_string),
+        "tags": "",
+        "save_path": save_path.to_string_lossy(),
+        "content_path": content_path.to_string_lossy(),
+        "added_on": stats.added_date.timestamp(),
+        "completion_on": stats.done_date.timestamp(),
+        "amount_left": stats.left_until_done,
+        "completed": stats.size_when_done.saturating_sub(stats.left_until_done),
+        "downloaded": stats.downloaded_ever,
+        "uploaded": stats.uploaded_ever,
+        "seeding_time": stats.seconds_seeding,
+        "time_active": stats.seconds_downloading + stats.seconds_seeding,
+        "seq_dl": mode.sequential,
+        // libtransmission cant raise single pieces
+        "f_l_piece_prio": false,
+        "magnet_uri": magnet_link(&info),
+        "tracker": info.trackers.first().map(|tracker| tracker.announce.clone()).unwrap_or_default(),
+        "private": info.is_private,
+    })
+}
+
+#[derive(Deserialize)]
+struct InfoQuery {
+    filter: Option<String>,
+    category: Option<String>,
+    hashes: Option<String>,
+}
+
+fn matches_filter(filter: &str, stats: &TorrentStats) -> bool {
+    let done = stats.percent_done >= 1.0;
+    let stopped = stats.state == TorrentState::Stopped;
+    match filter {
+        "downloading" => !done && !stopped,
+        "seeding" => done && !stopped,
+        "completed" => done,
+        "paused" | "stopped" => stopped,
+        "active" => stats.piece_download_speed_kbps > 0.0 || stats.piece_upload_speed_kbps > 0.0,
+        "inactive" => {
+            stats.piece_download_speed_kbps <= 0.0 && stats.piece_upload_speed_kbps <= 0.0
+        }
+        "resumed" | "running" => !stopped,
+        "stalled" => stats.is_stalled,
+        "errored" => stats.state == TorrentState::Error,
+        _ => true,
+    }
+}
+
+async fn torrents_info(
+    Extension(data): Extension<SharedData>,
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+    Query(query): Query<InfoQuery>,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    let mut torrents = match &query.hashes {
+        Some(hashes) => select_torrents(&data, hashes),
+        None => select_torrents(&data, "all"),
+    };
+    torrents.sort_by_key(|torrent| torrent.id());
+    let torrents = torrents
+        .iter()
+        .filter(|torrent| match &query.filter {
+            Some(filter) => matches_filter(filter, &TorrentStats::from(torrent.stats())),
+            None => true,
+        })
+        .map(|torrent| torrent_json(&data, &state, torrent))
+        .filter(|torrent| match &query.category {
+            Some(category) => torrent["category"] == *category,
+            None => true,
+        })
+        .collect::<Vec<_>>();
+    Json(torrents).into_response()
+}
+
+#[derive(Deserialize)]
+struct HashQuery {
+    hash: String,
+}
+
+async fn torrent_files(
+    Extension(data): Extension<SharedData>,
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+    Query(query): Query<HashQuery>,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    let torrent = match select_torrents(&data, &query.hash).into_iter().next() {
+        Some(torrent) => torrent,
+        None => return (StatusCode::NOT_FOUND, "Torrent hash was not found").into_response(),
+    };
+    let stats = torrent.stats();
+    let info = torrent.info();
+    let completed = data.pieces.files_completed(&torrent, &info);
+    let files = info
+        .files
+        .iter()
+        .zip(completed)
+        .enumerate()
+        .map(|(index, (file, completed))| {
+            json!({
+                "index": index,
+                "name": file.name,
+                "size": file.length,
+                "progress": if file.length == 0 { 1.0 } else { completed as f64 / file.length as f64 },
+                "priority": if file.dnd == 0 { 1 } else { 0 },
+                "is_seed": stats.percent_done >= 1.0,
+                "piece_range": [file.first_piece, file.last_piece],
+                "availability": -1,
+            })
+        })
+        .collect::<Vec<_>>();
+    Json(files).into_response()
+}
+
+fn form_flag(value: &str) -> bool {
+    value.eq_ignore_ascii_case("true")
+}
+
+/// Options of an added torrent from the add form's fields, piece priorities like
+/// `firstLastPiecePrio` aren't supported and are ignored.
+///
+/// Without a save path torrents go to the save path of their category.
+fn add_options(fields: &Map<String, Value>, category_path: Option<String>) -> AddTorrentOptions {
+    let flag = |name: &str| {
+        fields
+            .get(name)
+            .and_then(Value::as_str)
+            .map(form_flag)
+            .unwrap_or(false)
+    };
+    AddTorrentOptions {
+        paused: flag("paused") || flag("stopped"),
+        sequential_download: flag("sequentialDownload"),
+        download_dir: fields
+            .get("savepath")
+            .and_then(Value::as_str)
+            .filter(|savepath| !savepath.is_empty())
+            .map(String::from)
+            .or(category_path),
+        start_at: None,
+    }
+}
+
+async fn torrents_add(
+    Extension(data): Extension<SharedData>,
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+    mut multipart: Multipart,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    let mut urls = vec![];
+    let mut metainfos = vec![];
+    let mut fields = Map::new();
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        match name.as_str() {
+            "torrents" => match field.bytes().await {
+                Ok(bytes) => metainfos.push(bytes),
+                Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+            },
+            _ => match field.text().await {
+                Ok(text) if name == "urls" => urls.extend(
+                    text.lines()
+                        .map(str::trim)
+                        .filter(|url| !url.is_empty())
+                        .map(String::from),
+                ),
+                Ok(text) => {
+                    fields.insert(name, Value::String(text));
+                }
+                Err(err) => return (StatusCode::BAD_REQUEST, err.to_string()).into_response(),
+            },
+        }
+    }
+    let category = fields
+        .get("category")
+        .and_then(Value::as_str)
+        .filter(|category| !category.is_empty())
+        .map(String::from);
+    let category_path = category
+        .as_ref()
+        .and_then(|category| state.categories.get(category))
+        .map(|category| category.save_path.clone())
+        .filter(|save_path| !save_path.is_empty());
+    let options = || add_options(&fields, category_path.clone());
+
+    let mut added = vec![];
+    let headers = fields
+        .get("cookie")
+        .and_then(Value::as_str)
+        .filter(|cookie| !cookie.is_empty())
+        .map(|cookie| HttpHeader {
+            name: "cookie".into(),
+            value: cookie.into(),
+        })
+        .into_iter()
+        .collect::<Vec<_>>();
+    for url in urls.iter() {
+        added.push(data.add_url(url, &headers, None, options()).await);
+    }
+    for metainfo in metainfos.iter() {
+        added.push(data.add_metainfo(metainfo, options()).await);
+    }
+
+    let mut failed = added.is_empty();
+    for result in added {
+        match result {
+            Ok(id) => {
+                if let (Some(category), Some(torrent)) = (&category, data.torrents.get(&id)) {
+                    state
+                        .torrent_categories
+                        .insert(data.torrent_hash(torrent.value()), category.clone());
+                }
+            }
+            Err(err) => {
+                log::warn!("qbittorrent add failed {}", err);
+                failed = true;
+            }
+        }
+    }
+    if category.is_some() {
+        state.save().await;
+    }
+    if failed {
+        "Fails.".into_response()
+    } else {
+        "Ok.".into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct HashesForm {
+    hashes: String,
+    #[serde(rename = "deleteFiles", default)]
+    delete_files: String,
+}
+
+async fn torrents_pause(
+    Extension(data): Extension<SharedData>,
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+    Form(form): Form<HashesForm>,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    for torrent in select_torrents(&data, &form.hashes) {
+        data.stop_torrent(&torrent);
+    }
+    StatusCode::OK.into_response()
+}
+
+async fn torrents_resume(
+    Extension(data): Extension<SharedData>,
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+    Form(form): Form<HashesForm>,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    for torrent in select_torrents(&data, &form.hashes) {
+        data.start_torrent(&torrent);
+    }
+    StatusCode::OK.into_response()
+}
+
+async fn torrents_delete(
+    Extension(data): Extension<SharedData>,
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+    Form(form): Form<HashesForm>,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    let delete_files = form_flag(&form.delete_files);
+    for torrent in select_torrents(&data, &form.hashes) {
+        state
+            .torrent_categories
+            .remove(&data.torrent_hash(&torrent));
+        data.remove_torrent(torrent.id(), delete_files).await;
+    }
+    state.save().await;
+    StatusCode::OK.into_response()
+}
+
+fn categories_json(state: &QbittorrentState) -> Value {
+    Value::Object(
+        state
+            .categories
+            .iter()
+            .map(|category| {
+                (
+                    category.key().clone(),
+                    json!({
+                        "name": category.name,
+                        "savePath": category.save_path,
+                    }),
+                )
+            })
+            .collect(),
+    )
+}
+
+async fn categories(
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    Json(categories_json(&state)).into_response()
+}
+
+#[derive(Deserialize)]
+struct CategoryForm {
+    category: String,
+    #[serde(rename = "savePath", default)]
+    save_path: String,
+}
+
+async fn create_category(
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+    Form(form): Form<CategoryForm>,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    if form.category.trim().is_empty() {
+        return (StatusCode::BAD_REQUEST, "Invalid category name").into_response();
+    }
+    state.categories.insert(
+        form.category.clone(),
+        Category {
+            name: form.category,
+            save_path: form.save_path,
+        },
+    );
+    state.save().await;
+    StatusCode::OK.into_response()
+}
+
+#[derive(Deserialize)]
+struct RemoveCategoriesForm {
+    categories: String,
+}
+
+async fn remove_categories(
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+    Form(form): Form<RemoveCategoriesForm>,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    for category in form.categories.lines().map(str::trim) {
+        state.categories.remove(category);
+        state
+            .torrent_categories
+            .retain(|_, torrent_category| torrent_category != category);
+    }
+    state.save().await;
+    StatusCode::OK.into_response()
+}
+
+#[derive(Deserialize)]
+struct SetCategoryForm {
+    hashes: String,
+    category: String,
+}
+
+async fn set_category(
+    Extension(data): Extension<SharedData>,
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+    Form(form): Form<SetCategoryForm>,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    if !form.category.is_empty() && !state.categories.contains_key(&form.category) {
+        return (StatusCode::CONFLICT, "Category does not exist").into_response();
+    }
+    for torrent in select_torrents(&data, &form.hashes) {
+        let hash = data.torrent_hash(&torrent);
+        if form.category.is_empty() {
+            state.torrent_categories.remove(&hash);
+        } else {
+            state.torrent_categories.insert(hash, form.category.clone());
+        }
+    }
+    state.save().await;
+    StatusCode::OK.into_response()
+}
+
+/// Always answers with a full update, which every client has to support
+async fn maindata(
+    Extension(data): Extension<SharedData>,
+    Extension(state): Extension<Arc<QbittorrentState>>,
+    headers: HeaderMap,
+) -> Response {
+    if !state.is_authorized(&headers) {
+        return forbidden();
+    }
+    let rid = state.rid.fetch_add(1, Ordering::Relaxed) + 1;
+    let mut torrents = Map::new();
+    let mut download_speed = 0;
+    let mut upload_speed = 0;
+    let mut downloaded = 0;
+    let mut uploaded = 0;
+    for torrent in select_torrents(&data, "all") {
+        let torrent = torrent_json(&data, &state, &torrent);
+        download_speed += torrent["dlspeed"].as_i64().unwrap_or_default();
+        upload_speed += torrent["upspeed"].as_i64().unwrap_or_default();
+        downloaded += torrent["downloaded"].as_u64().unwrap_or_default();
+        uploaded += torrent["uploaded"].as_u64().unwrap_or_default();
+        if let Some(hash) = torrent["hash"].as_str() {
+            torrents.insert(hash.to_string(), torrent.clone());
+        }
+    }
+    Json(json!({
+        "rid": rid,
+        "full_update": true,
+        "torrents": torrents,
+        "categories": categories_json(&state),
+        "server_state": {
+            "dl_info_speed": download_speed,
+            "up_info_speed": upload_speed,
+            "dl_info_data": downloaded,
+            "up_info_data": uploaded,
+            "connection_status": "connected",
+        },
+    }))
+    .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cookie(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", value.parse().unwrap());
+        headers
+    }
+
+    async fn log_in(state: &Arc<QbittorrentState>) -> String {
+        let form = LoginForm {
+            username: "admin".into(),
+            password: "adminadmin".into(),
+        };
+        let response = login(Extension(state.clone()), Form(form)).await;
+        let set_cookie = response.headers()["set-cookie"].to_str().unwrap();
+        let sid = set_cookie
+            .strip_prefix("SID=")
+            .and_then(|cookie| cookie.split(';').next())
+            .unwrap();
+        sid.to_string()
+    }
+
+    #[tokio::test]
+    async fn sessions_expire_and_log_out() {
+        let state = Arc::new(QbittorrentState::default());
+        let sid = log_in(&state).await;
+        assert!(state.has_session(&cookie(&format!("theme=dark; SID={}", sid))));
+        assert!(!state.has_session(&cookie("SID=unknown")));
+        assert!(!state.has_session(&HeaderMap::new()));
+
+        // Using a session keeps it alive
+        *state.sessions.get_mut(&sid).unwrap() = Instant::now() - SESSION_TIMEOUT / 2;
+        assert!(state.has_session(&cookie(&format!("SID={}", sid))));
+        assert!(state.sessions.get(&sid).unwrap().elapsed() < SESSION_TIMEOUT / 2);
+
+        *state.sessions.get_mut(&sid).unwrap() = Instant::now() - SESSION_TIMEOUT;
+        assert!(!state.has_session(&cookie(&format!("SID={}", sid))));
+        // Expired sessions are dropped at the next login
+        let other = log_in(&state).await;
+        assert!(!state.sessions.contains_key(&sid));
+
+        logout(Extension(state.clone()), cookie(&format!("SID={}", other))).await;
+        assert!(!state.has_session(&cookie(&format!("SID={}", other))));
+    }
+
+    #[test]
+    fn categories_are_restored() {
+        let state = QbittorrentState::default();
+        state.categories.insert(
+            "movies".into(),
+            Category {
+                name: "movies".into(),
+                save_path: "/downloads/movies".into(),
+            },
+        );
+        state
+            .torrent_categories
+            .insert("ab".repeat(20), "movies".into());
+        let saved = serde_json::to_vec(&state.saved()).unwrap();
+
+        let restored = QbittorrentState::from_saved(&saved);
+        assert_eq!(
+            categories_json(&restored),
+            json!({"movies": {"name": "movies", "savePath": "/downloads/movies"}})
+        );
+        assert_eq!(restored.category_of(&"ab".repeat(20)), "movies");
+        assert_eq!(restored.category_of(&"cd".repeat(20)), "");
+        assert!(QbittorrentState::from_saved(b"not json")
+            .categories
+            .is_empty());
+    }
+
+    #[test]
+    fn hashes_select_all_or_a_list() {
+        assert_eq!(parse_hashes("all"), None);
+        assert_eq!(parse_hashes("AB12|all"), None);
+        assert_eq!(
+            parse_hashes("AB12| cd34 "),
+            Some(vec!["ab12".to_string(), "cd34".to_string()])
+        );
+        assert_eq!(parse_hashes("ab12"), Some(vec!["ab12".to_string()]));
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Map<String, Value> {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), Value::String(value.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn add_form_sets_the_options() {
+        let options = add_options(
+            &fields(&[
+                ("sequentialDownload", "true"),
+                ("firstLastPiecePrio", "true"),
+                ("stopped", "True"),
+            ]),
+            None,
+        );
+        assert!(options.sequential_download);
+        assert!(options.paused);
+        assert_eq!(options.download_dir, None);
+
+        let options = add_options(
+            &fields(&[("firstLastPiecePrio", "true"), ("paused", "false")]),
+            None,
+        );
+        assert!(!options.sequential_download);
+        assert!(!options.paused);
+    }
+
+    #[test]
+    fn save_path_falls_back_to_the_category() {
+        let category_path = Some("/downloads/movies".to_string());
+        let options = add_options(
+            &fields(&[("savepath", "/downloads/new")]),
+            category_path.clone(),
+        );
+        assert_eq!(options.download_dir.as_deref(), Some("/downloads/new"));
+        let options = add_options(&fields(&[("savepath", "")]), category_path);
+        assert_eq!(options.download_dir.as_deref(), Some("/downloads/movies"));
+    }
+}
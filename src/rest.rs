@@ -0,0 +1,831 @@
+use std::future::Future;
+
+use axum::{
+    body::Body,
+    extract::{FromRequest, Multipart, Path, Query, RequestParts},
+    http::{header, HeaderMap, Request, StatusCode},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use transmission::Torrent;
+
+use crate::{
+    auth,
+    context::SharedData,
+    structures::{AddTorrentOptions, TorrentFile, TorrentState},
+    torrent_struc::{TorrentInfoSummary, TorrentStats},
+};
+
+type ApiResult<T> = Result<T, (StatusCode, String)>;
+
+/// Versioned REST API for clients that can't use GraphQL
+pub fn router() -> Router {
+    Router::new()
+        .route("/openapi.json", get(openapi))
+        .route("/torrents", get(list_torrents).post(add_torrents))
+        .route("/torrents/:id", get(get_torrent).delete(remove_torrent))
+        .route("/torrents/:id/files", get(torrent_files))
+        .route("/torrents/:id/start", post(start_torrent))
+        .route("/torrents/:id/stop", post(stop_torrent))
+}
+
+fn authorize(headers: &HeaderMap) -> ApiResult<()> {
+    if auth::check_basic_auth(headers) {
+        Ok(())
+    } else {
+        Err((StatusCode::UNAUTHORIZED, "Unauthorized".into()))
+    }
+}
+
+fn find_torrent(data: &SharedData, torrent_id: i32) -> ApiResult<Torrent> {
+    data.torrents
+        .get(&torrent_id)
+        .map(|torrent| torrent.value().clone())
+        .ok_or((StatusCode::NOT_FOUND, "Torrent not found".into()))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TorrentBody {
+    id: i32,
+    name: String,
+    state: TorrentState,
+    stats: TorrentStats,
+    info: TorrentInfoSummary,
+}
+
+impl From<&Torrent> for TorrentBody {
+    fn from(torrent: &Torrent) -> Self {
+        let stats = TorrentStats::from(torrent.stats());
+        Self {
+            id: torrent.id(),
+            name: torrent.name().into(),
+            state: stats.state,
+            stats,
+            info: (&torrent.info()).into(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct FileBody {
+    #[serde(flatten)]
+    file: TorrentFile,
+    download_link: Option<String>,
+    stream_link: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, rename_all = "camelCase")]
+struct AddTorrentBody {
+    magnet_link: Option<String>,
+    /// Base64 encoded contents of a .torrent file
+    metainfo: Option<String>,
+    #[serde(flatten)]
+    options: AddTorrentOptions,
+}
+
+/// Torrent of an add request
+enum NewTorrent {
+    Magnet(String),
+    Metainfo(Vec<u8>),
+}
+
+/// Torrents of an add request, magnet links first
+struct AddRequest {
+    torrents: Vec<NewTorrent>,
+    options: AddTorrentOptions,
+}
+
+#[derive(Serialize)]
+struct AddedBody {
+    ids: Vec<i32>,
+    errors: Vec<AddError>,
+}
+
+#[derive(Serialize)]
+struct AddError {
+    /// Position of the torrent in the request, magnet links come first
+    index: usize,
+    error: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RemoveQuery {
+    #[serde(default)]
+    delete_data: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct LinkQuery {
+    expiry_secs: Option<u64>,
+}
+
+async fn list_torrents(
+    Extension(data): Extension<SharedData>,
+    headers: HeaderMap,
+) -> ApiResult<Json<Vec<TorrentBody>>> {
+    authorize(&headers)?;
+    let mut torrents = data
+        .torrents
+        .iter()
+        .map(|torrent| TorrentBody::from(torrent.value()))
+        .collect::<Vec<_>>();
+    torrents.sort_by_key(|torrent| torrent.id);
+    Ok(Json(torrents))
+}
+
+async fn get_torrent(
+    Extension(data): Extension<SharedData>,
+    headers: HeaderMap,
+    Path(torrent_id): Path<i32>,
+) -> ApiResult<Json<TorrentBody>> {
+    authorize(&headers)?;
+    let torrent = find_torrent(&data, torrent_id)?;
+    Ok(Json(TorrentBody::from(&torrent)))
+}
+
+async fn torrent_files(
+    Extension(data): Extension<SharedData>,
+    headers: HeaderMap,
+    Path(torrent_id): Path<i32>,
+    Query(query): Query<LinkQuery>,
+) -> ApiResult<Json<Vec<FileBody>>> {
+    authorize(&headers)?;
+    let torrent = find_torrent(&data, torrent_id)?;
+    let mut files = vec![];
+    let info = torrent.info();
+    for (index, file) in info.files.iter().enumerate() {
+        let file = TorrentFile::new(torrent_id, &info.hash_string, index as u32, file);
+        files.push(FileBody {
+            download_link: file
+                .signed_download_link(&data.download_dirs, query.expiry_secs)
+                .await,
+            stream_link: file.signed_stream_link(query.expiry_secs),
+            file,
+        });
+    }
+    Ok(Json(files))
+}
+
+/// Boolean field of a multipart form
+fn form_flag(name: &str, text: &str) -> ApiResult<bool> {
+    match text.to_lowercase().as_str() {
+        "true" | "1" => Ok(true),
+        "false" | "0" | "" => Ok(false),
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid {}, expected true or false", name),
+        )),
+    }
+}
+
+/// Adds torrents from a JSON body, or from a multipart form with `.torrent` files
+async fn add_torrents(
+    Extension(data): Extension<SharedData>,
+    req: Request<Body>,
+) -> ApiResult<(StatusCode, Json<AddedBody>)> {
+    authorize(req.headers())?;
+    let request = read_add_request(req).await?;
+    add_all(request, |torrent, options| {
+        let data = data.clone();
+        async move {
+            match torrent {
+                NewTorrent::Magnet(magnet_link) => data.add_magnet(&magnet_link, options).await,
+                NewTorrent::Metainfo(metainfo) => data.add_metainfo(&metainfo, options).await,
+            }
+        }
+    })
+    .await
+}
+
+async fn read_add_request(req: Request<Body>) -> ApiResult<AddRequest> {
+    let is_multipart = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.starts_with("multipart/form-data"))
+        .unwrap_or(false);
+    let mut parts = RequestParts::new(req);
+    let mut magnet_links = vec![];
+    let mut metainfos = vec![];
+    let options;
+    if is_multipart {
+        let mut multipart = Multipart::from_request(&mut parts)
+            .await
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        let mut form = AddTorrentOptions::default();
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?
+        {
+            let name = field.name().unwrap_or_default().to_string();
+            if name == "torrent" {
+                let bytes = field
+                    .bytes()
+                    .await
+                    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+                metainfos.push(bytes.to_vec());
+                continue;
+            }
+            let text = field
+                .text()
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+            match name.as_str() {
+                "magnetLink" => magnet_links.push(text),
+                "paused" => form.paused = form_flag(&name, &text)?,
+                "sequentialDownload" => form.sequential_download = form_flag(&name, &text)?,
+                "downloadDir" => form.download_dir = Some(text),
+                "startAt" => {
+                    form.start_at = Some(text.parse().map_err(|err| {
+                        (StatusCode::BAD_REQUEST, format!("Invalid startAt {}", err))
+                    })?)
+                }
+                _ => return Err((StatusCode::BAD_REQUEST, format!("Unknown field {}", name))),
+            }
+        }
+        options = form;
+    } else {
+        let Json(body) = Json::<AddTorrentBody>::from_request(&mut parts)
+            .await
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        magnet_links.extend(body.magnet_link);
+        if let Some(metainfo) = body.metainfo {
+            metainfos.push(
+                base64::decode(metainfo)
+                    .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?,
+            );
+        }
+        options = body.options;
+    }
+    if magnet_links.is_empty() && metainfos.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "No torrent given".into()));
+    }
+    let torrents = magnet_links
+        .into_iter()
+        .map(NewTorrent::Magnet)
+        .chain(metainfos.into_iter().map(NewTorrent::Metainfo))
+        .collect();
+    Ok(AddRequest { torrents, options })
+}
+
+/// Every torrent is tried so the client learns the ids of the ones that
+/// were added even when a later one fails
+async fn add_all<F, Fut>(request: AddRequest, add: F) -> ApiResult<(StatusCode, Json<AddedBody>)>
+where
+    F: Fn(NewTorrent, AddTorrentOptions) -> Fut,
+    Fut: Future<Output = Result<i32, String>>,
+{
+    let mut ids = vec![];
+    let mut errors = vec![];
+    for (index, torrent) in request.torrents.into_iter().enumerate() {
+        match add(torrent, request.options.clone()).await {
+            Ok(id) => ids.push(id),
+            Err(error) => errors.push(AddError { index, error }),
+        }
+    }
+    if ids.is_empty() {
+        let errors = errors
+            .into_iter()
+            .map(|error| error.error)
+            .collect::<Vec<_>>();
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, errors.join(", ")));
+    }
+    Ok((StatusCode::CREATED, Json(AddedBody { ids, errors })))
+}
+
+async fn start_torrent(
+    Extension(data): Extension<SharedData>,
+    headers: HeaderMap,
+    Path(torrent_id): Path<i32>,
+) -> ApiResult<StatusCode> {
+    authorize(&headers)?;
+    data.start_torrent(&find_torrent(&data, torrent_id)?);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn stop_torrent(
+    Extension(data): Extension<SharedData>,
+    headers: HeaderMap,
+    Path(torrent_id): Path<i32>,
+) -> ApiResult<StatusCode> {
+    authorize(&headers)?;
+    data.stop_torrent(&find_torrent(&data, torrent_id)?);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn remove_torrent(
+    Extension(data): Extension<SharedData>,
+    headers: HeaderMap,
+    Path(torrent_id): Path<i32>,
+    Query(query): Query<RemoveQuery>,
+) -> ApiResult<StatusCode> {
+    authorize(&headers)?;
+    if data.remove_torrent(torrent_id, query.delete_data).await {
+        Ok(StatusCode::NO_CONTENT)
+    } else {
+        Err((StatusCode::NOT_FOUND, "Torrent not found".into()))
+    }
+}
+
+async fn openapi() -> Json<Value> {
+    Json(openapi_document())
+}
+
+fn openapi_document() -> Value {
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "torexpo",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": "REST API of torexpo. Uses HTTP basic auth when credentials are configured.",
+        },
+        "servers": [{ "url": "/api/v1" }],
+        "components": {
+            "securitySchemes": {
+                "basicAuth": { "type": "http", "scheme": "basic" },
+            },
+            "schemas": openapi_schemas(),
+        },
+        "security": [{ "basicAuth": [] }],
+        "paths": openapi_paths(),
+    })
+}
+
+/// Schemas of the JSON bodies, matching the serialization of `torrent_struc`
+fn openapi_schemas() -> Value {
+    let torrent_state = json!({
+        "type": "string",
+        "enum": [
+            "Downloading", "DownloadingWait", "Seeding", "SeedingWait",
+            "Stopped", "Checking", "CheckingWait", "Error",
+        ],
+    });
+    let torrent_stats = json!({
+        "type": "object",
+        "properties": {
+            "id": { "type": "integer" },
+            "state": { "$ref": "#/components/schemas/TorrentState" },
+            "error": { "type": "string" },
+            "errorString": { "type": "string" },
+            "percentComplete": { "type": "number" },
+            "percentDone": { "type": "number" },
+            "pieceDownloadSpeedKbps": { "type": "number" },
+            "pieceUploadSpeedKbps": { "type": "number" },
+            "eta": { "type": "integer" },
+            "peersConnected": { "type": "integer" },
+            "sizeWhenDone": { "type": "integer", "format": "int64" },
+            "leftUntilDone": { "type": "integer", "format": "int64" },
+            "uploadedEver": { "type": "integer", "format": "int64" },
+            "downloadedEver": { "type": "integer", "format": "int64" },
+            "ratio": { "type": "number" },
+            "addedDate": { "type": "string", "format": "date-time" },
+            "doneDate": { "type": "string", "format": "date-time" },
+            "finished": { "type": "boolean" },
+            "isStalled": { "type": "boolean" },
+        },
+        "additionalProperties": true,
+    });
+    let tracker_info = json!({
+        "type": "object",
+        "properties": {
+            "tier": { "type": "integer" },
+            "announce": { "type": "string" },
+            "scrape": { "type": "string" },
+            "id": { "type": "integer" },
+        },
+    });
+    let torrent_info = json!({
+        "type": "object",
+        "properties": {
+            "name": { "type": "string" },
+            "totalSize": { "type": "integer", "format": "int64" },
+            "hashHex": { "type": "string" },
+            "magnetLink": { "type": "string" },
+            "comment": { "type": "string" },
+            "creator": { "type": "string" },
+            "dateCreated": { "type": "string", "format": "date-time" },
+            "fileCount": { "type": "integer" },
+            "pieceSize": { "type": "integer" },
+            "pieceCount": { "type": "integer" },
+            "isPrivate": { "type": "boolean" },
+            "isFolder": { "type": "boolean" },
+            "webseeds": { "type": "array", "items": { "type": "string" } },
+            "trackers": {
+                "type": "array",
+                "items": { "$ref": "#/components/schemas/TrackerInfo" },
+            },
+        },
+    });
+    let torrent = json!({
+        "type": "object",
+        "properties": {
+            "id": { "type": "integer", "format": "int32" },
+            "name": { "type": "string" },
+            "state": { "$ref": "#/components/schemas/TorrentState" },
+            "stats": { "$ref": "#/components/schemas/TorrentStats" },
+            "info": { "$ref": "#/components/schemas/TorrentInfo" },
+        },
+    });
+    let torrent_file = json!({
+        "type": "object",
+        "properties": {
+            "index": { "type": "integer" },
+            "length": { "type": "integer", "format": "int64" },
+            "name": { "type": "string" },
+            "dnd": { "type": "integer" },
+            "isRenamed": { "type": "boolean" },
+            "firstPiece": { "type": "integer" },
+            "lastPiece": { "type": "integer" },
+            "offset": { "type": "integer", "format": "int64" },
+            "downloadLink": { "type": "string", "nullable": true },
+            "streamLink": { "type": "string", "nullable": true },
+        },
+    });
+    let add_torrent_options = json!({
+        "type": "object",
+        "properties": {
+            "paused": { "type": "boolean", "default": false },
+            "sequentialDownload": { "type": "boolean", "default": false },
+            "downloadDir": { "type": "string" },
+            "startAt": { "type": "string", "format": "date-time" },
+        },
+    });
+    let add_torrent = json!({
+        "allOf": [
+            { "$ref": "#/components/schemas/AddTorrentOptions" },
+            {
+                "type": "object",
+                "properties": {
+                    "magnetLink": { "type": "string" },
+                    "metainfo": {
+                        "type": "string",
+                        "format": "byte",
+                        "description": "Base64 encoded .torrent file",
+                    },
+                },
+            },
+        ],
+    });
+    let add_torrent_form = json!({
+        "allOf": [
+            { "$ref": "#/components/schemas/AddTorrentOptions" },
+            {
+                "type": "object",
+                "properties": {
+                    "magnetLink": { "type": "string" },
+                    "torrent": {
+                        "type": "array",
+                        "items": { "type": "string", "format": "binary" },
+                    },
+                },
+            },
+        ],
+    });
+    let added = json!({
+        "type": "object",
+        "properties": {
+            "ids": { "type": "array", "items": { "type": "integer", "format": "int32" } },
+            "errors": {
+                "type": "array",
+                "description": "Torrents that could not be added, magnet links are indexed first",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "index": { "type": "integer" },
+                        "error": { "type": "string" },
+                    },
+                },
+            },
+        },
+    });
+    json!({
+        "TorrentState": torrent_state,
+        "TorrentStats": torrent_stats,
+        "TrackerInfo": tracker_info,
+        "TorrentInfo": torrent_info,
+        "Torrent": torrent,
+        "TorrentFile": torrent_file,
+        "AddTorrentOptions": add_torrent_options,
+        "AddTorrent": add_torrent,
+        "AddTorrentForm": add_torrent_form,
+        "Added": added,
+    })
+}
+
+fn openapi_paths() -> Value {
+    let torrent_id = json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "schema": { "type": "integer", "format": "int32" },
+    });
+    let error = json!({
+        "description": "Error message",
+        "content": { "text/plain": { "schema": { "type": "string" } } },
+    });
+    let done = json!({ "description": "Done" });
+    let json_body = |description: &str, schema: Value| {
+        json!({
+            "description": description,
+            "content": { "application/json": { "schema": schema } },
+        })
+    };
+    let torrents = json!({
+        "get": {
+            "summary": "List torrents",
+            "operationId": "listTorrents",
+            "responses": {
+                "200": json_body("Torrents", json!({
+                    "type": "array",
+                    "items": { "$ref": "#/components/schemas/Torrent" },
+                })),
+                "401": error,
+            },
+        },
+        "post": {
+            "summary": "Add torrents from magnet links or .torrent files",
+            "operationId": "addTorrents",
+            "requestBody": {
+                "required": true,
+                "content": {
+                    "application/json": {
+                        "schema": { "$ref": "#/components/schemas/AddTorrent" },
+                    },
+                    "multipart/form-data": {
+                        "schema": { "$ref": "#/components/schemas/AddTorrentForm" },
+                    },
+                },
+            },
+            "responses": {
+                "201": json_body(
+                    "Ids of the added torrents and errors of the ones that failed",
+                    json!({ "$ref": "#/components/schemas/Added" }),
+                ),
+                "400": error,
+                "401": error,
+                "422": error,
+            },
+        },
+    });
+    let torrent = json!({
+        "parameters": [torrent_id],
+        "get": {
+            "summary": "Get a torrent",
+            "operationId": "getTorrent",
+            "responses": {
+                "200": json_body("Torrent", json!({ "$ref": "#/components/schemas/Torrent" })),
+                "401": error,
+                "404": error,
+            },
+        },
+        "delete": {
+            "summary": "Remove a torrent",
+            "operationId": "removeTorrent",
+            "parameters": [{
+                "name": "deleteData",
+                "in": "query",
+                "schema": { "type": "boolean", "default": false },
+            }],
+            "responses": { "204": done, "401": error, "404": error },
+        },
+    });
+    let files = json!({
+        "parameters": [torrent_id],
+        "get": {
+            "summary": "List files of a torrent with signed links",
+            "operationId": "torrentFiles",
+            "parameters": [{
+                "name": "expirySecs",
+                "in": "query",
+                "schema": { "type": "integer", "format": "int64" },
+            }],
+            "responses": {
+                "200": json_body("Files", json!({
+                    "type": "array",
+                    "items": { "$ref": "#/components/schemas/TorrentFile" },
+                })),
+                "401": error,
+                "404": error,
+            },
+        },
+    });
+    let action = |summary: &str, operation_id: &str| {
+        json!({
+            "parameters": [torrent_id],
+            "post": {
+                "summary": summary,
+                "operationId": operation_id,
+                "responses": { "204": done, "401": error, "404": error },
+            },
+        })
+    };
+    json!({
+        "/torrents": torrents,
+        "/torrents/{id}": torrent,
+        "/torrents/{id}/files": files,
+        "/torrents/{id}/start": action("Start a torrent", "startTorrent"),
+        "/torrents/{id}/stop": action("Stop a torrent", "stopTorrent"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_request(body: &str) -> Request<Body> {
+        Request::builder()
+            .method("POST")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    fn form_request(fields: &[(&str, &str)]) -> Request<Body> {
+        let mut body = String::new();
+        for (name, value) in fields {
+            body.push_str(&format!(
+                "--boundary\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                name, value
+            ));
+        }
+        body.push_str("--boundary--\r\n");
+        Request::builder()
+            .method("POST")
+            .header(
+                header::CONTENT_TYPE,
+                "multipart/form-data; boundary=boundary",
+            )
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn status_of(req: Request<Body>) -> Option<StatusCode> {
+        read_add_request(req).await.err().map(|(status, _)| status)
+    }
+
+    #[tokio::test]
+    async fn bad_add_requests_are_refused() {
+        let bad_request = Some(StatusCode::BAD_REQUEST);
+        assert_eq!(status_of(json_request("{")).await, bad_request);
+        assert_eq!(status_of(json_request("{}")).await, bad_request);
+        assert_eq!(
+            status_of(json_request(r#"{"metainfo": "not base64!"}"#)).await,
+            bad_request
+        );
+        assert_eq!(
+            status_of(json_request(
+                r#"{"magnetLink": "magnet:?xt=a", "paused": "yes"}"#
+            ))
+            .await,
+            bad_request
+        );
+        assert_eq!(
+            status_of(form_request(&[
+                ("magnetLink", "magnet:?xt=a"),
+                ("paused", "maybe")
+            ]))
+            .await,
+            bad_request
+        );
+        assert_eq!(
+            status_of(form_request(&[
+                ("magnetLink", "magnet:?xt=a"),
+                ("startAt", "tomorrow")
+            ]))
+            .await,
+            bad_request
+        );
+        assert_eq!(
+            status_of(form_request(&[
+                ("magnetLink", "magnet:?xt=a"),
+                ("label", "tv")
+            ]))
+            .await,
+            bad_request
+        );
+        assert_eq!(
+            status_of(form_request(&[("paused", "true")])).await,
+            bad_request
+        );
+    }
+
+    #[tokio::test]
+    async fn add_requests_are_read() {
+        let request = read_add_request(form_request(&[
+            ("magnetLink", "magnet:?xt=a"),
+            ("torrent", "metainfo"),
+            ("magnetLink", "magnet:?xt=b"),
+            ("paused", "1"),
+            ("downloadDir", "/downloads/tv"),
+        ]))
+        .await
+        .unwrap();
+        assert!(request.options.paused);
+        assert_eq!(
+            request.options.download_dir.as_deref(),
+            Some("/downloads/tv")
+        );
+        let torrents = request
+            .torrents
+            .iter()
+            .map(|torrent| match torrent {
+                NewTorrent::Magnet(link) => link.clone(),
+                NewTorrent::Metainfo(metainfo) => String::from_utf8(metainfo.clone()).unwrap(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(torrents, vec!["magnet:?xt=a", "magnet:?xt=b", "metainfo"]);
+    }
+
+    fn add_magnets(links: &[&str]) -> AddRequest {
+        AddRequest {
+            torrents: links
+                .iter()
+                .map(|link| NewTorrent::Magnet(link.to_string()))
+                .collect(),
+            options: AddTorrentOptions::default(),
+        }
+    }
+
+    /// Adds magnet links, failing the ones containing `bad`
+    async fn add_magnet(torrent: NewTorrent, _options: AddTorrentOptions) -> Result<i32, String> {
+        match torrent {
+            NewTorrent::Magnet(link) if link.contains("bad") => Err(format!("Invalid {}", link)),
+            NewTorrent::Magnet(link) => Ok(link.len() as i32),
+            NewTorrent::Metainfo(_) => Err("Invalid metainfo".into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn partial_adds_list_the_errors() {
+        let request = add_magnets(&["magnet:?a", "magnet:?bad", "magnet:?ccc"]);
+        let (status, Json(added)) = add_all(request, add_magnet).await.unwrap();
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(
+            serde_json::to_value(&added).unwrap(),
+            json!({
+                "ids": [9, 11],
+                "errors": [{ "index": 1, "error": "Invalid magnet:?bad" }],
+            })
+        );
+
+        let request = add_magnets(&["magnet:?bad", "magnet:?bad2"]);
+        let (status, error) = add_all(request, add_magnet).await.err().unwrap();
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error, "Invalid magnet:?bad, Invalid magnet:?bad2");
+    }
+
+    #[test]
+    fn openapi_lists_the_routes() {
+        let document = serde_json::to_string(&openapi_document()).unwrap();
+        let document = serde_json::from_str::<Value>(&document).unwrap();
+        let paths = document["paths"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                "/torrents",
+                "/torrents/{id}",
+                "/torrents/{id}/files",
+                "/torrents/{id}/start",
+                "/torrents/{id}/stop",
+            ]
+        );
+
+        // Every reference names a schema of the document
+        fn refs(value: &Value, found: &mut Vec<String>) {
+            match value {
+                Value::Object(object) => {
+                    if let Some(Value::String(reference)) = object.get("$ref") {
+                        found.push(reference.clone());
+                    }
+                    object.values().for_each(|value| refs(value, found));
+                }
+                Value::Array(array) => array.iter().for_each(|value| refs(value, found)),
+                _ => {}
+            }
+        }
+        let mut found = vec![];
+        refs(&document, &mut found);
+        assert!(!found.is_empty());
+        for reference in found {
+            let name = reference.strip_prefix("#/components/schemas/").unwrap();
+            assert!(
+                document["components"]["schemas"].get(name).is_some(),
+                "{}",
+                name
+            );
+        }
+    }
+}
@@ -0,0 +1,478 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use async_graphql::{Enum, InputObject, SimpleObject};
+use chrono::{NaiveDateTime, Utc};
+use dashmap::DashMap;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use transmission::Torrent;
+
+use crate::{context::SharedData, library::tracker_hosts, new_id, CONFIG_DIR};
+
+/// How often retention rules are enforced
+const CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RetentionRule {
+    pub id: String,
+    pub name: String,
+    /// Host of one of the torrent's trackers, matching its subdomains too
+    pub tracker_host: Option<String>,
+    /// Regex matched against the torrent's name
+    pub name_regex: Option<String>,
+    /// Remove torrents finished this many days ago
+    pub max_age_days: Option<u32>,
+    /// Remove torrents once they reach this ratio
+    pub min_ratio: Option<f64>,
+    /// Remove the oldest finished torrents while the matching torrents take more bytes
+    pub max_total_size: Option<u64>,
+    /// Delete the data of removed torrents too
+    pub delete_data: bool,
+    pub enabled: bool,
+}
+
+#[derive(InputObject)]
+pub struct RetentionRuleInput {
+    pub name: String,
+    pub tracker_host: Option<String>,
+    pub name_regex: Option<String>,
+    pub max_age_days: Option<u32>,
+    pub min_ratio: Option<f64>,
+    pub max_total_size: Option<u64>,
+    #[graphql(default)]
+    pub delete_data: bool,
+    #[graphql(default = true)]
+    pub enabled: bool,
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Debug)]
+pub enum RetentionReason {
+    /// Finished longer ago than the rule's age
+    Age,
+    /// Reached the rule's ratio
+    Ratio,
+    /// Matching torrents take more than the rule's size cap
+    TotalSize,
+}
+
+/// A torrent a retention rule removes
+#[derive(SimpleObject, Clone)]
+pub struct RetentionCandidate {
+    pub torrent_id: i32,
+    pub torrent_name: String,
+    pub rule_id: String,
+    pub rule_name: String,
+    pub reason: RetentionReason,
+    /// Bytes of data the torrent has
+    pub size: u64,
+    pub done_date: NaiveDateTime,
+    pub delete_data: bool,
+}
+
+/// What the rules look at of a torrent
+struct TorrentFacts {
+    id: i32,
+    name: String,
+    tracker_hosts: Vec<String>,
+    finished: bool,
+    size: u64,
+    ratio: f64,
+    done_date: NaiveDateTime,
+}
+
+impl TorrentFacts {
+    fn new(torrent: &Torrent, tracker_hosts: Vec<String>) -> Self {
+        let stats = torrent.stats();
+        Self {
+            id: torrent.id(),
+            name: torrent.name().into(),
+            tracker_hosts,
+            finished: stats.percent_done >= 1.0 && stats.done_date.timestamp() > 0,
+            size: stats.size_when_done.saturating_sub(stats.left_until_done),
+            ratio: stats.ratio as f64,
+            done_date: stats.done_date,
+        }
+    }
+}
+
+impl RetentionRule {
+    fn new(id: String, input: RetentionRuleInput) -> Result<Self, String> {
+        if let Some(name_regex) = &input.name_regex {
+            Regex::new(name_regex).map_err(|err| format!("Invalid name regex {}", err))?;
+        }
+        if input.max_age_days.is_none()
+            && input.min_ratio.is_none()
+            && input.max_total_size.is_none()
+        {
+            return Err("Retention rule needs an age, ratio or size limit".into());
+        }
+        Ok(Self {
+            id,
+            name: input.name,
+            tracker_host: input.tracker_host,
+            name_regex: input.name_regex,
+            max_age_days: input.max_age_days,
+            min_ratio: input.min_ratio,
+            max_total_size: input.max_total_size,
+            delete_data: input.delete_data,
+            enabled: input.enabled,
+        })
+    }
+
+    /// Whether the torrent falls under the rule, rules without a tracker or name are global
+    fn matches(&self, torrent: &TorrentFacts) -> bool {
+        if let Some(host) = &self.tracker_host {
+            let host = host.to_lowercase();
+            let matched = torrent
+                .tracker_hosts
+                .iter()
+                .any(|tracker| *tracker == host || tracker.ends_with(&format!(".{}", host)));
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(name_regex) = &self.name_regex {
+            let matched = Regex::new(name_regex)
+                .map(|name_regex| name_regex.is_match(&torrent.name))
+                .unwrap_or(false);
+            if !matched {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn candidate(&self, torrent: &TorrentFacts, reason: RetentionReason) -> RetentionCandidate {
+        RetentionCandidate {
+            torrent_id: torrent.id,
+            torrent_name: torrent.name.clone(),
+            rule_id: self.id.clone(),
+            rule_name: self.name.clone(),
+            reason,
+            size: torrent.size,
+            done_date: torrent.done_date,
+            delete_data: self.delete_data,
+        }
+    }
+}
+
+/// Rules removing finished torrents, each torrent is removed by the first rule selecting it
+#[derive(Default)]
+pub struct RetentionManager {
+    rules: Mutex<Vec<RetentionRule>>,
+    /// Tracker hosts of each torrent, read once as they come from the torrent's info
+    tracker_hosts: DashMap<i32, Vec<String>>,
+}
+
+impl RetentionManager {
+    fn path() -> PathBuf {
+        Path::new(&CONFIG_DIR.clone()).join("retention.json")
+    }
+
+    pub async fn load() -> Self {
+        let manager = RetentionManager::default();
+        if let Ok(saved) = tokio::fs::read(Self::path()).await {
+            match serde_json::from_slice::<Vec<RetentionRule>>(&saved) {
+                Ok(rules) => *manager.rules.lock().unwrap() = rules,
+                Err(err) => log::warn!("Cant read retention rules {:#?}", err),
+            }
+        }
+        manager
+    }
+
+    async fn save(&self) {
+        match serde_json::to_vec_pretty(&self.rules()) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save retention rules {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save retention rules {:#?}", err),
+        }
+    }
+
+    pub fn rules(&self) -> Vec<RetentionRule> {
+        self.rules.lock().unwrap().clone()
+    }
+
+    /// Adds the rule after the existing ones
+    pub async fn add_rule(&self, input: RetentionRuleInput) -> Result<RetentionRule, String> {
+        let id = new_id();
+        let rule = RetentionRule::new(id, input)?;
+        self.rules.lock().unwrap().push(rule.clone());
+        self.save().await;
+        Ok(rule)
+    }
+
+    pub async fn update_rule(
+        &self,
+        id: &str,
+        input: RetentionRuleInput,
+    ) -> Result<RetentionRule, String> {
+        let rule = {
+            let mut rules = self.rules.lock().unwrap();
+            let rule = rules
+                .iter_mut()
+                .find(|rule| rule.id == id)
+                .ok_or("Retention rule not found")?;
+            *rule = RetentionRule::new(rule.id.clone(), input)?;
+            rule.clone()
+        };
+        self.save().await;
+        Ok(rule)
+    }
+
+    pub async fn remove_rule(&self, id: &str) -> bool {
+        let removed = {
+            let mut rules = self.rules.lock().unwrap();
+            let count = rules.len();
+            rules.retain(|rule| rule.id != id);
+            rules.len() != count
+        };
+        if removed {
+            self.save().await;
+        }
+        removed
+    }
+
+    /// Torrents the enabled rules would remove now
+    pub fn candidates(&self, torrents: &DashMap<i32, Torrent>) -> Vec<RetentionCandidate> {
+        let rules = self
+            .rules()
+            .into_iter()
+            .filter(|rule| rule.enabled)
+            .collect::<Vec<_>>();
+        if rules.is_empty() {
+            return vec![];
+        }
+        let by_tracker = rules.iter().any(|rule| rule.tracker_host.is_some());
+        self.tracker_hosts
+            .retain(|torrent_id, _| torrents.contains_key(torrent_id));
+        let facts = torrents
+            .iter()
+            .map(|torrent| {
+                let torrent = torrent.value();
+                let tracker_hosts = if by_tracker {
+                    self.tracker_hosts
+                        .entry(torrent.id())
+                        .or_insert_with(|| tracker_hosts(torrent))
+                        .clone()
+                } else {
+                    vec![]
+                };
+                TorrentFacts::new(torrent, tracker_hosts)
+            })
+            .collect::<Vec<_>>();
+        self.candidates_at(&facts, Utc::now().naive_utc())
+    }
+
+    fn candidates_at(&self, facts: &[TorrentFacts], now: NaiveDateTime) -> Vec<RetentionCandidate> {
+        let mut selected = HashSet::new();
+        let mut candidates = vec![];
+        for rule in self.rules().into_iter().filter(|rule| rule.enabled) {
+            let mut matching = facts
+                .iter()
+                .filter(|torrent| !selected.contains(&torrent.id) && rule.matches(torrent))
+                .collect::<Vec<_>>();
+            let mut select = |torrent: &TorrentFacts, reason| {
+                selected.insert(torrent.id);
+                candidates.push(rule.candidate(torrent, reason));
+            };
+            matching.retain(|torrent| {
+                if !torrent.finished {
+                    return true;
+                }
+                let too_old = rule
+                    .max_age_days
+                    .map(|days| now - torrent.done_date >= chrono::Duration::days(days as i64))
+                    .unwrap_or(false);
+                if too_old {
+                    select(torrent, RetentionReason::Age);
+                    return false;
+                }
+                let ratio_reached = rule
+                    .min_ratio
+                    .map(|ratio| torrent.ratio >= ratio)
+                    .unwrap_or(false);
+                if ratio_reached {
+                    select(torrent, RetentionReason::Ratio);
+                    return false;
+                }
+                true
+            });
+            if let Some(max_total_size) = rule.max_total_size {
+                let mut total = matching.iter().map(|torrent| torrent.size).sum::<u64>();
+                matching.retain(|torrent| torrent.finished);
+                matching.sort_by_key(|torrent| torrent.done_date);
+                for torrent in matching {
+                    if total <= max_total_size {
+                        break;
+                    }
+                    total -= torrent.size;
+                    select(torrent, RetentionReason::TotalSize);
+                }
+            }
+        }
+        candidates
+    }
+
+    /// Removes the torrents the rules select, returning them
+    pub async fn enforce(&self, data: &SharedData) -> Vec<RetentionCandidate> {
+        let candidates = self.candidates(&data.torrents);
+        for candidate in candidates.iter() {
+            log::info!(
+                "Retention rule {} removing {}",
+                candidate.rule_name,
+                candidate.torrent_name
+            );
+            data.remove_torrent(candidate.torrent_id, candidate.delete_data)
+                .await;
+        }
+        candidates
+    }
+}
+
+/// Periodically removes the torrents selected by retention rules
+pub async fn retention_enforcer(data: SharedData) {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        data.retention.enforce(&data).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> NaiveDateTime {
+        NaiveDateTime::parse_from_str("2022-10-04 12:00:00", "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn facts(id: i32, host: &str, size: u64, ratio: f64, days_ago: Option<i64>) -> TorrentFacts {
+        TorrentFacts {
+            id,
+            name: format!("Torrent {}", id),
+            tracker_hosts: vec![host.to_string()],
+            finished: days_ago.is_some(),
+            size,
+            ratio,
+            done_date: now() - chrono::Duration::days(days_ago.unwrap_or_default()),
+        }
+    }
+
+    fn manager(rules: Vec<RetentionRuleInput>) -> RetentionManager {
+        let rules = rules
+            .into_iter()
+            .enumerate()
+            .map(|(index, input)| RetentionRule::new(index.to_string(), input).unwrap())
+            .collect();
+        RetentionManager {
+            rules: Mutex::new(rules),
+            ..Default::default()
+        }
+    }
+
+    fn input() -> RetentionRuleInput {
+        RetentionRuleInput {
+            name: "rule".into(),
+            tracker_host: None,
+            name_regex: None,
+            max_age_days: None,
+            min_ratio: None,
+            max_total_size: None,
+            delete_data: false,
+            enabled: true,
+        }
+    }
+
+    fn selected(candidates: &[RetentionCandidate]) -> Vec<(i32, String, RetentionReason)> {
+        candidates
+            .iter()
+            .map(|candidate| {
+                (
+                    candidate.torrent_id,
+                    candidate.rule_id.clone(),
+                    candidate.reason,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rule_needs_a_limit() {
+        assert!(RetentionRule::new("0".into(), input()).is_err());
+    }
+
+    #[test]
+    fn selects_old_and_seeded_torrents() {
+        let manager = manager(vec![RetentionRuleInput {
+            max_age_days: Some(7),
+            min_ratio: Some(2.0),
+            ..input()
+        }]);
+        let facts = [
+            facts(1, "tracker.example", 100, 0.5, Some(10)),
+            facts(2, "tracker.example", 100, 0.5, Some(2)),
+            facts(3, "tracker.example", 100, 2.5, Some(1)),
+            facts(4, "tracker.example", 100, 3.0, None),
+        ];
+        let candidates = manager.candidates_at(&facts, now());
+        assert_eq!(
+            selected(&candidates),
+            vec![
+                (1, "0".to_string(), RetentionReason::Age),
+                (3, "0".to_string(), RetentionReason::Ratio),
+            ]
+        );
+    }
+
+    #[test]
+    fn total_size_removes_oldest_finished_first() {
+        let manager = manager(vec![RetentionRuleInput {
+            max_total_size: Some(250),
+            ..input()
+        }]);
+        let facts = [
+            facts(1, "tracker.example", 100, 0.0, Some(3)),
+            facts(2, "tracker.example", 100, 0.0, Some(5)),
+            facts(3, "tracker.example", 100, 0.0, None),
+        ];
+        let candidates = manager.candidates_at(&facts, now());
+        assert_eq!(
+            selected(&candidates),
+            vec![(2, "0".to_string(), RetentionReason::TotalSize)]
+        );
+    }
+
+    #[test]
+    fn first_matching_rule_selects_a_torrent() {
+        let manager = manager(vec![
+            RetentionRuleInput {
+                tracker_host: Some("Tracker.example".into()),
+                max_age_days: Some(1),
+                ..input()
+            },
+            RetentionRuleInput {
+                max_age_days: Some(1),
+                ..input()
+            },
+        ]);
+        let facts = [
+            facts(1, "announce.tracker.example", 100, 0.0, Some(3)),
+            facts(2, "othertracker.example", 100, 0.0, Some(3)),
+        ];
+        let candidates = manager.candidates_at(&facts, now());
+        assert_eq!(
+            selected(&candidates),
+            vec![
+                (1, "0".to_string(), RetentionReason::Age),
+                (2, "1".to_string(), RetentionReason::Age),
+            ]
+        );
+    }
+}
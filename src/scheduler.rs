@@ -0,0 +1,232 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use async_graphql::SimpleObject;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use transmission::Torrent;
+
+use crate::{
+    context::{SharedData, TorrentHashes},
+    new_id, CONFIG_DIR,
+};
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduledJob {
+    pub id: String,
+    /// Id of the torrent in this session, torrents are found by hash after a restart
+    #[serde(skip)]
+    pub torrent_id: i32,
+    pub torrent_name: String,
+    pub hash: String,
+    /// When the torrent is started
+    pub at: DateTime<Utc>,
+    pub created: DateTime<Utc>,
+}
+
+/// Torrents waiting to be started at a given time
+#[derive(Default)]
+pub struct Scheduler {
+    jobs: Mutex<Vec<ScheduledJob>>,
+    hashes: Arc<TorrentHashes>,
+}
+
+impl Scheduler {
+    fn path() -> PathBuf {
+        Path::new(&CONFIG_DIR.clone()).join("schedule.json")
+    }
+
+    /// Loads the jobs of the given torrents, stopping them until their jobs are due
+    pub async fn load(torrents: &DashMap<i32, Torrent>, hashes: &Arc<TorrentHashes>) -> Self {
+        let scheduler = Scheduler {
+            hashes: hashes.clone(),
+            ..Default::default()
+        };
+        let saved = match tokio::fs::read(Self::path()).await {
+            Ok(saved) => serde_json::from_slice::<Vec<ScheduledJob>>(&saved),
+            Err(_) => return scheduler,
+        };
+        match saved {
+            Ok(saved) => {
+                let mut jobs = scheduler.jobs.lock().unwrap();
+                for mut job in saved {
+                    let torrent = torrents
+                        .iter()
+                        .find(|torrent| hashes.hash(torrent.value()) == job.hash);
+                    if let Some(torrent) = torrent {
+                        // The client resumes torrents it was running before the restart
+                        torrent.value().stop();
+                        job.torrent_id = *torrent.key();
+                        jobs.push(job);
+                    }
+                }
+            }
+            Err(err) => log::warn!("Cant read scheduled jobs {:#?}", err),
+        }
+        scheduler
+    }
+
+    async fn save(&self) {
+        match serde_json::to_vec_pretty(&self.jobs(None)) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save scheduled jobs {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save scheduled jobs {:#?}", err),
+        }
+    }
+
+    /// Jobs of the torrent, or all jobs, soonest first
+    pub fn jobs(&self, torrent_id: Option<i32>) -> Vec<ScheduledJob> {
+        let mut jobs = self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|job| torrent_id.map(|id| job.torrent_id == id).unwrap_or(true))
+            .cloned()
+            .collect::<Vec<_>>();
+        jobs.sort_by_key(|job| job.at);
+        jobs
+    }
+
+    /// Stops the torrent and starts it at the given time, replacing its earlier job
+    pub async fn schedule_start(&self, torrent: &Torrent, at: DateTime<Utc>) -> ScheduledJob {
+        torrent.stop();
+        let job = ScheduledJob {
+            id: new_id(),
+            torrent_id: torrent.id(),
+            torrent_name: torrent.name().into(),
+            hash: self.hashes.hash(torrent),
+            at,
+            created: Utc::now(),
+        };
+        {
+            let mut jobs = self.jobs.lock().unwrap();
+            jobs.retain(|scheduled| scheduled.torrent_id != job.torrent_id);
+            jobs.push(job.clone());
+        }
+        self.save().await;
+        job
+    }
+
+    pub async fn cancel(&self, id: &str) -> bool {
+        let removed = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let count = jobs.len();
+            jobs.retain(|job| job.id != id);
+            jobs.len() != count
+        };
+        if removed {
+            self.save().await;
+        }
+        removed
+    }
+
+    /// Drops the jobs of a removed torrent
+    pub async fn forget(&self, torrent_id: i32) {
+        let removed = {
+            let mut jobs = self.jobs.lock().unwrap();
+            let count = jobs.len();
+            jobs.retain(|job| job.torrent_id != torrent_id);
+            jobs.len() != count
+        };
+        if removed {
+            self.save().await;
+        }
+    }
+
+    /// Takes the jobs whose time has come
+    fn take_due(&self) -> Vec<ScheduledJob> {
+        self.take_due_at(Utc::now())
+    }
+
+    fn take_due_at(&self, now: DateTime<Utc>) -> Vec<ScheduledJob> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let (due, waiting) = jobs.drain(..).partition(|job| job.at <= now);
+        *jobs = waiting;
+        due
+    }
+}
+
+/// Starts torrents when their scheduled time comes
+pub async fn scheduled_starter(data: SharedData) {
+    loop {
+        tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+        let due = data.scheduler.take_due();
+        if due.is_empty() {
+            continue;
+        }
+        for job in due.iter() {
+            if let Some(torrent) = data.torrents.get(&job.torrent_id) {
+                log::info!("Starting scheduled torrent {}", job.torrent_name);
+                torrent.value().start();
+            }
+        }
+        data.scheduler.save().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+
+    use super::*;
+
+    fn job(torrent_id: i32, at: DateTime<Utc>) -> ScheduledJob {
+        ScheduledJob {
+            id: format!("job-{}", torrent_id),
+            torrent_id,
+            torrent_name: format!("Torrent {}", torrent_id),
+            hash: String::new(),
+            at,
+            created: at - Duration::hours(1),
+        }
+    }
+
+    fn scheduler(jobs: Vec<ScheduledJob>) -> Scheduler {
+        Scheduler {
+            jobs: Mutex::new(jobs),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_due_jobs_are_taken() {
+        let now = Utc.timestamp_opt(1664884800, 0).unwrap();
+        let scheduler = scheduler(vec![
+            job(1, now + Duration::minutes(5)),
+            job(2, now),
+            job(3, now - Duration::minutes(5)),
+        ]);
+        let due = scheduler.take_due_at(now);
+        let due = due.iter().map(|job| job.torrent_id).collect::<Vec<_>>();
+        assert_eq!(due, vec![2, 3]);
+        let waiting = scheduler.jobs(None);
+        assert_eq!(waiting.len(), 1);
+        assert_eq!(waiting[0].torrent_id, 1);
+        assert!(scheduler.take_due_at(now).is_empty());
+    }
+
+    #[test]
+    fn jobs_are_listed_soonest_first() {
+        let now = Utc.timestamp_opt(1664884800, 0).unwrap();
+        let scheduler = scheduler(vec![
+            job(1, now + Duration::hours(2)),
+            job(2, now + Duration::hours(1)),
+        ]);
+        let ids = scheduler
+            .jobs(None)
+            .iter()
+            .map(|job| job.torrent_id)
+            .collect::<Vec<_>>();
+        assert_eq!(ids, vec![2, 1]);
+        assert_eq!(scheduler.jobs(Some(1)).len(), 1);
+        assert!(scheduler.jobs(Some(3)).is_empty());
+    }
+}
@@ -0,0 +1,558 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    process::Stdio,
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_graphql::{ComplexObject, Enum, InputObject, SimpleObject};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use futures_util::{stream::FuturesUnordered, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    process::Command,
+    sync::Semaphore,
+};
+
+use crate::{
+    context::SharedData,
+    download_dirs::DownloadDirs,
+    events::{TorrentEvent, TorrentEventKind},
+    new_id, CONFIG_DIR,
+};
+
+lazy_static::lazy_static! {
+    /// Only executables in this folder can be configured as scripts
+    static ref SCRIPT_DIR: PathBuf = std::env::var("TOREXPO_SCRIPT_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| std::path::Path::new(&CONFIG_DIR.clone()).join("scripts"));
+    /// Scripts allowed to run at the same time, others wait for their turn
+    static ref SCRIPT_CONCURRENCY: usize = std::env::var("TOREXPO_SCRIPT_CONCURRENCY")
+        .ok()
+        .and_then(|concurrency| concurrency.parse().ok())
+        .unwrap_or(2)
+        .max(1);
+    static ref PERMITS: Semaphore = Semaphore::new(*SCRIPT_CONCURRENCY);
+}
+
+/// Runs kept, oldest are forgotten first
+const MAX_RUNS: usize = 1000;
+/// Bytes of stdout and stderr kept for each run
+const MAX_OUTPUT: usize = 64 * 1024;
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum ScriptEvent {
+    Completed,
+    Removed,
+}
+
+impl ScriptEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Completed => "completed",
+            Self::Removed => "removed",
+        }
+    }
+}
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Script {
+    pub id: String,
+    pub name: String,
+    /// File name of the executable in the script folder
+    pub command: String,
+    pub args: Vec<String>,
+    /// Events the script runs on, all of them when empty
+    pub events: Vec<ScriptEvent>,
+    /// The script is killed after running this long
+    pub timeout_secs: u64,
+    pub enabled: bool,
+}
+
+#[derive(InputObject)]
+pub struct ScriptInput {
+    pub name: String,
+    pub command: String,
+    #[graphql(default)]
+    pub args: Vec<String>,
+    #[graphql(default)]
+    pub events: Vec<ScriptEvent>,
+    #[graphql(default = 300)]
+    pub timeout_secs: u64,
+    #[graphql(default = true)]
+    pub enabled: bool,
+}
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[graphql(complex)]
+#[serde(rename_all = "camelCase")]
+pub struct ScriptRun {
+    pub id: String,
+    pub script_id: String,
+    pub script_name: String,
+    pub event: ScriptEvent,
+    pub torrent_id: i32,
+    pub torrent_name: String,
+    pub torrent_hash: String,
+    pub started: DateTime<Utc>,
+    pub finished: Option<DateTime<Utc>>,
+    /// None when the script was killed or couldn't start
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    /// Why the script couldn't run
+    pub error: Option<String>,
+}
+
+#[ComplexObject]
+impl ScriptRun {
+    async fn stdout(&self) -> String {
+        ScriptOutput::load(&self.id).await.stdout
+    }
+
+    async fn stderr(&self) -> String {
+        ScriptOutput::load(&self.id).await.stderr
+    }
+}
+
+/// Output of a run, saved in its own file so the runs are cheap to save
+#[derive(Serialize, Deserialize, Default)]
+struct ScriptOutput {
+    stdout: String,
+    stderr: String,
+}
+
+impl ScriptOutput {
+    fn path(run_id: &str) -> PathBuf {
+        std::path::Path::new(&CONFIG_DIR.clone())
+            .join("script_runs")
+            .join(format!("{}.json", run_id))
+    }
+
+    async fn load(run_id: &str) -> Self {
+        match tokio::fs::read(Self::path(run_id)).await {
+            Ok(saved) => serde_json::from_slice(&saved).unwrap_or_default(),
+            Err(_) => Self::default(),
+        }
+    }
+
+    async fn save(&self, run_id: &str) {
+        let path = Self::path(run_id);
+        if let Some(parent) = path.parent() {
+            if let Err(err) = tokio::fs::create_dir_all(parent).await {
+                log::warn!("Cant create script output dir {:#?}", err);
+            }
+        }
+        match serde_json::to_vec(self) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(path, saved).await {
+                    log::warn!("Cant save script output {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save script output {:#?}", err),
+        }
+    }
+}
+
+/// Details of a torrent passed to scripts, kept so they are known once it is removed
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ScriptTorrent {
+    id: i32,
+    name: String,
+    hash: String,
+    download_dir: String,
+    files: Vec<ScriptFile>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ScriptFile {
+    name: String,
+    path: String,
+    length: u64,
+}
+
+impl ScriptTorrent {
+    fn new(dirs: &DownloadDirs, torrent: &transmission::Torrent) -> Self {
+        let info = torrent.info();
+        let download_dir = dirs.dir(torrent.id());
+        Self {
+            id: torrent.id(),
+            name: torrent.name().into(),
+            hash: info.hash_string.clone(),
+            download_dir: download_dir.to_string_lossy().into(),
+            files: info
+                .files
+                .iter()
+                .map(|file| ScriptFile {
+                    name: file.name.clone(),
+                    path: download_dir.join(&file.name).to_string_lossy().into(),
+                    length: file.length,
+                })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ScriptInputJson<'a> {
+    event: ScriptEvent,
+    torrent: &'a ScriptTorrent,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedScripts {
+    scripts: Vec<Script>,
+    runs: VecDeque<ScriptRun>,
+}
+
+/// Configured scripts and the output of their runs, saved in the config directory
+#[derive(Default)]
+pub struct ScriptManager {
+    scripts: DashMap<String, Script>,
+    runs: Mutex<VecDeque<ScriptRun>>,
+}
+
+/// Reads the output of a script to its end, keeping only the first bytes
+async fn read_capped(output: Option<impl AsyncRead + Unpin>) -> std::io::Result<String> {
+    let mut kept = vec![];
+    if let Some(mut output) = output {
+        (&mut output)
+            .take(MAX_OUTPUT as u64)
+            .read_to_end(&mut kept)
+            .await?;
+        // The rest is drained so the script doesn't block on a full pipe
+        tokio::io::copy(&mut output, &mut tokio::io::sink()).await?;
+    }
+    Ok(String::from_utf8_lossy(&kept).into_owned())
+}
+
+/// Checks the command names an executable in the script folder
+fn validate_command(command: &str) -> Result<(), String> {
+    if command.is_empty()
+        || command.contains('/')
+        || command.contains('\\')
+        || command.starts_with('.')
+    {
+        return Err("Command must be the file name of a script in the script folder".into());
+    }
+    Ok(())
+}
+
+impl ScriptManager {
+    fn path() -> PathBuf {
+        std::path::Path::new(&CONFIG_DIR.clone()).join("scripts.json")
+    }
+
+    pub async fn load() -> Self {
+        let manager = ScriptManager::default();
+        if let Ok(saved) = tokio::fs::read(Self::path()).await {
+            match serde_json::from_slice::<SavedScripts>(&saved) {
+                Ok(saved) => {
+                    for script in saved.scripts {
+                        manager.scripts.insert(script.id.clone(), script);
+                    }
+                    *manager.runs.lock().unwrap() = saved.runs;
+                }
+                Err(err) => log::warn!("Cant read scripts {:#?}", err),
+            }
+        }
+        manager
+    }
+
+    async fn save(&self) {
+        let saved = serde_json::to_vec_pretty(&SavedScripts {
+            scripts: self.scripts(),
+            runs: self.runs.lock().unwrap().clone(),
+        });
+        match saved {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save scripts {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save scripts {:#?}", err),
+        }
+    }
+
+    pub fn scripts(&self) -> Vec<Script> {
+        let mut scripts = self
+            .scripts
+            .iter()
+            .map(|script| script.value().clone())
+            .collect::<Vec<_>>();
+        scripts.sort_by(|a, b| a.name.cmp(&b.name));
+        scripts
+    }
+
+    /// Runs of the torrent's scripts, or of all scripts, latest first
+    pub fn runs(&self, torrent_id: Option<i32>, limit: usize) -> Vec<ScriptRun> {
+        self.runs
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|run| torrent_id.map(|id| run.torrent_id == id).unwrap_or(true))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+
+    pub async fn add_script(&self, input: ScriptInput) -> Result<Script, String> {
+        validate_command(&input.command)?;
+        let script = Script {
+            id: new_id(),
+            name: input.name,
+            command: input.command,
+            args: input.args,
+            events: input.events,
+            timeout_secs: input.timeout_secs,
+            enabled: input.enabled,
+        };
+        self.scripts.insert(script.id.clone(), script.clone());
+        self.save().await;
+        Ok(script)
+    }
+
+    pub async fn update_script(&self, id: &str, input: ScriptInput) -> Result<Script, String> {
+        validate_command(&input.command)?;
+        let script = {
+            let mut script = self.scripts.get_mut(id).ok_or("Script not found")?;
+            script.name = input.name;
+            script.command = input.command;
+            script.args = input.args;
+            script.events = input.events;
+            script.timeout_secs = input.timeout_secs;
+            script.enabled = input.enabled;
+            script.clone()
+        };
+        self.save().await;
+        Ok(script)
+    }
+
+    pub async fn remove_script(&self, id: &str) -> bool {
+        if self.scripts.remove(id).is_some() {
+            self.save().await;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Runs the scripts of a completed torrent and waits for them
+    pub async fn run_completed(&self, dirs: &DownloadDirs, torrent: &transmission::Torrent) {
+        self.run_event(ScriptEvent::Completed, ScriptTorrent::new(dirs, torrent))
+            .await;
+    }
+
+    /// Runs the enabled scripts wanting the event, a few at a time
+    async fn run_event(&self, event: ScriptEvent, torrent: ScriptTorrent) {
+        let runs = self
+            .scripts()
+            .into_iter()
+            .filter(|script| {
+                script.enabled && (script.events.is_empty() || script.events.contains(&event))
+            })
+            .map(|script| {
+                let torrent = torrent.clone();
+                async move {
+                    let _permit = PERMITS.acquire().await;
+                    let (run, output) = self.run(script, event, torrent).await;
+                    log::info!(
+                        "Script {} for {} exited with {:?}",
+                        run.script_name,
+                        run.torrent_name,
+                        run.exit_code
+                    );
+                    self.record(run, output).await;
+                }
+            });
+        futures_util::future::join_all(runs).await;
+    }
+
+    /// Runs the script, passing the torrent as env vars and JSON on stdin
+    async fn run(
+        &self,
+        script: Script,
+        event: ScriptEvent,
+        torrent: ScriptTorrent,
+    ) -> (ScriptRun, ScriptOutput) {
+        let mut run = ScriptRun {
+            id: new_id(),
+            script_id: script.id.clone(),
+            script_name: script.name.clone(),
+            event,
+            torrent_id: torrent.id,
+            torrent_name: torrent.name.clone(),
+            torrent_hash: torrent.hash.clone(),
+            started: Utc::now(),
+            finished: None,
+            exit_code: None,
+            timed_out: false,
+            error: None,
+        };
+        let mut output = ScriptOutput::default();
+        let input = serde_json::to_vec(&ScriptInputJson {
+            event,
+            torrent: &torrent,
+        })
+        .unwrap_or_default();
+        let files = torrent
+            .files
+            .iter()
+            .map(|file| file.path.as_str())
+            .collect::<Vec<_>>()
+            .join("\n");
+        let child = Command::new(SCRIPT_DIR.join(&script.command))
+            .args(&script.args)
+            .current_dir(&*SCRIPT_DIR)
+            .env("TOREXPO_EVENT", event.name())
+            .env("TOREXPO_TORRENT_ID", torrent.id.to_string())
+            .env("TOREXPO_TORRENT_NAME", &torrent.name)
+            .env("TOREXPO_TORRENT_HASH", &torrent.hash)
+            .env("TOREXPO_TORRENT_DIR", &torrent.download_dir)
+            .env("TOREXPO_TORRENT_FILES", files)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            Err(err) => {
+                run.error = Some(format!("Cant start script {}", err));
+                run.finished = Some(Utc::now());
+                return (run, output);
+            }
+        };
+        let stdin = child.stdin.take();
+        let write = async move {
+            if let Some(mut stdin) = stdin {
+                // Scripts that don't read stdin close it early
+                let _ = stdin.write_all(&input).await;
+            }
+        };
+        let (stdout, stderr) = (child.stdout.take(), child.stderr.take());
+        // Written while waiting so a script not reading stdin can't block on a full pipe
+        let finished = async {
+            let ((), stdout, stderr, status) = tokio::join!(
+                write,
+                read_capped(stdout),
+                read_capped(stderr),
+                child.wait()
+            );
+            Ok::<_, std::io::Error>((status?, stdout?, stderr?))
+        };
+        let timeout = Duration::from_secs(script.timeout_secs);
+        match tokio::time::timeout(timeout, finished).await {
+            Ok(Ok((status, stdout, stderr))) => {
+                run.exit_code = status.code();
+                output = ScriptOutput { stdout, stderr };
+            }
+            Ok(Err(err)) => run.error = Some(format!("Cant wait for script {}", err)),
+            // The child is killed when dropped
+            Err(_) => run.timed_out = true,
+        }
+        run.finished = Some(Utc::now());
+        (run, output)
+    }
+
+    async fn record(&self, run: ScriptRun, output: ScriptOutput) {
+        output.save(&run.id).await;
+        let forgotten = {
+            let mut runs = self.runs.lock().unwrap();
+            runs.push_back(run);
+            let excess = runs.len().saturating_sub(MAX_RUNS);
+            runs.drain(..excess).collect::<Vec<_>>()
+        };
+        for run in forgotten {
+            let _ = tokio::fs::remove_file(ScriptOutput::path(&run.id)).await;
+        }
+        self.save().await;
+    }
+}
+
+/// Runs the scripts of removed torrents, completed ones are run by the completion pipeline
+pub async fn script_runner(data: SharedData) {
+    let mut known = data
+        .torrents
+        .iter()
+        .map(|torrent| {
+            (
+                *torrent.key(),
+                ScriptTorrent::new(&data.download_dirs, torrent.value()),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let events = data.events.subscribe(None);
+    futures_util::pin_mut!(events);
+    let mut running = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            event = events.next() => match event {
+                Some(event) => {
+                    if let Some(torrent) = removed_torrent(&data, &mut known, &event) {
+                        running.push(data.scripts.run_event(ScriptEvent::Removed, torrent));
+                    }
+                }
+                None => break,
+            },
+            Some(()) = running.next(), if !running.is_empty() => {}
+        }
+    }
+}
+
+/// Keeps the details of torrents up to date and returns those of a removed torrent
+fn removed_torrent(
+    data: &SharedData,
+    known: &mut HashMap<i32, ScriptTorrent>,
+    event: &TorrentEvent,
+) -> Option<ScriptTorrent> {
+    if event.kind == TorrentEventKind::Removed {
+        return known.remove(&event.torrent_id);
+    }
+    let torrent = data.torrents.get(&event.torrent_id)?;
+    known.insert(
+        event.torrent_id,
+        ScriptTorrent::new(&data.download_dirs, torrent.value()),
+    );
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_is_a_script_file_name() {
+        assert!(validate_command("notify.sh").is_ok());
+        assert!(validate_command("move-done").is_ok());
+    }
+
+    #[test]
+    fn command_cant_leave_the_script_folder() {
+        for command in [
+            "",
+            "../notify.sh",
+            "/bin/sh",
+            "scripts/notify.sh",
+            "..\\notify.bat",
+            ".hidden",
+        ] {
+            assert!(validate_command(command).is_err(), "{}", command);
+        }
+    }
+
+    #[tokio::test]
+    async fn output_is_capped_and_drained() {
+        let output = vec![b'a'; MAX_OUTPUT * 2];
+        let kept = read_capped(Some(output.as_slice())).await.unwrap();
+        assert_eq!(kept.len(), MAX_OUTPUT);
+        assert_eq!(read_capped(None::<&[u8]>).await.unwrap(), "");
+    }
+}
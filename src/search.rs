@@ -0,0 +1,441 @@
+use std::{collections::HashMap, time::Duration};
+
+use async_graphql::{InputObject, SimpleObject};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use quick_xml::events::{BytesStart, Event};
+use serde::{Deserialize, Serialize};
+
+use crate::{new_id, CONFIG_DIR};
+
+const SEARCH_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// A Torznab compatible indexer, like a Jackett or Prowlarr endpoint
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Indexer {
+    pub id: String,
+    pub name: String,
+    /// Torznab api url, without query parameters
+    pub url: String,
+    #[graphql(skip)]
+    pub api_key: String,
+    pub enabled: bool,
+}
+
+#[derive(InputObject)]
+pub struct IndexerInput {
+    pub name: String,
+    pub url: String,
+    #[graphql(default)]
+    pub api_key: String,
+    #[graphql(default = true)]
+    pub enabled: bool,
+}
+
+#[derive(SimpleObject, Clone)]
+pub struct SearchResult {
+    pub title: String,
+    /// Size in bytes
+    pub size: u64,
+    pub seeders: u32,
+    pub peers: u32,
+    /// Info hash as lowercase hex, if the indexer gave one
+    pub info_hash: Option<String>,
+    /// Magnet link or .torrent url, accepted by `addMagnetLink` or `addTorrentUrl`
+    pub link: String,
+    pub categories: Vec<i32>,
+    pub publish_date: Option<DateTime<Utc>>,
+    /// Names of the indexers that returned the result
+    pub indexers: Vec<String>,
+}
+
+/// Configured indexers, saved in the config directory
+#[derive(Default)]
+pub struct IndexerManager {
+    indexers: DashMap<String, Indexer>,
+}
+
+impl IndexerManager {
+    fn path() -> std::path::PathBuf {
+        std::path::Path::new(&CONFIG_DIR.clone()).join("indexers.json")
+    }
+
+    pub async fn load() -> Self {
+        let indexers = IndexerManager::default();
+        if let Ok(saved) = tokio::fs::read(Self::path()).await {
+            match serde_json::from_slice::<Vec<Indexer>>(&saved) {
+                Ok(saved) => {
+                    for indexer in saved {
+                        indexers.indexers.insert(indexer.id.clone(), indexer);
+                    }
+                }
+                Err(err) => log::warn!("Cant read indexers {:#?}", err),
+            }
+        }
+        indexers
+    }
+
+    async fn save(&self) {
+        match serde_json::to_vec_pretty(&self.indexers()) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save indexers {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save indexers {:#?}", err),
+        }
+    }
+
+    pub fn indexers(&self) -> Vec<Indexer> {
+        let mut indexers = self
+            .indexers
+            .iter()
+            .map(|indexer| indexer.value().clone())
+            .collect::<Vec<_>>();
+        indexers.sort_by(|a, b| a.name.cmp(&b.name));
+        indexers
+    }
+
+    pub async fn add_indexer(&self, input: IndexerInput) -> Indexer {
+        let indexer = Indexer {
+            id: new_id(),
+            name: input.name,
+            url: input.url,
+            api_key: input.api_key,
+            enabled: input.enabled,
+        };
+        self.indexers.insert(indexer.id.clone(), indexer.clone());
+        self.save().await;
+        indexer
+    }
+
+    pub async fn remove_indexer(&self, id: &str) -> bool {
+        if self.indexers.remove(id).is_some() {
+            self.save().await;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Searches every enabled indexer, merging results with the same info hash.
+    ///
+    /// Indexers that fail are skipped so one broken indexer doesn't fail the search.
+    pub async fn search(
+        &self,
+        client: &reqwest::Client,
+        query: &str,
+        categories: &[i32],
+    ) -> Vec<SearchResult> {
+        let searches = self
+            .indexers()
+            .into_iter()
+            .filter(|indexer| indexer.enabled)
+            .map(|indexer| async move {
+                let results = search_indexer(client, &indexer, query, categories).await;
+                if let Err(err) = &results {
+                    log::warn!("Cant search indexer {} {}", indexer.name, err);
+                }
+                results.unwrap_or_default()
+            });
+        let mut merged: Vec<SearchResult> = vec![];
+        let mut by_key: HashMap<String, usize> = HashMap::new();
+        for result in futures_util::future::join_all(searches)
+            .await
+            .into_iter()
+            .flatten()
+        {
+            let key = result
+                .info_hash
+                .clone()
+                .unwrap_or_else(|| result.link.clone());
+            match by_key.get(&key) {
+                Some(index) => {
+                    let existing = &mut merged[*index];
+                    existing.seeders = existing.seeders.max(result.seeders);
+                    existing.peers = existing.peers.max(result.peers);
+                    // Magnet links can be added without contacting the indexer again
+                    if !existing.link.starts_with("magnet:") && result.link.starts_with("magnet:") {
+                        existing.link = result.link;
+                    }
+                    for indexer in result.indexers {
+                        if !existing.indexers.contains(&indexer) {
+                            existing.indexers.push(indexer);
+                        }
+                    }
+                }
+                None => {
+                    by_key.insert(key, merged.len());
+                    merged.push(result);
+                }
+            }
+        }
+        merged.sort_by_key(|item| std::cmp::Reverse(item.seeders));
+        merged
+    }
+}
+
+async fn search_indexer(
+    client: &reqwest::Client,
+    indexer: &Indexer,
+    query: &str,
+    categories: &[i32],
+) -> Result<Vec<SearchResult>, String> {
+    let mut params = vec![("t", "search".to_string()), ("q", query.to_string())];
+    if !indexer.api_key.is_empty() {
+        params.push(("apikey", indexer.api_key.clone()));
+    }
+    if !categories.is_empty() {
+        let categories = categories
+            .iter()
+            .map(|category| category.to_string())
+            .collect::<Vec<_>>();
+        params.push(("cat", categories.join(",")));
+    }
+    let body = client
+        .get(&indexer.url)
+        .query(&params)
+        .timeout(SEARCH_TIMEOUT)
+        .send()
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(|err| err.to_string())?
+        .text()
+        .await
+        .map_err(|err| err.to_string())?;
+    parse_torznab(&body, &indexer.name)
+}
+
+/// Item being read from a Torznab response
+#[derive(Default)]
+struct TorznabItem {
+    title: String,
+    link: Option<String>,
+    enclosure: Option<String>,
+    magnet: Option<String>,
+    info_hash: Option<String>,
+    size: u64,
+    seeders: u32,
+    peers: u32,
+    categories: Vec<i32>,
+    publish_date: Option<DateTime<Utc>>,
+}
+
+impl TorznabItem {
+    /// Sets the field read from the text of `element`
+    fn set_text(&mut self, element: &[u8], text: &str) {
+        match element {
+            b"title" => self.title = text.to_string(),
+            b"link" if text.starts_with("magnet:") => self.magnet = Some(text.to_string()),
+            b"link" => self.link = Some(text.to_string()),
+            b"size" => self.size = text.parse().unwrap_or(self.size),
+            b"pubDate" => {
+                self.publish_date = DateTime::parse_from_rfc2822(text)
+                    .ok()
+                    .map(|date| date.with_timezone(&Utc))
+            }
+            _ => {}
+        }
+    }
+}
+
+fn attribute(element: &BytesStart, name: &[u8]) -> Option<String> {
+    element
+        .attributes()
+        .filter_map(|attribute| attribute.ok())
+        .find(|attribute| attribute.key.as_ref() == name)
+        .and_then(|attribute| attribute.unescape_value().ok())
+        .map(|value| value.into_owned())
+}
+
+/// Parses the RSS of a Torznab response, including its `torznab:attr` elements
+fn parse_torznab(body: &str, indexer: &str) -> Result<Vec<SearchResult>, String> {
+    let mut reader = quick_xml::Reader::from_str(body);
+    reader.trim_text(true);
+    let mut results = vec![];
+    let mut item: Option<TorznabItem> = None;
+    let mut element = vec![];
+    loop {
+        match reader.read_event().map_err(|err| err.to_string())? {
+            Event::Start(start) if start.name().as_ref() == b"item" => {
+                item = Some(TorznabItem::default());
+            }
+            Event::End(end) if end.name().as_ref() == b"item" => {
+                if let Some(item) = item.take() {
+                    let info_hash = item
+                        .info_hash
+                        .map(|hash| hash.to_lowercase())
+                        .or_else(|| item.magnet.as_deref().and_then(magnet_info_hash));
+                    let link = item.magnet.or(item.enclosure).or(item.link);
+                    if let Some(link) = link {
+                        results.push(SearchResult {
+                            title: item.title,
+                            size: item.size,
+                            seeders: item.seeders,
+                            peers: item.peers,
+                            info_hash,
+                            link,
+                            categories: item.categories,
+                            publish_date: item.publish_date,
+                            indexers: vec![indexer.to_string()],
+                        });
+                    }
+                }
+            }
+            Event::Start(start) => element = start.name().as_ref().to_vec(),
+            Event::End(_) => element.clear(),
+            Event::Empty(empty) => {
+                let item = match item.as_mut() {
+                    Some(item) => item,
+                    None => continue,
+                };
+                match empty.name().as_ref() {
+                    b"enclosure" => {
+                        item.enclosure = attribute(&empty, b"url");
+                        if item.size == 0 {
+                            item.size = attribute(&empty, b"length")
+                                .and_then(|length| length.parse().ok())
+                                .unwrap_or(0);
+                        }
+                    }
+                    b"torznab:attr" => {
+                        let value = attribute(&empty, b"value").unwrap_or_default();
+                        match attribute(&empty, b"name").as_deref() {
+                            Some("seeders") => item.seeders = value.parse().unwrap_or(0),
+                            Some("peers") => item.peers = value.parse().unwrap_or(0),
+                            Some("size") => item.size = value.parse().unwrap_or(item.size),
+                            Some("infohash") => item.info_hash = Some(value),
+                            Some("magneturl") => item.magnet = Some(value),
+                            Some("category") => item.categories.extend(value.parse::<i32>().ok()),
+                            _ => {}
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            Event::Text(text) => {
+                if let Some(item) = item.as_mut() {
+                    let text = text.unescape().map_err(|err| err.to_string())?;
+                    item.set_text(&element, &text);
+                }
+            }
+            Event::CData(text) => {
+                if let Some(item) = item.as_mut() {
+                    item.set_text(&element, &String::from_utf8_lossy(&text));
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(results)
+}
+
+/// Info hash of a magnet link as lowercase hex, converting base32 hashes
+fn magnet_info_hash(magnet: &str) -> Option<String> {
+    let hash = magnet
+        .split(['?', '&'])
+        .find_map(|param| param.strip_prefix("xt=urn:btih:"))?;
+    match hash.len() {
+        40 => Some(hash.to_lowercase()),
+        32 => {
+            const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+            let mut bits = 0u64;
+            let mut bit_count = 0;
+            let mut hex = String::with_capacity(40);
+            for c in hash.to_uppercase().bytes() {
+                let value = ALPHABET.iter().position(|a| *a == c)? as u64;
+                bits = (bits << 5) | value;
+                bit_count += 5;
+                if bit_count >= 8 {
+                    bit_count -= 8;
+                    hex.push_str(&format!("{:02x}", (bits >> bit_count) & 0xff));
+                }
+            }
+            Some(hex)
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESPONSE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:torznab="http://torznab.com/schemas/2015/feed">
+  <channel>
+    <title>Indexer</title>
+    <item>
+      <title>Show.Name.S01E02.1080p</title>
+      <link>https://indexer.example/download/1</link>
+      <size>1000</size>
+      <pubDate>Tue, 04 Oct 2022 10:00:00 +0000</pubDate>
+      <enclosure url="https://indexer.example/download/1.torrent" length="1000" type="application/x-bittorrent"/>
+      <torznab:attr name="seeders" value="12"/>
+      <torznab:attr name="peers" value="20"/>
+      <torznab:attr name="category" value="5000"/>
+      <torznab:attr name="category" value="5040"/>
+      <torznab:attr name="infohash" value="0123456789ABCDEF0123456789ABCDEF01234567"/>
+      <torznab:attr name="magneturl" value="magnet:?xt=urn:btih:0123456789ABCDEF0123456789ABCDEF01234567&amp;dn=show"/>
+    </item>
+    <item>
+      <title><![CDATA[Other & Show]]></title>
+      <link>magnet:?xt=urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH&amp;dn=other</link>
+      <enclosure url="https://indexer.example/download/2.torrent" length="2000" type="application/x-bittorrent"/>
+    </item>
+    <item>
+      <title>No link</title>
+    </item>
+  </channel>
+</rss>"#;
+
+    #[test]
+    fn parses_torznab_items() {
+        let results = parse_torznab(RESPONSE, "indexer").unwrap();
+        assert_eq!(results.len(), 2);
+
+        let first = &results[0];
+        assert_eq!(first.title, "Show.Name.S01E02.1080p");
+        assert_eq!(first.size, 1000);
+        assert_eq!(first.seeders, 12);
+        assert_eq!(first.peers, 20);
+        assert_eq!(first.categories, vec![5000, 5040]);
+        assert_eq!(
+            first.info_hash.as_deref(),
+            Some("0123456789abcdef0123456789abcdef01234567")
+        );
+        assert!(first
+            .link
+            .starts_with("magnet:?xt=urn:btih:0123456789ABCDEF"));
+        assert!(first.link.ends_with("&dn=show"));
+        assert_eq!(
+            first.publish_date,
+            Some(
+                DateTime::parse_from_rfc3339("2022-10-04T10:00:00Z")
+                    .unwrap()
+                    .with_timezone(&Utc)
+            )
+        );
+        assert_eq!(first.indexers, vec!["indexer".to_string()]);
+
+        // The magnet link is preferred and its base32 hash converted to hex
+        let second = &results[1];
+        assert_eq!(second.title, "Other & Show");
+        assert_eq!(second.size, 2000);
+        assert_eq!(
+            second.link,
+            "magnet:?xt=urn:btih:AERUKZ4JVPG66AJDIVTYTK6N54ASGRLH&dn=other"
+        );
+        assert_eq!(
+            second.info_hash.as_deref(),
+            Some("0123456789abcdef0123456789abcdef01234567")
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_xml() {
+        assert!(parse_torznab("<rss><channel><item></channel></rss>", "indexer").is_err());
+    }
+}
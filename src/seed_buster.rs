@@ -1,22 +1,134 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use dashmap::DashMap;
 use transmission::Torrent;
 
-use crate::structures::TorrentState;
+use crate::{context::TorrentHashes, structures::TorrentState, CONFIG_DIR};
 
-pub async fn seed_buster(torrents: Arc<DashMap<i32, Torrent>>) {
+/// Torrents which keep seeding once complete, saved in the config directory by info hash.
+///
+/// Created torrents and torrents moved into the library are seeded, every other
+/// torrent is stopped when it completes.
+#[derive(Default)]
+pub struct SeedingTorrents {
+    /// Info hash of each kept torrent
+    torrents: DashMap<i32, String>,
+    hashes: Arc<TorrentHashes>,
+}
+
+impl SeedingTorrents {
+    fn path() -> PathBuf {
+        Path::new(&CONFIG_DIR.clone()).join("seeding.json")
+    }
+
+    /// Loads the kept torrents among the given ones, matched by info hash
+    pub async fn load(torrents: &DashMap<i32, Torrent>, hashes: &Arc<TorrentHashes>) -> Self {
+        let seeding = SeedingTorrents {
+            hashes: hashes.clone(),
+            ..Default::default()
+        };
+        let saved = match tokio::fs::read(Self::path()).await {
+            Ok(saved) => serde_json::from_slice::<HashSet<String>>(&saved),
+            Err(_) => return seeding,
+        };
+        match saved {
+            Ok(saved) => {
+                for torrent in torrents.iter() {
+                    let hash = hashes.hash(torrent.value());
+                    if saved.contains(&hash) {
+                        seeding.torrents.insert(*torrent.key(), hash);
+                    }
+                }
+            }
+            Err(err) => log::warn!("Cant read seeding torrents {:#?}", err),
+        }
+        seeding
+    }
+
+    async fn save(&self) {
+        let saved = self
+            .torrents
+            .iter()
+            .map(|torrent| torrent.value().clone())
+            .collect::<HashSet<_>>();
+        match serde_json::to_vec_pretty(&saved) {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save seeding torrents {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save seeding torrents {:#?}", err),
+        }
+    }
+
+    fn insert(&self, torrent_id: i32, hash: String) -> bool {
+        self.torrents.insert(torrent_id, hash).is_none()
+    }
+
+    pub fn is_kept(&self, torrent_id: i32) -> bool {
+        self.torrents.contains_key(&torrent_id)
+    }
+
+    /// Keeps the torrent seeding after it completes
+    pub async fn keep(&self, torrent: &Torrent) {
+        if self.insert(torrent.id(), self.hashes.hash(torrent)) {
+            self.save().await;
+        }
+    }
+
+    pub async fn forget(&self, torrent_id: i32) {
+        if self.torrents.remove(&torrent_id).is_some() {
+            self.save().await;
+        }
+    }
+}
+
+/// Whether the torrent finished downloading and has to stop instead of seeding
+fn should_stop(percent_done: f32, state: TorrentState, kept: bool) -> bool {
+    percent_done >= 1.0 && state == TorrentState::Seeding && !kept
+}
+
+pub async fn seed_buster(torrents: Arc<DashMap<i32, Torrent>>, seeding: Arc<SeedingTorrents>) {
     loop {
         tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
         {
             for torrent in torrents.iter() {
                 let stat = torrent.value().stats();
-                if stat.percent_done >= 1.0
-                    && TorrentState::from(stat.state) == TorrentState::Seeding
-                {
+                if should_stop(
+                    stat.percent_done,
+                    TorrentState::from(stat.state),
+                    seeding.is_kept(*torrent.key()),
+                ) {
                     torrent.value().stop();
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_torrents_are_stopped() {
+        assert!(should_stop(1.0, TorrentState::Seeding, false));
+        assert!(!should_stop(0.5, TorrentState::Seeding, false));
+        assert!(!should_stop(1.0, TorrentState::Stopped, false));
+    }
+
+    #[test]
+    fn kept_torrents_keep_seeding() {
+        let seeding = SeedingTorrents::default();
+        assert!(seeding.insert(3, "ab".repeat(20)));
+        assert!(!seeding.insert(3, "ab".repeat(20)));
+        assert!(seeding.is_kept(3));
+        assert!(!seeding.is_kept(4));
+        assert!(!should_stop(1.0, TorrentState::Seeding, seeding.is_kept(3)));
+        assert!(should_stop(1.0, TorrentState::Seeding, seeding.is_kept(4)));
+    }
+}
@@ -0,0 +1,188 @@
+use std::{
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_graphql::{ObjectType, Schema, SubscriptionType};
+use async_graphql_axum::GraphQLRequest;
+use axum::{
+    extract::Query,
+    http::{HeaderMap, StatusCode},
+    response::sse::{Event, KeepAlive, Sse},
+    Extension,
+};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+
+use crate::{auth, structures::MainSchema};
+
+lazy_static::lazy_static! {
+    static ref KEEPALIVE_SECS: u64 = std::env::var("TOREXPO_SSE_KEEPALIVE_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(15);
+}
+
+/// `Last-Event-ID` sent by a reconnecting SSE client
+pub struct LastEventId(pub u64);
+
+/// Id of the last global event sent on an SSE stream, updated by the events subscription
+pub struct SseCursor(pub Arc<AtomicU64>);
+
+#[derive(Deserialize)]
+pub struct TokenQuery {
+    token: Option<String>,
+}
+
+fn last_event_id(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+}
+
+/// Executes the subscription, resuming after the last event the client saw.
+///
+/// Each response comes with the id of the last global event sent so far, if any.
+fn subscription_responses<Query, Mutation, Subscription>(
+    schema: &Schema<Query, Mutation, Subscription>,
+    request: async_graphql::Request,
+    last_event_id: Option<u64>,
+) -> impl Stream<Item = (async_graphql::Response, Option<u64>)>
+where
+    Query: ObjectType + 'static,
+    Mutation: ObjectType + 'static,
+    Subscription: SubscriptionType + 'static,
+{
+    let cursor = Arc::new(AtomicU64::new(last_event_id.unwrap_or(0)));
+    let mut request = request.data(SseCursor(cursor.clone()));
+    if let Some(last_event_id) = last_event_id {
+        request = request.data(LastEventId(last_event_id));
+    }
+    schema.execute_stream(request).map(move |response| {
+        let id = cursor.load(Ordering::SeqCst);
+        (response, Some(id).filter(|id| *id > 0))
+    })
+}
+
+/// Executes a GraphQL subscription and streams its responses as server-sent events.
+///
+/// `EventSource` can't set headers, so the password can also be given as the `token` query parameter.
+pub async fn graphql_sse(
+    Extension(schema): Extension<MainSchema>,
+    headers: HeaderMap,
+    Query(token): Query<TokenQuery>,
+    req: GraphQLRequest,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, (StatusCode, String)> {
+    let token_valid = token
+        .token
+        .map(|token| auth::check_token(&token))
+        .unwrap_or(false);
+    if !token_valid && !auth::check_basic_auth(&headers) {
+        return Err((StatusCode::UNAUTHORIZED, "Unauthorized".into()));
+    }
+    let responses = subscription_responses(&schema, req.into_inner(), last_event_id(&headers)).map(
+        |(response, id)| {
+            let mut event = Event::default()
+                .event("next")
+                .data(serde_json::to_string(&response).unwrap_or_default());
+            if let Some(id) = id {
+                event = event.id(id.to_string());
+            }
+            Ok(event)
+        },
+    );
+    let complete =
+        futures_util::stream::once(async { Ok(Event::default().event("complete").data("")) });
+    Ok(Sse::new(responses.chain(complete)).keep_alive(
+        KeepAlive::new()
+            .interval(Duration::from_secs(*KEEPALIVE_SECS))
+            .text("keepalive"),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use async_graphql::{Context, EmptyMutation, Object};
+    use axum::http::HeaderValue;
+
+    use super::*;
+    use crate::{
+        events::{EventLog, TorrentEvent, TorrentEventKind},
+        structures::TorrentState,
+    };
+
+    struct Query;
+
+    #[Object]
+    impl Query {
+        async fn version(&self) -> &str {
+            "test"
+        }
+    }
+
+    struct Events;
+
+    /// The `events` subscription of the schema, over a plain event log
+    #[async_graphql::Subscription]
+    impl Events {
+        async fn events<'ctx>(&self, ctx: &Context<'ctx>) -> impl Stream<Item = TorrentEvent> {
+            let log = ctx.data_unchecked::<Arc<EventLog>>();
+            let after_id = ctx.data_opt::<LastEventId>().map(|id| id.0);
+            let cursor = ctx.data_opt::<SseCursor>().map(|cursor| cursor.0.clone());
+            log.subscribe(after_id).inspect(move |event| {
+                if let Some(cursor) = &cursor {
+                    cursor.store(event.id, Ordering::SeqCst);
+                }
+            })
+        }
+    }
+
+    fn publish(log: &EventLog, torrent_id: i32) {
+        log.publish(
+            TorrentEventKind::Added,
+            torrent_id,
+            "Torrent".into(),
+            Some(TorrentState::Downloading),
+        );
+    }
+
+    #[test]
+    fn last_event_id_is_read_from_the_header() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(last_event_id(&headers), None);
+        headers.insert("last-event-id", HeaderValue::from_static(" 42 "));
+        assert_eq!(last_event_id(&headers), Some(42));
+        headers.insert("last-event-id", HeaderValue::from_static("next"));
+        assert_eq!(last_event_id(&headers), None);
+    }
+
+    #[tokio::test]
+    async fn reconnecting_clients_get_the_events_they_missed() {
+        let log = Arc::new(EventLog::default());
+        publish(&log, 1);
+        publish(&log, 2);
+        publish(&log, 3);
+        let schema = Schema::build(Query, EmptyMutation, Events)
+            .data(log.clone())
+            .finish();
+        let request = async_graphql::Request::new("subscription { events { id torrentId } }");
+        let responses = subscription_responses(&schema, request, Some(1))
+            .take(2)
+            .collect::<Vec<_>>()
+            .await;
+        let ids = responses.iter().map(|(_, id)| *id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![Some(2), Some(3)]);
+        let torrent_ids = responses
+            .iter()
+            .map(|(response, _)| {
+                response.data.clone().into_json().unwrap()["events"]["torrentId"].clone()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(torrent_ids, vec![2, 3]);
+    }
+}
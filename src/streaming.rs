@@ -0,0 +1,258 @@
+use std::{io::SeekFrom, path::PathBuf, sync::Arc};
+
+use async_stream::try_stream;
+use axum::{
+    body::{boxed, BoxBody, Bytes, StreamBody},
+    extract::Path,
+    http::{HeaderMap, Response, StatusCode},
+    Extension,
+};
+use futures_util::Stream;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use transmission::Torrent;
+
+use crate::{
+    context::SharedData,
+    download_link::{content_disposition, decode_link, StreamLinkStructure},
+    pieces::TorrentLayout,
+    priority::FileBoost,
+};
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+const PIECE_POLL_MILLIS: u64 = 500;
+
+lazy_static::lazy_static! {
+    static ref PIECE_TIMEOUT_SECS: u64 = std::env::var("TOREXPO_STREAM_TIMEOUT_SECS")
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(60);
+}
+
+/// Waits until the piece is on disk, raising the file's priority while it is missing.
+///
+/// libtransmission only takes priorities per file, so the piece gets no more priority
+/// than any other missing piece of the file.
+///
+/// The piece is only hashed again once libtransmission verified more data. The files
+/// are located again then, as they move out of the incomplete directory and lose their
+/// `.part` suffix when they complete.
+async fn wait_for_piece(
+    torrent: &Torrent,
+    data: &SharedData,
+    layout: &mut Arc<TorrentLayout>,
+    boost: &mut Option<FileBoost>,
+    file_index: u32,
+    piece: u32,
+) -> std::io::Result<()> {
+    let deadline =
+        tokio::time::Instant::now() + std::time::Duration::from_secs(*PIECE_TIMEOUT_SECS);
+    let mut have_valid = torrent.stats().have_valid;
+    if data.pieces.has_piece(torrent, layout, piece).await? {
+        return Ok(());
+    }
+    if boost.is_none() {
+        *boost = Some(data.priority_modes.boost_file(torrent, file_index));
+    }
+    loop {
+        if tokio::time::Instant::now() >= deadline {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("Timed out waiting for piece {piece}"),
+            ));
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(PIECE_POLL_MILLIS)).await;
+        let stats = torrent.stats();
+        if stats.have_valid == have_valid {
+            continue;
+        }
+        have_valid = stats.have_valid;
+        Arc::make_mut(layout)
+            .relocate(&data.download_dirs, torrent.id())
+            .await?;
+        if data.pieces.recheck_piece(torrent, layout, piece).await? {
+            return Ok(());
+        }
+    }
+}
+
+/// Streams `start..=end` of the file, waiting for each piece before reading it.
+///
+/// The file stays raised from the first missing piece until the stream is dropped.
+fn stream_file(
+    torrent: Torrent,
+    data: SharedData,
+    layout: Arc<TorrentLayout>,
+    file_index: u32,
+    start: u64,
+    end: u64,
+    content_length: u64,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    try_stream! {
+        let mut layout = layout;
+        let mut boost = None;
+        let mut position = start;
+        let mut opened: Option<PathBuf> = None;
+        let mut disk_file: Option<tokio::fs::File> = None;
+        while position <= end && content_length > 0 {
+            let file_offset = layout.files[file_index as usize].offset;
+            let piece = layout.piece_at(file_offset + position);
+            wait_for_piece(&torrent, &data, &mut layout, &mut boost, file_index, piece).await?;
+            let piece_end = (piece as u64 + 1) * layout.piece_size - file_offset;
+            let chunk_end = piece_end.min(end + 1).min(position + CHUNK_SIZE);
+
+            // Open the file again when it moved since it was opened
+            let path = &layout.files[file_index as usize].path;
+            if opened.as_ref() != Some(path) {
+                opened = Some(path.clone());
+                disk_file = None;
+            }
+            let disk = match disk_file.as_mut() {
+                Some(disk) => disk,
+                None => disk_file.insert(tokio::fs::File::open(path).await?),
+            };
+            disk.seek(SeekFrom::Start(position)).await?;
+            let mut buf = vec![0u8; (chunk_end - position) as usize];
+            disk.read_exact(&mut buf).await?;
+            position = chunk_end;
+            yield Bytes::from(buf);
+        }
+    }
+}
+
+/// Parses a single `bytes=` range into an inclusive range
+fn parse_range(range: &str, length: u64) -> Option<(u64, u64)> {
+    let range = range.trim().strip_prefix("bytes=")?.trim();
+    let (start, end) = range.split_once('-')?;
+    let (start, end) = if start.is_empty() {
+        let suffix = end.parse::<u64>().ok()?;
+        (length.saturating_sub(suffix), length.checked_sub(1)?)
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = if end.is_empty() {
+            length.checked_sub(1)?
+        } else {
+            end.parse::<u64>().ok()?.min(length.checked_sub(1)?)
+        };
+        (start, end)
+    };
+    if start > end {
+        return None;
+    }
+    Some((start, end))
+}
+
+pub async fn serve_stream(
+    Path(download_link): Path<String>,
+    Extension(data): Extension<SharedData>,
+    headers: HeaderMap,
+) -> Result<Response<BoxBody>, (StatusCode, String)> {
+    log::info!("Requested stream link {download_link}");
+    let structure = decode_link::<StreamLinkStructure>(&download_link)?;
+    let torrent = data
+        .torrent_by_hash(&structure.hash)
+        .ok_or((StatusCode::NOT_FOUND, "Torrent not found".to_string()))?;
+    let info = torrent.info();
+    let file = info
+        .files
+        .get(structure.file_index as usize)
+        .ok_or((StatusCode::NOT_FOUND, "File not found".to_string()))?;
+    let file_name = file.name.clone();
+    let file_length = file.length;
+    let layout = TorrentLayout::load(&data.download_dirs, torrent.id(), &info)
+        .await
+        .map(Arc::new)
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+    log::info!(
+        "Streaming {}",
+        layout.files[structure.file_index as usize]
+            .path
+            .to_string_lossy()
+    );
+
+    // Multi range requests get the whole file, which RFC 7233 allows
+    let range = headers
+        .get("range")
+        .and_then(|range| range.to_str().ok())
+        .filter(|range| !range.contains(','))
+        .map(|range| parse_range(range, file_length));
+    let (start, end, status) = match range {
+        Some(Some((start, end))) => (start, end, StatusCode::PARTIAL_CONTENT),
+        Some(None) => {
+            return Response::builder()
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header("content-range", format!("bytes */{}", file_length))
+                .body(boxed(axum::body::Empty::new()))
+                .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()));
+        }
+        None => (0, file_length.saturating_sub(1), StatusCode::OK),
+    };
+    let content_length = if file_length == 0 { 0 } else { end - start + 1 };
+
+    let body = stream_file(
+        torrent,
+        data,
+        layout,
+        structure.file_index,
+        start,
+        end,
+        content_length,
+    );
+
+    let mut response = Response::builder()
+        .status(status)
+        .header("accept-ranges", "bytes")
+        .header("content-length", content_length)
+        .header(
+            "content-type",
+            mime_guess::from_path(&file_name)
+                .first_or_octet_stream()
+                .to_string(),
+        );
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(
+            "content-range",
+            format!("bytes {}-{}/{}", start, end, file_length),
+        );
+    }
+    // The path on disk may have a `.part` suffix until the file completes
+    if let Some(filename) = std::path::Path::new(&file_name).file_name() {
+        response = response.header(
+            "content-disposition",
+            content_disposition("inline", &filename.to_string_lossy()),
+        );
+    }
+    response
+        .body(boxed(StreamBody::new(body)))
+        .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn range_reads_start_and_end() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 99)));
+        assert_eq!(parse_range(" bytes=100-199 ", 1000), Some((100, 199)));
+        // The end is clamped to the file
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some((900, 999)));
+    }
+
+    #[test]
+    fn range_reads_open_ended_and_suffix() {
+        assert_eq!(parse_range("bytes=500-", 1000), Some((500, 999)));
+        assert_eq!(parse_range("bytes=-100", 1000), Some((900, 999)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some((0, 999)));
+    }
+
+    #[test]
+    fn range_rejects_unsatisfiable_and_invalid() {
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range("bytes=200-100", 1000), None);
+        assert_eq!(parse_range("bytes=-0", 1000), None);
+        assert_eq!(parse_range("bytes=0-", 0), None);
+        assert_eq!(parse_range("items=0-99", 1000), None);
+        assert_eq!(parse_range("bytes=a-b", 1000), None);
+        assert_eq!(parse_range("bytes=0-99,200-299", 1000), None);
+    }
+}
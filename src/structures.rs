@@ -1,18 +1,36 @@
-use std::{
-    borrow::Cow,
-    sync::{Arc, Mutex},
-};
+use std::sync::{atomic::Ordering, Arc, Mutex};
 
 use async_graphql::*;
-use chrono::{DateTime, Duration, Utc};
-use futures_util::Stream;
-use magic_crypt::MagicCryptTrait;
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use futures_util::{Stream, StreamExt};
 use serde::{Deserialize, Serialize};
+use tokio::sync::OnceCell;
 
 use crate::{
+    archive::ArchiveFormat,
     context::SharedData,
+    cookie_profiles::{CookieProfile, CookieProfileInput},
+    create_torrent::{creation_stream, TorrentCreation},
+    disk_space::DiskSpace,
+    download_dirs::DownloadDirs,
+    download_link::{
+        encode_link, expiry_from_secs, ArchiveLinkStructure, DownloadLinkStructure,
+        StreamLinkStructure,
+    },
+    events::TorrentEvent,
+    extract::ExtractedArchive,
+    feeds::{Feed, FeedHistoryEntry, FeedInput},
+    library::{LibraryHistoryEntry, LibraryRule, LibraryRuleInput},
+    priority::PriorityMode,
+    retention::{RetentionCandidate, RetentionRule, RetentionRuleInput},
+    scheduler::ScheduledJob,
+    scripts::{Script, ScriptInput, ScriptRun},
+    search::{Indexer, IndexerInput, SearchResult},
+    sse::{LastEventId, SseCursor},
     torrent_struc::{TorrentInfo, TorrentStats},
-    DOWNLOAD_DIR, MCRYPT,
+    tracker::TrackedTorrent,
+    webhooks::{Webhook, WebhookDelivery, WebhookInput},
 };
 
 pub type MainSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
@@ -25,18 +43,19 @@ impl MutationRoot {
         &self,
         ctx: &Context<'ctx>,
         magnet_link: String,
+        options: Option<AddTorrentOptions>,
     ) -> Result<i32> {
         let data = ctx.data::<SharedData>()?;
-        let torrent = data.client.add_torrent_magnet(&magnet_link)?;
-        let id = torrent.id();
-        data.torrents.insert(id, torrent);
-        Ok(id)
+        Ok(data
+            .add_magnet(&magnet_link, options.unwrap_or_default())
+            .await?)
     }
 
     pub async fn add_torrent_file<'ctx>(
         &self,
         ctx: &Context<'ctx>,
         torrent: Upload,
+        options: Option<AddTorrentOptions>,
     ) -> Result<i32> {
         let torrent_file = torrent.value(ctx)?;
         let tmpdir = tempfile::tempdir()?;
@@ -52,25 +71,415 @@ impl MutationRoot {
         let torrent = data
             .client
             .add_torrent_file(path.to_str().ok_or("Not valid path")?)?;
-        let id = torrent.id();
-        data.torrents.insert(id, torrent);
-        Ok(id)
+        Ok(data
+            .insert_torrent(torrent, options.unwrap_or_default())
+            .await?)
+    }
+
+    /// Adds a magnet link, or downloads a .torrent file from an http url and adds it.
+    ///
+    /// Without `cookieProfile` the cookies of the profile matching the url's domain are sent.
+    pub async fn add_torrent_url<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        url: String,
+        #[graphql(default)] headers: Vec<HttpHeader>,
+        cookie_profile: Option<String>,
+        options: Option<AddTorrentOptions>,
+    ) -> Result<i32> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data
+            .add_url(
+                &url,
+                &headers,
+                cookie_profile.as_deref(),
+                options.unwrap_or_default(),
+            )
+            .await?)
+    }
+
+    /// Adds or replaces a named set of cookies for a tracker domain
+    pub async fn set_cookie_profile<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        profile: CookieProfileInput,
+    ) -> Result<CookieProfile> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.cookie_profiles.set_profile(profile).await?)
+    }
+
+    pub async fn remove_cookie_profile<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        name: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.cookie_profiles.remove_profile(&name).await {
+            Ok("success".into())
+        } else {
+            Err("Cookie profile not found".into())
+        }
     }
 
     pub async fn remove<'ctx>(&self, ctx: &Context<'ctx>, torrent_id: i32) -> Result<String> {
         let data = ctx.data::<SharedData>()?;
-        if let Some((_id, torrent)) = data.torrents.remove(&torrent_id) {
-            torrent.remove(true);
+        if data.remove_torrent(torrent_id, true).await {
             Ok("success".into())
         } else {
             Err("Torrent not found".into())
         }
     }
 
+    /// Sets the priority mode of a torrent, or of one of its files
+    pub async fn set_priority_mode<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        torrent_id: i32,
+        file_index: Option<u32>,
+        mode: PriorityMode,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        let torrent = data
+            .torrents
+            .get(&torrent_id)
+            .map(|torrent| torrent.value().clone())
+            .ok_or("Torrent not found")?;
+        data.priority_modes
+            .set_mode(&torrent, file_index, mode)
+            .await?;
+        Ok("success".into())
+    }
+
+    /// Subscribes to an RSS or Atom feed
+    pub async fn add_feed<'ctx>(&self, ctx: &Context<'ctx>, feed: FeedInput) -> Result<Feed> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.feeds.add_feed(feed).await?)
+    }
+
+    pub async fn update_feed<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        feed_id: String,
+        feed: FeedInput,
+    ) -> Result<Feed> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.feeds.update_feed(&feed_id, feed).await?)
+    }
+
+    pub async fn remove_feed<'ctx>(&self, ctx: &Context<'ctx>, feed_id: String) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.feeds.remove_feed(&feed_id).await {
+            Ok("success".into())
+        } else {
+            Err("Feed not found".into())
+        }
+    }
+
+    /// Checks the feed now instead of waiting for its interval
+    pub async fn refresh_feed<'ctx>(&self, ctx: &Context<'ctx>, feed_id: String) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.feeds.refresh_feed(&feed_id) {
+            Ok("success".into())
+        } else {
+            Err("Feed not found".into())
+        }
+    }
+
+    /// Adds a Torznab indexer used by `search`
+    pub async fn add_indexer<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        indexer: IndexerInput,
+    ) -> Result<Indexer> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.indexers.add_indexer(indexer).await)
+    }
+
+    pub async fn remove_indexer<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        indexer_id: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.indexers.remove_indexer(&indexer_id).await {
+            Ok("success".into())
+        } else {
+            Err("Indexer not found".into())
+        }
+    }
+
+    /// Adds a webhook called with a JSON payload on torrent events
+    pub async fn add_webhook<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        webhook: WebhookInput,
+    ) -> Result<Webhook> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.webhooks.add_webhook(webhook).await)
+    }
+
+    pub async fn update_webhook<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        webhook_id: String,
+        webhook: WebhookInput,
+    ) -> Result<Webhook> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.webhooks.update_webhook(&webhook_id, webhook).await?)
+    }
+
+    pub async fn remove_webhook<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        webhook_id: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.webhooks.remove_webhook(&webhook_id).await {
+            Ok("success".into())
+        } else {
+            Err("Webhook not found".into())
+        }
+    }
+
+    /// Adds a script run when torrents complete or are removed
+    pub async fn add_script<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        script: ScriptInput,
+    ) -> Result<Script> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.scripts.add_script(script).await?)
+    }
+
+    pub async fn update_script<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        script_id: String,
+        script: ScriptInput,
+    ) -> Result<Script> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.scripts.update_script(&script_id, script).await?)
+    }
+
+    pub async fn remove_script<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        script_id: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.scripts.remove_script(&script_id).await {
+            Ok("success".into())
+        } else {
+            Err("Script not found".into())
+        }
+    }
+
+    /// Extracts the zip, tar and 7z archives of a torrent into folders next to them
+    pub async fn extract_archives<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        torrent_id: i32,
+    ) -> Result<Vec<ExtractedArchive>> {
+        let data = ctx.data::<SharedData>()?;
+        let torrent = data
+            .torrents
+            .get(&torrent_id)
+            .map(|torrent| torrent.value().clone())
+            .ok_or("Torrent not found")?;
+        Ok(data.extractor.extract(&data.download_dirs, &torrent).await)
+    }
+
+    /// Adds a rule placing completed torrents in the library, tried after the existing ones
+    pub async fn add_library_rule<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        rule: LibraryRuleInput,
+    ) -> Result<LibraryRule> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.library.add_rule(rule).await?)
+    }
+
+    pub async fn update_library_rule<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        rule_id: String,
+        rule: LibraryRuleInput,
+    ) -> Result<LibraryRule> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.library.update_rule(&rule_id, rule).await?)
+    }
+
+    pub async fn remove_library_rule<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        rule_id: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.library.remove_rule(&rule_id).await {
+            Ok("success".into())
+        } else {
+            Err("Library rule not found".into())
+        }
+    }
+
+    /// Applies the first matching library rule to the torrent now
+    pub async fn apply_library_rules<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        torrent_id: i32,
+    ) -> Result<Option<LibraryHistoryEntry>> {
+        let data = ctx.data::<SharedData>()?;
+        let torrent = data
+            .torrents
+            .get(&torrent_id)
+            .map(|torrent| torrent.value().clone())
+            .ok_or("Torrent not found")?;
+        Ok(data
+            .library
+            .apply(
+                &data.download_dirs,
+                &data.seeding,
+                &data.extractor,
+                &torrent,
+            )
+            .await)
+    }
+
+    /// Adds a rule removing finished torrents, checked after the existing ones
+    pub async fn add_retention_rule<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        rule: RetentionRuleInput,
+    ) -> Result<RetentionRule> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.retention.add_rule(rule).await?)
+    }
+
+    pub async fn update_retention_rule<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        rule_id: String,
+        rule: RetentionRuleInput,
+    ) -> Result<RetentionRule> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.retention.update_rule(&rule_id, rule).await?)
+    }
+
+    pub async fn remove_retention_rule<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        rule_id: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.retention.remove_rule(&rule_id).await {
+            Ok("success".into())
+        } else {
+            Err("Retention rule not found".into())
+        }
+    }
+
+    /// Removes the torrents selected by the retention rules now, returning them
+    pub async fn apply_retention_rules<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+    ) -> Result<Vec<RetentionCandidate>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.retention.enforce(data).await)
+    }
+
+    /// Stops the torrent and starts it at the given time, replacing its earlier schedule
+    pub async fn schedule_start<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        torrent_id: i32,
+        at: DateTime<Utc>,
+    ) -> Result<ScheduledJob> {
+        let data = ctx.data::<SharedData>()?;
+        let torrent = data
+            .torrents
+            .get(&torrent_id)
+            .map(|torrent| torrent.value().clone())
+            .ok_or("Torrent not found")?;
+        Ok(data.scheduler.schedule_start(&torrent, at).await)
+    }
+
+    pub async fn cancel_scheduled_job<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        job_id: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.scheduler.cancel(&job_id).await {
+            Ok("success".into())
+        } else {
+            Err("Scheduled job not found".into())
+        }
+    }
+
+    /// Queues a torrent of a file or folder in the download directory.
+    ///
+    /// Its pieces are hashed in the background, `torrentCreation` reports the progress.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn create_torrent<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        path: String,
+        #[graphql(default)] trackers: Vec<String>,
+        #[graphql(default)] webseeds: Vec<String>,
+        piece_size: Option<u32>,
+        #[graphql(default)] private: bool,
+        comment: Option<String>,
+        #[graphql(default)] seed: bool,
+    ) -> Result<TorrentCreation> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data
+            .creator
+            .create(path, trackers, webseeds, piece_size, private, comment, seed)
+            .await?)
+    }
+
+    /// Forgets a torrent creation and deletes its .torrent file
+    pub async fn remove_torrent_creation<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        creation_id: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.creator.remove(&creation_id).await {
+            Ok("success".into())
+        } else {
+            Err("Torrent creation not found".into())
+        }
+    }
+
+    /// Lets peers announce the torrent to the embedded tracker
+    pub async fn allow_tracker_torrent<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        info_hash: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        data.tracker.allow(&info_hash).await?;
+        Ok("success".into())
+    }
+
+    pub async fn disallow_tracker_torrent<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        info_hash: String,
+    ) -> Result<String> {
+        let data = ctx.data::<SharedData>()?;
+        if data.tracker.disallow(&info_hash).await {
+            Ok("success".into())
+        } else {
+            Err("Torrent not allowed on the tracker".into())
+        }
+    }
+
     pub async fn start<'ctx>(&self, ctx: &Context<'ctx>, torrent_id: i32) -> Result<String> {
         let data = ctx.data::<SharedData>()?;
         if let Some(torrent) = &data.torrents.get(&torrent_id) {
-            torrent.start();
+            data.start_torrent(torrent);
             Ok("success".into())
         } else {
             Err("Torrent not found".into())
@@ -80,7 +489,7 @@ impl MutationRoot {
     pub async fn stop<'ctx>(&self, ctx: &Context<'ctx>, torrent_id: i32) -> Result<String> {
         let data = ctx.data::<SharedData>()?;
         if let Some(torrent) = &data.torrents.get(&torrent_id) {
-            torrent.stop();
+            data.stop_torrent(torrent);
             Ok("success".into())
         } else {
             Err("Torrent not found".into())
@@ -88,6 +497,29 @@ impl MutationRoot {
     }
 }
 
+#[derive(SimpleObject, InputObject, Default, Clone, Serialize, Deserialize)]
+#[graphql(name = "TorrentOptions", input_name = "AddTorrentOptions")]
+#[serde(default, rename_all = "camelCase")]
+pub struct AddTorrentOptions {
+    /// Add the torrent without starting it
+    #[graphql(default)]
+    pub paused: bool,
+    /// Download files one after another, in the order of the torrent
+    #[graphql(default)]
+    pub sequential_download: bool,
+    /// Directory to download the torrent to instead of the default one
+    pub download_dir: Option<String>,
+    /// Keep the torrent stopped until this time
+    pub start_at: Option<DateTime<Utc>>,
+}
+
+/// Header sent when downloading a .torrent file
+#[derive(InputObject)]
+pub struct HttpHeader {
+    pub name: String,
+    pub value: String,
+}
+
 pub struct QueryRoot;
 
 #[Object]
@@ -98,21 +530,179 @@ impl QueryRoot {
             .torrents
             .iter()
             .map(|f| f.value().clone())
-            .map(|f| Torrent { torrent: f })
+            .map(Torrent::new)
             .collect::<Vec<_>>();
         Ok(torrents)
     }
     async fn torrent<'ctx>(&self, ctx: &Context<'ctx>, torrent_id: i32) -> Result<Option<Torrent>> {
         let data = ctx.data::<SharedData>()?;
-        let torrent = data.torrents.get(&torrent_id).map(|t| Torrent {
-            torrent: t.value().clone(),
-        });
+        let torrent = data
+            .torrents
+            .get(&torrent_id)
+            .map(|t| Torrent::new(t.value().clone()));
         Ok(torrent)
     }
+
+    async fn feeds<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<Feed>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.feeds.feeds())
+    }
+
+    /// Items grabbed from feeds, latest first
+    async fn feed_history<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        feed_id: Option<String>,
+        #[graphql(default = 100)] limit: usize,
+    ) -> Result<Vec<FeedHistoryEntry>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.feeds.history(feed_id.as_deref(), limit))
+    }
+
+    async fn webhooks<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<Webhook>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.webhooks.webhooks())
+    }
+
+    /// Webhook deliveries waiting to be sent or retried
+    async fn webhook_deliveries<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<WebhookDelivery>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.webhooks.deliveries())
+    }
+
+    async fn scripts<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<Script>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.scripts.scripts())
+    }
+
+    /// Runs of scripts with their captured output, latest first
+    async fn script_runs<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        torrent_id: Option<i32>,
+        #[graphql(default = 100)] limit: usize,
+    ) -> Result<Vec<ScriptRun>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.scripts.runs(torrent_id, limit))
+    }
+
+    async fn library_rules<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<LibraryRule>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.library.rules())
+    }
+
+    /// What library rules did with completed torrents, latest first
+    async fn library_history<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        torrent_id: Option<i32>,
+        #[graphql(default = 100)] limit: usize,
+    ) -> Result<Vec<LibraryHistoryEntry>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.library.history(torrent_id, limit))
+    }
+
+    /// Torrents created from local files, latest first
+    async fn torrent_creations<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<TorrentCreation>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.creator.creations())
+    }
+
+    /// Torrents allowed on the embedded tracker, with their scrape stats
+    async fn tracker_torrents<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<TrackedTorrent>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.tracker.torrents())
+    }
+
+    /// Announce urls of the embedded tracker, added to the torrents created here
+    async fn tracker_announce_urls<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<String>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.tracker.announce_urls())
+    }
+
+    /// Torrents waiting to be started, soonest first
+    async fn scheduled_jobs<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        torrent_id: Option<i32>,
+    ) -> Result<Vec<ScheduledJob>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.scheduler.jobs(torrent_id))
+    }
+
+    async fn retention_rules<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<RetentionRule>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.retention.rules())
+    }
+
+    /// Torrents the retention rules would remove now, without removing them
+    async fn retention_dry_run<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+    ) -> Result<Vec<RetentionCandidate>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.retention.candidates(&data.torrents))
+    }
+
+    /// Free and used space of the download and config volumes
+    async fn disk_space<'ctx>(&self, ctx: &Context<'ctx>) -> Result<DiskSpace> {
+        let disk = ctx.data::<SharedData>()?.disk.clone();
+        Ok(tokio::task::spawn_blocking(move || disk.disk_space()).await?)
+    }
+
+    async fn cookie_profiles<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<CookieProfile>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.cookie_profiles.profiles())
+    }
+
+    async fn indexers<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<Indexer>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.indexers.indexers())
+    }
+
+    /// Searches the enabled indexers, most seeded results first
+    async fn search<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        query: String,
+        #[graphql(default)] categories: Vec<i32>,
+    ) -> Result<Vec<SearchResult>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.indexers.search(&data.http, &query, &categories).await)
+    }
 }
 
 pub struct Torrent {
     pub torrent: transmission::Torrent,
+    stats: OnceCell<TorrentStats>,
+    info: OnceCell<TorrentInfo>,
+}
+
+impl Torrent {
+    pub fn new(torrent: transmission::Torrent) -> Self {
+        Self {
+            torrent,
+            stats: OnceCell::new(),
+            info: OnceCell::new(),
+        }
+    }
+
+    /// Stats of the torrent, fetched from transmission at most once per object
+    pub async fn cached_stats(&self) -> &TorrentStats {
+        self.stats
+            .get_or_init(|| async { self.torrent.stats().into() })
+            .await
+    }
+
+    /// Info of the torrent, its full info is only fetched from transmission for the
+    /// fields that need it
+    pub async fn cached_info(&self, data: &SharedData) -> &TorrentInfo {
+        self.info
+            .get_or_init(|| async {
+                TorrentInfo::new(self.torrent.clone(), data.torrent_hash(&self.torrent))
+            })
+            .await
+    }
 }
 
 #[Object]
@@ -126,12 +716,12 @@ impl Torrent {
     }
 
     async fn state(&self) -> Result<TorrentState> {
-        let status = self.torrent.stats().state;
-        Ok(status.into())
+        Ok(self.cached_stats().await.state)
     }
 
-    async fn info(&self) -> Result<TorrentInfo> {
-        Ok(self.torrent.info().into())
+    async fn info<'ctx>(&self, ctx: &Context<'ctx>) -> Result<&TorrentInfo> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(self.cached_info(data).await)
     }
 
     // async fn set_seed_ratio(&self, ratio: f64) -> Result<String> {
@@ -139,14 +729,79 @@ impl Torrent {
     //     Ok("success".into())
     // }
 
-    async fn stats(&self) -> Result<TorrentStats> {
-        Ok(self.torrent.stats().into())
+    async fn stats(&self) -> Result<&TorrentStats> {
+        Ok(self.cached_stats().await)
+    }
+
+    /// Priority mode of the whole torrent
+    async fn priority_mode<'ctx>(&self, ctx: &Context<'ctx>) -> Result<PriorityMode> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.priority_modes.mode(self.torrent.id(), None))
+    }
+
+    /// Archives of the torrent that were extracted, with the extracted files
+    async fn extracted_archives<'ctx>(&self, ctx: &Context<'ctx>) -> Result<Vec<ExtractedArchive>> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.extractor.archives(&self.torrent))
+    }
+
+    /// Link to download the whole torrent, or a folder inside it, as a single archive
+    async fn download_archive_link<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        #[graphql(default_with = "ArchiveFormat::Zip")] format: ArchiveFormat,
+        #[graphql(desc = "Folder inside the torrent to archive instead of the whole torrent")]
+        folder: Option<String>,
+        expiry_secs: Option<u64>,
+    ) -> Result<Option<String>> {
+        if let Some(folder) = &folder {
+            let folder = std::path::Path::new(folder);
+            if !folder
+                .components()
+                .all(|component| matches!(component, std::path::Component::Normal(_)))
+            {
+                return Err("Invalid folder".into());
+            }
+        }
+        let data = ctx.data::<SharedData>()?;
+        let dirs = data.download_dirs.clone();
+        let torrent_id = self.torrent.id();
+        let name = self.torrent.name().to_string();
+        let checked = folder.clone();
+        let exists = tokio::task::spawn_blocking(move || {
+            let mut path = dirs.data_path(torrent_id, &name);
+            if let Some(folder) = checked {
+                path = path.join(folder);
+            }
+            path.exists()
+        })
+        .await?;
+        if !exists {
+            return Ok(None);
+        }
+        let coded = ArchiveLinkStructure {
+            hash: data.torrent_hash(&self.torrent).into(),
+            folder: folder.map(Into::into),
+            format,
+            expiry: expiry_from_secs(expiry_secs),
+        };
+        Ok(encode_link(&coded).map(|link| format!("/archive/{}", link)))
     }
 }
 
-#[derive(Debug, SimpleObject)]
+#[derive(Debug, SimpleObject, Serialize)]
 #[graphql(complex)]
+#[serde(rename_all = "camelCase")]
 pub struct TorrentFile {
+    #[graphql(skip)]
+    #[serde(skip)]
+    pub torrent_id: i32,
+    /// Info hash of the torrent, signed into links
+    #[graphql(skip)]
+    #[serde(skip)]
+    pub torrent_hash: String,
+    /// Index of the file in the torrent
+    pub index: u32,
     /// The length of the file in bytes
     pub length: u64,
     /// Name of the file
@@ -162,47 +817,45 @@ pub struct TorrentFile {
 
 #[ComplexObject]
 impl TorrentFile {
-    async fn download_link(&self, expiry_secs: Option<u64>) -> Option<String> {
-        let path = std::path::Path::new(&DOWNLOAD_DIR.clone()).join(&self.name);
-        let pathcheck = path.clone();
-        let exists = tokio::task::spawn_blocking(move || pathcheck.exists()).await;
-        match exists {
-            Ok(exists) => {
-                if exists {
-                    let coded = DownloadLinkStructure {
-                        file: path.to_string_lossy(),
-                        expiry: expiry_secs
-                            .map(|secs| chrono::Utc::now() + Duration::seconds(secs as i64)),
-                    };
-                    let bincoded = bincode::serialize(&coded);
-                    match bincoded {
-                        Ok(bincoded) => {
-                            let encrypted = MCRYPT.encrypt_bytes_to_base64(&bincoded);
-                            let urlencoded = urlencoding::encode(&encrypted);
-                            Some(format!("/download/{}", urlencoded))
-                        }
-                        Err(_) => None,
-                    }
-                } else {
-                    None
-                }
-            }
-            Err(_) => None,
-        }
+    async fn download_link<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        expiry_secs: Option<u64>,
+    ) -> Option<String> {
+        let data = ctx.data::<SharedData>().ok()?;
+        self.signed_download_link(&data.download_dirs, expiry_secs)
+            .await
+    }
+
+    /// Priority mode set on this file, in addition to the torrent's
+    async fn priority_mode<'ctx>(&self, ctx: &Context<'ctx>) -> Result<PriorityMode> {
+        let data = ctx.data::<SharedData>()?;
+        Ok(data.priority_modes.mode(self.torrent_id, Some(self.index)))
     }
-}
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct DownloadLinkStructure<'a> {
-    pub file: Cow<'a, str>,
-    pub expiry: Option<DateTime<Utc>>,
+    /// Link to stream the file, usable while it is still downloading.
+    ///
+    /// Reads wait for the requested pieces to be downloaded. Priority is raised for the
+    /// whole file meanwhile, not for the pieces being read, so after a seek the read
+    /// position competes with the rest of the file.
+    async fn stream_link(&self, expiry_secs: Option<u64>) -> Option<String> {
+        self.signed_stream_link(expiry_secs)
+    }
 }
 
-impl From<transmission::torrent::torrentinfo::TorrentFile> for TorrentFile {
-    fn from(file: transmission::torrent::torrentinfo::TorrentFile) -> Self {
+impl TorrentFile {
+    pub fn new(
+        torrent_id: i32,
+        torrent_hash: &str,
+        index: u32,
+        file: &transmission::torrent::torrentinfo::TorrentFile,
+    ) -> Self {
         Self {
+            torrent_id,
+            torrent_hash: torrent_hash.to_string(),
+            index,
             length: file.length,
-            name: file.name,
+            name: file.name.clone(),
             dnd: file.dnd,
             is_renamed: file.is_renamed,
             first_piece: file.first_piece,
@@ -210,6 +863,37 @@ impl From<transmission::torrent::torrentinfo::TorrentFile> for TorrentFile {
             offset: file.offset,
         }
     }
+
+    /// Signed download link, if the file exists on disk
+    pub async fn signed_download_link(
+        &self,
+        dirs: &Arc<DownloadDirs>,
+        expiry_secs: Option<u64>,
+    ) -> Option<String> {
+        let (dirs, torrent_id, name) = (dirs.clone(), self.torrent_id, self.name.clone());
+        let exists =
+            tokio::task::spawn_blocking(move || dirs.data_path(torrent_id, &name).exists()).await;
+        if !matches!(exists, Ok(true)) {
+            return None;
+        }
+        // The link names the file, it is located again when downloaded as it may have moved
+        let coded = DownloadLinkStructure {
+            hash: self.torrent_hash.as_str().into(),
+            name: self.name.as_str().into(),
+            expiry: expiry_from_secs(expiry_secs),
+        };
+        encode_link(&coded).map(|link| format!("/download/{}", link))
+    }
+
+    /// Signed link to stream the file while it downloads
+    pub fn signed_stream_link(&self, expiry_secs: Option<u64>) -> Option<String> {
+        let coded = StreamLinkStructure {
+            hash: self.torrent_hash.clone(),
+            file_index: self.index,
+            expiry: expiry_from_secs(expiry_secs),
+        };
+        encode_link(&coded).map(|link| format!("/stream/{}", link))
+    }
 }
 
 pub struct SubscriptionRoot;
@@ -230,51 +914,97 @@ impl SubscriptionRoot {
             .ok_or("Torrent not found")?
             .clone();
         drop(torrent);
-        let torrents = data.torrents.clone();
-        let last_sent = Arc::new(Mutex::new(None));
-
-        let str = async_stream::stream! {
-            loop {
-                tokio::time::sleep(std::time::Duration::from_millis(refresh_duration_millis)).await;
-                {
-                    let tmp_torrent = torrents.get(&torrent_id);
-                    let tmp_torrent = match tmp_torrent {
-                        Some(torrent) => torrent.clone(),
-                        None => break
-                    };
-                    let be = bincode::serialize(&tmp_torrent);
-                    if let Ok(be) = be {
-                        let mut should_yield = false;
-                        match &*last_sent.lock().unwrap(){
-                            Some(be2)=>{
-                                if &be != be2{
-                                    should_yield = true
-                                }
+        Ok(monitor_torrent_stream(
+            data.torrents.clone(),
+            torrent_id,
+            auto_stop,
+            refresh_duration_millis,
+        )
+        .map(Torrent::new))
+    }
+
+    /// Progress of a torrent creation, until it is done or failed
+    async fn torrent_creation<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        creation_id: String,
+    ) -> Result<impl Stream<Item = TorrentCreation>> {
+        let data = ctx.data::<SharedData>()?;
+        data.creator
+            .creation(&creation_id)
+            .ok_or("Torrent creation not found")?;
+        Ok(creation_stream(data.creator.clone(), creation_id))
+    }
+
+    /// Torrents being added, removed, changing state or completing.
+    ///
+    /// Events after `afterId`, or after the `Last-Event-ID` of an SSE request, are sent first
+    /// if they are still kept in memory.
+    async fn events<'ctx>(
+        &self,
+        ctx: &Context<'ctx>,
+        after_id: Option<u64>,
+    ) -> Result<impl Stream<Item = TorrentEvent>> {
+        let data = ctx.data::<SharedData>()?;
+        let after_id = after_id.or_else(|| ctx.data_opt::<LastEventId>().map(|id| id.0));
+        let cursor = ctx.data_opt::<SseCursor>().map(|cursor| cursor.0.clone());
+        Ok(data.events.subscribe(after_id).inspect(move |event| {
+            if let Some(cursor) = &cursor {
+                cursor.store(event.id, Ordering::SeqCst);
+            }
+        }))
+    }
+}
+
+/// Polls a torrent, yielding it whenever it changes until it is removed,
+/// or until it completes when `auto_stop` is set
+pub fn monitor_torrent_stream(
+    torrents: Arc<DashMap<i32, transmission::Torrent>>,
+    torrent_id: i32,
+    auto_stop: bool,
+    refresh_duration_millis: u64,
+) -> impl Stream<Item = transmission::Torrent> {
+    let last_sent = Arc::new(Mutex::new(None));
+
+    let str = async_stream::stream! {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_millis(refresh_duration_millis)).await;
+            {
+                let tmp_torrent = torrents.get(&torrent_id);
+                let tmp_torrent = match tmp_torrent {
+                    Some(torrent) => torrent.clone(),
+                    None => break
+                };
+                let be = bincode::serialize(&tmp_torrent);
+                if let Ok(be) = be {
+                    let mut should_yield = false;
+                    match &*last_sent.lock().unwrap(){
+                        Some(be2)=>{
+                            if &be != be2{
+                                should_yield = true
                             }
-                            None => should_yield=true
                         }
-                        if should_yield {
-                            {
-                                *last_sent.lock().unwrap() = Some(be);
-                            }
-                            yield Torrent{
-                                torrent:tmp_torrent.clone()
-                            }
+                        None => should_yield=true
+                    }
+                    if should_yield {
+                        {
+                            *last_sent.lock().unwrap() = Some(be);
                         }
-                    };
-                    if auto_stop && tmp_torrent.stats().percent_done >= 1.0 {
-                        tmp_torrent.stop();
-                        break;
+                        yield tmp_torrent.clone()
                     }
+                };
+                if auto_stop && tmp_torrent.stats().percent_done >= 1.0 {
+                    tmp_torrent.stop();
+                    break;
                 }
             }
-        };
+        }
+    };
 
-        Ok(str)
-    }
+    str
 }
 
-#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Serialize, Debug)]
 #[graphql(remote = "transmission::torrent::TorrentState")]
 pub enum TorrentState {
     /// The torrent is downloading
@@ -1,9 +1,15 @@
 use async_graphql::*;
-use chrono::NaiveDateTime;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use serde::{Serialize, Serializer};
+use tokio::sync::OnceCell;
 
-use crate::structures::{TorrentFile, TorrentState};
+use crate::{
+    download_link::{encode_link, expiry_from_secs, MetainfoLinkStructure},
+    structures::{TorrentFile, TorrentState},
+};
 
-#[derive(SimpleObject)]
+#[derive(SimpleObject, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct TorrentStats {
     /// The ID of the torrent.
     pub id: i32,
@@ -54,16 +60,21 @@ pub struct TorrentStats {
     pub downloaded_ever: u64,
     pub have_valid: u64,
     pub have_unchecked: u64,
+    #[serde(serialize_with = "serialize_utc")]
     pub manual_announce_time: NaiveDateTime,
     /// Seed ratio
     pub ratio: f32,
     /// Date and time added
+    #[serde(serialize_with = "serialize_utc")]
     pub added_date: NaiveDateTime,
     /// Date and time finished
+    #[serde(serialize_with = "serialize_utc")]
     pub done_date: NaiveDateTime,
     /// Date and time started
+    #[serde(serialize_with = "serialize_utc")]
     pub start_date: NaiveDateTime,
     /// Date and time of last activity
+    #[serde(serialize_with = "serialize_utc")]
     pub activity_date: NaiveDateTime,
     /// How long it has been idle
     pub idle_secs: i32,
@@ -126,7 +137,7 @@ impl From<transmission::torrent::TorrentStats> for TorrentStats {
     }
 }
 
-#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Serialize)]
 #[graphql(remote = "transmission::error::Error")]
 pub enum TorrentError {
     /// A general state of non-error.
@@ -152,82 +163,269 @@ pub enum TorrentError {
     MakeMetaCancelled,
 }
 
-#[derive(SimpleObject)]
+/// Info of a torrent.
+///
+/// Name and hash are read without the full info, which libtransmission builds with
+/// every file, piece and tracker. It is only fetched when another field is selected.
 pub struct TorrentInfo {
+    torrent: transmission::Torrent,
+    /// Lowercase hex info hash
+    hash: String,
+    info: OnceCell<transmission::torrent::TorrentInfo>,
+}
+
+#[Object]
+impl TorrentInfo {
     /// Total download size in bytes
-    pub total_size: u64,
+    async fn total_size(&self) -> u64 {
+        self.full().await.total_size
+    }
+
     /// Original name of the torrent
-    pub original_name: String,
+    async fn original_name(&self) -> &str {
+        &self.full().await.original_name
+    }
+
     /// Name of the torrent
-    pub name: String,
-    pub torrent: String,
+    async fn name(&self) -> &str {
+        self.torrent.name()
+    }
+
+    async fn torrent(&self) -> &str {
+        &self.full().await.torrent
+    }
+
     /// Webseeds of the torrent
-    pub webseeds: Vec<String>,
+    async fn webseeds(&self) -> &[String] {
+        &self.full().await.webseeds
+    }
+
     /// Comment on the torrent
-    pub comment: String,
+    async fn comment(&self) -> &str {
+        &self.full().await.comment
+    }
+
     /// The torrent's creator
-    pub creator: String,
+    async fn creator(&self) -> &str {
+        &self.full().await.creator
+    }
+
     /// Files of the torrent
-    pub files: Vec<TorrentFile>,
+    async fn files(&self) -> Vec<TorrentFile> {
+        self.full()
+            .await
+            .files
+            .iter()
+            .enumerate()
+            .map(|(index, f)| TorrentFile::new(self.torrent.id(), &self.hash, index as u32, f))
+            .collect()
+    }
+
     /// Pieces of the torrent
-    ///
-    /// This is skipped in Serialization due to it's size.
-    /// If you want it serialized you will have to do it manually.
-    pub pieces: Vec<TorrentPiece>,
+    async fn pieces(&self) -> Vec<TorrentPiece> {
+        self.full().await.pieces.iter().map(|f| f.into()).collect()
+    }
+
     /// Trackers of the torrent
-    pub trackers: Vec<TrackerInfo>,
+    async fn trackers(&self) -> Vec<TrackerInfo> {
+        self.full()
+            .await
+            .trackers
+            .iter()
+            .map(|f| f.into())
+            .collect()
+    }
+
     /// Date the torrent was created
-    pub date_created: NaiveDateTime,
+    async fn date_created(&self) -> NaiveDateTime {
+        self.full().await.date_created
+    }
+
     /// Number of trackers
-    pub tracker_count: u32,
+    async fn tracker_count(&self) -> u32 {
+        self.full().await.tracker_count
+    }
+
     /// Number of webseeds
-    pub webseed_count: u32,
+    async fn webseed_count(&self) -> u32 {
+        self.full().await.webseed_count
+    }
+
     /// Number of files
-    pub file_count: u32,
+    async fn file_count(&self) -> u32 {
+        self.full().await.file_count
+    }
+
     /// Sice of pieces in bytes
-    pub piece_size: u32,
+    async fn piece_size(&self) -> u32 {
+        self.full().await.piece_size
+    }
+
     /// Number of pieces
-    pub piece_count: u32,
-    pub hash: [u8; 20],
+    async fn piece_count(&self) -> u32 {
+        self.full().await.piece_count
+    }
+
+    #[graphql(deprecation = "Use hashHex or hashBase32")]
+    async fn hash(&self) -> [u8; 20] {
+        self.full().await.hash
+    }
+
     /// String hash of the torrent
-    pub hash_string: String,
-    pub is_private: bool,
+    async fn hash_string(&self) -> &str {
+        &self.hash
+    }
+
+    /// Info hash as lowercase hex
+    async fn hash_hex(&self) -> &str {
+        &self.hash
+    }
+
+    /// Info hash as RFC 4648 base32, as used by older magnet links
+    async fn hash_base32(&self) -> String {
+        hash_base32(&self.full().await.hash)
+    }
+
+    /// Magnet link of the torrent including its trackers and webseeds
+    async fn magnet_link(&self) -> String {
+        magnet_link(self.full().await)
+    }
+
+    /// Link to download the original .torrent metainfo file
+    async fn metainfo_download_link(&self, expiry_secs: Option<u64>) -> Option<String> {
+        let path = std::path::PathBuf::from(&self.full().await.torrent);
+        let pathcheck = path.clone();
+        let exists = tokio::task::spawn_blocking(move || pathcheck.is_file()).await;
+        match exists {
+            Ok(true) => {
+                let coded = MetainfoLinkStructure {
+                    file: path.to_string_lossy(),
+                    name: self.torrent.name().into(),
+                    expiry: expiry_from_secs(expiry_secs),
+                };
+                encode_link(&coded).map(|link| format!("/metainfo/{}", link))
+            }
+            _ => None,
+        }
+    }
+
+    async fn is_private(&self) -> bool {
+        self.full().await.is_private
+    }
+
     /// Is it a torrent of a folder?
+    async fn is_folder(&self) -> bool {
+        self.full().await.is_folder
+    }
+}
+
+impl TorrentInfo {
+    pub fn new(torrent: transmission::Torrent, hash: String) -> Self {
+        Self {
+            torrent,
+            hash,
+            info: OnceCell::new(),
+        }
+    }
+
+    /// The full info from transmission, fetched at most once per object
+    async fn full(&self) -> &transmission::torrent::TorrentInfo {
+        self.info
+            .get_or_init(|| async { self.torrent.info() })
+            .await
+    }
+}
+
+/// Serializable summary of a torrent's info, used by the REST API
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TorrentInfoSummary {
+    pub name: String,
+    pub total_size: u64,
+    pub hash_hex: String,
+    pub magnet_link: String,
+    pub comment: String,
+    pub creator: String,
+    #[serde(serialize_with = "serialize_utc")]
+    pub date_created: NaiveDateTime,
+    pub file_count: u32,
+    pub piece_size: u32,
+    pub piece_count: u32,
+    pub is_private: bool,
     pub is_folder: bool,
+    pub webseeds: Vec<String>,
+    pub trackers: Vec<TrackerInfo>,
 }
 
-impl From<transmission::torrent::TorrentInfo> for TorrentInfo {
-    fn from(torrent_info: transmission::torrent::TorrentInfo) -> Self {
+impl From<&transmission::torrent::TorrentInfo> for TorrentInfoSummary {
+    fn from(info: &transmission::torrent::TorrentInfo) -> Self {
         Self {
-            total_size: torrent_info.total_size,
-            original_name: torrent_info.original_name,
-            name: torrent_info.name,
-            torrent: torrent_info.torrent,
-            webseeds: torrent_info.webseeds,
-            comment: torrent_info.comment,
-            creator: torrent_info.creator,
-            files: torrent_info.files.into_iter().map(|f| f.into()).collect(),
-            pieces: torrent_info.pieces.into_iter().map(|f| f.into()).collect(),
-            trackers: torrent_info
-                .trackers
-                .into_iter()
-                .map(|f| f.into())
-                .collect(),
-            date_created: torrent_info.date_created,
-            tracker_count: torrent_info.tracker_count,
-            webseed_count: torrent_info.webseed_count,
-            file_count: torrent_info.file_count,
-            piece_size: torrent_info.piece_size,
-            piece_count: torrent_info.piece_count,
-            hash: torrent_info.hash,
-            hash_string: torrent_info.hash_string,
-            is_private: torrent_info.is_private,
-            is_folder: torrent_info.is_folder,
+            name: info.name.clone(),
+            total_size: info.total_size,
+            hash_hex: hash_hex(&info.hash),
+            magnet_link: magnet_link(info),
+            comment: info.comment.clone(),
+            creator: info.creator.clone(),
+            date_created: info.date_created,
+            file_count: info.file_count,
+            piece_size: info.piece_size,
+            piece_count: info.piece_count,
+            is_private: info.is_private,
+            is_folder: info.is_folder,
+            webseeds: info.webseeds.clone(),
+            trackers: info.trackers.iter().map(|f| f.into()).collect(),
         }
     }
 }
 
-#[derive(SimpleObject)]
+/// libtransmission dates are in UTC, serialize them with the offset so
+/// clients get RFC 3339 date-times
+pub fn serialize_utc<S: Serializer>(
+    date: &NaiveDateTime,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    Utc.from_utc_datetime(date).serialize(serializer)
+}
+
+pub fn hash_hex(hash: &[u8; 20]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn hash_base32(hash: &[u8; 20]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+    // 20 bytes are exactly 32 base32 characters, so no padding is needed
+    let mut encoded = String::with_capacity(32);
+    for chunk in hash.chunks(5) {
+        let bits = chunk
+            .iter()
+            .fold(0u64, |bits, byte| (bits << 8) | *byte as u64);
+        for i in (0..8).rev() {
+            encoded.push(ALPHABET[((bits >> (i * 5)) & 0x1f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+pub fn magnet_link(info: &transmission::torrent::TorrentInfo) -> String {
+    let mut link = format!(
+        "magnet:?xt=urn:btih:{}&dn={}",
+        hash_hex(&info.hash),
+        urlencoding::encode(&info.name)
+    );
+    if info.total_size > 0 {
+        link.push_str(&format!("&xl={}", info.total_size));
+    }
+    for tracker in info.trackers.iter() {
+        link.push_str(&format!("&tr={}", urlencoding::encode(&tracker.announce)));
+    }
+    for webseed in info.webseeds.iter() {
+        link.push_str(&format!("&ws={}", urlencoding::encode(webseed)));
+    }
+    link
+}
+
+#[derive(SimpleObject, Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct TorrentPiece {
     /// Last time the piece was checked
     pub time_checked: NaiveDateTime,
@@ -237,8 +435,8 @@ pub struct TorrentPiece {
     pub dnd: i8,
 }
 
-impl From<transmission::torrent::torrentinfo::TorrentPiece> for TorrentPiece {
-    fn from(piece: transmission::torrent::torrentinfo::TorrentPiece) -> Self {
+impl From<&transmission::torrent::torrentinfo::TorrentPiece> for TorrentPiece {
+    fn from(piece: &transmission::torrent::torrentinfo::TorrentPiece) -> Self {
         Self {
             time_checked: piece.time_checked,
             hash: piece.hash,
@@ -248,7 +446,7 @@ impl From<transmission::torrent::torrentinfo::TorrentPiece> for TorrentPiece {
     }
 }
 
-#[derive(SimpleObject)]
+#[derive(SimpleObject, Serialize)]
 pub struct TrackerInfo {
     pub tier: i32,
     pub announce: String,
@@ -256,13 +454,88 @@ pub struct TrackerInfo {
     pub id: u32,
 }
 
-impl From<transmission::torrent::torrentinfo::TrackerInfo> for TrackerInfo {
-    fn from(tracker_info: transmission::torrent::torrentinfo::TrackerInfo) -> Self {
+impl From<&transmission::torrent::torrentinfo::TrackerInfo> for TrackerInfo {
+    fn from(tracker_info: &transmission::torrent::torrentinfo::TrackerInfo) -> Self {
         Self {
             tier: tracker_info.tier,
-            announce: tracker_info.announce,
-            scrape: tracker_info.scrape,
+            announce: tracker_info.announce.clone(),
+            scrape: tracker_info.scrape.clone(),
             id: tracker_info.id,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use transmission::torrent::torrentinfo::{TorrentInfo, TrackerInfo};
+
+    use super::*;
+
+    fn info(trackers: &[&str]) -> TorrentInfo {
+        TorrentInfo {
+            total_size: 1024,
+            original_name: "Big Buck Bunny".into(),
+            name: "Big Buck Bunny".into(),
+            torrent: "bunny.torrent".into(),
+            webseeds: vec![],
+            comment: String::new(),
+            creator: String::new(),
+            files: vec![],
+            pieces: vec![],
+            trackers: trackers
+                .iter()
+                .enumerate()
+                .map(|(id, announce)| TrackerInfo {
+                    tier: 0,
+                    announce: announce.to_string(),
+                    scrape: String::new(),
+                    id: id as u32,
+                })
+                .collect(),
+            date_created: NaiveDateTime::from_timestamp_opt(1664884800, 0).unwrap(),
+            tracker_count: trackers.len() as u32,
+            webseed_count: 0,
+            file_count: 0,
+            piece_size: 1024,
+            piece_count: 1,
+            hash: [0xab; 20],
+            hash_string: "ab".repeat(20),
+            is_private: false,
+            is_folder: false,
+        }
+    }
+
+    #[test]
+    fn hash_is_encoded_as_base32() {
+        let hash: [u8; 20] = std::array::from_fn(|i| i as u8);
+        assert_eq!(hash_base32(&hash), "AAAQEAYEAUDAOCAJBIFQYDIOB4IBCEQT");
+        assert_eq!(hash_base32(&[0xff; 20]), "7".repeat(32));
+    }
+
+    #[test]
+    fn magnet_link_escapes_name_and_trackers() {
+        let link = magnet_link(&info(&["http://tracker.example/announce?passkey=a&b=c"]));
+        assert_eq!(
+            link,
+            format!(
+                "magnet:?xt=urn:btih:{}&dn=Big%20Buck%20Bunny&xl=1024\
+                 &tr=http%3A%2F%2Ftracker.example%2Fannounce%3Fpasskey%3Da%26b%3Dc",
+                "ab".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn magnet_link_lists_every_tracker() {
+        let link = magnet_link(&info(&["udp://one.example:80", "udp://two.example:80"]));
+        assert!(link.ends_with("&tr=udp%3A%2F%2Fone.example%3A80&tr=udp%3A%2F%2Ftwo.example%3A80"));
+    }
+
+    #[test]
+    fn dates_are_serialized_in_utc() {
+        let summary = TorrentInfoSummary::from(&info(&[]));
+        let json = serde_json::to_value(&summary).unwrap();
+        assert_eq!(json["dateCreated"], "2022-10-04T12:00:00Z");
+    }
+}
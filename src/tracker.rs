@@ -0,0 +1,759 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use async_graphql::SimpleObject;
+use axum::{
+    extract::{ConnectInfo, RawQuery},
+    http::{HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    routing::get,
+    Extension, Router,
+};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use tokio::net::UdpSocket;
+
+use crate::{
+    bencode::Bencode, context::SharedData, env_flag, torrent_struc::hash_hex, CONFIG_DIR,
+    PUBLIC_URL,
+};
+
+lazy_static::lazy_static! {
+    /// Serve a tracker at `/announce` and `/scrape`
+    pub static ref TRACKER_ENABLED: bool = env_flag("TOREXPO_TRACKER");
+    /// Take the address of HTTP peers from `X-Forwarded-For`, only for a reverse proxy
+    /// setting it, otherwise peers are served at the address connecting to the tracker
+    static ref TRUST_FORWARDED_FOR: bool = env_flag("TOREXPO_TRACKER_TRUST_FORWARDED_FOR");
+    /// Port of the UDP tracker, disabled when not set
+    static ref UDP_PORT: Option<u16> = std::env::var("TOREXPO_TRACKER_UDP_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok());
+    /// Keys the connection ids handed to UDP clients
+    static ref CONNECTION_KEY: [u8; 32] = {
+        let mut key = [0u8; 32];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        key
+    };
+}
+
+/// Seconds peers wait between announces
+const ANNOUNCE_INTERVAL: u32 = 300;
+/// Peers that haven't announced for this long are dropped
+const PEER_TIMEOUT: Duration = Duration::from_secs(3 * ANNOUNCE_INTERVAL as u64);
+const DEFAULT_NUM_WANT: usize = 50;
+const MAX_NUM_WANT: usize = 200;
+/// Magic number starting UDP connect requests
+const UDP_PROTOCOL_ID: u64 = 0x41727101980;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum AnnounceEvent {
+    None,
+    Started,
+    Completed,
+    Stopped,
+}
+
+/// An announce of a peer, from HTTP or UDP
+pub struct Announce {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub addr: SocketAddr,
+    pub left: u64,
+    pub event: AnnounceEvent,
+    pub num_want: Option<usize>,
+}
+
+pub struct AnnounceReply {
+    pub seeders: u32,
+    pub leechers: u32,
+    pub peers: Vec<SocketAddr>,
+}
+
+struct Peer {
+    addr: SocketAddr,
+    left: u64,
+    last_seen: Instant,
+}
+
+#[derive(Default)]
+struct Swarm {
+    peers: HashMap<[u8; 20], Peer>,
+    /// Times a peer announced it completed the torrent
+    completed: u32,
+}
+
+impl Swarm {
+    fn stats(&self, info_hash: String) -> TrackedTorrent {
+        let seeders = self.peers.values().filter(|peer| peer.left == 0).count() as u32;
+        TrackedTorrent {
+            info_hash,
+            seeders,
+            leechers: self.peers.len() as u32 - seeders,
+            completed: self.completed,
+        }
+    }
+}
+
+/// Scrape stats of a torrent on the embedded tracker
+#[derive(SimpleObject, Clone)]
+pub struct TrackedTorrent {
+    /// Info hash in hex
+    pub info_hash: String,
+    pub seeders: u32,
+    pub leechers: u32,
+    /// Times the torrent was completed by a peer of the tracker
+    pub completed: u32,
+}
+
+/// Tracker for the torrents on its allowlist
+#[derive(Default)]
+pub struct Tracker {
+    /// Info hashes in lowercase hex
+    allowed: Mutex<BTreeSet<String>>,
+    swarms: Mutex<HashMap<[u8; 20], Swarm>>,
+}
+
+/// Info hash given in hex as 20 bytes
+fn parse_info_hash(info_hash: &str) -> Result<[u8; 20], String> {
+    let mut hash = [0u8; 20];
+    if info_hash.len() == 40 && info_hash.is_ascii() {
+        for (index, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&info_hash[index * 2..index * 2 + 2], 16)
+                .map_err(|_| "Not valid info hash")?;
+        }
+        return Ok(hash);
+    }
+    Err("Not valid info hash".into())
+}
+
+impl Tracker {
+    fn path() -> PathBuf {
+        Path::new(&CONFIG_DIR.clone()).join("tracker.json")
+    }
+
+    pub async fn load() -> Self {
+        let tracker = Tracker::default();
+        if let Ok(saved) = tokio::fs::read(Self::path()).await {
+            match serde_json::from_slice::<BTreeSet<String>>(&saved) {
+                Ok(allowed) => *tracker.allowed.lock().unwrap() = allowed,
+                Err(err) => log::warn!("Cant read tracker allowlist {:#?}", err),
+            }
+        }
+        tracker
+    }
+
+    async fn save(&self) {
+        let saved = serde_json::to_vec_pretty(&*self.allowed.lock().unwrap());
+        match saved {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save tracker allowlist {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save tracker allowlist {:#?}", err),
+        }
+    }
+
+    /// Announce urls of the tracker, empty when it is disabled or its public url unknown
+    pub fn announce_urls(&self) -> Vec<String> {
+        if !*TRACKER_ENABLED || PUBLIC_URL.is_empty() {
+            return vec![];
+        }
+        let mut urls = vec![format!("{}/announce", *PUBLIC_URL)];
+        let host = reqwest::Url::parse(&PUBLIC_URL)
+            .ok()
+            .and_then(|url| url.host_str().map(String::from));
+        if let (Some(host), Some(port)) = (host, *UDP_PORT) {
+            urls.push(format!("udp://{}:{}/announce", host, port));
+        }
+        urls
+    }
+
+    /// Adds the info hash, in hex, to the allowlist
+    pub async fn allow(&self, info_hash: &str) -> Result<(), String> {
+        let hash = parse_info_hash(info_hash)?;
+        self.allowed.lock().unwrap().insert(hash_hex(&hash));
+        self.save().await;
+        Ok(())
+    }
+
+    /// Removes the info hash from the allowlist and forgets its peers
+    pub async fn disallow(&self, info_hash: &str) -> bool {
+        let hash = match parse_info_hash(info_hash) {
+            Ok(hash) => hash,
+            Err(_) => return false,
+        };
+        let removed = self.allowed.lock().unwrap().remove(&hash_hex(&hash));
+        if removed {
+            self.swarms.lock().unwrap().remove(&hash);
+            self.save().await;
+        }
+        removed
+    }
+
+    fn is_allowed(&self, info_hash: &[u8; 20]) -> bool {
+        self.allowed.lock().unwrap().contains(&hash_hex(info_hash))
+    }
+
+    /// Stats of the allowed torrents
+    pub fn torrents(&self) -> Vec<TrackedTorrent> {
+        let allowed = self.allowed.lock().unwrap().clone();
+        let mut swarms = self.swarms.lock().unwrap();
+        allowed
+            .into_iter()
+            .map(|info_hash| match parse_info_hash(&info_hash) {
+                Ok(hash) => {
+                    let swarm = swarms.entry(hash).or_default();
+                    swarm
+                        .peers
+                        .retain(|_, peer| peer.last_seen.elapsed() < PEER_TIMEOUT);
+                    swarm.stats(info_hash)
+                }
+                Err(_) => Swarm::default().stats(info_hash),
+            })
+            .collect()
+    }
+
+    /// Stats of the given allowed torrents, or all of them when none are given
+    fn scrape(&self, info_hashes: &[[u8; 20]]) -> Vec<([u8; 20], TrackedTorrent)> {
+        self.torrents()
+            .into_iter()
+            .filter_map(|torrent| {
+                parse_info_hash(&torrent.info_hash)
+                    .ok()
+                    .map(|hash| (hash, torrent))
+            })
+            .filter(|(hash, _)| info_hashes.is_empty() || info_hashes.contains(hash))
+            .collect()
+    }
+
+    /// Records the peer and returns others of the swarm
+    pub fn announce(&self, announce: Announce) -> Result<AnnounceReply, String> {
+        if !self.is_allowed(&announce.info_hash) {
+            return Err("Torrent not allowed on this tracker".into());
+        }
+        let mut swarms = self.swarms.lock().unwrap();
+        let swarm = swarms.entry(announce.info_hash).or_default();
+        swarm
+            .peers
+            .retain(|_, peer| peer.last_seen.elapsed() < PEER_TIMEOUT);
+        if announce.event == AnnounceEvent::Stopped {
+            swarm.peers.remove(&announce.peer_id);
+        } else {
+            if announce.event == AnnounceEvent::Completed {
+                swarm.completed += 1;
+            }
+            swarm.peers.insert(
+                announce.peer_id,
+                Peer {
+                    addr: announce.addr,
+                    left: announce.left,
+                    last_seen: Instant::now(),
+                },
+            );
+        }
+        let num_want = announce
+            .num_want
+            .unwrap_or(DEFAULT_NUM_WANT)
+            .min(MAX_NUM_WANT);
+        // Seeders only need peers still downloading
+        let peers = swarm
+            .peers
+            .iter()
+            .filter(|(id, peer)| **id != announce.peer_id && (announce.left > 0 || peer.left > 0))
+            .map(|(_, peer)| peer.addr)
+            .take(num_want)
+            .collect();
+        let stats = swarm.stats(String::new());
+        Ok(AnnounceReply {
+            seeders: stats.seeders,
+            leechers: stats.leechers,
+            peers,
+        })
+    }
+}
+
+/// Peer address with IPv4 mapped IPv6 addresses unmapped
+fn peer_addr(ip: IpAddr, port: u16) -> SocketAddr {
+    let ip = match ip {
+        IpAddr::V6(ip) => ip
+            .to_ipv4()
+            .filter(|_| ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff])
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(ip)),
+        ip => ip,
+    };
+    SocketAddr::new(ip, port)
+}
+
+/// Compact IPv4 and IPv6 peer lists
+fn compact_peers(peers: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let (mut peers4, mut peers6) = (vec![], vec![]);
+    for peer in peers {
+        match peer.ip() {
+            IpAddr::V4(ip) => {
+                peers4.extend_from_slice(&ip.octets());
+                peers4.extend_from_slice(&peer.port().to_be_bytes());
+            }
+            IpAddr::V6(ip) => {
+                peers6.extend_from_slice(&ip.octets());
+                peers6.extend_from_slice(&peer.port().to_be_bytes());
+            }
+        }
+    }
+    (peers4, peers6)
+}
+
+/// Query parameters with their values as bytes, info hashes aren't valid utf8
+fn query_pairs(query: &str) -> Vec<(String, Vec<u8>)> {
+    query
+        .split('&')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = urlencoding::decode(key).ok()?.into_owned();
+            Some((
+                key,
+                urlencoding::decode_binary(value.as_bytes()).into_owned(),
+            ))
+        })
+        .collect()
+}
+
+fn bencoded(body: Bencode) -> Response {
+    let mut response = body.to_bytes().into_response();
+    response
+        .headers_mut()
+        .insert("content-type", HeaderValue::from_static("text/plain"));
+    response
+}
+
+fn failure(reason: &str) -> Response {
+    bencoded(Bencode::dict([("failure reason", Bencode::string(reason))]))
+}
+
+/// Address of an HTTP peer, the last one the proxy added when it is trusted
+fn client_ip(headers: &HeaderMap, addr: SocketAddr) -> IpAddr {
+    if *TRUST_FORWARDED_FOR {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+            .next_back();
+        if let Some(ip) = forwarded {
+            return ip;
+        }
+    }
+    addr.ip()
+}
+
+fn http_announce(tracker: &Tracker, query: &str, ip: IpAddr) -> Result<AnnounceReply, String> {
+    let pairs = query_pairs(query);
+    let param = |name: &str| {
+        pairs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_slice())
+    };
+    let number = |name: &str| {
+        param(name)
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .and_then(|value| value.parse::<u64>().ok())
+    };
+    let info_hash = param("info_hash")
+        .and_then(|hash| <[u8; 20]>::try_from(hash).ok())
+        .ok_or("Not valid info_hash")?;
+    let peer_id = param("peer_id")
+        .and_then(|id| <[u8; 20]>::try_from(id).ok())
+        .ok_or("Not valid peer_id")?;
+    let port = number("port")
+        .and_then(|port| u16::try_from(port).ok())
+        .ok_or("Not valid port")?;
+    let event = match param("event") {
+        Some(b"started") => AnnounceEvent::Started,
+        Some(b"completed") => AnnounceEvent::Completed,
+        Some(b"stopped") => AnnounceEvent::Stopped,
+        _ => AnnounceEvent::None,
+    };
+    tracker.announce(Announce {
+        info_hash,
+        peer_id,
+        addr: peer_addr(ip, port),
+        left: number("left").unwrap_or(0),
+        event,
+        num_want: number("numwant").map(|num_want| num_want as usize),
+    })
+}
+
+async fn announce(
+    Extension(data): Extension<SharedData>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    RawQuery(query): RawQuery,
+) -> Response {
+    let ip = client_ip(&headers, addr);
+    match http_announce(&data.tracker, &query.unwrap_or_default(), ip) {
+        Ok(reply) => {
+            let (peers4, peers6) = compact_peers(&reply.peers);
+            bencoded(Bencode::dict([
+                ("complete", Bencode::Int(reply.seeders as i64)),
+                ("incomplete", Bencode::Int(reply.leechers as i64)),
+                ("interval", Bencode::Int(ANNOUNCE_INTERVAL as i64)),
+                ("peers", Bencode::Bytes(peers4)),
+                ("peers6", Bencode::Bytes(peers6)),
+            ]))
+        }
+        Err(err) => failure(&err),
+    }
+}
+
+async fn scrape(Extension(data): Extension<SharedData>, RawQuery(query): RawQuery) -> Response {
+    let info_hashes = query_pairs(&query.unwrap_or_default())
+        .into_iter()
+        .filter(|(key, _)| key == "info_hash")
+        .filter_map(|(_, hash)| <[u8; 20]>::try_from(hash).ok())
+        .collect::<Vec<_>>();
+    let files = data
+        .tracker
+        .scrape(&info_hashes)
+        .into_iter()
+        .map(|(hash, torrent)| {
+            let stats = Bencode::dict([
+                ("complete", Bencode::Int(torrent.seeders as i64)),
+                ("downloaded", Bencode::Int(torrent.completed as i64)),
+                ("incomplete", Bencode::Int(torrent.leechers as i64)),
+            ]);
+            (hash.to_vec(), stats)
+        })
+        .collect();
+    bencoded(Bencode::dict([("files", Bencode::Dict(files))]))
+}
+
+/// Routes of the HTTP tracker, none when it is disabled
+pub fn router() -> Router {
+    if *TRACKER_ENABLED {
+        Router::new()
+            .route("/announce", get(announce))
+            .route("/scrape", get(scrape))
+    } else {
+        Router::new()
+    }
+}
+
+/// Connection id handed to an address, valid for two minutes
+fn connection_id(addr: &SocketAddr, window: u64) -> u64 {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(&*CONNECTION_KEY).expect("HMAC accepts any key length");
+    mac.update(addr.ip().to_string().as_bytes());
+    mac.update(&addr.port().to_be_bytes());
+    mac.update(&window.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+    u64::from_be_bytes(
+        digest[..8]
+            .try_into()
+            .expect("digest is longer than 8 bytes"),
+    )
+}
+
+fn connection_window() -> u64 {
+    chrono::Utc::now().timestamp() as u64 / 60
+}
+
+fn valid_connection(addr: &SocketAddr, id: u64) -> bool {
+    let window = connection_window();
+    id == connection_id(addr, window) || id == connection_id(addr, window.saturating_sub(1))
+}
+
+fn read_u32(packet: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(packet[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(packet: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(packet[at..at + 8].try_into().unwrap())
+}
+
+fn udp_error(transaction_id: u32, message: &str) -> Vec<u8> {
+    let mut reply = 3u32.to_be_bytes().to_vec();
+    reply.extend_from_slice(&transaction_id.to_be_bytes());
+    reply.extend_from_slice(message.as_bytes());
+    reply
+}
+
+/// Answers a UDP tracker packet, as in BEP 15
+fn udp_reply(tracker: &Tracker, packet: &[u8], addr: SocketAddr) -> Option<Vec<u8>> {
+    if packet.len() < 16 {
+        return None;
+    }
+    let connection = read_u64(packet, 0);
+    let action = read_u32(packet, 8);
+    let transaction_id = read_u32(packet, 12);
+    let mut reply = action.to_be_bytes().to_vec();
+    reply.extend_from_slice(&transaction_id.to_be_bytes());
+    if action == 0 {
+        if connection != UDP_PROTOCOL_ID {
+            return None;
+        }
+        reply.extend_from_slice(&connection_id(&addr, connection_window()).to_be_bytes());
+        return Some(reply);
+    }
+    if !valid_connection(&addr, connection) {
+        return Some(udp_error(transaction_id, "Connection expired"));
+    }
+    match action {
+        1 if packet.len() >= 98 => {
+            let event = match read_u32(packet, 80) {
+                1 => AnnounceEvent::Completed,
+                2 => AnnounceEvent::Started,
+                3 => AnnounceEvent::Stopped,
+                _ => AnnounceEvent::None,
+            };
+            let num_want = read_u32(packet, 92) as i32;
+            let port = u16::from_be_bytes([packet[96], packet[97]]);
+            let announce = Announce {
+                info_hash: packet[16..36].try_into().unwrap(),
+                peer_id: packet[36..56].try_into().unwrap(),
+                addr: peer_addr(addr.ip(), port),
+                left: read_u64(packet, 64),
+                event,
+                num_want: usize::try_from(num_want).ok(),
+            };
+            match tracker.announce(announce) {
+                Ok(announced) => {
+                    reply.extend_from_slice(&ANNOUNCE_INTERVAL.to_be_bytes());
+                    reply.extend_from_slice(&announced.leechers.to_be_bytes());
+                    reply.extend_from_slice(&announced.seeders.to_be_bytes());
+                    // The reply can only hold peers of the client's address family
+                    let (peers4, peers6) = compact_peers(&announced.peers);
+                    match peer_addr(addr.ip(), addr.port()) {
+                        SocketAddr::V4(_) => reply.extend_from_slice(&peers4),
+                        SocketAddr::V6(_) => reply.extend_from_slice(&peers6),
+                    }
+                    Some(reply)
+                }
+                Err(err) => Some(udp_error(transaction_id, &err)),
+            }
+        }
+        2 => {
+            let info_hashes = packet[16..]
+                .chunks_exact(20)
+                .map(|hash| hash.try_into().unwrap())
+                .collect::<Vec<[u8; 20]>>();
+            let scraped = tracker.scrape(&info_hashes);
+            for hash in info_hashes.iter() {
+                let torrent = scraped
+                    .iter()
+                    .find(|(scraped, _)| scraped == hash)
+                    .map(|(_, torrent)| torrent);
+                let (seeders, completed, leechers) = torrent
+                    .map(|torrent| (torrent.seeders, torrent.completed, torrent.leechers))
+                    .unwrap_or_default();
+                reply.extend_from_slice(&seeders.to_be_bytes());
+                reply.extend_from_slice(&completed.to_be_bytes());
+                reply.extend_from_slice(&leechers.to_be_bytes());
+            }
+            Some(reply)
+        }
+        _ => Some(udp_error(transaction_id, "Unknown action")),
+    }
+}
+
+/// Serves the UDP tracker until its socket fails, when it is enabled
+pub async fn udp_tracker(data: SharedData) {
+    let port = match (*TRACKER_ENABLED, *UDP_PORT) {
+        (true, Some(port)) => port,
+        _ => return,
+    };
+    let socket = match UdpSocket::bind(("0.0.0.0", port)).await {
+        Ok(socket) => socket,
+        Err(err) => {
+            log::error!("Cant bind udp tracker {:#?}", err);
+            return;
+        }
+    };
+    let mut packet = [0u8; 2048];
+    loop {
+        let (length, addr) = match socket.recv_from(&mut packet).await {
+            Ok(received) => received,
+            Err(err) => {
+                log::error!("Cant receive udp tracker packet {:#?}", err);
+                return;
+            }
+        };
+        if let Some(reply) = udp_reply(&data.tracker, &packet[..length], addr) {
+            if let Err(err) = socket.send_to(&reply, addr).await {
+                log::warn!("Cant answer udp tracker packet {:#?}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH: [u8; 20] = [0xab; 20];
+
+    fn tracker() -> Tracker {
+        let tracker = Tracker::default();
+        tracker.allowed.lock().unwrap().insert(hash_hex(&HASH));
+        tracker
+    }
+
+    fn query(peer: char, port: u16, left: u64, event: &str) -> String {
+        format!(
+            "info_hash={}&peer_id={}&port={}&left={}&event={}",
+            urlencoding::encode_binary(&HASH),
+            peer.to_string().repeat(20),
+            port,
+            left,
+            event
+        )
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn http_announce_returns_the_swarm() {
+        let tracker = tracker();
+        let reply = http_announce(&tracker, &query('a', 6881, 100, "started"), ip("10.0.0.1"));
+        let reply = reply.unwrap();
+        assert_eq!((reply.seeders, reply.leechers), (0, 1));
+        assert!(reply.peers.is_empty());
+
+        let reply = http_announce(&tracker, &query('b', 6882, 0, "started"), ip("10.0.0.2"));
+        let reply = reply.unwrap();
+        assert_eq!((reply.seeders, reply.leechers), (1, 1));
+        assert_eq!(
+            reply.peers,
+            vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+
+        // Seeders only get peers still downloading, mapped addresses are unmapped
+        let reply = http_announce(&tracker, &query('c', 6883, 0, ""), ip("::ffff:10.0.0.3"));
+        let reply = reply.unwrap();
+        assert_eq!(
+            reply.peers,
+            vec!["10.0.0.1:6881".parse::<SocketAddr>().unwrap()]
+        );
+
+        let reply = http_announce(&tracker, &query('a', 6881, 100, "stopped"), ip("10.0.0.1"));
+        let reply = reply.unwrap();
+        assert_eq!((reply.seeders, reply.leechers), (2, 0));
+        assert_eq!(reply.peers.len(), 2);
+        let torrents = tracker.torrents();
+        assert_eq!(torrents.len(), 1);
+        assert_eq!(torrents[0].info_hash, hash_hex(&HASH));
+        assert_eq!(torrents[0].seeders, 2);
+    }
+
+    #[test]
+    fn http_announce_checks_the_request() {
+        let tracker = tracker();
+        let query = query('a', 6881, 0, "");
+        assert!(http_announce(
+            &tracker,
+            &query.replace("port=6881", "port=70000"),
+            ip("10.0.0.1")
+        )
+        .is_err());
+        assert!(http_announce(
+            &tracker,
+            &query.replace("info_hash", "hash"),
+            ip("10.0.0.1")
+        )
+        .is_err());
+        assert!(http_announce(&Tracker::default(), &query, ip("10.0.0.1")).is_err());
+    }
+
+    fn udp_packet(connection: u64, action: u32, transaction_id: u32, body: &[u8]) -> Vec<u8> {
+        let mut packet = connection.to_be_bytes().to_vec();
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(body);
+        packet
+    }
+
+    fn udp_announce(connection: u64, peer_id: u8, left: u64, port: u16) -> Vec<u8> {
+        let mut body = HASH.to_vec();
+        body.extend_from_slice(&[peer_id; 20]);
+        body.extend_from_slice(&0u64.to_be_bytes());
+        body.extend_from_slice(&left.to_be_bytes());
+        body.extend_from_slice(&0u64.to_be_bytes());
+        // Started event, default ip, key and num want
+        body.extend_from_slice(&2u32.to_be_bytes());
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&0u32.to_be_bytes());
+        body.extend_from_slice(&(-1i32).to_be_bytes());
+        body.extend_from_slice(&port.to_be_bytes());
+        udp_packet(connection, 1, 9, &body)
+    }
+
+    #[test]
+    fn udp_connect_then_announce_and_scrape() {
+        let tracker = tracker();
+        let addr = "10.0.0.1:50000".parse().unwrap();
+        assert!(udp_reply(&tracker, &[0; 15], addr).is_none());
+        assert!(udp_reply(&tracker, &udp_packet(1, 0, 7, &[]), addr).is_none());
+
+        let reply = udp_reply(&tracker, &udp_packet(UDP_PROTOCOL_ID, 0, 7, &[]), addr).unwrap();
+        assert_eq!(reply.len(), 16);
+        assert_eq!((read_u32(&reply, 0), read_u32(&reply, 4)), (0, 7));
+        let connection = read_u64(&reply, 8);
+
+        let leecher = "10.0.0.2:50000".parse().unwrap();
+        let reply = udp_reply(&tracker, &udp_packet(UDP_PROTOCOL_ID, 0, 8, &[]), leecher).unwrap();
+        let reply = udp_reply(
+            &tracker,
+            &udp_announce(read_u64(&reply, 8), 2, 100, 6882),
+            leecher,
+        )
+        .unwrap();
+        assert_eq!(reply.len(), 20);
+
+        let reply = udp_reply(&tracker, &udp_announce(connection, 1, 0, 6881), addr).unwrap();
+        assert_eq!((read_u32(&reply, 0), read_u32(&reply, 4)), (1, 9));
+        assert_eq!(read_u32(&reply, 8), ANNOUNCE_INTERVAL);
+        assert_eq!((read_u32(&reply, 12), read_u32(&reply, 16)), (1, 1));
+        assert_eq!(&reply[20..], &[10u8, 0, 0, 2, 0x1a, 0xe2]);
+
+        let reply = udp_reply(&tracker, &udp_packet(connection, 2, 10, &HASH), addr).unwrap();
+        assert_eq!((read_u32(&reply, 0), read_u32(&reply, 4)), (2, 10));
+        // Seeders, completed and leechers
+        assert_eq!(
+            (
+                read_u32(&reply, 8),
+                read_u32(&reply, 12),
+                read_u32(&reply, 16)
+            ),
+            (1, 0, 1)
+        );
+    }
+
+    #[test]
+    fn udp_rejects_unknown_connections() {
+        let tracker = tracker();
+        let addr = "10.0.0.1:50000".parse().unwrap();
+        let reply = udp_reply(&tracker, &udp_announce(1, 1, 0, 6881), addr).unwrap();
+        assert_eq!((read_u32(&reply, 0), read_u32(&reply, 4)), (3, 9));
+        assert_eq!(&reply[8..], b"Connection expired");
+
+        // Connection ids are bound to the address they were given to
+        let reply = udp_reply(&tracker, &udp_packet(UDP_PROTOCOL_ID, 0, 7, &[]), addr).unwrap();
+        let other = "10.0.0.3:50000".parse().unwrap();
+        let reply = udp_reply(
+            &tracker,
+            &udp_announce(read_u64(&reply, 8), 1, 0, 6881),
+            other,
+        );
+        assert_eq!(read_u32(&reply.unwrap(), 0), 3);
+    }
+}
@@ -0,0 +1,479 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use transmission::Torrent;
+
+use crate::{
+    auth,
+    context::SharedData,
+    new_id,
+    structures::{AddTorrentOptions, HttpHeader, TorrentState},
+    torrent_struc::{magnet_link, TorrentError, TorrentStats},
+    CONFIG_DIR, DOWNLOAD_DIR, INCOMPLETE_DIR, RENAME_PARTIAL_FILES,
+};
+
+const SESSION_ID_HEADER: &str = "x-transmission-session-id";
+const RPC_VERSION: i32 = 17;
+const RPC_VERSION_MINIMUM: i32 = 14;
+/// Torrents with activity within this many seconds are "recently-active"
+const RECENTLY_ACTIVE_SECS: i64 = 60;
+
+lazy_static::lazy_static! {
+    /// Session id used for the CSRF handshake, changes on every start
+    static ref SESSION_ID: String = new_id();
+}
+
+#[derive(Deserialize)]
+pub struct RpcRequest {
+    method: String,
+    #[serde(default)]
+    arguments: Map<String, Value>,
+    tag: Option<Value>,
+}
+
+/// Handles Transmission RPC requests on `/transmission/rpc`
+pub async fn transmission_rpc(
+    Extension(data): Extension<SharedData>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if !auth::check_basic_auth(&headers) {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [("www-authenticate", "Basic realm=\"Transmission\"")],
+            "Unauthorized",
+        )
+            .into_response();
+    }
+    if !session_id_valid(&headers) {
+        return session_conflict();
+    }
+    let request = match serde_json::from_slice::<RpcRequest>(&body) {
+        Ok(request) => request,
+        Err(err) => {
+            return (StatusCode::BAD_REQUEST, format!("Invalid request: {}", err)).into_response()
+        }
+    };
+    log::info!("Transmission rpc {}", request.method);
+    let result = match request.method.as_str() {
+        "torrent-get" => torrent_get(&data, &request.arguments).await,
+        "torrent-add" => torrent_add(&data, &request.arguments).await,
+        "torrent-start" | "torrent-start-now" => {
+            for torrent in select_torrents(&data, request.arguments.get("ids")) {
+                data.start_torrent(&torrent);
+            }
+            Ok(json!({}))
+        }
+        "torrent-stop" => {
+            for torrent in select_torrents(&data, request.arguments.get("ids")) {
+                data.stop_torrent(&torrent);
+            }
+            Ok(json!({}))
+        }
+        "torrent-remove" => {
+            let delete_data = request
+                .arguments
+                .get("delete-local-data")
+                .and_then(Value::as_bool)
+                .unwrap_or(false);
+            for torrent in select_torrents(&data, request.arguments.get("ids")) {
+                data.remove_torrent(torrent.id(), delete_data).await;
+            }
+            Ok(json!({}))
+        }
+        "session-get" => Ok(session_get()),
+        "session-stats" => Ok(session_stats(&data)),
+        _ => Err("method name not recognized".to_string()),
+    };
+    let (result, arguments) = match result {
+        Ok(arguments) => ("success".to_string(), arguments),
+        Err(err) => (err, json!({})),
+    };
+    let mut response = json!({
+        "result": result,
+        "arguments": arguments,
+    });
+    if let Some(tag) = request.tag {
+        response["tag"] = tag;
+    }
+    Json(response).into_response()
+}
+
+fn session_id_valid(headers: &HeaderMap) -> bool {
+    headers
+        .get(SESSION_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        == Some(SESSION_ID.as_str())
+}
+
+/// CSRF handshake, clients without the current session id get it back
+/// with a 409 and retry the request with it
+fn session_conflict() -> Response {
+    (
+        StatusCode::CONFLICT,
+        [(SESSION_ID_HEADER, SESSION_ID.as_str())],
+        format!("{}: {}", SESSION_ID_HEADER, *SESSION_ID),
+    )
+        .into_response()
+}
+
+/// Resolves the `ids` argument: a single id, a list of ids or hashes,
+/// `"recently-active"`, or every torrent when absent
+fn select_torrents(data: &SharedData, ids: Option<&Value>) -> Vec<Torrent> {
+    let mut torrents = data
+        .torrents
+        .iter()
+        .map(|torrent| torrent.value().clone())
+        .collect::<Vec<_>>();
+    torrents.sort_by_key(|torrent| torrent.id());
+    let matches = |torrent: &Torrent, id: &Value| match id {
+        Value::Number(number) => number.as_i64() == Some(torrent.id() as i64),
+        Value::String(hash) => data.torrent_hash(torrent).eq_ignore_ascii_case(hash),
+        _ => false,
+    };
+    match ids {
+        None => torrents,
+        Some(Value::String(ids)) if ids == "recently-active" => {
+            let now = chrono::Utc::now().naive_utc();
+            torrents
+                .into_iter()
+                .filter(|torrent| {
+                    (now - torrent.stats().activity_date).num_seconds() < RECENTLY_ACTIVE_SECS
+                })
+                .collect()
+        }
+        Some(Value::Array(ids)) => torrents
+            .into_iter()
+            .filter(|torrent| ids.iter().any(|id| matches(torrent, id)))
+            .collect(),
+        Some(id) => torrents
+            .into_iter()
+            .filter(|torrent| matches(torrent, id))
+            .collect(),
+    }
+}
+
+fn rpc_status(state: TorrentState) -> i32 {
+    match state {
+        TorrentState::Stopped | TorrentState::Error => 0,
+        TorrentState::CheckingWait => 1,
+        TorrentState::Checking => 2,
+        TorrentState::DownloadingWait => 3,
+        TorrentState::Downloading => 4,
+        TorrentState::SeedingWait => 5,
+        TorrentState::Seeding => 6,
+    }
+}
+
+fn rpc_error(error: TorrentError) -> i32 {
+    match error {
+        TorrentError::NoError => 0,
+        TorrentError::StatTrackerWarn => 1,
+        TorrentError::StatTracker => 2,
+        _ => 3,
+    }
+}
+
+/// Transmission reports speeds in bytes per second, libtransmission in kB/s
+fn bytes_per_sec(kbps: f32) -> i64 {
+    (kbps * 1000.0) as i64
+}
+
+fn torrent_fields(data: &SharedData, torrent: &Torrent, fields: &[String]) -> Map<String, Value> {
+    let stats = TorrentStats::from(torrent.stats());
+    // Info and file progress are only fetched when a requested field needs them
+    let mut info = None;
+    let mut completed = None;
+    let mut fields_map = Map::new();
+    for field in fields {
+        if let Some(value) = stats_field(data, torrent, &stats, field) {
+            fields_map.insert(field.clone(), value);
+            continue;
+        }
+        let info = info.get_or_insert_with(|| torrent.info());
+        if matches!(field.as_str(), "files" | "fileStats") && completed.is_none() {
+            completed = Some(data.pieces.files_completed(torrent, info));
+        }
+        let completed = completed.as_deref().unwrap_or_default();
+        if let Some(value) = info_field(info, completed, field) {
+            fields_map.insert(field.clone(), value);
+        }
+    }
+    fields_map
+}
+
+fn stats_field(
+    data: &SharedData,
+    torrent: &Torrent,
+    stats: &TorrentStats,
+    field: &str,
+) -> Option<Value> {
+    let value = match field {
+        "id" => json!(torrent.id()),
+        "status" => json!(rpc_status(stats.state)),
+        "error" => json!(rpc_error(stats.error)),
+        "errorString" => json!(stats.error_string),
+        "percentDone" => json!(stats.percent_done),
+        "metadataPercentComplete" => json!(stats.metadata_percent_complete),
+        "recheckProgress" => json!(stats.recheck_progress),
+        "rateDownload" => json!(bytes_per_sec(stats.piece_download_speed_kbps)),
+        "rateUpload" => json!(bytes_per_sec(stats.piece_upload_speed_kbps)),
+        "eta" => json!(stats.eta),
+        "sizeWhenDone" => json!(stats.size_when_done),
+        "leftUntilDone" => json!(stats.left_until_done),
+        "desiredAvailable" => json!(stats.desired_available),
+        "uploadedEver" => json!(stats.uploaded_ever),
+        "downloadedEver" => json!(stats.downloaded_ever),
+        "corruptEver" => json!(stats.corrupt_ever),
+        "haveValid" => json!(stats.have_valid),
+        "haveUnchecked" => json!(stats.have_unchecked),
+        "uploadRatio" => json!(stats.ratio),
+        "addedDate" => json!(stats.added_date.timestamp()),
+        "doneDate" => json!(stats.done_date.timestamp()),
+        "startDate" => json!(stats.start_date.timestamp()),
+        "activityDate" => json!(stats.activity_date.timestamp()),
+        "isFinished" => json!(stats.finished),
+        "isStalled" => json!(stats.is_stalled),
+        "peersConnected" => json!(stats.peers_connected),
+        "peersSendingToUs" => json!(stats.peers_sending_to_us),
+        "peersGettingFromUs" => json!(stats.peers_getting_from_us),
+        "webseedsSendingToUs" => json!(stats.webseeds_sending_to_us),
+        "queuePosition" => json!(stats.queue_position),
+        "secondsDownloading" => json!(stats.seconds_downloading),
+        "secondsSeeding" => json!(stats.seconds_seeding),
+        "downloadDir" => json!(data.download_dirs.dir(torrent.id()).to_string_lossy()),
+        "labels" => json!([]),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Field of the torrent's info, `completed` has the bytes downloaded of each file
+/// when files are requested
+fn info_field(
+    info: &transmission::torrent::TorrentInfo,
+    completed: &[u64],
+    field: &str,
+) -> Option<Value> {
+    let value = match field {
+        "name" => json!(info.name),
+        "hashString" => json!(info.hash_string),
+        "totalSize" => json!(info.total_size),
+        "comment" => json!(info.comment),
+        "creator" => json!(info.creator),
+        "dateCreated" => json!(info.date_created.timestamp()),
+        "isPrivate" => json!(info.is_private),
+        "pieceCount" => json!(info.piece_count),
+        "pieceSize" => json!(info.piece_size),
+        "torrentFile" => json!(info.torrent),
+        "magnetLink" => json!(magnet_link(info)),
+        "webseeds" => json!(info.webseeds),
+        "trackers" => Value::Array(
+            info.trackers
+                .iter()
+                .map(|tracker| {
+                    json!({
+                        "id": tracker.id,
+                        "tier": tracker.tier,
+                        "announce": tracker.announce,
+                        "scrape": tracker.scrape,
+                    })
+                })
+                .collect(),
+        ),
+        "files" => Value::Array(
+            info.files
+                .iter()
+                .zip(completed)
+                .map(|(file, completed)| {
+                    json!({
+                        "name": file.name,
+                        "length": file.length,
+                        "bytesCompleted": completed,
+                    })
+                })
+                .collect(),
+        ),
+        "fileStats" => Value::Array(
+            info.files
+                .iter()
+                .zip(completed)
+                .map(|(file, completed)| {
+                    json!({
+                        "bytesCompleted": completed,
+                        "wanted": file.dnd == 0,
+                        "priority": 0,
+                    })
+                })
+                .collect(),
+        ),
+        _ => return None,
+    };
+    Some(value)
+}
+
+async fn torrent_get(data: &SharedData, arguments: &Map<String, Value>) -> Result<Value, String> {
+    let fields = arguments
+        .get("fields")
+        .and_then(Value::as_array)
+        .ok_or("no fields specified")?
+        .iter()
+        .filter_map(|field| field.as_str().map(String::from))
+        .collect::<Vec<_>>();
+    let torrents = select_torrents(data, arguments.get("ids"))
+        .iter()
+        .map(|torrent| Value::Object(torrent_fields(data, torrent, &fields)))
+        .collect::<Vec<_>>();
+    Ok(json!({ "torrents": torrents }))
+}
+
+async fn torrent_add(data: &SharedData, arguments: &Map<String, Value>) -> Result<Value, String> {
+    let options = AddTorrentOptions {
+        paused: arguments
+            .get("paused")
+            .and_then(Value::as_bool)
+            .unwrap_or(false),
+        download_dir: arguments
+            .get("download-dir")
+            .and_then(Value::as_str)
+            .map(String::from),
+        ..Default::default()
+    };
+    let id = if let Some(metainfo) = arguments.get("metainfo").and_then(Value::as_str) {
+        let metainfo = base64::decode(metainfo).map_err(|_| "invalid or corrupt torrent file")?;
+        data.add_metainfo(&metainfo, options).await?
+    } else if let Some(filename) = arguments.get("filename").and_then(Value::as_str) {
+        if filename.starts_with("magnet:")
+            || filename.starts_with("http://")
+            || filename.starts_with("https://")
+        {
+            let headers = arguments
+                .get("cookies")
+                .and_then(Value::as_str)
+                .map(|cookies| HttpHeader {
+                    name: "cookie".into(),
+                    value: cookies.into(),
+                })
+                .into_iter()
+                .collect::<Vec<_>>();
+            data.add_url(filename, &headers, None, options).await?
+        } else {
+            // Local paths are not read, they could point anywhere on the server
+            return Err("invalid or corrupt torrent file".into());
+        }
+    } else {
+        return Err("no filename or metainfo specified".into());
+    };
+    let torrent = data
+        .torrents
+        .get(&id)
+        .map(|torrent| torrent.value().clone())
+        .ok_or("torrent not found")?;
+    let info = torrent.info();
+    Ok(json!({
+        "torrent-added": {
+            "id": id,
+            "name": info.name,
+            "hashString": info.hash_string,
+        }
+    }))
+}
+
+fn session_get() -> Value {
+    json!({
+        "download-dir": DOWNLOAD_DIR.clone(),
+        "config-dir": CONFIG_DIR.clone(),
+        "version": format!("3.00 (torexpo {})", env!("CARGO_PKG_VERSION")),
+        "rpc-version": RPC_VERSION,
+        "rpc-version-minimum": RPC_VERSION_MINIMUM,
+        "session-id": SESSION_ID.clone(),
+        "seedRatioLimit": 0.0,
+        "seedRatioLimited": false,
+        "idle-seeding-limit": 0,
+        "idle-seeding-limit-enabled": false,
+        "speed-limit-down-enabled": false,
+        "speed-limit-up-enabled": false,
+        "alt-speed-enabled": false,
+        "incomplete-dir-enabled": INCOMPLETE_DIR.is_some(),
+        "incomplete-dir": INCOMPLETE_DIR.clone().unwrap_or_default(),
+        "rename-partial-files": *RENAME_PARTIAL_FILES,
+        "start-added-torrents": true,
+        "units": {
+            "speed-units": ["kB/s", "MB/s", "GB/s", "TB/s"],
+            "speed-bytes": 1000,
+            "size-units": ["kB", "MB", "GB", "TB"],
+            "size-bytes": 1000,
+            "memory-units": ["KiB", "MiB", "GiB", "TiB"],
+            "memory-bytes": 1024,
+        },
+    })
+}
+
+fn session_stats(data: &SharedData) -> Value {
+    let mut active = 0;
+    let mut paused = 0;
+    let mut download_speed = 0;
+    let mut upload_speed = 0;
+    let mut uploaded = 0;
+    let mut downloaded = 0;
+    for torrent in data.torrents.iter() {
+        let stats = TorrentStats::from(torrent.value().stats());
+        if stats.state == TorrentState::Stopped {
+            paused += 1;
+        } else {
+            active += 1;
+        }
+        download_speed += bytes_per_sec(stats.piece_download_speed_kbps);
+        upload_speed += bytes_per_sec(stats.piece_upload_speed_kbps);
+        uploaded += stats.uploaded_ever;
+        downloaded += stats.downloaded_ever;
+    }
+    let totals = json!({
+        "uploadedBytes": uploaded,
+        "downloadedBytes": downloaded,
+        "filesAdded": data.torrents.len(),
+        "sessionCount": 1,
+        "secondsActive": 0,
+    });
+    json!({
+        "activeTorrentCount": active,
+        "pausedTorrentCount": paused,
+        "torrentCount": data.torrents.len(),
+        "downloadSpeed": download_speed,
+        "uploadSpeed": upload_speed,
+        "cumulative-stats": totals.clone(),
+        "current-stats": totals,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn session_id_is_handed_out_with_a_conflict() {
+        assert!(!session_id_valid(&HeaderMap::new()));
+        let response = session_conflict();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let session_id = response.headers().get(SESSION_ID_HEADER).unwrap().clone();
+        assert_eq!(session_id, SESSION_ID.as_str());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(SESSION_ID_HEADER, session_id);
+        assert!(session_id_valid(&headers));
+    }
+
+    #[test]
+    fn stale_session_id_is_rejected() {
+        let mut headers = HeaderMap::new();
+        headers.insert(SESSION_ID_HEADER, HeaderValue::from_static("stale"));
+        assert!(!session_id_valid(&headers));
+    }
+}
@@ -0,0 +1,420 @@
+use std::{
+    collections::HashSet,
+    future::Future,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use notify::{PollWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use crate::{context::SharedData, structures::AddTorrentOptions};
+
+lazy_static::lazy_static! {
+    /// Folder watched for new .torrent and .magnet files, disabled when not set
+    static ref WATCH_DIR: Option<String> = std::env::var("TOREXPO_WATCH_DIR").ok();
+}
+
+/// Add options of a folder, read from this file inside it
+const FOLDER_CONFIG: &str = "torexpo.json";
+const DONE_DIR: &str = "done";
+const FAILED_DIR: &str = "failed";
+
+/// Files modified more recently may still be being copied
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+/// Rescan interval when file system events are missed
+const RESCAN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Torrent read from a file of the watch folder
+enum Dropped {
+    Metainfo(Vec<u8>),
+    Magnet(String),
+}
+
+/// Adds torrents dropped into the watch folder or its subfolders.
+///
+/// Files are moved to the `done` or `failed` subfolder of their folder once processed.
+/// The magnet links of a .magnet file which couldn't be added go to a file of the same
+/// name in `failed`, so dropping it again only retries those.
+pub async fn watch_folder(data: SharedData) {
+    let root = match &*WATCH_DIR {
+        Some(root) => PathBuf::from(root),
+        None => return,
+    };
+    if let Err(err) = tokio::fs::create_dir_all(&root).await {
+        log::warn!("Cant create watch folder {:#?}", err);
+        return;
+    }
+
+    let (sender, mut changes) = mpsc::unbounded_channel();
+    let _watcher = match start_watcher(&root, sender) {
+        Ok(watcher) => Some(watcher),
+        Err(err) => {
+            log::warn!("Cant watch folder, rescanning periodically {:#?}", err);
+            None
+        }
+    };
+
+    let add = |dropped: Dropped, options: AddTorrentOptions| {
+        let data = data.clone();
+        async move {
+            match dropped {
+                Dropped::Metainfo(metainfo) => data.add_metainfo(&metainfo, options).await,
+                Dropped::Magnet(link) => data.add_magnet(&link, options).await,
+            }
+        }
+    };
+    let mut settling = false;
+    loop {
+        let interval = if settling {
+            SETTLE_TIME
+        } else {
+            RESCAN_INTERVAL
+        };
+        tokio::select! {
+            _ = changes.recv() => {
+                // Events come in bursts while files are written
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                while changes.try_recv().is_ok() {}
+            }
+            _ = tokio::time::sleep(interval) => {}
+        }
+        settling = scan(&root, &add).await;
+    }
+}
+
+/// Watches with inotify, falling back to polling where it isn't available
+fn start_watcher(
+    root: &Path,
+    sender: mpsc::UnboundedSender<()>,
+) -> notify::Result<Box<dyn Watcher + Send>> {
+    let handler = |sender: mpsc::UnboundedSender<()>| {
+        move |event: notify::Result<notify::Event>| {
+            if event.is_ok() {
+                let _ = sender.send(());
+            }
+        }
+    };
+    let mut watcher: Box<dyn Watcher + Send> =
+        match notify::recommended_watcher(handler(sender.clone())) {
+            Ok(watcher) => Box::new(watcher),
+            Err(err) => {
+                log::warn!("Cant use inotify, polling watch folder {:#?}", err);
+                Box::new(PollWatcher::new(
+                    handler(sender),
+                    notify::Config::default().with_poll_interval(SETTLE_TIME),
+                )?)
+            }
+        };
+    watcher.watch(root, RecursiveMode::Recursive)?;
+    Ok(watcher)
+}
+
+/// Processes the files of the watch folder and its subfolders.
+///
+/// Returns whether some files were skipped because they are still being written.
+async fn scan<F, Fut>(root: &Path, add: &F) -> bool
+where
+    F: Fn(Dropped, AddTorrentOptions) -> Fut,
+    Fut: Future<Output = Result<i32, String>>,
+{
+    let mut settling = false;
+    let mut folders = vec![(root.to_path_buf(), AddTorrentOptions::default())];
+    let mut visited = HashSet::new();
+    while let Some((folder, inherited)) = folders.pop() {
+        if !visited.insert(folder.clone()) {
+            continue;
+        }
+        let mut entries = match tokio::fs::read_dir(&folder).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                log::warn!("Cant read watch folder {:#?}", err);
+                continue;
+            }
+        };
+        let options = folder_options(&folder, inherited).await;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let path = entry.path();
+            let metadata = match entry.metadata().await {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                let name = entry.file_name();
+                if name != DONE_DIR && name != FAILED_DIR {
+                    folders.push((path, options.clone()));
+                }
+                continue;
+            }
+            let extension = path
+                .extension()
+                .and_then(|extension| extension.to_str())
+                .map(str::to_lowercase);
+            if !matches!(extension.as_deref(), Some("torrent") | Some("magnet")) {
+                continue;
+            }
+            let modified = metadata
+                .modified()
+                .ok()
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok());
+            if modified.map(|age| age < SETTLE_TIME).unwrap_or(false) {
+                settling = true;
+                continue;
+            }
+            let moved = match add_file(&path, options.clone(), add).await {
+                Ok(added) if added.failed.is_empty() => {
+                    log::info!("Added {:?} from watch folder {}", added.ids, path.display());
+                    move_to(&path, &folder.join(DONE_DIR)).await
+                }
+                Ok(added) => {
+                    log::info!("Added {:?} from watch folder {}", added.ids, path.display());
+                    for (link, err) in added.failed.iter() {
+                        log::warn!("Cant add {} from watch folder {}", link, err);
+                    }
+                    split_magnets(&path, &folder, &added).await
+                }
+                Err(err) => {
+                    log::warn!("Cant add {} from watch folder {}", path.display(), err);
+                    move_to(&path, &folder.join(FAILED_DIR)).await
+                }
+            };
+            if let Err(err) = moved {
+                log::warn!("Cant move processed file {:#?}", err);
+            }
+        }
+    }
+    settling
+}
+
+/// Options from the folder's config, or those of its parent folder
+async fn folder_options(folder: &Path, inherited: AddTorrentOptions) -> AddTorrentOptions {
+    match tokio::fs::read(folder.join(FOLDER_CONFIG)).await {
+        Ok(config) => serde_json::from_slice(&config).unwrap_or_else(|err| {
+            log::warn!("Cant read watch folder config {:#?}", err);
+            inherited
+        }),
+        Err(_) => inherited,
+    }
+}
+
+/// Torrents added from a file, some magnet links of a .magnet file may have failed
+struct AddedFile {
+    ids: Vec<i32>,
+    /// Magnet links which were added
+    added: Vec<String>,
+    /// Magnet links which couldn't be added, with the reason
+    failed: Vec<(String, String)>,
+}
+
+/// Adds the torrent of a .torrent file, or every magnet link of a .magnet file.
+///
+/// Fails when nothing could be added.
+async fn add_file<F, Fut>(
+    path: &Path,
+    options: AddTorrentOptions,
+    add: &F,
+) -> Result<AddedFile, String>
+where
+    F: Fn(Dropped, AddTorrentOptions) -> Fut,
+    Fut: Future<Output = Result<i32, String>>,
+{
+    let contents = tokio::fs::read(path)
+        .await
+        .map_err(|err| format!("Cant read file {}", err))?;
+    let is_magnet = path
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("magnet"))
+        .unwrap_or(false);
+    if !is_magnet {
+        let id = add(Dropped::Metainfo(contents), options).await?;
+        return Ok(AddedFile {
+            ids: vec![id],
+            added: vec![],
+            failed: vec![],
+        });
+    }
+    let text = String::from_utf8(contents).map_err(|_| "Not valid magnet file")?;
+    let links = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with("magnet:"))
+        .collect::<Vec<_>>();
+    if links.is_empty() {
+        return Err("No magnet link found".into());
+    }
+    let mut added = AddedFile {
+        ids: vec![],
+        added: vec![],
+        failed: vec![],
+    };
+    for link in links {
+        match add(Dropped::Magnet(link.into()), options.clone()).await {
+            Ok(id) => {
+                added.ids.push(id);
+                added.added.push(link.into());
+            }
+            Err(err) => added.failed.push((link.into(), err)),
+        }
+    }
+    if added.ids.is_empty() {
+        let errors = added.failed.into_iter().map(|(_link, err)| err);
+        return Err(errors.collect::<Vec<_>>().join(", "));
+    }
+    Ok(added)
+}
+
+/// Path for a file of that name in the folder, keeping earlier files of the same name
+async fn free_path(folder: &Path, name: &str) -> std::io::Result<PathBuf> {
+    tokio::fs::create_dir_all(folder).await?;
+    let target = folder.join(name);
+    if tokio::fs::metadata(&target).await.is_err() {
+        return Ok(target);
+    }
+    Ok(folder.join(format!(
+        "{}-{}",
+        chrono::Utc::now().format("%Y%m%d%H%M%S"),
+        name
+    )))
+}
+
+/// Moves a file into the folder, keeping earlier files of the same name
+async fn move_to(path: &Path, folder: &Path) -> std::io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    tokio::fs::rename(path, free_path(folder, &name).await?).await
+}
+
+/// Moves a .magnet file to `done` with the links which were added, the failed
+/// links go to a file of the same name in `failed`
+async fn split_magnets(path: &Path, folder: &Path, added: &AddedFile) -> std::io::Result<()> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let failed = added
+        .failed
+        .iter()
+        .map(|(link, _err)| format!("{}\n", link))
+        .collect::<String>();
+    tokio::fs::write(free_path(&folder.join(FAILED_DIR), &name).await?, failed).await?;
+    let links = added
+        .added
+        .iter()
+        .map(|link| format!("{}\n", link))
+        .collect::<String>();
+    tokio::fs::write(path, links).await?;
+    move_to(path, &folder.join(DONE_DIR)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Writes a file old enough to be processed
+    fn drop_file(path: &Path, contents: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(60))
+            .unwrap();
+    }
+
+    /// Adds every torrent except magnet links containing `bad`, recording what was added
+    /// and whether it was paused
+    fn adder(
+        added: &Mutex<Vec<(String, bool)>>,
+    ) -> impl Fn(Dropped, AddTorrentOptions) -> std::future::Ready<Result<i32, String>> + '_ {
+        move |dropped, options| {
+            let name = match dropped {
+                Dropped::Metainfo(metainfo) => String::from_utf8(metainfo).unwrap(),
+                Dropped::Magnet(link) => link,
+            };
+            let result = if name.contains("bad") {
+                Err("Invalid magnet link".to_string())
+            } else {
+                let mut added = added.lock().unwrap();
+                added.push((name, options.paused));
+                Ok(added.len() as i32)
+            };
+            std::future::ready(result)
+        }
+    }
+
+    fn read(path: &Path) -> String {
+        std::fs::read_to_string(path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn processed_files_are_moved() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        drop_file(&root.join("movie.torrent"), "metainfo");
+        drop_file(&root.join("broken.magnet"), "magnet:?xt=bad\n");
+        drop_file(&root.join("notes.txt"), "magnet:?xt=ignored\n");
+        std::fs::write(root.join("copying.torrent"), "partial").unwrap();
+        let added = Mutex::new(vec![]);
+
+        assert!(scan(root, &adder(&added)).await);
+        assert_eq!(
+            *added.lock().unwrap(),
+            vec![("metainfo".to_string(), false)]
+        );
+        assert_eq!(read(&root.join("done/movie.torrent")), "metainfo");
+        assert_eq!(read(&root.join("failed/broken.magnet")), "magnet:?xt=bad\n");
+        assert!(root.join("notes.txt").exists());
+        assert!(root.join("copying.torrent").exists());
+        assert!(!root.join("movie.torrent").exists());
+        assert!(!root.join("broken.magnet").exists());
+    }
+
+    #[tokio::test]
+    async fn failed_magnet_links_are_kept_for_a_retry() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        drop_file(
+            &root.join("links.magnet"),
+            "magnet:?xt=first\n\nmagnet:?xt=bad\n  magnet:?xt=second  \n",
+        );
+        let added = Mutex::new(vec![]);
+
+        assert!(!scan(root, &adder(&added)).await);
+        assert_eq!(
+            read(&root.join("done/links.magnet")),
+            "magnet:?xt=first\nmagnet:?xt=second\n"
+        );
+        assert_eq!(read(&root.join("failed/links.magnet")), "magnet:?xt=bad\n");
+        assert!(!root.join("links.magnet").exists());
+
+        // Dropping the failed file again only retries its links
+        let failed = read(&root.join("failed/links.magnet"));
+        std::fs::remove_file(root.join("failed/links.magnet")).unwrap();
+        drop_file(&root.join("links.magnet"), &failed);
+        scan(root, &adder(&added)).await;
+        assert_eq!(added.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn subfolders_use_their_config() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        drop_file(&root.join("tv/torexpo.json"), r#"{"paused": true}"#);
+        drop_file(&root.join("tv/season/episode.magnet"), "magnet:?xt=episode");
+        drop_file(&root.join("movie.magnet"), "magnet:?xt=movie");
+        drop_file(&root.join("done/old.magnet"), "magnet:?xt=old");
+        let added = Mutex::new(vec![]);
+
+        scan(root, &adder(&added)).await;
+        let mut added = added.into_inner().unwrap();
+        added.sort();
+        assert_eq!(
+            added,
+            vec![
+                ("magnet:?xt=episode".to_string(), true),
+                ("magnet:?xt=movie".to_string(), false),
+            ]
+        );
+        assert!(root.join("tv/season/done/episode.magnet").exists());
+        assert!(root.join("done/old.magnet").exists());
+    }
+}
@@ -0,0 +1,458 @@
+use std::{sync::Mutex, time::Duration};
+
+use async_graphql::{Enum, InputObject, SimpleObject};
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{
+    context::SharedData,
+    events::{TorrentEvent, TorrentEventKind},
+    new_id,
+    structures::{TorrentFile, TorrentState},
+    torrent_struc::{TorrentInfoSummary, TorrentStats},
+    CONFIG_DIR, PUBLIC_URL,
+};
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+/// Deliveries are dropped after failing this many times
+const MAX_ATTEMPTS: u32 = 10;
+const FIRST_RETRY_SECS: i64 = 30;
+const MAX_RETRY_SECS: i64 = 6 * 60 * 60;
+/// Deliveries being sent aren't due again for this long, they are retried after a restart
+const LEASE_SECS: i64 = 60;
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum WebhookEvent {
+    Added,
+    Completed,
+    /// The torrent stopped with an error
+    Errored,
+    Removed,
+}
+
+impl WebhookEvent {
+    fn from_event(event: &TorrentEvent) -> Option<Self> {
+        match event.kind {
+            TorrentEventKind::Added => Some(Self::Added),
+            TorrentEventKind::Completed => Some(Self::Completed),
+            TorrentEventKind::Removed => Some(Self::Removed),
+            TorrentEventKind::StateChanged if event.state == Some(TorrentState::Error) => {
+                Some(Self::Errored)
+            }
+            TorrentEventKind::StateChanged => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Completed => "completed",
+            Self::Errored => "errored",
+            Self::Removed => "removed",
+        }
+    }
+}
+
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Webhook {
+    pub id: String,
+    pub name: String,
+    pub url: String,
+    /// Key of the `X-Torexpo-Signature` HMAC-SHA256, requests are unsigned without it
+    #[graphql(skip)]
+    pub secret: Option<String>,
+    /// Events sent to the webhook, all of them when empty
+    pub events: Vec<WebhookEvent>,
+    /// Expiry of the download links in the payload
+    pub link_expiry_secs: Option<u64>,
+    pub enabled: bool,
+}
+
+#[derive(InputObject)]
+pub struct WebhookInput {
+    pub name: String,
+    pub url: String,
+    pub secret: Option<String>,
+    #[graphql(default)]
+    pub events: Vec<WebhookEvent>,
+    pub link_expiry_secs: Option<u64>,
+    #[graphql(default = true)]
+    pub enabled: bool,
+}
+
+impl Webhook {
+    fn wants(&self, event: WebhookEvent) -> bool {
+        self.enabled && (self.events.is_empty() || self.events.contains(&event))
+    }
+}
+
+/// Payload of an event waiting to be delivered to a webhook
+#[derive(SimpleObject, Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct WebhookDelivery {
+    pub id: String,
+    pub webhook_id: String,
+    pub event: WebhookEvent,
+    pub torrent_id: i32,
+    #[graphql(skip)]
+    pub body: String,
+    /// Failed attempts so far
+    pub attempts: u32,
+    pub next_attempt: DateTime<Utc>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WebhookPayload {
+    event: WebhookEvent,
+    event_id: u64,
+    time: DateTime<Utc>,
+    torrent_id: i32,
+    name: String,
+    state: Option<TorrentState>,
+    /// None once the torrent is removed
+    stats: Option<TorrentStats>,
+    info: Option<TorrentInfoSummary>,
+    files: Vec<PayloadFile>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PayloadFile {
+    #[serde(flatten)]
+    file: TorrentFile,
+    download_link: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SavedWebhooks {
+    webhooks: Vec<Webhook>,
+    queue: Vec<WebhookDelivery>,
+}
+
+/// Configured webhooks and their retry queue, saved in the config directory
+#[derive(Default)]
+pub struct WebhookManager {
+    webhooks: DashMap<String, Webhook>,
+    queue: Mutex<Vec<WebhookDelivery>>,
+}
+
+/// Hex HMAC-SHA256 of the body
+fn signature(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key length");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn retry_delay(attempts: u32) -> chrono::Duration {
+    let secs = FIRST_RETRY_SECS.saturating_mul(1 << attempts.saturating_sub(1).min(20));
+    chrono::Duration::seconds(secs.min(MAX_RETRY_SECS))
+}
+
+impl WebhookManager {
+    fn path() -> std::path::PathBuf {
+        std::path::Path::new(&CONFIG_DIR.clone()).join("webhooks.json")
+    }
+
+    pub async fn load() -> Self {
+        let manager = WebhookManager::default();
+        if let Ok(saved) = tokio::fs::read(Self::path()).await {
+            match serde_json::from_slice::<SavedWebhooks>(&saved) {
+                Ok(saved) => {
+                    for webhook in saved.webhooks {
+                        manager.webhooks.insert(webhook.id.clone(), webhook);
+                    }
+                    *manager.queue.lock().unwrap() = saved.queue;
+                }
+                Err(err) => log::warn!("Cant read webhooks {:#?}", err),
+            }
+        }
+        manager
+    }
+
+    async fn save(&self) {
+        let saved = serde_json::to_vec_pretty(&SavedWebhooks {
+            webhooks: self.webhooks(),
+            queue: self.deliveries(),
+        });
+        match saved {
+            Ok(saved) => {
+                if let Err(err) = tokio::fs::write(Self::path(), saved).await {
+                    log::warn!("Cant save webhooks {:#?}", err);
+                }
+            }
+            Err(err) => log::warn!("Cant save webhooks {:#?}", err),
+        }
+    }
+
+    pub fn webhooks(&self) -> Vec<Webhook> {
+        let mut webhooks = self
+            .webhooks
+            .iter()
+            .map(|webhook| webhook.value().clone())
+            .collect::<Vec<_>>();
+        webhooks.sort_by(|a, b| a.name.cmp(&b.name));
+        webhooks
+    }
+
+    /// Deliveries waiting for their first or next attempt
+    pub fn deliveries(&self) -> Vec<WebhookDelivery> {
+        self.queue.lock().unwrap().clone()
+    }
+
+    pub async fn add_webhook(&self, input: WebhookInput) -> Webhook {
+        let webhook = Webhook {
+            id: new_id(),
+            name: input.name,
+            url: input.url,
+            secret: input.secret.filter(|secret| !secret.is_empty()),
+            events: input.events,
+            link_expiry_secs: input.link_expiry_secs,
+            enabled: input.enabled,
+        };
+        self.webhooks.insert(webhook.id.clone(), webhook.clone());
+        self.save().await;
+        webhook
+    }
+
+    pub async fn update_webhook(&self, id: &str, input: WebhookInput) -> Result<Webhook, String> {
+        let webhook = {
+            let mut webhook = self.webhooks.get_mut(id).ok_or("Webhook not found")?;
+            webhook.name = input.name;
+            webhook.url = input.url;
+            webhook.secret = input.secret.filter(|secret| !secret.is_empty());
+            webhook.events = input.events;
+            webhook.link_expiry_secs = input.link_expiry_secs;
+            webhook.enabled = input.enabled;
+            webhook.clone()
+        };
+        self.save().await;
+        Ok(webhook)
+    }
+
+    pub async fn remove_webhook(&self, id: &str) -> bool {
+        if self.webhooks.remove(id).is_none() {
+            return false;
+        }
+        self.queue
+            .lock()
+            .unwrap()
+            .retain(|delivery| delivery.webhook_id != id);
+        self.save().await;
+        true
+    }
+
+    /// Queues the event for every webhook that wants it
+    async fn enqueue(&self, data: &SharedData, event: &TorrentEvent) {
+        let kind = match WebhookEvent::from_event(event) {
+            Some(kind) => kind,
+            None => return,
+        };
+        let webhooks = self
+            .webhooks()
+            .into_iter()
+            .filter(|webhook| webhook.wants(kind))
+            .collect::<Vec<_>>();
+        if webhooks.is_empty() {
+            return;
+        }
+        let torrent = data
+            .torrents
+            .get(&event.torrent_id)
+            .map(|torrent| torrent.value().clone())
+            .filter(|_| kind != WebhookEvent::Removed);
+        let mut deliveries = vec![];
+        for webhook in webhooks {
+            let mut payload = WebhookPayload {
+                event: kind,
+                event_id: event.id,
+                time: event.time,
+                torrent_id: event.torrent_id,
+                name: event.name.clone(),
+                state: event.state,
+                stats: None,
+                info: None,
+                files: vec![],
+            };
+            if let Some(torrent) = &torrent {
+                let info = torrent.info();
+                payload.stats = Some(TorrentStats::from(torrent.stats()));
+                for (index, file) in info.files.iter().enumerate() {
+                    let file =
+                        TorrentFile::new(event.torrent_id, &info.hash_string, index as u32, file);
+                    let download_link = file
+                        .signed_download_link(&data.download_dirs, webhook.link_expiry_secs)
+                        .await
+                        .map(|link| format!("{}{}", *PUBLIC_URL, link));
+                    payload.files.push(PayloadFile {
+                        file,
+                        download_link,
+                    });
+                }
+                payload.info = Some((&info).into());
+            }
+            match serde_json::to_string(&payload) {
+                Ok(body) => deliveries.push(WebhookDelivery {
+                    id: new_id(),
+                    webhook_id: webhook.id,
+                    event: kind,
+                    torrent_id: event.torrent_id,
+                    body,
+                    attempts: 0,
+                    next_attempt: Utc::now(),
+                    last_error: None,
+                }),
+                Err(err) => log::warn!("Cant serialize webhook payload {:#?}", err),
+            }
+        }
+        self.queue.lock().unwrap().extend(deliveries);
+        self.save().await;
+    }
+
+    async fn send(
+        &self,
+        client: &reqwest::Client,
+        webhook: &Webhook,
+        delivery: &WebhookDelivery,
+    ) -> Result<(), String> {
+        let mut request = client
+            .post(&webhook.url)
+            .timeout(DELIVERY_TIMEOUT)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header("X-Torexpo-Event", delivery.event.name())
+            .header("X-Torexpo-Delivery", &delivery.id);
+        if let Some(secret) = &webhook.secret {
+            request = request.header(
+                "X-Torexpo-Signature",
+                format!("sha256={}", signature(secret, &delivery.body)),
+            );
+        }
+        request
+            .body(delivery.body.clone())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+
+    /// Attempts the deliveries that are due, retrying failures with exponential backoff
+    async fn deliver_due(&self, client: &reqwest::Client) {
+        let now = Utc::now();
+        // Due deliveries stay queued, and saved, while they are sent
+        let due = {
+            let mut queue = self.queue.lock().unwrap();
+            queue
+                .iter_mut()
+                .filter(|delivery| delivery.next_attempt <= now)
+                .map(|delivery| {
+                    delivery.next_attempt = now + chrono::Duration::seconds(LEASE_SECS);
+                    delivery.clone()
+                })
+                .collect::<Vec<_>>()
+        };
+        if due.is_empty() {
+            return;
+        }
+        let attempts = due.into_iter().map(|delivery| async move {
+            // Deliveries of removed webhooks are dropped
+            let webhook = match self.webhooks.get(&delivery.webhook_id) {
+                Some(webhook) => webhook.value().clone(),
+                None => return (delivery.id, String::new(), Ok(())),
+            };
+            let result = self.send(client, &webhook, &delivery).await;
+            (delivery.id, webhook.url, result)
+        });
+        let results = futures_util::future::join_all(attempts).await;
+        {
+            let mut queue = self.queue.lock().unwrap();
+            for (id, url, result) in results {
+                // Deliveries removed with their webhook while sending are gone already
+                let index = match queue.iter().position(|delivery| delivery.id == id) {
+                    Some(index) => index,
+                    None => continue,
+                };
+                let err = match result {
+                    Ok(()) => {
+                        queue.remove(index);
+                        continue;
+                    }
+                    Err(err) => err,
+                };
+                let delivery = &mut queue[index];
+                delivery.attempts += 1;
+                if delivery.attempts >= MAX_ATTEMPTS {
+                    log::warn!(
+                        "Dropping webhook delivery to {} after {} attempts {}",
+                        url,
+                        delivery.attempts,
+                        err
+                    );
+                    queue.remove(index);
+                    continue;
+                }
+                log::warn!("Cant deliver webhook to {} {}", url, err);
+                delivery.next_attempt = Utc::now() + retry_delay(delivery.attempts);
+                delivery.last_error = Some(err);
+            }
+        }
+        self.save().await;
+    }
+}
+
+/// Queues torrent events for the webhooks and delivers them
+pub async fn webhook_dispatcher(data: SharedData) {
+    let enqueue = async {
+        let events = data.events.subscribe(None);
+        futures_util::pin_mut!(events);
+        while let Some(event) = events.next().await {
+            data.webhooks.enqueue(&data, &event).await;
+        }
+    };
+    // Delivered on their own so slow webhooks don't hold up reading events
+    let deliver = async {
+        let mut interval = tokio::time::interval(Duration::from_millis(1000));
+        loop {
+            interval.tick().await;
+            data.webhooks.deliver_due(&data.http).await;
+        }
+    };
+    tokio::join!(enqueue, deliver);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_is_hex_hmac_sha256() {
+        assert_eq!(
+            signature("key", "The quick brown fox jumps over the lazy dog"),
+            "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_the_limit() {
+        assert_eq!(retry_delay(1), chrono::Duration::seconds(30));
+        assert_eq!(retry_delay(2), chrono::Duration::seconds(60));
+        assert_eq!(retry_delay(3), chrono::Duration::seconds(120));
+        assert_eq!(retry_delay(20), chrono::Duration::seconds(MAX_RETRY_SECS));
+        assert_eq!(
+            retry_delay(u32::MAX),
+            chrono::Duration::seconds(MAX_RETRY_SECS)
+        );
+    }
+}